@@ -0,0 +1,173 @@
+use serde_json::json;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::mock::format_timestamp;
+
+// Access log: one JSON line per request in ACCESS_LOG_FILE, when it's set, for
+// containers that write logs to a mounted volume rather than through a log driver.
+//   {"time": "...", "request_id": "...", "client": "ip=10.0.0.7", "method": "GET",
+//    "path": "/users", "status": 200, "duration_ms": 1.2}
+// Query strings are left out, since they can carry email addresses. Everything else is
+// still logged to stdout.
+// Once the file would grow past ACCESS_LOG_MAX_BYTES (10 MiB) it's renamed to `<file>.1`,
+// the one before that to `<file>.2` and so on, and a new file is started. The newest
+// ACCESS_LOG_KEEP (5) of the renamed files are kept.
+
+pub struct AccessLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    // The open file and its size; None until the first request, and after an error
+    file: Mutex<Option<(File, u64)>>,
+}
+
+impl AccessLog {
+    pub fn from_env() -> Option<AccessLog> {
+        let path = env::var("ACCESS_LOG_FILE")
+            .ok()
+            .filter(|path| !path.is_empty())?;
+        let max_bytes = env::var("ACCESS_LOG_MAX_BYTES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(10 * 1024 * 1024);
+        let keep = env::var("ACCESS_LOG_KEEP")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(5);
+        Some(AccessLog::new(path.into(), max_bytes, keep))
+    }
+
+    pub fn new(path: PathBuf, max_bytes: u64, keep: usize) -> AccessLog {
+        AccessLog {
+            path,
+            max_bytes,
+            keep,
+            file: Mutex::new(None),
+        }
+    }
+
+    // Writes the line for a finished request. Errors are logged, and the file is
+    // reopened for the next request.
+    pub fn record(
+        &self,
+        client: &str,
+        method: &str,
+        path: &str,
+        status: u16,
+        duration: Duration,
+        request_id: Option<&str>,
+    ) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = json!({
+            "time": format_timestamp(now.as_secs(), now.subsec_micros()),
+            "request_id": request_id,
+            "client": client,
+            "method": method,
+            "path": path,
+            "status": status,
+            "duration_ms": duration.as_micros() as f64 / 1000.0,
+        });
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = self.write(&mut file, format!("{}\n", line).as_bytes()) {
+            log!(
+                "Error writing the access log {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    fn write(&self, current: &mut Option<(File, u64)>, line: &[u8]) -> io::Result<()> {
+        let (mut file, mut size) = match current.take() {
+            Some(open) => open,
+            None => self.open()?,
+        };
+        let length = line.len() as u64;
+        if size > 0 && size + length > self.max_bytes {
+            drop(file);
+            self.rotate()?;
+            (file, size) = self.open()?;
+        }
+        file.write_all(line)?;
+        *current = Some((file, size + length));
+        Ok(())
+    }
+
+    fn open(&self) -> io::Result<(File, u64)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    // Moves the file to `<file>.1`, making room by moving each rotated file up one and
+    // deleting the oldest
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        let rotated = |number: usize| {
+            let mut path = OsString::from(&self.path);
+            path.push(format!(".{}", number));
+            PathBuf::from(path)
+        };
+        let ignore_missing = |result: io::Result<()>| match result {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        };
+        ignore_missing(fs::remove_file(rotated(self.keep)))?;
+        for number in (1..self.keep).rev() {
+            ignore_missing(fs::rename(rotated(number), rotated(number + 1)))?;
+        }
+        fs::rename(&self.path, rotated(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn full_files_are_rotated_and_old_ones_dropped() {
+        let dir = env::temp_dir().join(format!("access-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let log = AccessLog::new(path.clone(), 300, 2);
+        for status in 200..210 {
+            let duration = Duration::from_micros(1500);
+            log.record(
+                "ip=10.0.0.7",
+                "GET",
+                "/users",
+                status,
+                duration,
+                Some("abc"),
+            );
+        }
+
+        let mut files: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["access.log", "access.log.1", "access.log.2"]);
+        let contents = fs::read_to_string(&path).unwrap();
+        let last: Value = serde_json::from_str(contents.lines().last().unwrap()).unwrap();
+        assert_eq!(last["status"], 209);
+        assert_eq!(last["request_id"], "abc");
+        assert_eq!(last["duration_ms"], 1.5);
+        assert!(fs::metadata(&path).unwrap().len() <= 300);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::thread;
+
+use crate::errors::{error, ErrorCode};
+use crate::http::Response;
+use crate::server::{handle_client, refuse_client, AppState, Caller};
+
+// Identity of the process on the other end of a Unix socket, as reported by the kernel
+pub struct PeerCredentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+// Serves the API on the Unix socket at `path`. Callers are authenticated by their
+// peer UID instead of an API key: only UIDs listed in ADMIN_UIDS (by default root
+// and the user running the server) are accepted, and they are treated as admins.
+pub fn serve(path: &str, state: Arc<AppState>) -> io::Result<()> {
+    // A socket file left behind by a previous run would make bind fail. Anything else at
+    // the path is left alone: ADMIN_SOCKET is more likely wrong than the file unwanted.
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path),
+            ))
+        }
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    let allowed_uids = get_allowed_uids();
+    log!("Admin socket listening at {}", path);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let credentials = match peer_credentials(&stream) {
+                        Ok(credentials) => credentials,
+                        Err(e) => {
+                            log!("Error reading admin socket peer credentials: {}", e);
+                            continue;
+                        }
+                    };
+
+                    if !allowed_uids.contains(&credentials.uid) {
+                        log!(
+                            "Rejected admin socket connection from uid={} pid={}",
+                            credentials.uid, credentials.pid
+                        );
+                        let (status_line, body) = error(ErrorCode::Forbidden, "Forbidden");
+                        let response = Response::parse(&status_line, body);
+                        thread::spawn(move || refuse_client(stream, response));
+                        continue;
+                    }
+
+                    let state = Arc::clone(&state);
+                    let caller = Caller::LocalAdmin {
+                        uid: credentials.uid,
+                        gid: credentials.gid,
+                    };
+                    thread::spawn(move || handle_client(stream, &state, caller));
+                }
+                Err(e) => log!("Error: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// ADMIN_UIDS is a comma-separated list of UIDs allowed on the admin socket
+fn get_allowed_uids() -> Vec<u32> {
+    match env::var("ADMIN_UIDS") {
+        Ok(value) => value
+            .split(',')
+            .filter_map(|uid| uid.trim().parse().ok())
+            .collect(),
+        // SAFETY: getuid has no preconditions and cannot fail
+        Err(_) => vec![0, unsafe { libc::getuid() }],
+    }
+}
+
+pub fn peer_credentials(stream: &UnixStream) -> io::Result<PeerCredentials> {
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    // SAFETY: `credentials` and `length` are valid for writes and `length` holds the
+    // size of the buffer, as SO_PEERCRED requires
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(PeerCredentials {
+        pid: credentials.pid,
+        uid: credentials.uid,
+        gid: credentials.gid,
+    })
+}
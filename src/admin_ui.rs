@@ -0,0 +1,144 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::compression::Encoding;
+
+// The admin UI: a prebuilt static bundle in ADMIN_UI_DIR, served at /admin/ui/<file>
+// with its index.html at /admin/ui. The bundle has to be flat, every file directly in
+// ADMIN_UI_DIR. The files themselves are public; the API calls the UI makes need admin
+// credentials like any others.
+// Build tools can put `app.js.br` and `app.js.gz` next to `app.js`, compressed once at
+// the highest settings. Clients that accept one of those get it as it is, so the bundle
+// isn't compressed again on every request; other files are compressed like any response
+// (see `compression`).
+
+// Files precompressed in these encodings are looked for, in this order
+const PRECOMPRESSED: &[(Encoding, &str)] = &[(Encoding::Brotli, "br"), (Encoding::Gzip, "gz")];
+
+pub struct AdminUi {
+    dir: PathBuf,
+}
+
+pub struct Asset {
+    pub content_type: &'static str,
+    // How `body` is encoded when it was precompressed
+    pub encoding: Option<Encoding>,
+    pub body: Vec<u8>,
+}
+
+impl AdminUi {
+    pub fn from_env() -> Option<AdminUi> {
+        let dir = env::var("ADMIN_UI_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty())?;
+        Some(AdminUi { dir: dir.into() })
+    }
+
+    // The file for a path under /admin/ui, precompressed when there's a version in an
+    // encoding `accepts` allows. None when there's no such file.
+    pub fn asset(
+        &self,
+        path: &str,
+        accepts: impl Fn(Encoding) -> bool,
+    ) -> io::Result<Option<Asset>> {
+        let Some(name) = file_name(path) else {
+            return Ok(None);
+        };
+        let file = self.dir.join(name);
+        if !file.is_file() {
+            return Ok(None);
+        }
+        let content_type = content_type(name);
+        for &(encoding, extension) in PRECOMPRESSED {
+            if !accepts(encoding) {
+                continue;
+            }
+            match fs::read(self.dir.join(format!("{}.{}", name, extension))) {
+                Ok(body) => {
+                    return Ok(Some(Asset {
+                        content_type,
+                        encoding: Some(encoding),
+                        body,
+                    }))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Some(Asset {
+            content_type,
+            encoding: None,
+            body: fs::read(file)?,
+        }))
+    }
+}
+
+// The bundle file a path asks for. None for paths that could reach outside the
+// directory, hidden files and the precompressed copies themselves.
+fn file_name(path: &str) -> Option<&str> {
+    let name = match path.strip_prefix("/admin/ui")?.trim_start_matches('/') {
+        "" => "index.html",
+        name => name,
+    };
+    let precompressed = PRECOMPRESSED
+        .iter()
+        .any(|(_, extension)| name.ends_with(&format!(".{}", extension)));
+    (!name.starts_with('.') && !name.contains(['/', '\\']) && !precompressed).then_some(name)
+}
+
+fn content_type(name: &str) -> &'static str {
+    match name.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js" | "mjs") => "application/javascript",
+        Some("css") => "text/css",
+        Some("json" | "map") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precompressed_files_are_preferred() {
+        let dir = env::temp_dir().join(format!("admin-ui-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("index.html"), "<html>").unwrap();
+        fs::write(dir.join("app.js"), "plain").unwrap();
+        fs::write(dir.join("app.js.gz"), "gzipped").unwrap();
+        let ui = AdminUi { dir: dir.clone() };
+
+        let asset = ui.asset("/admin/ui/app.js", |_| true).unwrap().unwrap();
+        assert_eq!(
+            (asset.encoding, asset.body),
+            (Some(Encoding::Gzip), b"gzipped".to_vec())
+        );
+        assert_eq!(asset.content_type, "application/javascript");
+        let asset = ui.asset("/admin/ui/app.js", |_| false).unwrap().unwrap();
+        assert_eq!((asset.encoding, asset.body), (None, b"plain".to_vec()));
+        let asset = ui.asset("/admin/ui", |_| true).unwrap().unwrap();
+        assert_eq!(asset.content_type, "text/html; charset=utf-8");
+
+        for missing in [
+            "/admin/ui/app.js.gz",
+            "/admin/ui/..",
+            "/admin/ui/a/b",
+            "/admin/ui/x",
+        ] {
+            assert!(
+                ui.asset(missing, |_| true).unwrap().is_none(),
+                "{}",
+                missing
+            );
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
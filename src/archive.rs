@@ -0,0 +1,170 @@
+use postgres::{Client, Error as PostgresError, GenericClient};
+use serde_json::Value;
+use std::env;
+use std::time::Duration;
+
+use crate::audit;
+use crate::models::User;
+use crate::query_log::Timed;
+use crate::repo;
+
+// Cold storage for users nobody has touched in a long time. With ARCHIVE_INACTIVE_DAYS
+// set (it's off by default), a job moves users who haven't changed, and whose posts
+// haven't changed, in that many days out of `users` into `users_archive`, along with
+// their posts, notification preferences and profile. Soft-deleted users are archived
+// the same way. Archived users are gone from the API until an admin restores them
+// (POST /admin/archived-users/{id}/restore), with their IDs, timestamps and posts as
+// they were. Pending email changes aren't kept. Both moves are in the audit log, as
+// `archive` and `unarchive`.
+
+// How often the job looks for inactive users
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Users moved per transaction, so a first run over a large table doesn't hold locks
+// on all of it at once
+const BATCH_SIZE: i64 = 500;
+
+// Recorded as the actor of archival in the audit log
+const ACTOR: &str = "archiver";
+
+#[derive(Serialize)]
+pub struct ArchivedUser {
+    pub id: i32,
+    // The user as it was archived
+    pub user: Value,
+    pub posts: i64,
+    pub archived_at: String,
+}
+
+// Days without changes after which users are archived; None when it's turned off
+pub fn inactive_days_from_env() -> Option<i32> {
+    env::var("ARCHIVE_INACTIVE_DAYS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|&days| days > 0)
+}
+
+// The table, created by a migration (see `migrations`)
+pub(crate) const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS users_archive (
+    id INTEGER PRIMARY KEY,
+    user_data JSONB NOT NULL,
+    posts JSONB NOT NULL DEFAULT '[]',
+    notification_preferences JSONB,
+    archived_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+ALTER TABLE users_archive ADD COLUMN IF NOT EXISTS profile JSONB;";
+
+// Archives the users inactive for `days`, returning their IDs
+pub fn archive_inactive(client: &mut Client, days: i32) -> Result<Vec<i32>, PostgresError> {
+    let mut archived = Vec::new();
+    loop {
+        let batch = repo::with_transaction(client, |transaction| {
+            let ids: Vec<i32> = transaction
+                .timed_query(
+                    "SELECT u.id FROM users u
+                     WHERE u.updated_at < now() - make_interval(days => $1)
+                       AND NOT EXISTS (SELECT 1 FROM posts p WHERE p.user_id = u.id
+                                       AND p.updated_at >= now() - make_interval(days => $1))
+                     ORDER BY u.id LIMIT $2
+                     FOR UPDATE OF u SKIP LOCKED",
+                    &[&days, &BATCH_SIZE],
+                )?
+                .iter()
+                .map(|row| row.try_get(0))
+                .collect::<Result<_, _>>()?;
+            for &id in &ids {
+                let old = repo::find_user(transaction, id, true)?;
+                audit::record(transaction, ACTOR, "archive", id, old.as_ref(), None)?;
+            }
+            transaction.timed_execute(
+                "INSERT INTO users_archive (id, user_data, posts, notification_preferences, profile)
+                 SELECT u.id, to_jsonb(u) - 'search',
+                        coalesce((SELECT jsonb_agg(to_jsonb(p) ORDER BY p.id)
+                                  FROM posts p WHERE p.user_id = u.id), '[]'),
+                        (SELECT to_jsonb(n) FROM notification_preferences n WHERE n.user_id = u.id),
+                        (SELECT to_jsonb(pr) FROM profiles pr WHERE pr.user_id = u.id)
+                 FROM users u WHERE u.id = ANY($1)",
+                &[&ids],
+            )?;
+            // Posts and profiles are archived with the user, whatever the delete policies
+            // say; preferences, pending email changes and verifications go with it
+            transaction.timed_execute("DELETE FROM posts WHERE user_id = ANY($1)", &[&ids])?;
+            transaction.timed_execute("DELETE FROM profiles WHERE user_id = ANY($1)", &[&ids])?;
+            transaction.timed_execute("DELETE FROM users WHERE id = ANY($1)", &[&ids])?;
+            Ok::<_, PostgresError>(ids)
+        })?;
+        let done = (batch.len() as i64) < BATCH_SIZE;
+        archived.extend(batch);
+        if done {
+            return Ok(archived);
+        }
+    }
+}
+
+// Archived users, most recently archived first
+pub fn list(
+    client: &mut impl GenericClient,
+    limit: i64,
+) -> Result<Vec<ArchivedUser>, PostgresError> {
+    let rows = client.timed_query(
+        "SELECT id, user_data::text, jsonb_array_length(posts)::bigint,
+                to_json(archived_at) #>> '{}'
+         FROM users_archive ORDER BY archived_at DESC, id LIMIT $1",
+        &[&limit],
+    )?;
+    rows.iter()
+        .map(|row| {
+            let user: String = row.try_get(1)?;
+            Ok(ArchivedUser {
+                id: row.try_get(0)?,
+                user: serde_json::from_str(&user).unwrap_or(Value::Null),
+                posts: row.try_get(2)?,
+                archived_at: row.try_get(3)?,
+            })
+        })
+        .collect()
+}
+
+// Moves an archived user back into `users` with their posts, preferences and profile, returning
+// the user; None if there's no archived user with the ID
+pub fn restore(
+    client: &mut impl GenericClient,
+    actor: &str,
+    id: i32,
+) -> Result<Option<User>, PostgresError> {
+    let Some(row) = client.timed_query_opt(
+        "DELETE FROM users_archive WHERE id = $1
+         RETURNING user_data::text, posts::text, notification_preferences::text, profile::text",
+        &[&id],
+    )?
+    else {
+        return Ok(None);
+    };
+    let (user, posts, preferences, profile): (String, String, Option<String>, Option<String>) =
+        (row.try_get(0)?, row.try_get(1)?, row.try_get(2)?, row.try_get(3)?);
+    client.timed_execute(
+        "INSERT INTO users (id, name, email, deleted_at, created_at, updated_at, version, verified)
+         SELECT id, name, email, deleted_at, created_at, updated_at, version, coalesce(verified, true)
+         FROM jsonb_populate_record(NULL::users, $1::text::jsonb)",
+        &[&user],
+    )?;
+    client.timed_execute(
+        "INSERT INTO posts SELECT * FROM jsonb_populate_recordset(NULL::posts, $1::text::jsonb)",
+        &[&posts],
+    )?;
+    client.timed_execute(
+        "INSERT INTO notification_preferences
+         SELECT * FROM jsonb_populate_record(NULL::notification_preferences, $1::text::jsonb)
+         WHERE $1 IS NOT NULL",
+        &[&preferences],
+    )?;
+    client.timed_execute(
+        "INSERT INTO profiles
+         SELECT * FROM jsonb_populate_record(NULL::profiles, $1::text::jsonb)
+         WHERE $1 IS NOT NULL",
+        &[&profile],
+    )?;
+    let user = repo::find_user(client, id, true)?;
+    audit::record(client, actor, "unarchive", id, None, user.as_ref())?;
+    Ok(user)
+}
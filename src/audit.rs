@@ -0,0 +1,144 @@
+use postgres::{Error as PostgresError, GenericClient, Row};
+use serde_json::Value;
+
+use crate::events;
+use crate::impersonation;
+use crate::models::User;
+use crate::query_log::Timed;
+use crate::repo;
+use crate::webhooks;
+
+// Audit trail of mutations to users. Entries are written with the same client
+// as the mutation, so callers record them inside the mutation's transaction and
+// an entry exists exactly when the change was committed. Changes made under an
+// impersonation session say so in their details (see `impersonation`). Recording a
+// change also stages its event for live subscribers, notifies other instances of it
+// (see `events`) and queues its webhook deliveries (see `webhooks`).
+
+#[derive(Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    // Who made the change, e.g. `ip=10.0.0.7`, `uid=0 gid=0` or `scim`
+    pub actor: String,
+    // create, update, delete, restore, impersonate or stop_impersonation
+    pub action: String,
+    pub entity_id: i32,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+    pub created_at: String,
+}
+
+pub fn record(
+    client: &mut impl GenericClient,
+    actor: &str,
+    action: &str,
+    entity_id: i32,
+    old: Option<&User>,
+    new: Option<&User>,
+) -> Result<(), PostgresError> {
+    record_with_details(client, actor, action, entity_id, old, new, None)
+}
+
+// Like `record`, with extra context about the change (e.g. policy decisions)
+pub fn record_with_details(
+    client: &mut impl GenericClient,
+    actor: &str,
+    action: &str,
+    entity_id: i32,
+    old: Option<&User>,
+    new: Option<&User>,
+    details: Option<&Value>,
+) -> Result<(), PostgresError> {
+    events::stage(action, entity_id, old, new);
+    events::notify(client, action, entity_id, old, new)?;
+    webhooks::enqueue(client, action, entity_id, old, new)?;
+    let to_json = |user: Option<&User>| user.and_then(|user| serde_json::to_string(user).ok());
+    let details = impersonation::flag(details).as_ref().map(Value::to_string);
+    client.timed_execute(
+        "INSERT INTO audit_log (actor, action, entity_id, old_data, new_data, details)
+         VALUES ($1, $2, $3, $4::text::jsonb, $5::text::jsonb, $6::text::jsonb)",
+        &[
+            &actor,
+            &action,
+            &entity_id,
+            &to_json(old),
+            &to_json(new),
+            &details,
+        ],
+    )?;
+    Ok(())
+}
+
+// All entries for one user, oldest first
+pub fn history(
+    client: &mut impl GenericClient,
+    entity_id: i32,
+) -> Result<Vec<AuditEntry>, PostgresError> {
+    let query = format!("SELECT {} FROM audit_log WHERE entity_id = $1 ORDER BY id", ENTRY_COLUMNS);
+    let rows = client.timed_query(&query, &[&entity_id])?;
+    rows.iter().map(entry_from_row).collect()
+}
+
+// One entry by its ID, e.g. to undo it
+pub fn entry(client: &mut impl GenericClient, id: i64) -> Result<Option<AuditEntry>, PostgresError> {
+    let query = format!("SELECT {} FROM audit_log WHERE id = $1", ENTRY_COLUMNS);
+    let row = client.timed_query_opt(&query, &[&id])?;
+    row.as_ref().map(entry_from_row).transpose()
+}
+
+const ENTRY_COLUMNS: &str = "id, actor, action, entity_id, old_data::text, new_data::text,
+    details::text, to_json(created_at) #>> '{}'";
+
+fn entry_from_row(row: &Row) -> Result<AuditEntry, PostgresError> {
+    let parse = |text: Option<String>| text.and_then(|text| serde_json::from_str(&text).ok());
+    Ok(AuditEntry {
+        id: row.try_get(0)?,
+        actor: row.try_get(1)?,
+        action: row.try_get(2)?,
+        entity_id: row.try_get(3)?,
+        old: parse(row.try_get(4)?),
+        new: parse(row.try_get(5)?),
+        details: parse(row.try_get(6)?),
+        created_at: row.try_get(7)?,
+    })
+}
+
+// The user as it was at `as_of` (any timestamp Postgres understands), rebuilt from its
+// entries: the state the last change before then left it in or, failing that, the one
+// the first change after found. Users with no entries (from before the audit log) are
+// taken as they are now, from their creation on. None if the user didn't exist, or was
+// archived, at the time. Soft-deleted users come back with their `deleted_at`.
+pub fn state_at(
+    client: &mut impl GenericClient,
+    entity_id: i32,
+    as_of: &str,
+) -> Result<Option<Value>, PostgresError> {
+    // Entries without states, like impersonation, don't change the user
+    let row = client.timed_query_opt(
+        "SELECT state::text, rank FROM (
+             (SELECT new_data AS state, 0 AS rank FROM audit_log
+              WHERE entity_id = $1 AND created_at <= $2::text::timestamptz
+                AND num_nonnulls(old_data, new_data) > 0
+              ORDER BY created_at DESC, id DESC LIMIT 1)
+             UNION ALL
+             (SELECT old_data, 1 FROM audit_log
+              WHERE entity_id = $1 AND created_at > $2::text::timestamptz
+                AND num_nonnulls(old_data, new_data) > 0
+              ORDER BY created_at, id LIMIT 1)
+             UNION ALL
+             (SELECT NULL, 2 FROM users WHERE id = $1 AND created_at <= $2::text::timestamptz)
+         ) AS known ORDER BY rank LIMIT 1",
+        &[&entity_id, &as_of],
+    )?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    if row.try_get::<_, i32>(1)? == 2 {
+        let user = repo::find_user(client, entity_id, true)?;
+        return Ok(user.and_then(|user| serde_json::to_value(user).ok()));
+    }
+    let state: Option<String> = row.try_get(0)?;
+    Ok(state.and_then(|state| serde_json::from_str(&state).ok()))
+}
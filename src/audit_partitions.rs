@@ -0,0 +1,218 @@
+use postgres::{Client, Error as PostgresError, GenericClient};
+use std::env;
+use std::time::Duration;
+
+use crate::query_log::Timed;
+
+// The audit log is partitioned by month of `created_at`, so old entries can be dropped
+// a whole partition at a time instead of with a DELETE that bloats the table. A job
+// keeps partitions for the current month and the next PARTITIONS_AHEAD ready, and
+// with AUDIT_RETENTION_MONTHS set (it's off by default) drops the partitions older
+// than that many months. Partitions are named `audit_log_y2026m10` and cover whole
+// months in UTC. Entries that somehow find no partition of their own land in
+// `audit_log_default`, and are moved out when their month's partition is created.
+// Databases with an audit log from before partitioning are converted on startup.
+
+const PARTITIONS_AHEAD: i32 = 2;
+
+// How often the job runs; hourly is plenty when partitions are made months ahead
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const CREATE_TABLE: &str = "CREATE TABLE audit_log (
+        id BIGSERIAL,
+        actor TEXT NOT NULL,
+        action TEXT NOT NULL,
+        entity_id INTEGER NOT NULL,
+        old_data JSONB,
+        new_data JSONB,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        details JSONB,
+        PRIMARY KEY (id, created_at)
+    ) PARTITION BY RANGE (created_at);
+    CREATE INDEX audit_log_entity_id ON audit_log (entity_id);
+    CREATE TABLE audit_log_default PARTITION OF audit_log DEFAULT;";
+
+// Months to keep partitions for; None keeps them forever
+pub fn retention_months_from_env() -> Option<i32> {
+    env::var("AUDIT_RETENTION_MONTHS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|&months| months > 0)
+}
+
+// Creates the partitioned audit log, or converts an unpartitioned one by copying its
+// entries into partitions, then makes sure this month's partitions exist
+pub fn migrate(client: &mut Client) -> Result<(), PostgresError> {
+    let mut transaction = client.transaction()?;
+    // Replicas starting together take turns, so only the first converts
+    transaction.timed_execute(
+        "SELECT pg_advisory_xact_lock(hashtext('audit_log partitions'))",
+        &[],
+    )?;
+    let kind: Option<i8> = transaction
+        .timed_query_one(
+            "SELECT (SELECT relkind FROM pg_class WHERE oid = to_regclass('audit_log'))",
+            &[],
+        )?
+        .try_get(0)?;
+    match kind.map(|kind| kind as u8) {
+        None => transaction.batch_execute(CREATE_TABLE)?,
+        Some(b'p') => {}
+        Some(_) => {
+            transaction.batch_execute(
+                "ALTER TABLE audit_log ADD COLUMN IF NOT EXISTS details JSONB;
+                 ALTER TABLE audit_log RENAME TO audit_log_unpartitioned;
+                 ALTER TABLE audit_log_unpartitioned
+                     RENAME CONSTRAINT audit_log_pkey TO audit_log_unpartitioned_pkey;
+                 ALTER INDEX IF EXISTS audit_log_entity_id RENAME TO audit_log_unpartitioned_entity_id;
+                 ALTER SEQUENCE audit_log_id_seq RENAME TO audit_log_unpartitioned_id_seq;",
+            )?;
+            transaction.batch_execute(CREATE_TABLE)?;
+            let first: Option<String> = transaction
+                .timed_query_one(
+                    "SELECT to_char(min(created_at) AT TIME ZONE 'UTC', 'YYYY-MM-DD')
+                     FROM audit_log_unpartitioned",
+                    &[],
+                )?
+                .try_get(0)?;
+            ensure_partitions(&mut transaction, first.as_deref())?;
+            let copied = transaction.timed_execute(
+                "INSERT INTO audit_log (id, actor, action, entity_id, old_data, new_data, created_at, details)
+                 SELECT id, actor, action, entity_id, old_data, new_data, created_at, details
+                 FROM audit_log_unpartitioned",
+                &[],
+            )?;
+            transaction.batch_execute(
+                "SELECT setval(pg_get_serial_sequence('audit_log', 'id'), max(id))
+                 FROM audit_log HAVING max(id) IS NOT NULL;
+                 DROP TABLE audit_log_unpartitioned;",
+            )?;
+            log!("Partitioned the audit log by month ({} entries)", copied);
+        }
+    }
+    ensure_partitions(&mut transaction, None)?;
+    transaction.commit()
+}
+
+// Creates the monthly partitions from the month of `since` (YYYY-MM-DD; this month
+// when None) until PARTITIONS_AHEAD months from now that don't exist yet, moving any
+// entries for them out of the default partition. Returns the partitions created.
+pub fn ensure_partitions(
+    client: &mut impl GenericClient,
+    since: Option<&str>,
+) -> Result<Vec<String>, PostgresError> {
+    let rows = client.timed_query(
+        "SELECT 'audit_log_' || to_char(m, '\"y\"YYYY\"m\"MM'),
+                to_char(m, 'YYYY-MM-DD'), to_char(m + interval '1 month', 'YYYY-MM-DD')
+         FROM generate_series(
+             date_trunc('month', coalesce($1::text::date::timestamp, now() AT TIME ZONE 'UTC')),
+             date_trunc('month', now() AT TIME ZONE 'UTC') + make_interval(months => $2),
+             interval '1 month') m
+         WHERE to_regclass('audit_log_' || to_char(m, '\"y\"YYYY\"m\"MM')) IS NULL",
+        &[&since, &PARTITIONS_AHEAD],
+    )?;
+    let mut created = Vec::new();
+    for row in &rows {
+        let name: String = row.try_get(0)?;
+        let from: String = row.try_get(1)?;
+        let to: String = row.try_get(2)?;
+        let mut transaction = client.transaction()?;
+        // The names and bounds come from the query above, never from a request
+        transaction.batch_execute(&format!(
+            "CREATE TABLE {name} (LIKE audit_log);
+             WITH moved AS (
+                 DELETE FROM audit_log_default
+                 WHERE created_at >= '{from} 00:00+00' AND created_at < '{to} 00:00+00'
+                 RETURNING *)
+             INSERT INTO {name} SELECT * FROM moved;
+             ALTER TABLE audit_log ATTACH PARTITION {name}
+                 FOR VALUES FROM ('{from} 00:00+00') TO ('{to} 00:00+00');"
+        ))?;
+        transaction.commit()?;
+        created.push(name);
+    }
+    Ok(created)
+}
+
+// Drops the partitions (and deletes the entries in the default partition) from before
+// the last `retention` whole months. Returns the partitions dropped.
+pub fn drop_expired(
+    client: &mut impl GenericClient,
+    retention: i32,
+) -> Result<Vec<String>, PostgresError> {
+    let row = client.timed_query_one(
+        "SELECT extract(year FROM now() AT TIME ZONE 'UTC')::int,
+                extract(month FROM now() AT TIME ZONE 'UTC')::int",
+        &[],
+    )?;
+    let current = month_index(row.try_get(0)?, row.try_get(1)?);
+    let partitions = client.timed_query(
+        "SELECT c.relname::text FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid
+         WHERE i.inhparent = to_regclass('audit_log') ORDER BY c.relname",
+        &[],
+    )?;
+    let mut dropped = Vec::new();
+    for row in &partitions {
+        let name: String = row.try_get(0)?;
+        if partition_month(&name).is_some_and(|month| month < current - retention) {
+            client.batch_execute(&format!("DROP TABLE IF EXISTS {}", name))?;
+            dropped.push(name);
+        }
+    }
+    client.timed_execute(
+        "DELETE FROM audit_log_default
+         WHERE created_at < (date_trunc('month', now() AT TIME ZONE 'UTC')
+                             - make_interval(months => $1)) AT TIME ZONE 'UTC'",
+        &[&retention],
+    )?;
+    Ok(dropped)
+}
+
+// The scheduled job: partitions ahead, and expired ones dropped with a retention
+pub fn maintain(client: &mut Client, retention: Option<i32>) -> Result<(), PostgresError> {
+    let created = ensure_partitions(client, None)?;
+    if !created.is_empty() {
+        log!("Created audit log partitions {}", created.join(", "));
+    }
+    if let Some(retention) = retention {
+        let dropped = drop_expired(client, retention)?;
+        if !dropped.is_empty() {
+            log!(
+                "Dropped expired audit log partitions {}",
+                dropped.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+// Months since year 0, so months can be compared and subtracted
+fn month_index(year: i32, month: i32) -> i32 {
+    year * 12 + month - 1
+}
+
+// The month a partition like `audit_log_y2026m10` holds; None for the default one
+fn partition_month(name: &str) -> Option<i32> {
+    let (year, month) = name.strip_prefix("audit_log_y")?.split_once('m')?;
+    let (year, month) = (year.parse().ok()?, month.parse().ok()?);
+    (1..=12).contains(&month).then(|| month_index(year, month))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partitions_are_named_after_their_month() {
+        assert_eq!(
+            partition_month("audit_log_y2026m10"),
+            Some(month_index(2026, 10))
+        );
+        assert_eq!(
+            partition_month("audit_log_y2026m01"),
+            Some(month_index(2025, 12) + 1)
+        );
+        assert_eq!(partition_month("audit_log_default"), None);
+        assert_eq!(partition_month("audit_log_y2026m13"), None);
+    }
+}
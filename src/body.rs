@@ -0,0 +1,89 @@
+use serde::de::DeserializeOwned;
+use std::env;
+use std::sync::OnceLock;
+
+use crate::errors::{AppError, ErrorCode};
+
+// Request bodies. Bodies longer than MAX_BODY_BYTES (1 MiB) are turned away with a 413
+// before they're read (see `server`). JSON bodies are parsed with `parse`, whose 400s
+// say where in the body the problem is, e.g.
+//   Invalid body at posts[2].title: invalid type: integer `5`, expected a string at line 1 column 40
+// Fields a body type doesn't have are ignored, unless STRICT_JSON=on, when they're
+// rejected (like serde's `deny_unknown_fields`) so typos in field names don't pass
+// silently.
+
+const DEFAULT_MAX_BYTES: usize = 1024 * 1024;
+
+static STRICT: OnceLock<bool> = OnceLock::new();
+
+pub fn max_bytes_from_env() -> usize {
+    env::var("MAX_BODY_BYTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MAX_BYTES)
+}
+
+fn strict() -> bool {
+    *STRICT.get_or_init(|| matches!(env::var("STRICT_JSON").as_deref(), Ok("on" | "true" | "1")))
+}
+
+// The body of a request: everything after the headers
+pub fn text(request: &str) -> &str {
+    request.split_once("\r\n\r\n").map(|(_, body)| body).unwrap_or_default()
+}
+
+// Deserializes the request's JSON body
+pub fn parse<T: DeserializeOwned>(request: &str) -> Result<T, AppError> {
+    parse_json(text(request), strict()).map_err(|message| AppError::Validation(ErrorCode::InvalidBody, message))
+}
+
+// Err with the message for a 400: what's wrong, and where
+fn parse_json<T: DeserializeOwned>(body: &str, strict: bool) -> Result<T, String> {
+    let mut deserializer = serde_json::Deserializer::from_str(body);
+    let mut track = serde_path_to_error::Track::new();
+    let mut unknown = Vec::new();
+    let tracked = serde_path_to_error::Deserializer::new(&mut deserializer, &mut track);
+    let value = serde_ignored::deserialize(tracked, |path| unknown.push(path.to_string()))
+        .and_then(|value| deserializer.end().map(|()| value))
+        .map_err(|e| match track.path().to_string() {
+            path if path == "." => format!("Invalid body: {}", e),
+            path => format!("Invalid body at {}: {}", path, e),
+        })?;
+    match unknown.first() {
+        Some(field) if strict => Err(format!("Invalid body: unknown field {}", field)),
+        _ => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::User;
+
+    #[test]
+    fn errors_say_where_the_body_is_wrong() {
+        let error = parse_json::<User>(r#"{"name": "Ada", "email": 5}"#, false).unwrap_err();
+        assert!(error.starts_with("Invalid body at email: invalid type: integer `5`"), "{}", error);
+
+        let error = parse_json::<Vec<User>>(r#"[{"name": "Ada", "email": "a@b.c"}, {"name": true}]"#, false).unwrap_err();
+        assert!(error.starts_with("Invalid body at [1].name: "), "{}", error);
+
+        let error = parse_json::<User>("not json", false).unwrap_err();
+        assert!(error.starts_with("Invalid body: expected"), "{}", error);
+        assert!(parse_json::<User>(r#"{"name": "Ada", "email": "a@b.c"} {}"#, false).is_err());
+    }
+
+    #[test]
+    fn unknown_fields_are_only_rejected_when_strict() {
+        let body = r#"{"name": "Ada", "email": "a@b.c", "emial": "typo@b.c"}"#;
+        assert_eq!(parse_json::<User>(body, false).unwrap().email, "a@b.c");
+        assert_eq!(parse_json::<User>(body, true).unwrap_err(), "Invalid body: unknown field emial");
+        assert!(parse_json::<User>(r#"{"name": "Ada", "email": "a@b.c"}"#, true).is_ok());
+    }
+
+    #[test]
+    fn the_body_starts_after_the_first_blank_line() {
+        assert_eq!(text("POST /users HTTP/1.1\r\nHost: x\r\n\r\n{\"a\":\r\n\r\n1}"), "{\"a\":\r\n\r\n1}");
+        assert_eq!(text("GET /users HTTP/1.1\r\n"), "");
+    }
+}
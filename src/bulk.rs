@@ -0,0 +1,161 @@
+use crate::repo::{Field, Op, Predicate};
+use crate::models::User;
+
+// Parsing for PATCH /users?filter=...&confirm=true, the bulk update endpoint.
+// Filters are one or more `field op value` conditions joined with `and`, e.g.
+//   email_domain eq "old-corp.com"
+//   name sw "Test " and email ew "@example.com"
+// Fields: name, email, email_domain. Operators: eq, ne, sw (starts with),
+// ew (ends with), co (contains). Values may be double-quoted to include spaces.
+
+// Changes applied to every matched user. Setting one email on many users makes
+// no sense, so emails can only be moved to another domain.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BulkPatch {
+    pub name: Option<String>,
+    pub email_domain: Option<String>,
+}
+
+impl BulkPatch {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.email_domain.is_none()
+    }
+
+    // The user's new (name, email)
+    pub fn apply(&self, user: &User) -> (String, String) {
+        let name = self.name.clone().unwrap_or_else(|| user.name.clone());
+        let email = match (&self.email_domain, user.email.rsplit_once('@')) {
+            (Some(domain), Some((local, _))) => format!("{}@{}", local, domain),
+            _ => user.email.clone(),
+        };
+        (name, email)
+    }
+}
+
+pub fn parse_filter(filter: &str) -> Result<Vec<Predicate>, String> {
+    let tokens = tokenize(filter)?;
+    let mut predicates = Vec::new();
+    let mut tokens = tokens.iter();
+    loop {
+        let (field, op, value) = match (tokens.next(), tokens.next(), tokens.next()) {
+            (Some(field), Some(op), Some(value)) => (field, op, value),
+            _ => return Err(format!("Expected `field op value` in filter: {}", filter)),
+        };
+        let field = match field.to_lowercase().as_str() {
+            "name" => Field::Name,
+            "email" => Field::Email,
+            "email_domain" => Field::EmailDomain,
+            other => return Err(format!("Unknown filter field: {}", other)),
+        };
+        let op = match op.to_lowercase().as_str() {
+            "eq" => Op::Eq,
+            "ne" => Op::Ne,
+            "sw" => Op::StartsWith,
+            "ew" => Op::EndsWith,
+            "co" => Op::Contains,
+            other => return Err(format!("Unknown filter operator: {}", other)),
+        };
+        predicates.push(Predicate {
+            field,
+            op,
+            value: value.clone(),
+        });
+
+        match tokens.next() {
+            None => return Ok(predicates),
+            Some(and) if and.eq_ignore_ascii_case("and") => continue,
+            Some(other) => return Err(format!("Expected `and` in filter, found {}", other)),
+        }
+    }
+}
+
+// Splits on whitespace, keeping double-quoted values (with `\"` escapes) together
+fn tokenize(filter: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = filter.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => value.extend(chars.next()),
+                    Some(c) => value.push(c),
+                    None => return Err("Unterminated quote in filter".to_string()),
+                }
+            }
+            tokens.push(value);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_are_parsed_into_predicates() {
+        let predicates =
+            parse_filter(r#"name sw "Test \"A\"" AND email_domain eq old.com"#).unwrap();
+        assert_eq!(
+            predicates,
+            vec![
+                Predicate {
+                    field: Field::Name,
+                    op: Op::StartsWith,
+                    value: "Test \"A\"".to_string()
+                },
+                Predicate {
+                    field: Field::EmailDomain,
+                    op: Op::Eq,
+                    value: "old.com".to_string()
+                },
+            ]
+        );
+
+        assert!(parse_filter("").is_err());
+        assert!(parse_filter("email eq").is_err());
+        assert!(parse_filter("id eq 5").is_err());
+        assert!(parse_filter("name like x").is_err());
+        assert!(parse_filter("name eq a or name eq b").is_err());
+        assert!(parse_filter("name eq \"open").is_err());
+    }
+
+    #[test]
+    fn domain_change_keeps_the_local_part() {
+        let user = User {
+            id: Some(1),
+            name: "Ada".to_string(),
+            email: "ada@old.com".to_string(),
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+            version: Some(1),
+            verified: None,
+        };
+        let patch = BulkPatch {
+            email_domain: Some("new.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            patch.apply(&user),
+            ("Ada".to_string(), "ada@new.com".to_string())
+        );
+        assert!(BulkPatch::default().is_empty());
+    }
+}
@@ -0,0 +1,228 @@
+use std::env;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::mock::Rng;
+
+// Chaos mode: artificial latency and errors on chosen routes, so client teams can check
+// their timeouts and retries against this server. It's on with `serve --mock` or
+// CHAOS=on, and starts without rules; they're replaced through PUT /admin/chaos:
+//   {"rules": [{"route": "GET /users/*", "latency_ms": 2000, "error_rate": 0.25}]}
+// A route is a method (`*` for any) and a path whose `*` segments match any one segment;
+// a trailing `**` matches the rest of the path. The first matching rule applies. Its
+// latency is added before the request is handled, and `error_rate` of the requests get
+// `error_status` (503 by default) instead of a real response.
+
+// Statuses a rule may inject
+const ERROR_STATUSES: &[(u16, &str)] = &[
+    (408, "REQUEST TIMEOUT"),
+    (429, "TOO MANY REQUESTS"),
+    (500, "INTERNAL SERVER ERROR"),
+    (502, "BAD GATEWAY"),
+    (503, "SERVICE UNAVAILABLE"),
+    (504, "GATEWAY TIMEOUT"),
+];
+
+// Longest latency a rule may add, so a typo can't hang clients for hours
+const MAX_LATENCY_MS: u64 = 5 * 60 * 1000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub route: String,
+    #[serde(default)]
+    pub latency_ms: u64,
+    // Share of matching requests that fail, from 0 to 1
+    #[serde(default)]
+    pub error_rate: f64,
+    #[serde(default = "default_error_status")]
+    pub error_status: u16,
+}
+
+fn default_error_status() -> u16 {
+    503
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Rules {
+    pub rules: Vec<Rule>,
+}
+
+// What to do to a request
+#[derive(Debug, PartialEq)]
+pub struct Fault {
+    pub latency: Duration,
+    // Status line to answer with instead of handling the request
+    pub error: Option<String>,
+}
+
+pub struct Chaos {
+    rules: RwLock<Vec<Rule>>,
+    rng: Mutex<Rng>,
+}
+
+impl Chaos {
+    // Chaos mode is always on for mock servers, and opt-in otherwise
+    pub fn from_env(mock: bool) -> Option<Chaos> {
+        let enabled = mock
+            || matches!(
+                env::var("CHAOS").as_deref(),
+                Ok("on") | Ok("true") | Ok("1")
+            );
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or_default();
+        enabled.then(|| Chaos {
+            rules: RwLock::new(Vec::new()),
+            rng: Mutex::new(Rng(seed)),
+        })
+    }
+
+    pub fn rules(&self) -> Rules {
+        Rules {
+            rules: self.rules.read().unwrap_or_else(|e| e.into_inner()).clone(),
+        }
+    }
+
+    // Returns a message suitable for the client when a rule is invalid
+    pub fn set_rules(&self, rules: Rules) -> Result<(), String> {
+        for rule in &rules.rules {
+            validate(rule)?;
+        }
+        *self.rules.write().unwrap_or_else(|e| e.into_inner()) = rules.rules;
+        Ok(())
+    }
+
+    // The fault to inject into a request, if a rule matches it.
+    // The chaos endpoint itself is exempt so rules can always be changed back.
+    pub fn fault(&self, method: &str, path: &str) -> Option<Fault> {
+        if path == "/admin/chaos" {
+            return None;
+        }
+        let rules = self.rules.read().unwrap_or_else(|e| e.into_inner());
+        let rule = rules
+            .iter()
+            .find(|rule| matches(&rule.route, method, path))?;
+        let fails = rule.error_rate > 0.0
+            && (self.rng.lock().unwrap_or_else(|e| e.into_inner()).below(1_000_000) as f64) < rule.error_rate * 1_000_000.0;
+        Some(Fault {
+            latency: Duration::from_millis(rule.latency_ms),
+            error: fails.then(|| status_line(rule.error_status)),
+        })
+    }
+}
+
+fn validate(rule: &Rule) -> Result<(), String> {
+    if rule.route.split_whitespace().count() != 2 {
+        return Err(format!(
+            "Route '{}' must look like 'GET /users/*'",
+            rule.route
+        ));
+    }
+    if rule.latency_ms > MAX_LATENCY_MS {
+        return Err(format!("latency_ms may be at most {}", MAX_LATENCY_MS));
+    }
+    if !(0.0..=1.0).contains(&rule.error_rate) {
+        return Err("error_rate must be between 0 and 1".to_string());
+    }
+    if !ERROR_STATUSES
+        .iter()
+        .any(|(status, _)| *status == rule.error_status)
+    {
+        let statuses: Vec<String> = ERROR_STATUSES
+            .iter()
+            .map(|(status, _)| status.to_string())
+            .collect();
+        return Err(format!(
+            "error_status must be one of {}",
+            statuses.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+fn matches(route: &str, method: &str, path: &str) -> bool {
+    let (route_method, route_path) = route.split_once(' ').unwrap_or_default();
+    if route_method != "*" && !route_method.eq_ignore_ascii_case(method) {
+        return false;
+    }
+    let mut segments = path.trim_matches('/').split('/');
+    for pattern in route_path.trim().trim_matches('/').split('/') {
+        if pattern == "**" {
+            return true;
+        }
+        match segments.next() {
+            Some(segment) if pattern == "*" || pattern == segment => {}
+            _ => return false,
+        }
+    }
+    segments.next().is_none()
+}
+
+fn status_line(status: u16) -> String {
+    let reason = ERROR_STATUSES
+        .iter()
+        .find(|(known, _)| *known == status)
+        .map(|(_, reason)| *reason)
+        .unwrap_or_default();
+    format!("HTTP/1.1 {} {}\r\n", status, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(route: &str, error_rate: f64) -> Rule {
+        Rule {
+            route: route.to_string(),
+            latency_ms: 100,
+            error_rate,
+            error_status: 503,
+        }
+    }
+
+    #[test]
+    fn routes_match_by_method_and_path_segments() {
+        assert!(matches("GET /users/*", "GET", "/users/7"));
+        assert!(!matches("GET /users/*", "GET", "/users"));
+        assert!(!matches("GET /users/*", "GET", "/users/7/audit"));
+        assert!(!matches("GET /users/*", "PUT", "/users/7"));
+        assert!(matches("* /users/*/audit", "GET", "/users/7/audit"));
+        assert!(matches("* /users/**", "DELETE", "/users/7/audit"));
+        assert!(matches("get /users", "GET", "/users"));
+    }
+
+    #[test]
+    fn the_first_matching_rule_applies() {
+        let chaos = Chaos::from_env(true).unwrap();
+        assert_eq!(chaos.fault("GET", "/users"), None);
+
+        let rules = Rules {
+            rules: vec![rule("GET /users", 1.0), rule("* /**", 0.0)],
+        };
+        chaos.set_rules(rules).unwrap();
+        let fault = chaos.fault("GET", "/users").unwrap();
+        assert_eq!(fault.latency, Duration::from_millis(100));
+        assert_eq!(
+            fault.error.as_deref(),
+            Some("HTTP/1.1 503 SERVICE UNAVAILABLE\r\n")
+        );
+        assert_eq!(chaos.fault("POST", "/users").unwrap().error, None);
+        assert_eq!(chaos.fault("PUT", "/admin/chaos"), None);
+
+        let mut invalid = rule("GET /users", 1.5);
+        assert!(chaos
+            .set_rules(Rules {
+                rules: vec![invalid.clone()]
+            })
+            .is_err());
+        invalid.error_rate = 0.5;
+        invalid.error_status = 418;
+        assert!(chaos
+            .set_rules(Rules {
+                rules: vec![invalid]
+            })
+            .is_err());
+        assert_eq!(chaos.rules().rules.len(), 2);
+    }
+}
@@ -0,0 +1,89 @@
+use postgres::Client;
+use sha2::{Digest, Sha256};
+
+use crate::errors::AppError;
+use crate::models::User;
+use crate::repo::{self, UserFilter};
+
+// Checksum of the user table for GET /users/checksum, so sync consumers can check
+// they're in step without fetching everything. It's the SHA-256 (hex) of one line per
+// matching user, in ID order:
+//   <id>\t<name>\t<email>\n
+// with names and emails exactly as stored. Rows are read in batches, so memory use
+// stays flat however many users match.
+
+const BATCH_SIZE: i32 = 1000;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Summary {
+    pub count: u64,
+    pub sha256: String,
+}
+
+#[derive(Default)]
+pub struct Checksum {
+    count: u64,
+    digest: Sha256,
+}
+
+impl Checksum {
+    // Users must be added in ID order
+    pub fn add(&mut self, user: &User) {
+        self.count += 1;
+        let line = format!("{}\t{}\t{}\n", user.id.unwrap_or_default(), user.name, user.email);
+        self.digest.update(line.as_bytes());
+    }
+
+    pub fn finish(self) -> Summary {
+        Summary {
+            count: self.count,
+            sha256: self.digest.finalize().iter().map(|byte| format!("{:02x}", byte)).collect(),
+        }
+    }
+}
+
+pub fn of_users(client: &mut Client, filter: &UserFilter) -> Result<Summary, AppError> {
+    let mut transaction = client.transaction()?;
+    let mut checksum = Checksum::default();
+    repo::for_each_user_batch(&mut transaction, filter, BATCH_SIZE, |users| {
+        users.iter().for_each(|user| checksum.add(user));
+        Ok::<_, AppError>(())
+    })?;
+    Ok(checksum.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: i32, name: &str, email: &str) -> User {
+        let mut user = User::new(name, email);
+        user.id = Some(id);
+        user
+    }
+
+    #[test]
+    fn checksums_cover_ids_names_and_emails() {
+        assert_eq!(
+            Checksum::default().finish(),
+            Summary {
+                count: 0,
+                sha256: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string(),
+            }
+        );
+
+        let checksum = |users: &[User]| {
+            let mut checksum = Checksum::default();
+            users.iter().for_each(|user| checksum.add(user));
+            checksum.finish()
+        };
+        let users = [user(1, "Ada", "ada@example.com"), user(2, "Alan", "alan@example.com")];
+        // printf '1\tAda\tada@example.com\n2\tAlan\talan@example.com\n' | sha256sum
+        let summary = checksum(&users);
+        assert_eq!(summary.count, 2);
+        assert_eq!(summary.sha256, "535410b54055a3564e8b6e323449af78bf0c4a93d3e126ee7310ca39106e3117");
+
+        assert_ne!(checksum(&[user(1, "Ada", "ada@example.com"), user(2, "Alan", "alan@example.org")]), summary);
+        assert_ne!(checksum(&[user(1, "Ada", "ada@example.com"), user(3, "Alan", "alan@example.com")]), summary);
+    }
+}
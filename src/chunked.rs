@@ -0,0 +1,137 @@
+use std::io::{self, Write};
+
+use crate::compression::StreamEncoder;
+use crate::http::Response;
+
+// Chunked transfer encoding (RFC 9112, section 7.1) for responses whose length
+// isn't known when the headers go out, such as streamed user lists. Each write
+// becomes one chunk; `finish` sends the terminating zero-length chunk.
+// The status line and headers are held back until the first chunk, so until then
+// the caller can still send a different response with `into_inner`.
+// With an encoder, every chunk is compressed as it's written.
+// Trailer fields (section 7.1.2) can follow the last chunk, for what's only known once
+// the body has been sent; the head should announce them in a `Trailer` header.
+
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+    // Status line and headers, until they've been sent
+    head: Option<String>,
+    encoder: Option<StreamEncoder>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W, mut head: Response, keep_alive: bool, encoder: Option<StreamEncoder>) -> Self {
+        if let Some(encoder) = &encoder {
+            head = head.header("Content-Encoding", encoder.encoding().name());
+        }
+        let head = head
+            .header("Transfer-Encoding", "chunked")
+            .header("Connection", if keep_alive { "keep-alive" } else { "close" });
+        ChunkedWriter {
+            inner,
+            head: Some(head.head()),
+            encoder,
+        }
+    }
+
+    // Whether anything has been written to the connection yet
+    pub fn started(&self) -> bool {
+        self.head.is_none()
+    }
+
+    pub fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(head) = self.head.take() {
+            self.inner.write_all(head.as_bytes())?;
+        }
+        // A zero-length chunk would end the body early
+        if data.is_empty() {
+            return Ok(());
+        }
+        match &mut self.encoder {
+            Some(encoder) => {
+                let encoded = encoder.encode(data)?;
+                self.write_raw_chunk(&encoded)
+            }
+            None => self.write_raw_chunk(data),
+        }
+    }
+
+    fn write_raw_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        write!(self.inner, "{:X}\r\n", data.len())?;
+        self.inner.write_all(data)?;
+        self.inner.write_all(b"\r\n")
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        self.finish_with_trailers(&[])
+    }
+
+    // Ends the body with these trailer fields
+    pub fn finish_with_trailers(mut self, trailers: &[(&str, String)]) -> io::Result<W> {
+        self.write_chunk(&[])?;
+        if let Some(encoder) = self.encoder.take() {
+            let trailer = encoder.finish()?;
+            self.write_raw_chunk(&trailer)?;
+        }
+        self.inner.write_all(b"0\r\n")?;
+        for (name, value) in trailers {
+            write!(self.inner, "{}: {}\r\n", name, value)?;
+        }
+        self.inner.write_all(b"\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_are_length_prefixed_in_hex() {
+        let mut writer = ChunkedWriter::new(Vec::new(), Response::new(200), true, None);
+        assert!(!writer.started());
+        writer.write_chunk(b"[").unwrap();
+        assert!(writer.started());
+        writer.write_chunk(b"").unwrap();
+        writer.write_chunk(&[b'x'; 26]).unwrap();
+        let output = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            format!(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: keep-alive\r\n\r\n\
+                 1\r\n[\r\n1A\r\n{}\r\n0\r\n\r\n",
+                "x".repeat(26)
+            )
+        );
+    }
+
+    #[test]
+    fn trailers_follow_the_last_chunk() {
+        let mut writer = ChunkedWriter::new(Vec::new(), Response::new(200), true, None);
+        writer.write_chunk(b"[]").unwrap();
+        let output = writer
+            .finish_with_trailers(&[("X-Row-Count", "0".to_string())])
+            .unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with("2\r\n[]\r\n0\r\nX-Row-Count: 0\r\n\r\n"));
+    }
+
+    #[test]
+    fn nothing_is_sent_before_the_first_chunk() {
+        let writer = ChunkedWriter::new(Vec::new(), Response::new(200), false, None);
+        assert!(writer.into_inner().is_empty());
+
+        let writer = ChunkedWriter::new(Vec::new(), Response::new(200), false, None);
+        let output = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert!(output.ends_with("Connection: close\r\n\r\n0\r\n\r\n"));
+    }
+}
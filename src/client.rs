@@ -0,0 +1,204 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::http_client::{self, HttpResponse};
+use crate::models::{BatchGet, BatchGetResult, User, UserPatch};
+
+// Typed blocking client for the user API, for other services (the `client` feature):
+//   let users = UsersClient::new("http://users:8080");
+//   users.create(&User::new("Ada", "ada@example.com"))?;
+//   let ada = users.get(1)?;
+//   users.patch(&ada, &UserPatch { name: Some("Ada L.".into()), ..Default::default() })?;
+// Each method mirrors one route of the server.
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    // The server answered with something other than 2xx
+    Status { status: u16, body: String },
+    // The response body wasn't the expected JSON
+    Decode(serde_json::Error),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "{}", e),
+            ClientError::Status { status, body } => write!(f, "HTTP {}: {}", status, body),
+            ClientError::Decode(e) => write!(f, "invalid response body: {}", e),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ClientError {
+    fn from(e: serde_json::Error) -> Self {
+        ClientError::Decode(e)
+    }
+}
+
+pub struct UsersClient {
+    base_url: String,
+    // Sent with every request
+    headers: Vec<(String, String)>,
+}
+
+impl UsersClient {
+    // `base_url` looks like `http://localhost:8080`
+    pub fn new(base_url: &str) -> UsersClient {
+        UsersClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            headers: Vec::new(),
+        }
+    }
+
+    // Identifies the caller to servers that rate limit by API key
+    pub fn with_api_key(mut self, api_key: &str) -> UsersClient {
+        self.headers
+            .push(("X-Api-Key".to_string(), api_key.to_string()));
+        self
+    }
+
+    // GET /users
+    pub fn list(&self) -> Result<Vec<User>, ClientError> {
+        let response = self.send("GET", "/users", &[], None)?;
+        Ok(serde_json::from_str(&response.body)?)
+    }
+
+    // GET /users/{id}
+    pub fn get(&self, id: i32) -> Result<User, ClientError> {
+        let response = self.send("GET", &format!("/users/{}", id), &[], None)?;
+        Ok(serde_json::from_str(&response.body)?)
+    }
+
+    // POST /users/batch-get: the users with the IDs in one request, and the IDs that
+    // weren't found
+    pub fn get_many(&self, ids: &[i32]) -> Result<BatchGetResult, ClientError> {
+        let body = serde_json::to_string(&BatchGet { ids: ids.to_vec() })?;
+        let response = self.send("POST", "/users/batch-get", &[], Some(&body))?;
+        Ok(serde_json::from_str(&response.body)?)
+    }
+
+    // POST /users. The server doesn't return the new user; look it up if you need its id.
+    pub fn create(&self, user: &User) -> Result<(), ClientError> {
+        let body = serde_json::to_string(user)?;
+        self.send("POST", "/users", &[], Some(&body))?;
+        Ok(())
+    }
+
+    // PUT /users/{id}: replaces name and email of `current`, which must be the latest
+    // copy from the server (its ETag is sent as `If-Match`, so a stale copy fails with 412)
+    pub fn update(&self, current: &User, name: &str, email: &str) -> Result<User, ClientError> {
+        let replacement = User::new(name, email);
+        self.send_update("PUT", current, &serde_json::to_string(&replacement)?)
+    }
+
+    // PATCH /users/{id}: changes only the fields set in `patch`, with the same
+    // `If-Match` check as `update`
+    pub fn patch(&self, current: &User, patch: &UserPatch) -> Result<User, ClientError> {
+        self.send_update("PATCH", current, &serde_json::to_string(patch)?)
+    }
+
+    // DELETE /users/{id}
+    pub fn delete(&self, id: i32) -> Result<(), ClientError> {
+        self.send("DELETE", &format!("/users/{}", id), &[], None)?;
+        Ok(())
+    }
+
+    fn send_update(&self, method: &str, current: &User, body: &str) -> Result<User, ClientError> {
+        let path = format!("/users/{}", current.id.unwrap_or_default());
+        let if_match = [("If-Match".to_string(), current.etag())];
+        let response = self.send(method, &path, &if_match, Some(body))?;
+        Ok(serde_json::from_str(&response.body)?)
+    }
+
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(String, String)],
+        body: Option<&str>,
+    ) -> Result<HttpResponse, ClientError> {
+        let mut all_headers = self.headers.clone();
+        all_headers.extend_from_slice(headers);
+        if body.is_some() {
+            all_headers.push(("Content-Type".to_string(), "application/json".to_string()));
+        }
+        let response = http_client::send(&self.base_url, method, path, &all_headers, body)?;
+        if !(200..300).contains(&response.status) {
+            return Err(ClientError::Status {
+                status: response.status,
+                body: response.body,
+            });
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Serves one canned response and returns the request it received
+    fn serve_once(response: &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0; 1024];
+            // The client always sends a Content-Length, so the head is enough to stop at
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let size = stream.read(&mut chunk).unwrap();
+                request.extend_from_slice(&chunk[..size]);
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (base_url, handle)
+    }
+
+    #[test]
+    fn updates_send_the_users_etag() {
+        let (base_url, server) = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Length: 43\r\n\r\n\
+             {\"id\":7,\"name\":\"Ada L.\",\"email\":\"ada@x.io\"}",
+        );
+        let mut current = User::new("Ada", "ada@x.io");
+        current.id = Some(7);
+        let patch = UserPatch {
+            name: Some("Ada L.".to_string()),
+            ..Default::default()
+        };
+        let updated = UsersClient::new(&base_url).patch(&current, &patch).unwrap();
+        assert_eq!(updated.name, "Ada L.");
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("PATCH /users/7 HTTP/1.1\r\n"));
+        assert!(request.contains(&format!("If-Match: {}\r\n", current.etag())));
+    }
+
+    #[test]
+    fn error_statuses_are_returned_as_errors() {
+        let (base_url, server) =
+            serve_once("HTTP/1.1 404 NOT FOUND\r\nContent-Length: 14\r\n\r\nUser not found");
+        match UsersClient::new(&base_url).get(3) {
+            Err(ClientError::Status { status, body }) => {
+                assert_eq!((status, body.as_str()), (404, "User not found"))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        server.join().unwrap();
+    }
+}
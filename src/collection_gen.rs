@@ -0,0 +1,364 @@
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::io;
+
+use crate::client_gen::{operations, Operation};
+use crate::openapi;
+use crate::output::{self, OutputFormat};
+
+const USAGE: &str = "usage: generate-collection [--out PATH] [--environment PATH]";
+
+const SCHEMA: &str = "https://schema.getpostman.com/json/collection/v2.1.0/collection.json";
+
+// Entry point for `app generate-collection [--out PATH] [--environment PATH]`.
+// Emits a Postman collection (v2.1, which Bruno imports too) for the API described in
+// `openapi::spec`, to stdout or to `--out`: a folder per resource, a request per
+// operation with an example body built from its schema, and the server's address and
+// the path parameters and headers as variables. `--environment` also writes those
+// variables as a Postman environment, to switch between servers with.
+pub fn run(args: &[String], format: OutputFormat) -> io::Result<()> {
+    let mut out = None;
+    let mut environment = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--out" => out = Some(args.next().ok_or_else(|| invalid(USAGE))?.clone()),
+            "--environment" => {
+                environment = Some(args.next().ok_or_else(|| invalid(USAGE))?.clone())
+            }
+            _ => return Err(invalid(USAGE)),
+        }
+    }
+
+    let spec = openapi::spec();
+    let collection = postman(&spec);
+    if let Some(path) = &environment {
+        fs::write(
+            path,
+            serde_json::to_string_pretty(&postman_environment(&collection))?,
+        )?;
+    }
+    let text = serde_json::to_string_pretty(&collection)?;
+    match out {
+        Some(path) => {
+            fs::write(&path, text)?;
+            output::print_record(
+                format,
+                &[
+                    ("path", path.into()),
+                    ("environment", environment.into()),
+                    ("requests", operations(&spec).len().into()),
+                ],
+            );
+        }
+        None => println!("{}", text),
+    }
+    Ok(())
+}
+
+// The collection: requests in a folder per first path segment, in the spec's order
+pub fn postman(spec: &Value) -> Value {
+    let mut folders: Vec<(String, Vec<Value>)> = Vec::new();
+    let mut variables = vec![variable("baseUrl", "http://localhost:8080")];
+    for operation in operations(spec) {
+        for parameter in &operation.parameters {
+            let (key, example) = match parameter["in"].as_str() {
+                Some("path") => (
+                    parameter["name"].as_str().unwrap_or_default().to_string(),
+                    path_example(parameter),
+                ),
+                Some("header") => (
+                    header_variable(parameter["name"].as_str().unwrap_or_default()),
+                    String::new(),
+                ),
+                _ => continue,
+            };
+            if !variables
+                .iter()
+                .any(|variable| variable["key"] == key.as_str())
+            {
+                variables.push(variable(&key, &example));
+            }
+        }
+
+        let folder = operation
+            .path
+            .trim_matches('/')
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let item = request(spec, &operation);
+        match folders.iter_mut().find(|(name, _)| *name == folder) {
+            Some((_, items)) => items.push(item),
+            None => folders.push((folder, vec![item])),
+        }
+    }
+
+    json!({
+        "info": {
+            "name": spec["info"]["title"],
+            "description": format!("Generated by `generate-collection` from the server's OpenAPI spec (version {}).", spec["info"]["version"].as_str().unwrap_or_default()),
+            "schema": SCHEMA,
+        },
+        "item": folders
+            .into_iter()
+            .map(|(name, items)| json!({ "name": name, "item": items }))
+            .collect::<Vec<_>>(),
+        "variable": variables,
+    })
+}
+
+// The collection's variables as an environment
+pub fn postman_environment(collection: &Value) -> Value {
+    let values: Vec<Value> = collection["variable"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|variable| json!({ "key": variable["key"], "value": variable["value"], "enabled": true }))
+        .collect();
+    json!({
+        "name": format!("{} (local)", collection["info"]["name"].as_str().unwrap_or_default()),
+        "values": values,
+    })
+}
+
+fn request(spec: &Value, operation: &Operation) -> Value {
+    let in_ = |location: &'static str| {
+        operation
+            .parameters
+            .iter()
+            .filter(move |parameter| parameter["in"] == location)
+    };
+
+    // `/users/{id}` is `/users/:id`, with `:id` set from the `{{id}}` variable
+    let segments: Vec<String> = operation
+        .path
+        .trim_matches('/')
+        .split('/')
+        .map(|segment| {
+            match segment
+                .strip_prefix('{')
+                .and_then(|segment| segment.strip_suffix('}'))
+            {
+                Some(name) => format!(":{}", name),
+                None => segment.to_string(),
+            }
+        })
+        .collect();
+    let mut url = json!({
+        "raw": format!("{{{{baseUrl}}}}/{}", segments.join("/")),
+        "host": ["{{baseUrl}}"],
+        "path": segments,
+    });
+    let path_variables: Vec<Value> = in_("path")
+        .map(|parameter| {
+            let name = parameter["name"].as_str().unwrap_or_default();
+            json!({ "key": name, "value": format!("{{{{{}}}}}", name) })
+        })
+        .collect();
+    if !path_variables.is_empty() {
+        url["variable"] = path_variables.into();
+    }
+    // Optional, so they're listed but off until filled in
+    let query: Vec<Value> = in_("query")
+        .map(|parameter| {
+            json!({
+                "key": parameter["name"],
+                "value": example(spec, &parameter["schema"], parameter["name"].as_str().unwrap_or_default()),
+                "description": parameter["description"],
+                "disabled": true,
+            })
+        })
+        .map(|mut parameter| {
+            parameter["value"] = text(&parameter["value"]).into();
+            parameter
+        })
+        .collect();
+    if !query.is_empty() {
+        url["query"] = query.into();
+    }
+
+    let mut headers: Vec<Value> = in_("header")
+        .map(|parameter| {
+            let name = parameter["name"].as_str().unwrap_or_default();
+            json!({
+                "key": name,
+                "value": format!("{{{{{}}}}}", header_variable(name)),
+                "description": parameter["description"],
+                "disabled": parameter["required"] != true,
+            })
+        })
+        .collect();
+    let mut request = json!({
+        "method": operation.method.to_uppercase(),
+        "url": url,
+        "description": operation.spec["summary"],
+    });
+    let body = &operation.spec["requestBody"]["content"]["application/json"]["schema"];
+    if !body.is_null() {
+        headers.push(json!({ "key": "Content-Type", "value": "application/json" }));
+        request["body"] = json!({
+            "mode": "raw",
+            "raw": serde_json::to_string_pretty(&example(spec, body, "")).unwrap_or_default(),
+            "options": { "raw": { "language": "json" } },
+        });
+    }
+    request["header"] = headers.into();
+
+    json!({
+        "name": operation.spec["summary"].as_str().unwrap_or(operation.path),
+        "request": request,
+    })
+}
+
+// A value matching a schema, for example bodies. Objects get their required
+// properties, or all of them when none are.
+fn example(spec: &Value, schema: &Value, name: &str) -> Value {
+    if let Some(reference) = schema["$ref"].as_str() {
+        let schema_name = reference.rsplit('/').next().unwrap_or_default();
+        return example(spec, &spec["components"]["schemas"][schema_name], name);
+    }
+    if let Some(first) = schema["enum"].as_array().and_then(|values| values.first()) {
+        return first.clone();
+    }
+    match schema["type"].as_str() {
+        Some("object") => {
+            let properties = schema["properties"]
+                .as_object()
+                .cloned()
+                .unwrap_or_default();
+            let required: Vec<&str> = schema["required"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .collect();
+            let object: Map<String, Value> = properties
+                .iter()
+                .filter(|(property, _)| {
+                    required.is_empty() || required.contains(&property.as_str())
+                })
+                .map(|(property, property_schema)| {
+                    (property.clone(), example(spec, property_schema, property))
+                })
+                .collect();
+            Value::Object(object)
+        }
+        Some("array") => json!([example(spec, &schema["items"], name)]),
+        Some("integer") => json!(1),
+        Some("number") => json!(1.5),
+        Some("boolean") => json!(true),
+        _ => json!(match (schema["format"].as_str(), name) {
+            (Some("date-time"), _) => "2024-01-01T00:00:00Z",
+            (Some("date"), _) => "2024-01-01",
+            (Some("uri"), _) => "https://example.com/ada.png",
+            (_, name) if name.ends_with("domain") => "example.com",
+            (_, name) if name.contains("email") => "ada@example.com",
+            (_, "name") => "Ada Lovelace",
+            _ => "text",
+        }),
+    }
+}
+
+// A path parameter's value to start with
+fn path_example(parameter: &Value) -> String {
+    match parameter["schema"]["type"].as_str() {
+        Some("integer") => "1".to_string(),
+        _ => text(&example(
+            &Value::Null,
+            &parameter["schema"],
+            parameter["name"].as_str().unwrap_or_default(),
+        )),
+    }
+}
+
+// Variable holding a header's value: `If-Match` is `ifMatch`
+fn header_variable(header: &str) -> String {
+    let mut words = header.split('-');
+    let first = words.next().unwrap_or_default().to_lowercase();
+    words.fold(first, |mut name, word| {
+        let mut chars = word.chars();
+        if let Some(initial) = chars.next() {
+            name.extend(initial.to_uppercase());
+            name.push_str(&chars.as_str().to_lowercase());
+        }
+        name
+    })
+}
+
+fn variable(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+// A value as it's written in a URL
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collections_cover_every_operation_with_examples() {
+        let spec = openapi::spec();
+        let collection = postman(&spec);
+        let folders = collection["item"].as_array().unwrap();
+        let requests: Vec<&Value> = folders
+            .iter()
+            .flat_map(|folder| folder["item"].as_array().unwrap())
+            .collect();
+        assert_eq!(requests.len(), operations(&spec).len());
+        assert_eq!(
+            folders
+                .iter()
+                .map(|folder| folder["name"].as_str().unwrap())
+                .collect::<Vec<_>>(),
+            ["posts", "users"]
+        );
+
+        let patch = requests
+            .iter()
+            .find(|item| {
+                item["request"]["method"] == "PATCH"
+                    && item["request"]["url"]["raw"] == "{{baseUrl}}/users/:id"
+            })
+            .unwrap();
+        assert_eq!(
+            patch["request"]["url"]["variable"],
+            json!([{ "key": "id", "value": "{{id}}" }])
+        );
+        assert_eq!(patch["request"]["header"][0]["value"], "{{ifMatch}}");
+        assert_eq!(patch["request"]["header"][0]["disabled"], false);
+        let create = requests
+            .iter()
+            .find(|item| item["request"]["description"] == "Create a user")
+            .unwrap();
+        let body: Value =
+            serde_json::from_str(create["request"]["body"]["raw"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({ "name": "Ada Lovelace", "email": "ada@example.com" })
+        );
+
+        let environment = postman_environment(&collection);
+        let keys: Vec<&str> = environment["values"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|value| value["key"].as_str())
+            .collect();
+        assert!(["baseUrl", "id", "email", "ifMatch", "idempotencyKey"]
+            .iter()
+            .all(|key| keys.contains(key)));
+        assert_eq!(header_variable("Idempotency-Key"), "idempotencyKey");
+    }
+}
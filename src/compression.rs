@@ -0,0 +1,338 @@
+use brotli::CompressorWriter;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::env;
+use std::io::{self, Write};
+
+// Response compression negotiated through `Accept-Encoding`.
+// Bodies of at least COMPRESSION_MIN_BYTES (1024) are brotli-, gzip- or deflate-encoded
+// when the client accepts it; smaller ones aren't worth the CPU. COMPRESSION=off
+// disables it.
+// Only bodies of the media types in COMPRESSION_TYPES are compressed, a comma-separated
+// list where `*` matches any part of a type, like `text/*`; by default JSON, NDJSON,
+// text, JavaScript and SVG. Images and archives are compressed already.
+// COMPRESSION_BROTLI_QUALITY (0-11, default 4) and COMPRESSION_GZIP_LEVEL (0-9, default
+// 6, also used for deflate) trade CPU for size. Brotli's highest qualities are far too
+// slow for responses built per request; they suit precompressed files (see `admin_ui`).
+
+const DEFAULT_TYPES: &str =
+    "application/json,application/*+json,application/x-ndjson,text/*,application/javascript,image/svg+xml";
+
+// Window size for brotli, as the base-2 logarithm of its bytes; 22 is the usual default
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    // HTTP's `deflate` is the zlib format, not raw deflate
+    Deflate,
+}
+
+impl Encoding {
+    // Value for `Content-Encoding`
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+pub struct Compression {
+    pub enabled: bool,
+    pub min_size: usize,
+    // 0 (fastest) to 11 (smallest)
+    pub brotli_quality: u32,
+    // 0 (none) to 9 (smallest), for gzip and deflate
+    pub gzip_level: u32,
+    // Media type patterns of the bodies that are compressed
+    pub types: Vec<String>,
+}
+
+impl Compression {
+    pub fn from_env() -> Compression {
+        let enabled = !matches!(
+            env::var("COMPRESSION").as_deref(),
+            Ok("off") | Ok("false") | Ok("0")
+        );
+        let number = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        let types = env::var("COMPRESSION_TYPES")
+            .ok()
+            .filter(|types| !types.is_empty())
+            .unwrap_or_else(|| DEFAULT_TYPES.to_string());
+        Compression {
+            enabled,
+            min_size: number("COMPRESSION_MIN_BYTES", 1024) as usize,
+            brotli_quality: number("COMPRESSION_BROTLI_QUALITY", 4).min(11) as u32,
+            gzip_level: number("COMPRESSION_GZIP_LEVEL", 6).min(9) as u32,
+            types: types
+                .split(',')
+                .map(|pattern| pattern.trim().to_lowercase())
+                .filter(|pattern| !pattern.is_empty())
+                .collect(),
+        }
+    }
+
+    // Whether a body of this type and size is compressed for clients that accept it.
+    // Bodies without a type are treated as JSON, like almost all responses. Streamed
+    // bodies have no size up front and always qualify.
+    pub fn applies_to(&self, content_type: Option<&str>, size: Option<usize>) -> bool {
+        self.enabled
+            && size.is_none_or(|size| size >= self.min_size)
+            && self.compresses_type(content_type.unwrap_or("application/json"))
+    }
+
+    fn compresses_type(&self, content_type: &str) -> bool {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        let media_type = media_type.to_lowercase();
+        self.types
+            .iter()
+            .any(|pattern| match pattern.split_once('*') {
+                Some((prefix, suffix)) => {
+                    media_type.len() >= prefix.len() + suffix.len()
+                        && media_type.starts_with(prefix)
+                        && media_type.ends_with(suffix)
+                }
+                None => *pattern == media_type,
+            })
+    }
+
+    // Encoding to use for a response, given the request's `Accept-Encoding`
+    pub fn negotiate(
+        &self,
+        accept_encoding: Option<&str>,
+        content_type: Option<&str>,
+        size: Option<usize>,
+    ) -> Option<Encoding> {
+        if !self.applies_to(content_type, size) {
+            return None;
+        }
+        accept_encoding.and_then(preferred_encoding)
+    }
+
+    pub fn compress(&self, encoding: Encoding, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = self.encoder(encoding);
+        let mut body = encoder.encode(data)?;
+        body.extend(encoder.finish()?);
+        Ok(body)
+    }
+
+    // An encoder at the configured quality
+    pub fn encoder(&self, encoding: Encoding) -> StreamEncoder {
+        let level = match encoding {
+            Encoding::Brotli => self.brotli_quality,
+            Encoding::Gzip | Encoding::Deflate => self.gzip_level,
+        };
+        StreamEncoder::new(encoding, level)
+    }
+}
+
+// The supported encoding with the highest q-value, preferring brotli, then gzip on ties
+fn preferred_encoding(accept_encoding: &str) -> Option<Encoding> {
+    [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate]
+        .into_iter()
+        .map(|encoding| (encoding, quality(accept_encoding, encoding)))
+        .filter(|(_, quality)| *quality > 0.0)
+        .reduce(|best, next| if next.1 > best.1 { next } else { best })
+        .map(|(encoding, _)| encoding)
+}
+
+// Whether the client takes this encoding
+pub fn accepts(accept_encoding: Option<&str>, encoding: Encoding) -> bool {
+    accept_encoding.is_some_and(|accept_encoding| quality(accept_encoding, encoding) > 0.0)
+}
+
+// The q-value `Accept-Encoding` gives an encoding. `*` stands for any encoding not
+// listed; `q=0` rules one out.
+fn quality(accept_encoding: &str, encoding: Encoding) -> f32 {
+    let mut listed = None;
+    let mut any = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or_default().trim().to_lowercase();
+        let quality = parts
+            .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match (name.as_str(), encoding) {
+            ("br", Encoding::Brotli)
+            | ("gzip" | "x-gzip", Encoding::Gzip)
+            | ("deflate", Encoding::Deflate) => listed = Some(quality),
+            ("*", _) => any = Some(quality),
+            _ => {}
+        }
+    }
+    listed.or(any).unwrap_or(0.0)
+}
+
+// Compresses a body that's sent in pieces. Each piece is flushed so it can go out
+// right away, rather than waiting for the compressor's window to fill.
+pub enum StreamEncoder {
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl StreamEncoder {
+    // `level` is brotli's quality, or gzip's and deflate's level
+    pub fn new(encoding: Encoding, level: u32) -> StreamEncoder {
+        match encoding {
+            Encoding::Brotli => StreamEncoder::Brotli(Box::new(CompressorWriter::new(
+                Vec::new(),
+                4096,
+                level,
+                BROTLI_WINDOW,
+            ))),
+            Encoding::Gzip => {
+                StreamEncoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::new(level)))
+            }
+            Encoding::Deflate => StreamEncoder::Deflate(ZlibEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(level),
+            )),
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        match self {
+            StreamEncoder::Brotli(_) => Encoding::Brotli,
+            StreamEncoder::Gzip(_) => Encoding::Gzip,
+            StreamEncoder::Deflate(_) => Encoding::Deflate,
+        }
+    }
+
+    // Compressed bytes for the next piece of the body
+    pub fn encode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            StreamEncoder::Brotli(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            StreamEncoder::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            StreamEncoder::Deflate(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    // The remaining bytes, including the gzip or zlib trailer
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            StreamEncoder::Brotli(encoder) => Ok(encoder.into_inner()),
+            StreamEncoder::Gzip(encoder) => encoder.finish(),
+            StreamEncoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use std::io::Read;
+
+    #[test]
+    fn encodings_are_chosen_by_quality() {
+        assert_eq!(
+            preferred_encoding("gzip, deflate, br"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            preferred_encoding("gzip, deflate, br;q=0.9"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(preferred_encoding("deflate"), Some(Encoding::Deflate));
+        assert_eq!(
+            preferred_encoding("gzip;q=0.5, deflate"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(
+            preferred_encoding("br;q=0, gzip;q=0, *"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(preferred_encoding("*;q=0"), None);
+        assert_eq!(preferred_encoding("zstd, identity"), None);
+        assert!(accepts(Some("gzip, br"), Encoding::Brotli));
+        assert!(!accepts(Some("gzip"), Encoding::Brotli));
+
+        let compression = compression(&["application/json", "text/*"]);
+        assert_eq!(compression.negotiate(Some("gzip"), None, Some(99)), None);
+        assert_eq!(
+            compression.negotiate(Some("gzip"), None, Some(100)),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            compression.negotiate(Some("gzip"), None, None),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(compression.negotiate(None, None, Some(100)), None);
+    }
+
+    #[test]
+    fn only_configured_types_are_compressed() {
+        let compression = compression(&["application/json", "text/*", "application/*+json"]);
+        assert!(compression.applies_to(Some("text/csv; charset=utf-8"), Some(100)));
+        assert!(compression.applies_to(Some("Application/JSON"), Some(100)));
+        assert!(compression.applies_to(Some("application/scim+json"), Some(100)));
+        assert!(compression.applies_to(None, Some(100)));
+        assert!(!compression.applies_to(Some("image/png"), Some(100)));
+        assert!(!compression.applies_to(Some("application/json-seq"), Some(100)));
+        assert!(!compression.applies_to(Some("text"), Some(100)));
+    }
+
+    fn compression(types: &[&str]) -> Compression {
+        Compression {
+            enabled: true,
+            min_size: 100,
+            brotli_quality: 4,
+            gzip_level: 6,
+            types: types.iter().map(|pattern| pattern.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn streamed_pieces_decode_to_the_whole_body() {
+        let mut encoder = StreamEncoder::new(Encoding::Gzip, 6);
+        let mut body = encoder.encode(b"[1,").unwrap();
+        assert!(!body.is_empty());
+        body.extend(encoder.encode(b"2]").unwrap());
+        body.extend(encoder.finish().unwrap());
+        let mut decoded = String::new();
+        GzDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "[1,2]");
+
+        let compression = compression(&[]);
+        let body = compression.compress(Encoding::Deflate, b"hello").unwrap();
+        let mut decoded = String::new();
+        ZlibDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "hello");
+
+        let mut encoder = compression.encoder(Encoding::Brotli);
+        let mut body = encoder.encode(b"[1,").unwrap();
+        assert!(!body.is_empty());
+        body.extend(encoder.encode(b"2]").unwrap());
+        body.extend(encoder.finish().unwrap());
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&body[..], 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "[1,2]");
+    }
+}
@@ -0,0 +1,158 @@
+use std::cell::RefCell;
+use std::env;
+
+use crate::router::{allowed_methods, get_header, get_path};
+
+// CORS, so browser apps on other origins can call the API. Preflight answers come from
+// the route table (see `router::ROUTES`): a path allows exactly the methods its route
+// supports, so new routes get correct preflights without any CORS settings of their own.
+// Requests from origins that aren't allowed get no CORS headers, and browsers block them.
+// Like the request ID, the headers for the request being handled are kept per thread, so
+// every response to it carries them, errors included.
+
+// Request headers the API reads
+const ALLOWED_HEADERS: &str =
+    "Accept-Encoding, Authorization, Content-Type, If-Match, If-None-Match, X-Api-Key, X-Request-Id";
+// Response headers browser apps may read
+const EXPOSED_HEADERS: &str = "ETag, Retry-After, X-Cache, X-Disposable-Email, X-Email-Change, X-Request-Id";
+
+thread_local! {
+    static CURRENT: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+pub struct Cors {
+    // Allowed origins like `https://app.example.com`; empty means any origin
+    origins: Vec<String>,
+    // How long browsers may cache a preflight answer
+    max_age_secs: u64,
+}
+
+impl Cors {
+    pub fn new(origins: Vec<String>, max_age_secs: u64) -> Cors {
+        Cors {
+            origins,
+            max_age_secs,
+        }
+    }
+
+    // Reads CORS_ALLOWED_ORIGINS, a comma-separated list of origins or `*`, and
+    // CORS_MAX_AGE_SECS (default 600). Returns `None` (no CORS) unless origins are set.
+    pub fn from_env() -> Option<Cors> {
+        Cors::from_settings(|name| env::var(name).ok())
+    }
+
+    // Like `from_env`, with the settings looked up by `setting` (see `reload`)
+    pub fn from_settings(setting: impl Fn(&str) -> Option<String>) -> Option<Cors> {
+        let origins = setting("CORS_ALLOWED_ORIGINS")?;
+        let origins: Vec<String> = origins
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_string())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+        if origins.is_empty() {
+            return None;
+        }
+        let max_age_secs = setting("CORS_MAX_AGE_SECS")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(600);
+        let any = origins.iter().any(|origin| origin == "*");
+        Some(Cors::new(
+            if any { Vec::new() } else { origins },
+            max_age_secs,
+        ))
+    }
+
+    // CORS headers for the response to a request, empty unless it comes from an allowed
+    // origin. Preflights (`OPTIONS` with `Access-Control-Request-Method`) also get the
+    // methods of the path's route, and nothing at all for unknown paths or methods the
+    // route doesn't support.
+    pub fn headers(&self, request: &str) -> String {
+        let Some(origin) = get_header(request, "Origin") else {
+            return String::new();
+        };
+        let allow_origin = match self.origins.is_empty() {
+            true => "*",
+            false if self.origins.iter().any(|allowed| allowed == origin) => origin,
+            false => return String::new(),
+        };
+        let mut headers = format!(
+            "Access-Control-Allow-Origin: {}\r\nVary: Origin\r\n",
+            allow_origin
+        );
+
+        let preflight = request.starts_with("OPTIONS ");
+        match get_header(request, "Access-Control-Request-Method") {
+            Some(method) if preflight => {
+                let Some(methods) = allowed_methods(get_path(request)) else {
+                    return String::new();
+                };
+                if !methods.contains(&method) {
+                    return String::new();
+                }
+                headers.push_str(&format!(
+                    "Access-Control-Allow-Methods: {}\r\nAccess-Control-Allow-Headers: {}\r\nAccess-Control-Max-Age: {}\r\n",
+                    methods.join(", "),
+                    ALLOWED_HEADERS,
+                    self.max_age_secs
+                ));
+            }
+            _ => headers.push_str(&format!(
+                "Access-Control-Expose-Headers: {}\r\n",
+                EXPOSED_HEADERS
+            )),
+        }
+        headers
+    }
+}
+
+// Sets the CORS headers for the request this thread is handling
+pub fn set_current(headers: String) {
+    CURRENT.with(|current| *current.borrow_mut() = headers);
+}
+
+// CORS header lines for the current request, if any
+pub fn header_lines() -> String {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors() -> Cors {
+        Cors::new(vec!["https://app.example".to_string()], 60)
+    }
+
+    #[test]
+    fn preflights_list_the_routes_methods() {
+        let preflight = |path: &str, method: &str| {
+            cors().headers(&format!(
+                "OPTIONS {} HTTP/1.1\r\nOrigin: https://app.example\r\nAccess-Control-Request-Method: {}\r\n\r\n",
+                path, method
+            ))
+        };
+        let headers = preflight("/users/7", "DELETE");
+        assert!(headers.contains("Access-Control-Allow-Origin: https://app.example\r\n"));
+        assert!(headers
+            .contains("Access-Control-Allow-Methods: GET, HEAD, PUT, PATCH, DELETE, OPTIONS\r\n"));
+        assert!(headers.contains("Access-Control-Max-Age: 60\r\n"));
+
+        assert_eq!(preflight("/users/7/audit", "DELETE"), "");
+        assert_eq!(preflight("/nowhere", "GET"), "");
+    }
+
+    #[test]
+    fn only_allowed_origins_get_headers() {
+        let request = |origin: &str| format!("GET /users HTTP/1.1\r\nOrigin: {}\r\n\r\n", origin);
+        let headers = cors().headers(&request("https://app.example"));
+        assert!(headers.contains("Access-Control-Expose-Headers: ETag"));
+        assert!(!headers.contains("Allow-Methods"));
+        assert_eq!(cors().headers(&request("https://evil.example")), "");
+        assert_eq!(cors().headers("GET /users HTTP/1.1\r\n\r\n"), "");
+
+        let any = Cors::new(Vec::new(), 60);
+        assert!(any
+            .headers(&request("https://evil.example"))
+            .starts_with("Access-Control-Allow-Origin: *\r\n"));
+    }
+}
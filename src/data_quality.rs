@@ -0,0 +1,94 @@
+use postgres::{Error as PostgresError, GenericClient};
+use serde_json::{json, Value};
+
+use crate::query_log::Timed;
+
+// Data quality report for GET /admin/data-quality.
+// Every check runs in SQL over live (not soft-deleted) users and reports how many
+// rows are affected plus a few sample ids, enough to drive a cleanup job without
+// dumping the table.
+
+const SAMPLE_SIZE: i64 = 10;
+
+// Emails that differ only by case or by dots in the local part
+// (`Ada.Lovelace@Example.com` vs `adalovelace@example.com`) belong to the same person
+const NORMALIZED_EMAIL: &str = "replace(lower(split_part(email, '@', 1)), '.', '') \
+    || '@' || lower(split_part(email, '@', 2))";
+
+// Row-level checks: name, description, and the condition flagging a row
+const ROW_CHECKS: &[(&str, &str, &str)] = &[
+    (
+        "empty_names",
+        "Name is empty or only whitespace",
+        "btrim(name) = ''",
+    ),
+    (
+        "invalid_emails",
+        "Email isn't shaped like local@domain.tld",
+        "email !~ '^[^@[:space:]]+@[^@[:space:]]+\\.[^@[:space:]]+$'",
+    ),
+    (
+        "untrimmed_values",
+        "Name or email has leading or trailing whitespace",
+        "name <> btrim(name) OR email <> btrim(email)",
+    ),
+];
+
+pub fn report(client: &mut impl GenericClient) -> Result<Value, PostgresError> {
+    let mut checks = vec![duplicate_emails(client)?];
+    for (name, description, condition) in ROW_CHECKS {
+        let query = format!(
+            "SELECT count(*), (array_agg(id ORDER BY id))[1:$1]
+             FROM users WHERE deleted_at IS NULL AND ({})",
+            condition
+        );
+        let row = client.timed_query_one(&query, &[&(SAMPLE_SIZE as i32)])?;
+        let count: i64 = row.try_get(0)?;
+        let sample_ids: Option<Vec<i32>> = row.try_get(1)?;
+        checks.push(json!({
+            "check": name,
+            "description": description,
+            "count": count,
+            "sample_ids": sample_ids.unwrap_or_default(),
+        }));
+    }
+
+    let total: i64 = client
+        .timed_query_one("SELECT count(*) FROM users WHERE deleted_at IS NULL", &[])?
+        .try_get(0)?;
+    Ok(json!({ "users_checked": total, "checks": checks }))
+}
+
+// Groups of users sharing a normalized email. `count` is the number of groups;
+// samples are the id lists of the first groups.
+fn duplicate_emails(client: &mut impl GenericClient) -> Result<Value, PostgresError> {
+    let query = format!(
+        "WITH groups AS (
+             SELECT array_agg(id ORDER BY id) AS ids
+             FROM users WHERE deleted_at IS NULL
+             GROUP BY {}
+             HAVING count(*) > 1
+         )
+         SELECT count(*),
+                coalesce(sum(array_length(ids, 1)), 0)::bigint,
+                (SELECT json_agg(ids)::text FROM
+                    (SELECT ids FROM groups ORDER BY ids[1] LIMIT $1) sample)
+         FROM groups",
+        NORMALIZED_EMAIL
+    );
+    let row = client.timed_query_one(&query, &[&SAMPLE_SIZE])?;
+    let groups: i64 = row.try_get(0)?;
+    let users: i64 = row.try_get(1)?;
+    let sample: Option<String> = row.try_get(2)?;
+    let sample: Value = sample
+        .and_then(|sample| serde_json::from_str(&sample).ok())
+        .unwrap_or_else(|| json!([]));
+
+    Ok(json!({
+        "check": "duplicate_emails",
+        "description": "Emails equal when ignoring case and dots in the local part",
+        "count": groups,
+        "affected_users": users,
+        "sample_ids": sample,
+    }))
+}
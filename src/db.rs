@@ -0,0 +1,112 @@
+use postgres::error::SqlState;
+use postgres::{Client, NoTls, Error as PostgresError, Transaction};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::audit_partitions;
+use crate::config::get_db_url;
+use crate::delete_policy;
+use crate::errors::AppError;
+use crate::migrations;
+use crate::pool::{Pool, PoolError};
+use crate::repo;
+use crate::retry;
+use crate::server::AppState;
+use crate::slow_requests;
+use crate::standby::Standby;
+
+// Database access for the handlers: the connection pool, retried units of work,
+// transactions, and the schema set up at startup
+
+pub(crate) type DbPool = Pool<Client, PostgresError>;
+
+// Opens a connection to the database, which is the standby while failed over to one
+pub(crate) fn connect(standby: Option<&Standby>) -> Result<Client, PostgresError> {
+    match standby {
+        Some(standby) => standby.connect(&get_db_url()),
+        None => Client::connect(&get_db_url(), NoTls),
+    }
+}
+
+// Connection pool sized by DB_POOL_SIZE; checkouts wait up to DB_POOL_TIMEOUT_SECS
+pub(crate) fn create_db_pool(standby: Option<Arc<Standby>>) -> DbPool {
+    let setting = |name: &str, default: u64| {
+        env::var(name)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    };
+
+    // Connections are opened on demand, so nothing here needs a database until then
+    Pool::new(
+        setting("DB_POOL_SIZE", 10) as usize,
+        Duration::from_secs(setting("DB_POOL_TIMEOUT_SECS", 5)),
+        move || connect(standby.as_deref()),
+        |client: &Client| !client.is_closed(),
+    )
+}
+
+// Runs database work on a pooled connection. Transient failures (a restarting
+// database, dropped connections, serialization failures) are retried with backoff
+// on a fresh connection, so `work` may run more than once.
+// A connection that turns out to be on a read-only server (an old primary demoted by
+// a failover) is closed rather than reused, so the retry reconnects and, with a
+// multi-host DATABASE_URL and target_session_attrs=read-write, finds the new primary.
+pub(crate) fn with_client<T>(
+    state: &AppState,
+    mut work: impl FnMut(&mut Client) -> Result<T, AppError>,
+) -> Result<T, AppError> {
+    // Connections to the database failed over from (or back from) are not reused
+    if let Some(standby) = &state.standby {
+        state.db.advance(standby.generation());
+    }
+    let attempt = || match state.db.get() {
+        Ok(mut client) => {
+            let result = slow_requests::time_database(|| work(&mut client));
+            if matches!(&result, Err(AppError::Db(e)) if is_read_only(e)) {
+                client.discard();
+            }
+            result
+        }
+        Err(PoolError::Connect(e)) => Err(AppError::Db(e)),
+        Err(PoolError::Timeout) => Err(AppError::Timeout),
+    };
+    state.retry.run(attempt, |e| {
+        matches!(e, AppError::Db(e) if retry::is_transient(e))
+    })
+}
+
+fn is_read_only(e: &PostgresError) -> bool {
+    e.code() == Some(&SqlState::READ_ONLY_SQL_TRANSACTION)
+}
+
+// Runs a handler's database work in one transaction. Returning an `AppError`
+// undoes everything it did and sends its error response instead.
+pub(crate) fn in_transaction(
+    state: &AppState,
+    mut work: impl FnMut(&mut Transaction) -> Result<(String, String), AppError>,
+) -> (String, String) {
+    match with_client(state, |client| repo::with_transaction(client, &mut work)) {
+        Ok(response) => response,
+        Err(e) => e.into_response(),
+    }
+}
+
+// Sets up the database: applies the pending migrations (see `migrations`), then brings
+// what follows the configuration in line.
+// Returns `Result<(), PostgresError>`:
+// - `Ok(())` on success, indicating no specific data is returned, only that the operation completed successfully.
+// - `Err(PostgresError)` if there's an error connecting to the database or executing the SQL.
+pub fn set_database() -> Result<(), PostgresError> {
+    // Connect to db
+    let mut client = Client::connect(&get_db_url(), NoTls)?;
+    migrations::apply(&mut client)?;
+    // One row per committed mutation; old/new hold the user as serialized by the API,
+    // details any extra context such as a flagged disposable email. Partitioned by
+    // month (see `audit_partitions`).
+    audit_partitions::migrate(&mut client)?;
+    // Foreign keys to users follow the delete policies (see `delete_policy`)
+    delete_policy::migrate(&mut client)?;
+    Ok(())
+}
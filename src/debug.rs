@@ -0,0 +1,223 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::env;
+use std::time::{Duration, Instant};
+
+use crate::rate_limit::RateLimiter;
+use crate::router;
+use crate::server::Caller;
+
+// Verbose debugging of single requests. An admin request with `X-Debug: true` gets its
+// response wrapped as `{"response": ..., "debug": {...}}`, the debug section holding
+// the matched route, how long each stage of request handling took, the SQL executed
+// and the response cache's decision. Statement durations run until the next statement
+// or the end of the database work, so they include reading the rows.
+// On by default in debug builds only; DEBUG_HEADER=on or off overrides that. At most
+// DEBUG_REQUESTS_PER_MINUTE (10) requests per admin are debugged, as tracing adds
+// overhead; the rest are served as usual. 0 disables debugging.
+
+pub struct Debugging {
+    limiter: RateLimiter,
+}
+
+impl Debugging {
+    // None when debugging is disabled
+    pub fn from_env() -> Option<Debugging> {
+        let enabled = match env::var("DEBUG_HEADER").as_deref() {
+            Ok("on") | Ok("true") | Ok("1") => true,
+            Ok("off") | Ok("false") | Ok("0") => false,
+            _ => cfg!(debug_assertions),
+        };
+        if !enabled {
+            return None;
+        }
+        let per_minute: f64 = env::var("DEBUG_REQUESTS_PER_MINUTE")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(10.0);
+        if !(per_minute > 0.0 && per_minute.is_finite()) {
+            return None;
+        }
+        // Statements are captured from the Postgres driver's debug log
+        if log::set_logger(&SQL_CAPTURE).is_ok() {
+            log::set_max_level(LevelFilter::Debug);
+        }
+        Some(Debugging {
+            limiter: RateLimiter::new(per_minute / 60.0, per_minute, HashSet::new()),
+        })
+    }
+
+    // Whether to debug this request
+    pub fn wanted(&self, request: &str, caller: &Caller) -> bool {
+        if router::get_header(request, "X-Debug") != Some("true") || !caller.is_admin() {
+            return false;
+        }
+        match self.limiter.check(&caller.identity()) {
+            Ok(()) => true,
+            Err(_) => {
+                log!("Not debugging the request: too many X-Debug requests");
+                false
+            }
+        }
+    }
+}
+
+struct Trace {
+    started: Instant,
+    // When the last stage ended
+    marked: Instant,
+    stages: Vec<(&'static str, Duration)>,
+    queries: Vec<(String, Instant, Option<Duration>)>,
+    cache: &'static str,
+}
+
+thread_local! {
+    // The trace of the request this thread is handling, while it's being debugged
+    static TRACE: RefCell<Option<Trace>> = const { RefCell::new(None) };
+}
+
+// Starts tracing the current request, or stops tracing when `debugging` is false
+pub fn start(debugging: bool, started: Instant) {
+    let trace = debugging.then(|| Trace {
+        started,
+        marked: started,
+        stages: Vec::new(),
+        queries: Vec::new(),
+        cache: "off",
+    });
+    TRACE.with(|current| *current.borrow_mut() = trace);
+}
+
+pub fn is_active() -> bool {
+    TRACE.with(|current| current.borrow().is_some())
+}
+
+// Ends a stage of request handling, which began when the last one ended
+pub fn mark(stage: &'static str) {
+    with_trace(|trace| {
+        let now = Instant::now();
+        trace.stages.push((stage, now - trace.marked));
+        trace.marked = now;
+    });
+}
+
+// What the response cache did: `hit`, `miss`, `invalidate` or `bypass`
+pub fn cache(decision: &'static str) {
+    with_trace(|trace| trace.cache = decision);
+}
+
+// Called when a unit of database work is done, ending its last statement
+pub fn end_database_work() {
+    with_trace(end_query);
+}
+
+// Wraps the response body with the debug section and stops tracing
+pub fn attach(request: &str, content: String) -> String {
+    let Some(trace) = TRACE.with(|current| current.borrow_mut().take()) else {
+        return content;
+    };
+    let response = serde_json::from_str(&content).unwrap_or(Value::String(content));
+    let stages: Vec<Value> = trace
+        .stages
+        .iter()
+        .map(|(name, duration)| json!({ "name": name, "ms": millis(*duration) }))
+        .collect();
+    let queries: Vec<Value> = trace
+        .queries
+        .iter()
+        .map(|(sql, _, duration)| json!({ "sql": sql, "ms": duration.map(millis) }))
+        .collect();
+    json!({
+        "response": response,
+        "debug": {
+            "route": router::find_route(router::get_path(request)).map(|route| route.pattern),
+            "total_ms": millis(trace.started.elapsed()),
+            "stages": stages,
+            "queries": queries,
+            "cache": trace.cache,
+        },
+    })
+    .to_string()
+}
+
+fn with_trace(f: impl FnOnce(&mut Trace)) {
+    TRACE.with(|current| {
+        if let Some(trace) = current.borrow_mut().as_mut() {
+            f(trace);
+        }
+    });
+}
+
+fn end_query(trace: &mut Trace) {
+    if let Some((_, started, duration @ None)) = trace.queries.last_mut() {
+        *duration = Some(started.elapsed());
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// Records the statements tokio-postgres logs, on the thread running them, while that
+// thread's request is being debugged
+struct SqlCapture;
+
+static SQL_CAPTURE: SqlCapture = SqlCapture;
+
+impl Log for SqlCapture {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Debug
+            && metadata.target().starts_with("tokio_postgres")
+            && is_active()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        let Some(sql) = statement(&message) else {
+            return;
+        };
+        with_trace(|trace| {
+            end_query(trace);
+            trace.queries.push((sql.to_string(), Instant::now(), None));
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+// The SQL in a tokio-postgres debug message, e.g. `preparing query s3: SELECT 1`
+fn statement(message: &str) -> Option<&str> {
+    if message.starts_with("preparing query ") {
+        return message.split_once(": ").map(|(_, sql)| sql);
+    }
+    message
+        .strip_prefix("executing simple query: ")
+        .or_else(|| message.strip_prefix("executing statement batch: "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statements_are_read_from_driver_messages() {
+        assert_eq!(
+            statement("preparing query s3: SELECT $1::text: 'x'"),
+            Some("SELECT $1::text: 'x'")
+        );
+        assert_eq!(
+            statement("preparing query s4 with types [INT4]: SELECT $1"),
+            Some("SELECT $1")
+        );
+        assert_eq!(statement("executing statement batch: BEGIN"), Some("BEGIN"));
+        assert_eq!(
+            statement("executing statement s3 with parameters: []"),
+            None
+        );
+    }
+}
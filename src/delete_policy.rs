@@ -0,0 +1,171 @@
+use postgres::{Error as PostgresError, GenericClient};
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::OnceLock;
+
+use crate::query_log::Timed;
+
+// What deleting a user does to its related records, set per relation:
+//   POSTS_ON_USER_DELETE     cascade (default), restrict or orphan
+//   PROFILES_ON_USER_DELETE  cascade (default) or restrict
+// - cascade: the records go with the user. A soft-deleted user keeps them (hidden,
+//   and back on restore); they're removed with the user's row.
+// - restrict: a user with any is a 409 listing them, and stays as it is.
+// - orphan: the records are kept, detached from the user (`user_id` set to null).
+// Deletes go through `repo::delete_user`, and the foreign keys are migrated to match
+// at startup, so removing a user row directly behaves the same way. Archiving moves
+// the records along with the user, whatever the policy.
+
+static POLICIES: OnceLock<Vec<(&'static Relation, Policy)>> = OnceLock::new();
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Policy {
+    Cascade,
+    Restrict,
+    Orphan,
+}
+
+struct Relation {
+    // The table, which points at users with `user_id`
+    table: &'static str,
+    setting: &'static str,
+    // The name Postgres gave the foreign key
+    constraint: &'static str,
+    // The records' own key, to list them by
+    key: &'static str,
+    // Whether records can live without a user; `user_id` is the key of 1:1 records
+    can_orphan: bool,
+}
+
+const RELATIONS: &[Relation] = &[
+    Relation {
+        table: "posts",
+        setting: "POSTS_ON_USER_DELETE",
+        constraint: "posts_user_id_fkey",
+        key: "id",
+        can_orphan: true,
+    },
+    Relation {
+        table: "profiles",
+        setting: "PROFILES_ON_USER_DELETE",
+        constraint: "profiles_user_id_fkey",
+        key: "user_id",
+        can_orphan: false,
+    },
+];
+
+// A user's records that a restrict policy won't leave behind: keys by table
+pub type Dependents = BTreeMap<&'static str, Vec<i32>>;
+
+impl Policy {
+    fn parse(value: &str) -> Option<Policy> {
+        match value {
+            "cascade" => Some(Policy::Cascade),
+            "restrict" => Some(Policy::Restrict),
+            "orphan" => Some(Policy::Orphan),
+            _ => None,
+        }
+    }
+
+    // `ON DELETE` action of the foreign key, and its code in `pg_constraint.confdeltype`
+    fn action(self) -> (&'static str, &'static str) {
+        match self {
+            Policy::Cascade => ("CASCADE", "c"),
+            Policy::Restrict => ("RESTRICT", "r"),
+            Policy::Orphan => ("SET NULL", "n"),
+        }
+    }
+}
+
+// The policy of each relation. Values that don't apply fall back to cascade; `config`
+// reports them.
+fn policies() -> &'static [(&'static Relation, Policy)] {
+    POLICIES.get_or_init(|| {
+        RELATIONS
+            .iter()
+            .map(|relation| {
+                let policy = env::var(relation.setting)
+                    .ok()
+                    .and_then(|value| Policy::parse(&value))
+                    .filter(|&policy| policy != Policy::Orphan || relation.can_orphan)
+                    .unwrap_or(Policy::Cascade);
+                (relation, policy)
+            })
+            .collect()
+    })
+}
+
+// Points the foreign keys' `ON DELETE` at the configured policies. Keys that already
+// match are left alone, so restarts don't revalidate them.
+pub fn migrate(client: &mut impl GenericClient) -> Result<(), PostgresError> {
+    for &(relation, policy) in policies() {
+        let (action, code) = policy.action();
+        let current = client.timed_query_opt(
+            "SELECT confdeltype::text FROM pg_constraint WHERE conname = $1 AND conrelid = $2::text::regclass",
+            &[&relation.constraint, &relation.table],
+        )?;
+        if current.map(|row| row.try_get::<_, String>(0)).transpose()?.as_deref() == Some(code) {
+            continue;
+        }
+        let nullable = match policy {
+            Policy::Orphan => "ALTER COLUMN user_id DROP NOT NULL,",
+            _ => "",
+        };
+        client.batch_execute(&format!(
+            "ALTER TABLE {table} {nullable}
+                 DROP CONSTRAINT IF EXISTS {constraint},
+                 ADD CONSTRAINT {constraint} FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE {action}",
+            table = relation.table,
+            constraint = relation.constraint,
+        ))?;
+    }
+    Ok(())
+}
+
+// The user's records under a restrict policy; empty if it can be deleted
+pub fn dependents(client: &mut impl GenericClient, user_id: i32) -> Result<Dependents, PostgresError> {
+    let mut dependents = Dependents::new();
+    for &(relation, policy) in policies() {
+        if policy != Policy::Restrict {
+            continue;
+        }
+        let query = format!(
+            "SELECT {key} FROM {table} WHERE user_id = $1 ORDER BY {key}",
+            key = relation.key,
+            table = relation.table
+        );
+        let keys = client
+            .timed_query(&query, &[&user_id])?
+            .iter()
+            .map(|row| row.try_get(0))
+            .collect::<Result<Vec<i32>, _>>()?;
+        if !keys.is_empty() {
+            dependents.insert(relation.table, keys);
+        }
+    }
+    Ok(dependents)
+}
+
+// Detaches the user's records under an orphan policy
+pub fn orphan(client: &mut impl GenericClient, user_id: i32) -> Result<(), PostgresError> {
+    for &(relation, policy) in policies() {
+        if policy == Policy::Orphan {
+            let query = format!("UPDATE {} SET user_id = NULL WHERE user_id = $1", relation.table);
+            client.timed_execute(&query, &[&user_id])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies_map_to_foreign_key_actions() {
+        assert_eq!(Policy::parse("restrict"), Some(Policy::Restrict));
+        assert_eq!(Policy::parse("set null"), None);
+        assert_eq!(Policy::Orphan.action(), ("SET NULL", "n"));
+        assert!(RELATIONS.iter().all(|relation| relation.constraint == format!("{}_user_id_fkey", relation.table)));
+    }
+}
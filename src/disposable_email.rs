@@ -0,0 +1,137 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+
+// Detection of throwaway email providers.
+// The bundled list ships with the binary; DISPOSABLE_DOMAINS_FILE adds more domains
+// from a file so the list can be kept current without a rebuild.
+// DISPOSABLE_EMAIL_ACTION decides what happens to such addresses: `allow`, `flag`
+// (the default: accepted, but marked in the response and audit log) or `reject`.
+
+const BUNDLED_DOMAINS: &str = include_str!("disposable_domains.txt");
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Action {
+    Allow,
+    Flag,
+    Reject,
+}
+
+// Outcome for a single address
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Decision {
+    NotDisposable,
+    Allowed,
+    Flagged,
+    Rejected,
+}
+
+impl Decision {
+    // Value for the response header and audit log, if the address was disposable
+    pub fn label(self) -> Option<&'static str> {
+        match self {
+            Decision::NotDisposable => None,
+            Decision::Allowed => Some("allowed"),
+            Decision::Flagged => Some("flagged"),
+            Decision::Rejected => Some("rejected"),
+        }
+    }
+}
+
+pub struct DisposableEmails {
+    domains: HashSet<String>,
+    action: Action,
+}
+
+impl DisposableEmails {
+    pub fn new(domains: HashSet<String>, action: Action) -> DisposableEmails {
+        DisposableEmails { domains, action }
+    }
+
+    pub fn from_env() -> DisposableEmails {
+        let mut domains = parse_domains(BUNDLED_DOMAINS);
+        if let Ok(path) = env::var("DISPOSABLE_DOMAINS_FILE") {
+            match fs::read_to_string(&path) {
+                Ok(extra) => domains.extend(parse_domains(&extra)),
+                Err(e) => log!("Error reading {}: {}", path, e),
+            }
+        }
+
+        let action = match env::var("DISPOSABLE_EMAIL_ACTION").as_deref() {
+            Ok("allow") => Action::Allow,
+            Ok("reject") => Action::Reject,
+            Ok("flag") | Err(_) => Action::Flag,
+            Ok(other) => {
+                log!("Unknown DISPOSABLE_EMAIL_ACTION {:?}, using flag", other);
+                Action::Flag
+            }
+        };
+        DisposableEmails::new(domains, action)
+    }
+
+    pub fn is_disposable(&self, email: &str) -> bool {
+        let domain = match email.rsplit_once('@') {
+            Some((_, domain)) => domain.to_lowercase(),
+            None => return false,
+        };
+        // Check the domain and each parent: a.b.mailinator.com, b.mailinator.com, ...
+        let mut candidate = domain.as_str();
+        loop {
+            if self.domains.contains(candidate) {
+                return true;
+            }
+            match candidate.split_once('.') {
+                Some((_, parent)) if parent.contains('.') => candidate = parent,
+                _ => return false,
+            }
+        }
+    }
+
+    pub fn check(&self, email: &str) -> Decision {
+        if !self.is_disposable(email) {
+            return Decision::NotDisposable;
+        }
+        match self.action {
+            Action::Allow => Decision::Allowed,
+            Action::Flag => Decision::Flagged,
+            Action::Reject => Decision::Rejected,
+        }
+    }
+}
+
+// One domain per line; blank lines and `#` comments are ignored
+fn parse_domains(list: &str) -> HashSet<String> {
+    list.lines()
+        .map(|line| {
+            line.split('#')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_lowercase()
+        })
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_domains_and_subdomains_are_detected() {
+        let emails = DisposableEmails::new(parse_domains(BUNDLED_DOMAINS), Action::Reject);
+        assert!(emails.is_disposable("someone@mailinator.com"));
+        assert!(emails.is_disposable("someone@Inbox.YOPMAIL.com"));
+        assert!(!emails.is_disposable("someone@example.com"));
+        assert!(!emails.is_disposable("someone@com"));
+        assert_eq!(emails.check("x@guerrillamail.com"), Decision::Rejected);
+        assert_eq!(emails.check("x@example.com"), Decision::NotDisposable);
+    }
+
+    #[test]
+    fn list_files_allow_comments() {
+        let domains = parse_domains("# header\nfoo.io  # trailing\n\nBAR.dev\n");
+        assert_eq!(domains.len(), 2);
+        assert!(domains.contains("foo.io") && domains.contains("bar.dev"));
+    }
+}
@@ -0,0 +1,132 @@
+use postgres::{Error as PostgresError, GenericClient};
+use std::env;
+use std::time::Duration;
+
+use crate::email::{Email, Mailer};
+use crate::query_log::Timed;
+
+// Verification of email changes. A new address given to PUT, PATCH or a bulk update
+// isn't written to the user: it's kept as the user's pending change, and a token is
+// sent to it. POST /users/{id}/email-change/confirm with the token applies the change;
+// DELETE /users/{id}/email-change cancels it. A user has at most one pending change,
+// so asking for another address replaces it (and its token).
+// Only a hash of the token is stored. Tokens expire after EMAIL_CHANGE_TOKEN_TTL_HOURS
+// (24); EMAIL_CHANGE_VERIFICATION=off writes new addresses directly again.
+
+pub struct EmailChanges {
+    pub verify: bool,
+    pub token_ttl: Duration,
+}
+
+impl EmailChanges {
+    pub fn from_env() -> EmailChanges {
+        let verify = !matches!(
+            env::var("EMAIL_CHANGE_VERIFICATION").as_deref(),
+            Ok("off") | Ok("false") | Ok("0")
+        );
+        let ttl_hours: u64 = env::var("EMAIL_CHANGE_TOKEN_TTL_HOURS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(24);
+        EmailChanges {
+            verify,
+            token_ttl: Duration::from_secs(ttl_hours * 3600),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct PendingChange {
+    pub new_email: String,
+    pub requested_at: String,
+    pub expires_at: String,
+}
+
+// Body of POST /users/{id}/email-change/confirm
+#[derive(Deserialize)]
+pub struct Confirmation {
+    pub token: String,
+}
+
+// Stores a pending change for the user, replacing any earlier one
+pub fn request(
+    client: &mut impl GenericClient,
+    user_id: i32,
+    new_email: &str,
+    token: &str,
+    ttl: Duration,
+) -> Result<(), PostgresError> {
+    client.timed_execute(
+        "INSERT INTO pending_email_changes (user_id, new_email, token_hash, expires_at)
+         VALUES ($1, $2, sha256(convert_to($3, 'UTF8')), now() + make_interval(secs => $4))
+         ON CONFLICT (user_id) DO UPDATE SET new_email = EXCLUDED.new_email,
+             token_hash = EXCLUDED.token_hash, created_at = now(), expires_at = EXCLUDED.expires_at",
+        &[&user_id, &new_email, &token, &ttl.as_secs_f64()],
+    )?;
+    Ok(())
+}
+
+// The user's pending change, unless there's none or it has expired
+pub fn pending(
+    client: &mut impl GenericClient,
+    user_id: i32,
+) -> Result<Option<PendingChange>, PostgresError> {
+    let row = client.timed_query_opt(
+        "SELECT new_email, to_json(created_at) #>> '{}', to_json(expires_at) #>> '{}'
+         FROM pending_email_changes WHERE user_id = $1 AND expires_at > now()",
+        &[&user_id],
+    )?;
+    row.map(|row| {
+        Ok(PendingChange {
+            new_email: row.try_get(0)?,
+            requested_at: row.try_get(1)?,
+            expires_at: row.try_get(2)?,
+        })
+    })
+    .transpose()
+}
+
+// Removes the user's pending change if the token is its current one and hasn't
+// expired, returning the new address. None leaves the pending change as it was.
+pub fn take(
+    client: &mut impl GenericClient,
+    user_id: i32,
+    token: &str,
+) -> Result<Option<String>, PostgresError> {
+    let row = client.timed_query_opt(
+        "DELETE FROM pending_email_changes
+         WHERE user_id = $1 AND token_hash = sha256(convert_to($2, 'UTF8')) AND expires_at > now()
+         RETURNING new_email",
+        &[&user_id, &token],
+    )?;
+    row.map(|row| row.try_get(0)).transpose()
+}
+
+// False if the user had no pending change
+pub fn cancel(client: &mut impl GenericClient, user_id: i32) -> Result<bool, PostgresError> {
+    Ok(client.timed_execute(
+        "DELETE FROM pending_email_changes WHERE user_id = $1 AND expires_at > now()",
+        &[&user_id],
+    )? > 0)
+}
+
+// Sends the confirmation token to the new address. Call it once the change is
+// committed, so tokens that were rolled back are never sent.
+pub fn deliver(mailer: &dyn Mailer, user_id: i32, new_email: &str, token: &str) {
+    let email = Email {
+        to: new_email.to_string(),
+        subject: "Confirm your new email address".to_string(),
+        body: format!(
+            "To use this address for your account, confirm it with POST \
+             /users/{}/email-change/confirm and this token:\n\n{}\n",
+            user_id, token
+        ),
+    };
+    if let Err(e) = mailer.send(&email) {
+        log!(
+            "Email change confirmation for user {} not sent: {}",
+            user_id,
+            e
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use crate::config;
+
+// Which email domains may register.
+// A non-empty allow list restricts sign-ups to those domains (e.g. the corporate ones);
+// the deny list blocks domains regardless. Entries also match their subdomains, so
+// `example.com` covers `mail.example.com`. Loaded from EMAIL_ALLOWED_DOMAINS and
+// EMAIL_DENIED_DOMAINS and replaceable at runtime through PUT /admin/email-policy.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct EmailPolicy {
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+    #[serde(default)]
+    pub denied_domains: Vec<String>,
+}
+
+impl EmailPolicy {
+    pub fn from_env() -> EmailPolicy {
+        EmailPolicy {
+            allowed_domains: config::get_list("EMAIL_ALLOWED_DOMAINS"),
+            denied_domains: config::get_list("EMAIL_DENIED_DOMAINS"),
+        }
+        .normalized()
+    }
+
+    // Lowercases entries and drops blanks and leading `@`/`.` so `@Example.com` works
+    pub fn normalized(self) -> EmailPolicy {
+        let clean = |domains: Vec<String>| {
+            domains
+                .iter()
+                .map(|domain| domain.trim().trim_start_matches(['@', '.']).to_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect()
+        };
+        EmailPolicy {
+            allowed_domains: clean(self.allowed_domains),
+            denied_domains: clean(self.denied_domains),
+        }
+    }
+
+    // Returns a message suitable for the client when the address isn't acceptable
+    pub fn check(&self, email: &str) -> Result<(), String> {
+        let domain = match email.rsplit_once('@') {
+            Some((local, domain)) if !local.is_empty() && !domain.is_empty() => {
+                domain.to_lowercase()
+            }
+            _ => return Err(format!("'{}' is not a valid email address", email)),
+        };
+
+        if let Some(denied) = matching(&self.denied_domains, &domain) {
+            return Err(format!("Email domain '{}' is blocked ({})", domain, denied));
+        }
+        if !self.allowed_domains.is_empty() && matching(&self.allowed_domains, &domain).is_none() {
+            return Err(format!(
+                "Email domain '{}' is not allowed; use one of: {}",
+                domain,
+                self.allowed_domains.join(", ")
+            ));
+        }
+        Ok(())
+    }
+}
+
+// The list entry covering `domain`, either exactly or as a parent domain
+fn matching<'a>(list: &'a [String], domain: &str) -> Option<&'a str> {
+    list.iter()
+        .find(|entry| {
+            domain == entry.as_str()
+                || domain
+                    .strip_suffix(entry.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+        .map(String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_domains(value: &str) -> Vec<String> {
+        value.split(',').map(str::to_string).collect()
+    }
+
+    fn policy(allowed: &str, denied: &str) -> EmailPolicy {
+        EmailPolicy {
+            allowed_domains: parse_domains(allowed),
+            denied_domains: parse_domains(denied),
+        }
+        .normalized()
+    }
+
+    #[test]
+    fn denied_domains_and_their_subdomains_are_rejected() {
+        let policy = policy("", "Spam.example, @blocked.org");
+        assert!(policy.check("a@good.com").is_ok());
+        assert!(policy.check("a@spam.example").is_err());
+        assert!(policy.check("a@MAIL.spam.example").is_err());
+        assert!(policy.check("a@notspam.example").is_ok());
+        assert!(policy.check("a@blocked.org").is_err());
+        assert!(policy.check("not-an-email").is_err());
+    }
+
+    #[test]
+    fn allow_list_restricts_to_listed_domains() {
+        let policy = policy("corp.com", "contractors.corp.com");
+        assert!(policy.check("a@corp.com").is_ok());
+        assert!(policy.check("a@eu.corp.com").is_ok());
+        assert!(policy.check("a@contractors.corp.com").is_err());
+        assert!(policy.check("a@gmail.com").is_err());
+        assert_eq!(EmailPolicy::default().check("a@anything.io"), Ok(()));
+    }
+}
@@ -0,0 +1,65 @@
+use postgres::{Error as PostgresError, GenericClient};
+use std::env;
+use std::time::Duration;
+
+use crate::email::{Email, Mailer};
+use crate::query_log::Timed;
+
+// Verification of new users' email addresses. Creating a user through POST /users
+// mails a token to the address; GET /users/verify?token=... with it marks the user
+// `verified`. Lists can be narrowed to either kind with `?verified=true|false`.
+// Only a hash of the token is stored. Tokens expire after
+// EMAIL_VERIFICATION_TOKEN_TTL_HOURS (24).
+
+pub fn token_ttl_from_env() -> Duration {
+    let ttl_hours: u64 = env::var("EMAIL_VERIFICATION_TOKEN_TTL_HOURS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(24);
+    Duration::from_secs(ttl_hours * 3600)
+}
+
+// Stores the user's token, replacing any earlier one
+pub fn request(
+    client: &mut impl GenericClient,
+    user_id: i32,
+    token: &str,
+    ttl: Duration,
+) -> Result<(), PostgresError> {
+    client.timed_execute(
+        "INSERT INTO email_verifications (user_id, token_hash, expires_at)
+         VALUES ($1, sha256(convert_to($2, 'UTF8')), now() + make_interval(secs => $3))
+         ON CONFLICT (user_id) DO UPDATE SET token_hash = EXCLUDED.token_hash,
+             created_at = now(), expires_at = EXCLUDED.expires_at",
+        &[&user_id, &token, &ttl.as_secs_f64()],
+    )?;
+    Ok(())
+}
+
+// Uses up the token if it's current, returning the ID of the user it was sent to.
+// None if the token is unknown or has expired.
+pub fn take(client: &mut impl GenericClient, token: &str) -> Result<Option<i32>, PostgresError> {
+    let row = client.timed_query_opt(
+        "DELETE FROM email_verifications
+         WHERE token_hash = sha256(convert_to($1, 'UTF8')) AND expires_at > now()
+         RETURNING user_id",
+        &[&token],
+    )?;
+    row.map(|row| row.try_get(0)).transpose()
+}
+
+// Sends the verification token to the new user. Call it once the user is committed,
+// so tokens that were rolled back are never sent.
+pub fn deliver(mailer: &dyn Mailer, user_id: i32, email: &str, token: &str) {
+    let email = Email {
+        to: email.to_string(),
+        subject: "Confirm your email address".to_string(),
+        body: format!(
+            "To confirm this address for your account, open GET /users/verify?token={}\n",
+            token
+        ),
+    };
+    if let Err(e) = mailer.send(&email) {
+        log!("Email verification for user {} not sent: {}", user_id, e);
+    }
+}
@@ -0,0 +1,518 @@
+use postgres::error::SqlState;
+use postgres::Error as PostgresError;
+
+use crate::delete_policy::Dependents;
+use crate::handlers::{
+    BAD_REQUEST, CONFLICT, FORBIDDEN, INTERNAL_SERVER_ERROR, METHOD_NOT_ALLOWED, NOT_FOUND,
+    PRECONDITION_FAILED, PRECONDITION_REQUIRED, REQUEST_TIMEOUT, TOO_MANY_REQUESTS, UNAUTHORIZED,
+    UNPROCESSABLE_ENTITY,
+};
+use crate::request_id;
+
+// Machine-readable error codes. Every error response carries one in a JSON body,
+//   {"code": "USER_NOT_FOUND", "message": "User not found", "request_id": "..."}
+// so clients can branch on the code rather than the message, which may change.
+// Codes are stable: new ones may be added, existing ones aren't renamed or reused.
+// GET /errors lists them all. SCIM endpoints keep the error format of their spec.
+// Handlers fail with an `AppError`, which `AppError::into_response` turns into the
+// error response, so the same failure always gets the same status and code.
+
+const NOT_IMPLEMENTED: &str = "HTTP/1.1 501 NOT IMPLEMENTED\r\n";
+const PAYLOAD_TOO_LARGE: &str = "HTTP/1.1 413 PAYLOAD TOO LARGE\r\n";
+const HEADERS_TOO_LARGE: &str = "HTTP/1.1 431 REQUEST HEADER FIELDS TOO LARGE\r\n";
+const SERVICE_UNAVAILABLE: &str = "HTTP/1.1 503 SERVICE UNAVAILABLE\r\n";
+const UPGRADE_REQUIRED: &str = "HTTP/1.1 426 UPGRADE REQUIRED\r\n";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    InvalidBody,
+    InvalidId,
+    InvalidQuery,
+    InvalidHeader,
+    PayloadTooLarge,
+    TransferEncodingNotSupported,
+    HeadersTooLarge,
+    ConfirmationRequired,
+    Unauthorized,
+    Forbidden,
+    RouteNotFound,
+    UserNotFound,
+    RecordNotFound,
+    NoPendingEmailChange,
+    FeatureDisabled,
+    MethodNotAllowed,
+    RequestTimeout,
+    VersionConflict,
+    DependentsExist,
+    EmailTaken,
+    PreconditionFailed,
+    IdempotencyKeyReused,
+    ValidationFailed,
+    EmailRejected,
+    EmailTokenInvalid,
+    VerificationTokenInvalid,
+    BulkLimitExceeded,
+    PreconditionRequired,
+    RateLimited,
+    DatabaseError,
+    InternalError,
+    NotImplemented,
+    DatabaseTimeout,
+    InjectedFault,
+    UpgradeRequired,
+    NotReady,
+    TooManyConnections,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ErrorInfo {
+    pub code: &'static str,
+    pub status: u16,
+    pub description: &'static str,
+}
+
+impl ErrorCode {
+    pub const ALL: &'static [ErrorCode] = &[
+        ErrorCode::InvalidBody,
+        ErrorCode::InvalidId,
+        ErrorCode::InvalidQuery,
+        ErrorCode::InvalidHeader,
+        ErrorCode::PayloadTooLarge,
+        ErrorCode::TransferEncodingNotSupported,
+        ErrorCode::HeadersTooLarge,
+        ErrorCode::ConfirmationRequired,
+        ErrorCode::Unauthorized,
+        ErrorCode::Forbidden,
+        ErrorCode::RouteNotFound,
+        ErrorCode::UserNotFound,
+        ErrorCode::RecordNotFound,
+        ErrorCode::NoPendingEmailChange,
+        ErrorCode::FeatureDisabled,
+        ErrorCode::MethodNotAllowed,
+        ErrorCode::RequestTimeout,
+        ErrorCode::VersionConflict,
+        ErrorCode::DependentsExist,
+        ErrorCode::EmailTaken,
+        ErrorCode::PreconditionFailed,
+        ErrorCode::IdempotencyKeyReused,
+        ErrorCode::ValidationFailed,
+        ErrorCode::EmailRejected,
+        ErrorCode::EmailTokenInvalid,
+        ErrorCode::VerificationTokenInvalid,
+        ErrorCode::BulkLimitExceeded,
+        ErrorCode::PreconditionRequired,
+        ErrorCode::RateLimited,
+        ErrorCode::DatabaseError,
+        ErrorCode::InternalError,
+        ErrorCode::NotImplemented,
+        ErrorCode::DatabaseTimeout,
+        ErrorCode::InjectedFault,
+        ErrorCode::UpgradeRequired,
+        ErrorCode::NotReady,
+        ErrorCode::TooManyConnections,
+    ];
+
+    // The code, the status line it's sent with, and what it means
+    fn definition(self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            ErrorCode::InvalidBody => (
+                "INVALID_BODY",
+                BAD_REQUEST,
+                "The request body is missing, isn't valid JSON or doesn't have the expected fields",
+            ),
+            ErrorCode::InvalidId => (
+                "INVALID_ID",
+                BAD_REQUEST,
+                "The ID in the path isn't a valid user ID",
+            ),
+            ErrorCode::InvalidQuery => (
+                "INVALID_QUERY",
+                BAD_REQUEST,
+                "A query parameter is missing or has an invalid value",
+            ),
+            ErrorCode::InvalidHeader => (
+                "INVALID_HEADER",
+                BAD_REQUEST,
+                "A request header has an invalid value",
+            ),
+            ErrorCode::PayloadTooLarge => (
+                "PAYLOAD_TOO_LARGE",
+                PAYLOAD_TOO_LARGE,
+                "The request body is longer than the server's MAX_BODY_BYTES",
+            ),
+            ErrorCode::TransferEncodingNotSupported => (
+                "TRANSFER_ENCODING_NOT_SUPPORTED",
+                NOT_IMPLEMENTED,
+                "Request bodies must be sent with a Content-Length, not a Transfer-Encoding",
+            ),
+            ErrorCode::HeadersTooLarge => (
+                "HEADERS_TOO_LARGE",
+                HEADERS_TOO_LARGE,
+                "The request line and headers together are longer than the server accepts",
+            ),
+            ErrorCode::ConfirmationRequired => (
+                "CONFIRMATION_REQUIRED",
+                BAD_REQUEST,
+                "The bulk update wasn't applied; repeat it with confirm=true",
+            ),
+            ErrorCode::Unauthorized => (
+                "UNAUTHORIZED",
+                UNAUTHORIZED,
+                "The bearer token is unknown or expired, or the endpoint needs one",
+            ),
+            ErrorCode::Forbidden => ("FORBIDDEN", FORBIDDEN, "Only admins may do this"),
+            ErrorCode::RouteNotFound => (
+                "ROUTE_NOT_FOUND",
+                NOT_FOUND,
+                "No endpoint exists at this path",
+            ),
+            ErrorCode::UserNotFound => (
+                "USER_NOT_FOUND",
+                NOT_FOUND,
+                "No user with this ID exists (or it isn't in the required state)",
+            ),
+            ErrorCode::RecordNotFound => (
+                "RECORD_NOT_FOUND",
+                NOT_FOUND,
+                "No record of the kind in the path exists with this ID",
+            ),
+            ErrorCode::NoPendingEmailChange => (
+                "NO_PENDING_EMAIL_CHANGE",
+                NOT_FOUND,
+                "The user has no email change waiting for confirmation, or it expired",
+            ),
+            ErrorCode::FeatureDisabled => (
+                "FEATURE_DISABLED",
+                NOT_FOUND,
+                "The endpoint belongs to a feature that is turned off on this server",
+            ),
+            ErrorCode::MethodNotAllowed => (
+                "METHOD_NOT_ALLOWED",
+                METHOD_NOT_ALLOWED,
+                "The path exists but not for this method; see the Allow header",
+            ),
+            ErrorCode::RequestTimeout => (
+                "REQUEST_TIMEOUT",
+                REQUEST_TIMEOUT,
+                "The request wasn't received in time",
+            ),
+            ErrorCode::VersionConflict => (
+                "VERSION_CONFLICT",
+                CONFLICT,
+                "The user's version changed; fetch it again and retry",
+            ),
+            ErrorCode::DependentsExist => (
+                "DEPENDENTS_EXIST",
+                CONFLICT,
+                "The user has related records the delete policies don't let it leave behind; see `dependents`",
+            ),
+            ErrorCode::EmailTaken => (
+                "EMAIL_TAKEN",
+                CONFLICT,
+                "Another live user already has this email address",
+            ),
+            ErrorCode::PreconditionFailed => (
+                "PRECONDITION_FAILED",
+                PRECONDITION_FAILED,
+                "The If-Match ETag is stale; fetch the user again and retry",
+            ),
+            ErrorCode::IdempotencyKeyReused => (
+                "IDEMPOTENCY_KEY_REUSED",
+                UNPROCESSABLE_ENTITY,
+                "The Idempotency-Key was already used for a request with a different body",
+            ),
+            ErrorCode::ValidationFailed => (
+                "VALIDATION_FAILED",
+                UNPROCESSABLE_ENTITY,
+                "The body is well-formed but a value in it isn't acceptable",
+            ),
+            ErrorCode::EmailRejected => (
+                "EMAIL_REJECTED",
+                UNPROCESSABLE_ENTITY,
+                "The email address is invalid or not allowed by the email policy",
+            ),
+            ErrorCode::EmailTokenInvalid => (
+                "EMAIL_TOKEN_INVALID",
+                BAD_REQUEST,
+                "The token doesn't confirm the user's pending email change",
+            ),
+            ErrorCode::VerificationTokenInvalid => (
+                "VERIFICATION_TOKEN_INVALID",
+                BAD_REQUEST,
+                "The email verification token is unknown or has expired",
+            ),
+            ErrorCode::BulkLimitExceeded => (
+                "BULK_LIMIT_EXCEEDED",
+                UNPROCESSABLE_ENTITY,
+                "The bulk update filter matches too many users",
+            ),
+            ErrorCode::PreconditionRequired => (
+                "PRECONDITION_REQUIRED",
+                PRECONDITION_REQUIRED,
+                "Updates need an If-Match header with the user's ETag",
+            ),
+            ErrorCode::RateLimited => (
+                "RATE_LIMITED",
+                TOO_MANY_REQUESTS,
+                "Too many requests from this client; retry after the Retry-After delay",
+            ),
+            ErrorCode::DatabaseError => (
+                "DATABASE_ERROR",
+                INTERNAL_SERVER_ERROR,
+                "The database failed or couldn't be reached; retrying may help",
+            ),
+            ErrorCode::InternalError => (
+                "INTERNAL_ERROR",
+                INTERNAL_SERVER_ERROR,
+                "Something unexpected went wrong on the server",
+            ),
+            ErrorCode::NotImplemented => (
+                "NOT_IMPLEMENTED",
+                NOT_IMPLEMENTED,
+                "The endpoint isn't available in mock mode",
+            ),
+            ErrorCode::DatabaseTimeout => (
+                "DATABASE_TIMEOUT",
+                SERVICE_UNAVAILABLE,
+                "No database connection became free in time; retry later",
+            ),
+            ErrorCode::InjectedFault => (
+                "INJECTED_FAULT",
+                SERVICE_UNAVAILABLE,
+                "Chaos mode failed the request on purpose; the status is the rule's error_status",
+            ),
+            ErrorCode::UpgradeRequired => (
+                "UPGRADE_REQUIRED",
+                UPGRADE_REQUIRED,
+                "The endpoint is a WebSocket; connect with a version 13 WebSocket handshake",
+            ),
+            ErrorCode::NotReady => (
+                "NOT_READY",
+                SERVICE_UNAVAILABLE,
+                "The server can't serve requests yet, e.g. its database is unreachable",
+            ),
+            ErrorCode::TooManyConnections => (
+                "TOO_MANY_CONNECTIONS",
+                SERVICE_UNAVAILABLE,
+                "The server already has MAX_CONNECTIONS connections open; retry later",
+            ),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        self.definition().0
+    }
+
+    pub fn status_line(self) -> &'static str {
+        self.definition().1
+    }
+
+    pub fn info(self) -> ErrorInfo {
+        let (code, status_line, description) = self.definition();
+        ErrorInfo {
+            code,
+            status: status_code(status_line),
+            description,
+        }
+    }
+}
+
+// Why a handler failed. Returning one from the work passed to `db::in_transaction`
+// also rolls the transaction back.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("database error: {0}")]
+    Db(#[from] PostgresError),
+    // No record with the ID, or not in the state the request needs; the code says of what
+    #[error("{1}")]
+    NotFound(ErrorCode, String),
+    // The request can't be served as sent; the code says why
+    #[error("{1}")]
+    Validation(ErrorCode, String),
+    // Admin only
+    #[error("Forbidden")]
+    Auth,
+    // Deleting the user would leave these records behind (see `delete_policy`)
+    #[error("The user has related records; remove them first")]
+    Restricted(Dependents),
+    // Serializing a response body only fails on a bug
+    #[error("couldn't serialize response: {0}")]
+    Serialization(#[from] serde_json::Error),
+    // No pooled connection became free in time
+    #[error("timed out waiting for a database connection")]
+    Timeout,
+    // Reading randomness for a token failed
+    #[error("couldn't generate a token: {0}")]
+    Token(#[source] std::io::Error),
+    // Writing a Parquet export failed (see `export`)
+    #[cfg(feature = "parquet")]
+    #[error("couldn't write the export: {0}")]
+    Export(String),
+    // A ready-made response, for errors that don't use the JSON error body (SCIM)
+    #[error("{}", .0.trim_end())]
+    Response(String, String),
+}
+
+impl AppError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            // Text Postgres can't store, like NUL characters, is the client's mistake
+            AppError::Db(e) if e.code() == Some(&SqlState::CHARACTER_NOT_IN_REPERTOIRE) => {
+                ErrorCode::InvalidBody
+            }
+            // Live users' emails are unique (the `users_email_live` index)
+            AppError::Db(e) if e.as_db_error().and_then(|e| e.constraint()) == Some("users_email_live") => {
+                ErrorCode::EmailTaken
+            }
+            AppError::Db(_) => ErrorCode::DatabaseError,
+            AppError::NotFound(code, _) | AppError::Validation(code, _) => *code,
+            AppError::Auth => ErrorCode::Forbidden,
+            AppError::Restricted(_) => ErrorCode::DependentsExist,
+            AppError::Serialization(_) | AppError::Token(_) => ErrorCode::InternalError,
+            #[cfg(feature = "parquet")]
+            AppError::Export(_) => ErrorCode::InternalError,
+            AppError::Timeout => ErrorCode::DatabaseTimeout,
+            AppError::Response(..) => ErrorCode::InternalError,
+        }
+    }
+
+    // The error response. Server-side failures are logged, and the client only
+    // learns what kind they were.
+    pub(crate) fn into_response(self) -> (String, String) {
+        let code = self.code();
+        match self {
+            AppError::Response(status_line, body) => (status_line, body),
+            AppError::Restricted(ref dependents) => {
+                let mut body = error_json(code, &self.to_string());
+                body["dependents"] = serde_json::json!(dependents);
+                (format!("{}Content-Type: application/json\r\n", code.status_line()), body.to_string())
+            }
+            AppError::Db(_) if code == ErrorCode::InvalidBody => {
+                error(code, "Text can't contain NUL characters")
+            }
+            AppError::Db(_) if code == ErrorCode::EmailTaken => {
+                error(code, "Another user already has this email address")
+            }
+            AppError::Db(_)
+            | AppError::Serialization(_)
+            | AppError::Timeout
+            | AppError::Token(_) => {
+                log!("{}", self);
+                let message = match code {
+                    ErrorCode::DatabaseError => "Database error",
+                    ErrorCode::DatabaseTimeout => "Database busy",
+                    _ => "Internal server error",
+                };
+                error(code, message)
+            }
+            e => error(code, &e.to_string()),
+        }
+    }
+}
+
+// The JSON error body for a code, with the current request's ID when there is one
+pub fn error_body(code: ErrorCode, message: &str) -> String {
+    error_json(code, message).to_string()
+}
+
+fn error_json(code: ErrorCode, message: &str) -> serde_json::Value {
+    let mut body = serde_json::json!({ "code": code.as_str(), "message": message });
+    if let Some(id) = request_id::current() {
+        body["request_id"] = id.into();
+    }
+    body
+}
+
+// An error response with the code's status.
+// Returns a `(String, String)` tuple for HTTP status and body, like the handlers.
+pub(crate) fn error(code: ErrorCode, message: &str) -> (String, String) {
+    error_with_status(code.status_line(), code, message)
+}
+
+// An error response with a status line other than the code's usual one
+pub(crate) fn error_with_status(
+    status_line: &str,
+    code: ErrorCode,
+    message: &str,
+) -> (String, String) {
+    (
+        format!("{}Content-Type: application/json\r\n", status_line),
+        error_body(code, message),
+    )
+}
+
+fn status_code(status_line: &str) -> u16 {
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_unique_and_have_error_statuses() {
+        let mut codes: Vec<&str> = ErrorCode::ALL.iter().map(|code| code.as_str()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), ErrorCode::ALL.len());
+        for code in ErrorCode::ALL {
+            let info = code.info();
+            assert!(info.status >= 400, "{}", info.code);
+            assert!(info
+                .code
+                .bytes()
+                .all(|byte| byte.is_ascii_uppercase() || byte == b'_'));
+        }
+        assert_eq!(ErrorCode::UserNotFound.info().status, 404);
+    }
+
+    #[test]
+    fn error_bodies_carry_the_code_and_request_id() {
+        let (status_line, body) = error(ErrorCode::UserNotFound, "User not found");
+        assert_eq!(
+            status_line,
+            "HTTP/1.1 404 NOT FOUND\r\nContent-Type: application/json\r\n"
+        );
+        assert_eq!(
+            body,
+            r#"{"code":"USER_NOT_FOUND","message":"User not found"}"#
+        );
+
+        request_id::set_current(Some("abc".to_string()));
+        let body: serde_json::Value =
+            serde_json::from_str(&error_body(ErrorCode::DatabaseError, "Database error")).unwrap();
+        request_id::set_current(None);
+        assert_eq!(body["code"], "DATABASE_ERROR");
+        assert_eq!(body["request_id"], "abc");
+    }
+
+    #[test]
+    fn app_errors_map_to_codes_without_leaking_details() {
+        let (status_line, body) =
+            AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string())
+                .into_response();
+        assert!(status_line.starts_with("HTTP/1.1 404"));
+        assert!(body.contains("USER_NOT_FOUND"));
+
+        let (status_line, body) =
+            AppError::Validation(ErrorCode::VersionConflict, "Version conflict".to_string())
+                .into_response();
+        assert!(status_line.starts_with("HTTP/1.1 409"));
+        assert!(body.contains("Version conflict"));
+
+        let invalid = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        let (status_line, body) = AppError::from(invalid).into_response();
+        assert!(status_line.starts_with("HTTP/1.1 500"));
+        assert_eq!(
+            body,
+            r#"{"code":"INTERNAL_ERROR","message":"Internal server error"}"#
+        );
+
+        assert_eq!(AppError::Auth.code(), ErrorCode::Forbidden);
+        assert_eq!(AppError::Timeout.code().info().status, 503);
+    }
+}
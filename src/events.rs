@@ -0,0 +1,345 @@
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::{Client, Error as PostgresError, GenericClient};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::process;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::models::User;
+use crate::query_log::Timed;
+
+// Change events for live subscribers, such as dashboards on GET /ws/users (see
+// `websocket`) or GET /users/events (see `sse`): a user was created, updated or deleted, with the user as it is now.
+// Every mutation's audit entry (see `audit`) stages its event on the request's thread;
+// `repo::with_transaction` publishes what was staged once the transaction commits and
+// drops it if it rolls back, so subscribers only hear about changes that happened.
+// Other instances hear about them through a Postgres NOTIFY sent in the same
+// transaction (so it's only delivered if it commits) on a channel per schema, like
+// `user_changes_public`; each instance LISTENs there and publishes the changes other
+// instances made to its own subscribers. Event IDs are per instance.
+// Each subscriber has a queue of QUEUE_SIZE events. One that falls that far behind is
+// disconnected rather than holding up mutations or buffering without limit.
+// The last RECENT_EVENTS events are kept, so subscribers that reconnect can resume
+// from the last event they saw.
+
+const QUEUE_SIZE: usize = 1024;
+const RECENT_EVENTS: usize = 1024;
+
+const CHANNEL_PREFIX: &str = "user_changes_";
+
+// NOTIFY payloads must be shorter than 8000 bytes; bigger events are sent without the
+// user
+const MAX_PAYLOAD: usize = 7900;
+
+// How long the listener waits for a notification before checking its connection
+const LISTEN_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long the listener waits before reconnecting after losing its connection
+const LISTEN_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ChangeEvent {
+    // Increases with every event this instance publishes
+    pub id: u64,
+    // created, updated or deleted
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub user_id: i32,
+    // The user after the change; deleted users as they were deleted
+    pub user: Option<Value>,
+}
+
+// A change as sent to other instances
+#[derive(Serialize, Deserialize)]
+struct Notification {
+    // The instance that made the change, which ignores its own notifications
+    instance: String,
+    #[serde(rename = "type")]
+    kind: String,
+    user_id: i32,
+    user: Option<Value>,
+}
+
+struct Hub {
+    subscribers: Vec<SyncSender<Arc<ChangeEvent>>>,
+    // Oldest first
+    recent: VecDeque<Arc<ChangeEvent>>,
+    last_id: u64,
+}
+
+static HUB: Mutex<Hub> = Mutex::new(Hub {
+    subscribers: Vec::new(),
+    recent: VecDeque::new(),
+    last_id: 0,
+});
+
+thread_local! {
+    // Events of the current transaction, waiting for it to commit
+    static STAGED: RefCell<Vec<(&'static str, i32, Option<Value>)>> = const { RefCell::new(Vec::new()) };
+}
+
+// A queue of the events published from now on. It's disconnected if the subscriber
+// falls too far behind.
+pub fn subscribe() -> Receiver<Arc<ChangeEvent>> {
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    HUB.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .subscribers
+        .push(sender);
+    receiver
+}
+
+// Like `subscribe`, for a subscriber that saw the events up to `last_id` before it
+// disconnected. Also returns the events it missed since, or None if they aren't all
+// kept any more (or `last_id` is from before this instance started).
+pub fn resume(last_id: u64) -> (Option<Vec<Arc<ChangeEvent>>>, Receiver<Arc<ChangeEvent>>) {
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    let mut hub = HUB.lock().unwrap_or_else(|e| e.into_inner());
+    let missed: Vec<_> = hub
+        .recent
+        .iter()
+        .filter(|event| event.id > last_id)
+        .cloned()
+        .collect();
+    let complete = last_id <= hub.last_id
+        && missed
+            .first()
+            .map_or(last_id == hub.last_id, |event| event.id == last_id + 1);
+    hub.subscribers.push(sender);
+    (complete.then_some(missed), receiver)
+}
+
+// Stages the event for an audited action, if it's a change subscribers hear about
+pub fn stage(action: &str, user_id: i32, old: Option<&User>, new: Option<&User>) {
+    let Some(kind) = kind_of(action) else {
+        return;
+    };
+    let user = new.or(old).and_then(|user| serde_json::to_value(user).ok());
+    STAGED.with(|staged| staged.borrow_mut().push((kind, user_id, user)));
+}
+
+// Tells the other instances about an audited change; they hear about it once the
+// transaction `client` is in commits
+pub fn notify(
+    client: &mut impl GenericClient,
+    action: &str,
+    user_id: i32,
+    old: Option<&User>,
+    new: Option<&User>,
+) -> Result<(), PostgresError> {
+    let Some(kind) = kind_of(action) else {
+        return Ok(());
+    };
+    let mut notification = Notification {
+        instance: instance().to_string(),
+        kind: kind.to_string(),
+        user_id,
+        user: new.or(old).and_then(|user| serde_json::to_value(user).ok()),
+    };
+    let mut payload = serde_json::to_string(&notification).unwrap_or_default();
+    if payload.len() > MAX_PAYLOAD {
+        notification.user = None;
+        payload = serde_json::to_string(&notification).unwrap_or_default();
+    }
+    client.timed_execute(
+        "SELECT pg_notify($1 || current_schema(), $2)",
+        &[&CHANNEL_PREFIX, &payload],
+    )?;
+    Ok(())
+}
+
+// Forgets the events staged on this thread, when a transaction starts or rolls back
+pub fn discard_staged() {
+    STAGED.with(|staged| staged.borrow_mut().clear());
+}
+
+// Publishes the events staged on this thread, once their transaction has committed
+pub fn publish_staged() {
+    let staged = STAGED.with(|staged| staged.take());
+    if staged.is_empty() {
+        return;
+    }
+    let mut hub = HUB.lock().unwrap_or_else(|e| e.into_inner());
+    for (kind, user_id, user) in staged {
+        publish(&mut hub, kind, user_id, user);
+    }
+}
+
+fn publish(hub: &mut Hub, kind: &'static str, user_id: i32, user: Option<Value>) {
+    hub.last_id += 1;
+    let event = Arc::new(ChangeEvent {
+        id: hub.last_id,
+        kind,
+        user_id,
+        user,
+    });
+    // Full queues are dropped along with those of subscribers that went away
+    hub.subscribers
+        .retain(|subscriber| subscriber.try_send(Arc::clone(&event)).is_ok());
+    if hub.recent.len() == RECENT_EVENTS {
+        hub.recent.pop_front();
+    }
+    hub.recent.push_back(event);
+}
+
+// Starts a thread that LISTENs for the changes other instances make and publishes
+// them here. `connect` opens its connection, again whenever it's lost or
+// `generation` changes (the database it connects to changed, see `standby`).
+pub fn listen(
+    connect: impl Fn() -> Result<Client, PostgresError> + Send + 'static,
+    generation: impl Fn() -> u64 + Send + 'static,
+) {
+    thread::spawn(move || {
+        let mut connected_before = false;
+        loop {
+            let connected_to = generation();
+            let result = connect().and_then(|mut client| {
+                let channel: String = client
+                    .timed_query_one("SELECT $1 || current_schema()", &[&CHANNEL_PREFIX])?
+                    .try_get(0)?;
+                client.batch_execute(&format!("LISTEN \"{}\"", channel.replace('"', "\"\"")))?;
+                // Changes made while the listener was away are lost, so subscribers
+                // have to start over
+                if connected_before {
+                    lose_events();
+                }
+                connected_before = true;
+                receive(&mut client, || generation() != connected_to)
+            });
+            if let Err(e) = result {
+                log!("Error listening for change events: {}", e);
+            }
+            thread::sleep(LISTEN_RETRY_DELAY);
+        }
+    });
+}
+
+// Publishes notifications as they come until the connection fails or is `stale`
+fn receive(client: &mut Client, stale: impl Fn() -> bool) -> Result<(), PostgresError> {
+    loop {
+        if stale() {
+            return Ok(());
+        }
+        let received = client
+            .notifications()
+            .timeout_iter(LISTEN_CHECK_INTERVAL)
+            .next()?;
+        let Some(notification) = received else {
+            // Quiet for a while: make sure that's not because the connection is gone
+            client.is_valid(LISTEN_CHECK_INTERVAL)?;
+            continue;
+        };
+        if let Some((kind, user_id, user)) = parse_notification(notification.payload()) {
+            let mut hub = HUB.lock().unwrap_or_else(|e| e.into_inner());
+            publish(&mut hub, kind, user_id, user);
+        }
+    }
+}
+
+// The event in a notification from another instance; None for this instance's own
+// (its subscribers have had them already) and ones it can't read
+fn parse_notification(payload: &str) -> Option<(&'static str, i32, Option<Value>)> {
+    let notification: Notification = serde_json::from_str(payload).ok()?;
+    let kind = match notification.kind.as_str() {
+        "created" => "created",
+        "updated" => "updated",
+        "deleted" => "deleted",
+        _ => return None,
+    };
+    (notification.instance != instance()).then_some((kind, notification.user_id, notification.user))
+}
+
+// Disconnects every subscriber and forgets the recent events, after events may have
+// been missed. The ID skips one, so subscribers resuming from any earlier event get a
+// reset.
+fn lose_events() {
+    let mut hub = HUB.lock().unwrap_or_else(|e| e.into_inner());
+    hub.subscribers.clear();
+    hub.recent.clear();
+    hub.last_id += 1;
+}
+
+// Identifies this process among the instances sharing the database. Process IDs alone
+// aren't enough, since every container's server may be PID 1.
+fn instance() -> &'static str {
+    static INSTANCE: OnceLock<String> = OnceLock::new();
+    INSTANCE.get_or_init(|| {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        format!("{}-{:x}", process::id(), started.as_nanos())
+    })
+}
+
+// Restored and unarchived users are back in the list, so subscribers see them created
+// again; archived ones are gone from it like deleted ones
+pub(crate) fn kind_of(action: &str) -> Option<&'static str> {
+    match action {
+        "create" | "restore" | "unarchive" => Some("created"),
+        "update" => Some("updated"),
+        "delete" | "archive" => Some("deleted"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_committed_changes_are_published() {
+        let events = subscribe();
+        stage("impersonate", 1, None, None);
+        stage("create", 7, None, None);
+        discard_staged();
+        stage("update", 8, None, None);
+        stage("restore", 9, None, None);
+        publish_staged();
+
+        // Other tests may publish too
+        let mine: Vec<(&str, i32)> = events
+            .try_iter()
+            .filter(|event| [7, 8, 9].contains(&event.user_id))
+            .map(|event| (event.kind, event.user_id))
+            .collect();
+        assert_eq!(mine, [("updated", 8), ("created", 9)]);
+    }
+
+    #[test]
+    fn subscribers_resume_from_their_last_event() {
+        let last_id = HUB.lock().unwrap().last_id;
+        stage("update", 10, None, None);
+        stage("delete", 10, None, None);
+        publish_staged();
+
+        let (missed, _) = resume(last_id);
+        let missed: Vec<(&str, i32)> = missed
+            .unwrap()
+            .iter()
+            .filter(|event| event.user_id == 10)
+            .map(|event| (event.kind, event.user_id))
+            .collect();
+        assert_eq!(missed, [("updated", 10), ("deleted", 10)]);
+        // IDs this instance never gave out can't be resumed from
+        assert_eq!(resume(u64::MAX).0, None);
+    }
+
+    #[test]
+    fn only_other_instances_notifications_are_published() {
+        let payload = |instance: &str, kind: &str| {
+            serde_json::json!({"instance": instance, "type": kind, "user_id": 3, "user": null})
+                .to_string()
+        };
+        assert_eq!(
+            parse_notification(&payload("other", "updated")),
+            Some(("updated", 3, None))
+        );
+        assert_eq!(parse_notification(&payload(instance(), "updated")), None);
+        assert_eq!(parse_notification(&payload("other", "renamed")), None);
+        assert_eq!(parse_notification("not json"), None);
+    }
+}
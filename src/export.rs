@@ -0,0 +1,305 @@
+use arrow_array::{ArrayRef, BooleanArray, Int32Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::db::{self, with_client};
+use crate::errors::{error, AppError, ErrorCode};
+use crate::handlers::{json_response, list_error, list_filter, OK_RESPONSE};
+use crate::models::User;
+use crate::repo::{self, UserFilter};
+use crate::router::{find_route, get_id, get_path, get_query_param};
+use crate::scheduler;
+use crate::server::{AppState, Caller};
+use crate::standby::Standby;
+
+// GET /users/export?format=parquet (admin only, needs the `parquet` feature): the users
+// as an Apache Parquet file for data warehouses, with the filters of GET /users. The
+// columns are id, name, email, created_at, updated_at, deleted_at (ISO 8601 text, null
+// when unset), version and verified; each batch of EXPORT_BATCH_SIZE users read through
+// the cursor is a Snappy-compressed row group.
+// Up to EXPORT_INLINE_MAX_ROWS (10000) users are sent in the response. Bigger exports
+// are written to a file in EXPORT_DIR (the system's temporary directory) by a
+// background job, and the response is a 202 pointing at the job:
+//   Location: /admin/exports/7
+//   {"id": 7, "status": "running", "rows": null, "error": null}
+// GET /admin/exports/{id} answers the same way until the file is ready, and then with the
+// file; jobs that failed say why. The last EXPORT_KEEP (5) finished exports are kept,
+// and jobs are forgotten when the server restarts.
+
+const EXPORT_BATCH_SIZE: i32 = 10_000;
+
+const PARQUET_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/vnd.apache.parquet\r\n\
+    Content-Disposition: attachment; filename=\"users.parquet\"\r\n";
+const ACCEPTED: &str = "HTTP/1.1 202 ACCEPTED\r\nContent-Type: application/json\r\n";
+
+pub struct Exports {
+    // Oldest first
+    jobs: Arc<Mutex<Vec<Job>>>,
+    next_id: AtomicU64,
+    dir: PathBuf,
+    inline_max_rows: i64,
+    keep: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Job {
+    pub id: u64,
+    // running, done or failed
+    pub status: &'static str,
+    pub rows: Option<u64>,
+    pub error: Option<String>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Exports {
+    pub fn from_env() -> Exports {
+        let setting = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        Exports {
+            jobs: Arc::new(Mutex::new(Vec::new())),
+            next_id: AtomicU64::new(1),
+            dir: env::var("EXPORT_DIR")
+                .ok()
+                .filter(|dir| !dir.is_empty())
+                .map_or_else(env::temp_dir, PathBuf::from),
+            inline_max_rows: setting("EXPORT_INLINE_MAX_ROWS", 10_000) as i64,
+            keep: setting("EXPORT_KEEP", 5) as usize,
+        }
+    }
+
+    // Writes the users to a file in the background; returns the job as it starts
+    fn start(&self, filter: UserFilter, standby: Option<Arc<Standby>>) -> Job {
+        let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let job = Job {
+            id,
+            status: "running",
+            rows: None,
+            error: None,
+            path: self.dir.join(format!("users-{}-{}.parquet", process::id(), id)),
+        };
+        jobs.push(job.clone());
+
+        let path = job.path.clone();
+        let jobs = Arc::clone(&self.jobs);
+        let keep = self.keep;
+        scheduler::once("parquet export", move || {
+            let result = write_file(&path, &filter, standby.as_deref()).map_err(|e| e.to_string());
+            let mut jobs = jobs.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(job) = jobs.iter_mut().find(|job| job.id == id) {
+                match &result {
+                    Ok(rows) => {
+                        job.status = "done";
+                        job.rows = Some(*rows);
+                    }
+                    Err(e) => {
+                        job.status = "failed";
+                        job.error = Some(e.clone());
+                    }
+                }
+            }
+            // Only the latest finished exports are kept
+            while jobs.iter().filter(|job| job.status != "running").count() > keep {
+                let Some(oldest) = jobs.iter().position(|job| job.status != "running") else {
+                    break;
+                };
+                let _ = fs::remove_file(&jobs.remove(oldest).path);
+            }
+            result.map(|rows| log!("Exported {} users to {}", rows, path.display()))
+        });
+        job
+    }
+
+    fn job(&self, id: u64) -> Option<Job> {
+        let jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        jobs.iter().find(|job| job.id == id).cloned()
+    }
+}
+
+// Handle GET /users/export and GET /admin/exports/{id}
+// Returns the status line and body; the body is binary once it's a file.
+pub(crate) fn response(request: &str, state: &AppState, caller: &Caller) -> (String, Vec<u8>) {
+    handle(request, state, caller).unwrap_or_else(|(status_line, content)| (status_line, content.into_bytes()))
+}
+
+fn handle(request: &str, state: &AppState, caller: &Caller) -> Result<(String, Vec<u8>), (String, String)> {
+    if !caller.is_admin() {
+        return Err(AppError::Auth.into_response());
+    }
+    if state.mock.is_some() {
+        return Err(error(ErrorCode::NotImplemented, "Not available in mock mode"));
+    }
+    let json = |status_line: &str, job: &Job| {
+        let (status_line, content) = json_response(status_line, job);
+        (status_line, content.into_bytes())
+    };
+    if find_route(get_path(request)).is_some_and(|route| route.pattern == "/admin/exports/{id}") {
+        let job = get_id(request)
+            .parse()
+            .ok()
+            .and_then(|id| state.exports.job(id))
+            .ok_or_else(|| AppError::NotFound(ErrorCode::RecordNotFound, "No such export".to_string()).into_response())?;
+        return match job.status {
+            "done" => match fs::read(&job.path) {
+                Ok(file) => Ok((PARQUET_RESPONSE.to_string(), file)),
+                Err(e) => {
+                    log!("Error reading export {}: {}", job.path.display(), e);
+                    Err(error(ErrorCode::InternalError, "Internal server error"))
+                }
+            },
+            "running" => Ok(json(&format!("{}Retry-After: 5\r\n", ACCEPTED), &job)),
+            _ => Ok(json(OK_RESPONSE, &job)),
+        };
+    }
+
+    if get_query_param(request, "format").as_deref() != Some("parquet") {
+        return Err(AppError::Validation(ErrorCode::InvalidQuery, "format must be parquet".to_string()).into_response());
+    }
+    let filter = list_filter(request, caller)?;
+    let count = with_client(state, |client| Ok(repo::count_users(client, &filter)?)).map_err(list_error)?;
+    if count > state.exports.inline_max_rows {
+        let job = state.exports.start(filter, state.standby.clone());
+        return Ok(json(&format!("{}Location: /admin/exports/{}\r\n", ACCEPTED, job.id), &job));
+    }
+    let file = with_client(state, |client| {
+        let mut transaction = client.transaction()?;
+        let mut writer = UsersWriter::new(Vec::new())?;
+        repo::for_each_user_batch(&mut transaction, &filter, EXPORT_BATCH_SIZE, |users| writer.write(&users))?;
+        Ok(writer.finish()?.0)
+    })
+    .map_err(list_error)?;
+    Ok((PARQUET_RESPONSE.to_string(), file))
+}
+
+// Writes the export to `path` on a connection of its own, returning how many users it has.
+// The file only appears under its name once it's complete.
+fn write_file(path: &PathBuf, filter: &UserFilter, standby: Option<&Standby>) -> Result<u64, AppError> {
+    let partial = path.with_extension("parquet.partial");
+    let result = (|| {
+        let mut client = db::connect(standby)?;
+        let mut transaction = client.transaction()?;
+        let file = File::create(&partial).map_err(|e| AppError::Export(e.to_string()))?;
+        let mut writer = UsersWriter::new(BufWriter::new(file))?;
+        repo::for_each_user_batch(&mut transaction, filter, EXPORT_BATCH_SIZE, |users| writer.write(&users))?;
+        let (mut file, rows) = writer.finish()?;
+        file.flush().map_err(|e| AppError::Export(e.to_string()))?;
+        fs::rename(&partial, path).map_err(|e| AppError::Export(e.to_string()))?;
+        Ok(rows)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    result
+}
+
+// Users written as Parquet, a row group per `write`
+struct UsersWriter<W: Write + Send> {
+    writer: ArrowWriter<W>,
+    schema: Arc<Schema>,
+    rows: u64,
+}
+
+impl<W: Write + Send> UsersWriter<W> {
+    fn new(out: W) -> Result<Self, AppError> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("name", DataType::Utf8, false),
+            Field::new("email", DataType::Utf8, false),
+            Field::new("created_at", DataType::Utf8, true),
+            Field::new("updated_at", DataType::Utf8, true),
+            Field::new("deleted_at", DataType::Utf8, true),
+            Field::new("version", DataType::Int32, true),
+            Field::new("verified", DataType::Boolean, true),
+        ]));
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let writer = ArrowWriter::try_new(out, Arc::clone(&schema), Some(properties)).map_err(export_error)?;
+        Ok(UsersWriter { writer, schema, rows: 0 })
+    }
+
+    fn write(&mut self, users: &[User]) -> Result<(), AppError> {
+        let text = |field: fn(&User) -> Option<&str>| -> ArrayRef { Arc::new(StringArray::from(users.iter().map(field).collect::<Vec<_>>())) };
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(Int32Array::from_iter_values(users.iter().map(|user| user.id.unwrap_or_default()))),
+            Arc::new(StringArray::from_iter_values(users.iter().map(|user| &user.name))),
+            Arc::new(StringArray::from_iter_values(users.iter().map(|user| &user.email))),
+            text(|user| user.created_at.as_deref()),
+            text(|user| user.updated_at.as_deref()),
+            text(|user| user.deleted_at.as_deref()),
+            Arc::new(Int32Array::from(users.iter().map(|user| user.version).collect::<Vec<_>>())),
+            Arc::new(BooleanArray::from(users.iter().map(|user| user.verified).collect::<Vec<_>>())),
+        ];
+        let batch = RecordBatch::try_new(Arc::clone(&self.schema), columns).map_err(|e| AppError::Export(e.to_string()))?;
+        self.writer.write(&batch).map_err(export_error)?;
+        // Each batch is a row group, so memory use stays flat
+        self.writer.flush().map_err(export_error)?;
+        self.rows += users.len() as u64;
+        Ok(())
+    }
+
+    // Writes the footer; returns the output and how many users went into it
+    fn finish(self) -> Result<(W, u64), AppError> {
+        Ok((self.writer.into_inner().map_err(export_error)?, self.rows))
+    }
+}
+
+fn export_error(e: parquet::errors::ParquetError) -> AppError {
+    AppError::Export(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::Array;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    #[test]
+    fn users_are_written_as_parquet_row_groups() {
+        let mut deleted = User::new("Deleted", "deleted@example.com");
+        deleted.id = Some(2);
+        deleted.deleted_at = Some("2024-05-01T12:00:00+00:00".to_string());
+        let mut live = User::new("Live", "live@example.com");
+        live.id = Some(1);
+        live.verified = Some(true);
+
+        let mut writer = UsersWriter::new(Vec::new()).unwrap();
+        writer.write(&[live]).unwrap();
+        writer.write(&[deleted]).unwrap();
+        let (file, rows) = writer.finish().unwrap();
+        assert_eq!(rows, 2);
+
+        let path = env::temp_dir().join(format!("users-export-test-{}.parquet", process::id()));
+        fs::write(&path, file).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        let batches: Vec<RecordBatch> = reader.with_batch_size(1).build().unwrap().map(Result::unwrap).collect();
+        let emails: Vec<&str> = batches
+            .iter()
+            .flat_map(|batch| {
+                let column = batch.column_by_name("email").unwrap();
+                let emails = column.as_any().downcast_ref::<StringArray>().unwrap();
+                emails.iter().map(Option::unwrap).collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(emails, ["live@example.com", "deleted@example.com"]);
+        let deleted_at = batches[1].column_by_name("deleted_at").unwrap();
+        assert!(batches[0].column_by_name("deleted_at").unwrap().is_null(0));
+        assert!(!deleted_at.is_null(0));
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::mock::format_timestamp;
+use crate::router::{find_route, get_header, get_path, get_query_param};
+use crate::server::Caller;
+
+// Which optional API features each route's requests use, per API key, for
+// GET /admin/features. Before deprecating a query parameter or header, maintainers can
+// see who still sends it and when they last did. Counts start over when the server
+// restarts.
+// Only the parameters and headers listed below are counted, so clients can't grow the
+// report by sending made-up ones. Add new optional features here as they're built.
+
+const QUERY_PARAMETERS: &[&str] = &[
+    "include_deleted",
+    "created_after",
+    "created_before",
+    "filter",
+    "confirm",
+    "window",
+    "include",
+    "fields",
+    "q",
+    "limit",
+    "inactive_days",
+];
+
+const HEADERS: &[&str] = &[
+    "If-None-Match",
+    "If-Match",
+    "Accept-Encoding",
+    "X-Request-Id",
+];
+
+// Label for requests without an API key
+const ANONYMOUS: &str = "(anonymous)";
+// Label for requests on the admin socket
+const ADMIN: &str = "(admin)";
+// Label for keys beyond FEATURE_METRICS_MAX_KEYS
+const OTHER: &str = "(other)";
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct KeyUsage {
+    pub requests: u64,
+    pub last_used: String,
+}
+
+#[derive(Default)]
+struct Usage {
+    // Requests to the route, whatever features they used
+    requests: u64,
+    // Feature name to API key label to usage
+    features: BTreeMap<String, BTreeMap<String, KeyUsage>>,
+}
+
+pub struct FeatureMetrics {
+    // Route (`GET /users/{id}`) to usage
+    routes: Mutex<BTreeMap<String, Usage>>,
+    // API key labels seen so far, to cap how many are tracked
+    keys: Mutex<HashSet<String>>,
+    max_keys: usize,
+    since: String,
+}
+
+impl FeatureMetrics {
+    pub fn from_env() -> FeatureMetrics {
+        let max_keys = env::var("FEATURE_METRICS_MAX_KEYS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(1000);
+        FeatureMetrics::new(max_keys)
+    }
+
+    pub fn new(max_keys: usize) -> FeatureMetrics {
+        FeatureMetrics {
+            routes: Mutex::new(BTreeMap::new()),
+            keys: Mutex::new(HashSet::new()),
+            max_keys,
+            since: now(),
+        }
+    }
+
+    // Counts a request and the features it uses. Requests to unknown paths aren't counted.
+    pub fn record(&self, request: &str, caller: &Caller) {
+        let Some(route) = route(request) else {
+            return;
+        };
+        let features = detect(request);
+        let key = match caller {
+            Caller::LocalAdmin { .. } => ADMIN.to_string(),
+            Caller::Remote { .. } => match get_header(request, "X-Api-Key") {
+                Some(key) => self.tracked(mask(key)),
+                None => ANONYMOUS.to_string(),
+            },
+        };
+
+        let now = now();
+        let mut routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        let usage = routes.entry(route).or_default();
+        usage.requests += 1;
+        for feature in features {
+            let by_key = usage.features.entry(feature).or_default();
+            let key_usage = by_key.entry(key.clone()).or_insert(KeyUsage {
+                requests: 0,
+                last_used: String::new(),
+            });
+            key_usage.requests += 1;
+            key_usage.last_used = now.clone();
+        }
+    }
+
+    // Usage per route and feature, routes and features in alphabetical order
+    pub fn report(&self) -> serde_json::Value {
+        let routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        let routes: Vec<serde_json::Value> = routes
+            .iter()
+            .map(|(route, usage)| {
+                let features: Vec<serde_json::Value> = usage
+                    .features
+                    .iter()
+                    .map(|(feature, by_key)| {
+                        serde_json::json!({
+                            "feature": feature,
+                            "requests": by_key.values().map(|usage| usage.requests).sum::<u64>(),
+                            "api_keys": by_key,
+                        })
+                    })
+                    .collect();
+                serde_json::json!({
+                    "route": route,
+                    "requests": usage.requests,
+                    "features": features,
+                })
+            })
+            .collect();
+        serde_json::json!({ "since": self.since, "routes": routes })
+    }
+
+    // The key's label, or OTHER once FEATURE_METRICS_MAX_KEYS keys are tracked
+    fn tracked(&self, label: String) -> String {
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        if !keys.contains(&label) && keys.len() >= self.max_keys {
+            return OTHER.to_string();
+        }
+        keys.insert(label.clone());
+        label
+    }
+}
+
+// The route a request is for, as its pattern, e.g. `GET /users/{id}`
+fn route(request: &str) -> Option<String> {
+    let method = request.split_whitespace().next()?;
+    let route = find_route(get_path(request))?;
+    Some(format!("{} {}", method, route.pattern))
+}
+
+// The optional features a request uses
+fn detect(request: &str) -> Vec<String> {
+    let mut features = Vec::new();
+    if request.starts_with("HEAD ") {
+        features.push("method:HEAD".to_string());
+    }
+    for parameter in QUERY_PARAMETERS {
+        if get_query_param(request, parameter).is_some() {
+            features.push(format!("query:{}", parameter));
+        }
+    }
+    for header in HEADERS {
+        if get_header(request, header).is_some() {
+            features.push(format!("header:{}", header));
+        }
+    }
+    // Optimistic concurrency through a `version` in update bodies
+    if request.starts_with("PUT ") || request.starts_with("PATCH ") {
+        let body = request.split("\r\n\r\n").last().unwrap_or_default();
+        let version = serde_json::from_str::<serde_json::Value>(body)
+            .is_ok_and(|body| body.get("version").is_some());
+        if version {
+            features.push("body:version".to_string());
+        }
+    }
+    features
+}
+
+// API keys are secrets, so only their first characters and a fingerprint are shown
+fn mask(key: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let prefix: String = key.chars().take(4).collect();
+    format!("{}…{:06x}", prefix, hasher.finish() & 0xff_ffff)
+}
+
+fn now() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format_timestamp(now.as_secs(), now.subsec_micros())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote() -> Caller {
+        Caller::Remote {
+            ip: "127.0.0.1".to_string(),
+        }
+    }
+
+    #[test]
+    fn features_are_detected_per_route() {
+        assert_eq!(
+            route("GET /users/42/audit HTTP/1.1\r\n\r\n").as_deref(),
+            Some("GET /users/{id}/audit")
+        );
+        assert_eq!(
+            route("DELETE /users/abc HTTP/1.1\r\n\r\n").as_deref(),
+            Some("DELETE /users/{id}")
+        );
+        assert_eq!(route("GET /nowhere HTTP/1.1\r\n\r\n"), None);
+
+        let request = "PATCH /users/7?include_deleted=true&made_up=1 HTTP/1.1\r\n\
+                       If-Match: *\r\nX-Made-Up: 1\r\n\r\n{\"version\": 3}";
+        assert_eq!(
+            detect(request),
+            ["query:include_deleted", "header:If-Match", "body:version"]
+        );
+        assert_eq!(detect("HEAD /users HTTP/1.1\r\n\r\n"), ["method:HEAD"]);
+    }
+
+    #[test]
+    fn usage_is_aggregated_per_api_key() {
+        let metrics = FeatureMetrics::new(1);
+        let request = |key: &str| {
+            format!(
+                "GET /users?created_after=2024-01-01 HTTP/1.1\r\nX-Api-Key: {}\r\n\r\n",
+                key
+            )
+        };
+        metrics.record(&request("secret-one"), &remote());
+        metrics.record(&request("secret-one"), &remote());
+        metrics.record(&request("secret-two"), &remote());
+        metrics.record("GET /users HTTP/1.1\r\n\r\n", &remote());
+
+        let report = metrics.report();
+        let route = &report["routes"][0];
+        assert_eq!(route["route"], "GET /users");
+        assert_eq!(route["requests"], 4);
+        let feature = &route["features"][0];
+        assert_eq!(feature["feature"], "query:created_after");
+        assert_eq!(feature["requests"], 3);
+
+        let keys = feature["api_keys"].as_object().unwrap();
+        let (label, usage) = keys.iter().find(|(label, _)| *label != OTHER).unwrap();
+        assert!(label.starts_with("secr…"));
+        assert!(!label.contains("one"));
+        assert_eq!(usage["requests"], 2);
+        // Only one key is tracked, the next one is counted as other
+        assert_eq!(keys[OTHER]["requests"], 1);
+    }
+}
@@ -0,0 +1,1236 @@
+use postgres::error::SqlState;
+use serde::Serialize;
+
+use crate::archive;
+use crate::audit;
+use crate::body;
+use crate::bulk::{self, BulkPatch};
+use crate::chaos::Rules;
+use crate::checksum;
+use crate::data_quality;
+use crate::db::{in_transaction, with_client};
+use crate::disposable_email::Decision;
+use crate::email_change::{self, Confirmation};
+use crate::email_policy::EmailPolicy;
+use crate::email_verification;
+use crate::health;
+use crate::idempotency::{self, Claim};
+use crate::impersonation;
+use crate::errors::{error, AppError, ErrorCode, ErrorInfo};
+use crate::metrics;
+use crate::migrations;
+use crate::notifications::{self, Preferences};
+use crate::models::{BatchGet, BatchGetResult, Post, User, UserPatch, UserUpsert, UserWithPosts};
+use crate::repo::{self, Deletion, Fields, Upsert, UserFilter};
+use crate::resource;
+use crate::router::{get_email, get_header, get_id, get_query_param};
+use crate::server::{AppState, Caller};
+use crate::storage;
+use crate::support_bundle;
+use crate::tokens;
+use crate::undo;
+use crate::user_profile::{self, Profile};
+
+// Request handlers, one per route (see `router`).
+// Handlers return a `(String, String)` tuple, representing the HTTP status line
+// and the response body. This is a custom choice for this simple server,
+// not a standard `Result` type.
+
+// Constants
+// Status line and fixed headers; the server turns them into an `http::Response`, which
+// adds `Content-Length` and `Connection` when it's written
+pub(crate) const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n";
+pub(crate) const CREATED_RESPONSE: &str = "HTTP/1.1 201 CREATED\r\nContent-Type: application/json\r\n";
+// Newline-delimited JSON, for lists asked for with `Accept: application/x-ndjson`
+pub(crate) const NDJSON_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\n";
+pub(crate) const NO_CONTENT: &str = "HTTP/1.1 204 NO CONTENT\r\n";
+pub(crate) const BAD_REQUEST: &str = "HTTP/1.1 400 BAD REQUEST\r\n";
+pub(crate) const UNAUTHORIZED: &str = "HTTP/1.1 401 UNAUTHORIZED\r\n";
+pub(crate) const FORBIDDEN: &str = "HTTP/1.1 403 FORBIDDEN\r\n";
+pub(crate) const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\n";
+pub(crate) const METHOD_NOT_ALLOWED: &str = "HTTP/1.1 405 METHOD NOT ALLOWED\r\n";
+pub(crate) const CONFLICT: &str = "HTTP/1.1 409 CONFLICT\r\n";
+pub(crate) const NOT_MODIFIED: &str = "HTTP/1.1 304 NOT MODIFIED\r\n";
+pub(crate) const PRECONDITION_FAILED: &str = "HTTP/1.1 412 PRECONDITION FAILED\r\n";
+pub(crate) const PRECONDITION_REQUIRED: &str = "HTTP/1.1 428 PRECONDITION REQUIRED\r\n";
+pub(crate) const UNPROCESSABLE_ENTITY: &str = "HTTP/1.1 422 UNPROCESSABLE ENTITY\r\n";
+pub(crate) const INTERNAL_SERVER_ERROR: &str = "HTTP/1.1 500 INTERNAL_SERVER_ERROR\r\n";
+pub(crate) const REQUEST_TIMEOUT: &str = "HTTP/1.1 408 REQUEST TIMEOUT\r\n";
+pub(crate) const TOO_MANY_REQUESTS: &str = "HTTP/1.1 429 TOO MANY REQUESTS\r\n";
+
+// Most IDs one POST /users/batch-get may ask for
+const MAX_BATCH_IDS: usize = 1000;
+
+// A response with a JSON body. Handlers never panic on a request: failures, even ones
+// that can only come from bugs like this one, become error responses (see `AppError`).
+pub(crate) fn json_response(status_line: &str, value: &impl Serialize) -> (String, String) {
+    match serde_json::to_string(value) {
+        Ok(body) => (status_line.to_string(), body),
+        Err(e) => AppError::from(e).into_response(),
+    }
+}
+
+// A list as newline-delimited JSON: one value per line, each line ending in `\n`
+pub(crate) fn ndjson_response(values: &[impl Serialize]) -> (String, String) {
+    let mut body = String::new();
+    for value in values {
+        match serde_json::to_string(value) {
+            Ok(line) => body.push_str(&line),
+            Err(e) => return AppError::from(e).into_response(),
+        }
+        body.push('\n');
+    }
+    (NDJSON_RESPONSE.to_string(), body)
+}
+
+// Whether the client asks for a list as NDJSON (`Accept: application/x-ndjson`) rather
+// than a JSON array. Clients that also accept JSON still get NDJSON; only a q of 0 turns
+// it down.
+pub(crate) fn wants_ndjson(request: &str) -> bool {
+    get_header(request, "Accept").is_some_and(|accept| {
+        accept.split(',').any(|range| {
+            let mut params = range.split(';');
+            let refused = |param: &str| {
+                param.split_once('=').is_some_and(|(name, q)| name.trim() == "q" && q.trim().parse::<f32>() == Ok(0.0))
+            };
+            params.next().is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("application/x-ndjson"))
+                && !params.any(refused)
+        })
+    })
+}
+
+// Handle POST request
+// Every mutation is recorded in the audit log within the same transaction.
+// New users start out unverified: once they're committed, a token to verify the
+// address with is mailed to it (see `email_verification`).
+// With an `Idempotency-Key` header, retries get the first response back instead of
+// creating the user again (see `idempotency`).
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_post_request(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    let user = match get_user_from_request_body(request) {
+        Ok(user) => user,
+        Err(e) => return e.into_response(),
+    };
+    let key = match idempotency::key(request) {
+        Ok(key) => key,
+        Err(e) => return e.into_response(),
+    };
+    let token = match tokens::new_token() {
+        Ok(token) => token,
+        Err(e) => return AppError::Token(e).into_response(),
+    };
+
+    // The ID of the new user, or None for a replayed response
+    let result = with_client(state, |client| repo::with_transaction(client, |transaction| {
+        if let Some(key) = key {
+            match idempotency::claim(transaction, &caller.identity(), key, body::text(request), state.idempotency_key_ttl)? {
+                Claim::New => {}
+                Claim::Replay(status_line, body) => return Ok((None, (status_line, body))),
+            }
+        }
+        let decision = check_email_policy(state, &user.email)
+            .map_err(|message| AppError::Validation(ErrorCode::EmailRejected, message))?;
+        let id = repo::create_user(transaction, &user)?;
+        let created = repo::find_user(transaction, id, false)?;
+        audit::record_with_details(transaction, &caller.identity(), "create", id, None, created.as_ref(), email_details(decision).as_ref())?;
+        email_verification::request(transaction, id, &token, state.verification_token_ttl)?;
+
+        let response = (with_email_header(OK_RESPONSE, decision), "User Created".to_string());
+        if let Some(key) = key {
+            idempotency::save(transaction, &caller.identity(), key, &response)?;
+        }
+        Ok::<_, AppError>((Some(id), response))
+    }));
+
+    match result {
+        Ok((id, response)) => {
+            if let Some(id) = id {
+                email_verification::deliver(state.mailer.as_ref(), id, &user.email, &token);
+            }
+            response
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle PUT /users/by-email/{email}
+// Creates the live user with the email, or renames it if it's there, in one statement
+// (see `repo::upsert_user_by_email`), for sync jobs pushing users from other systems.
+// The body is `{"name": "Ada"}`. A new user is a 201 with a Location, and goes through
+// the email policy and gets a verification mail like with POST; an existing one is a
+// 200, and a change to it is audited as an update.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_upsert_by_email_request(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    let email = get_email(request);
+    let upsert: UserUpsert = match body::parse(request) {
+        Ok(upsert) => upsert,
+        Err(e) => return e.into_response(),
+    };
+    if upsert.email.as_ref().is_some_and(|body_email| *body_email != email) {
+        return AppError::Validation(ErrorCode::ValidationFailed, "email doesn't match the one in the path".to_string()).into_response();
+    }
+    let token = match tokens::new_token() {
+        Ok(token) => token,
+        Err(e) => return AppError::Token(e).into_response(),
+    };
+
+    let result = with_client(state, |client| repo::with_transaction(client, |transaction| {
+        let before = repo::find_user_by_email_for_update(transaction, &email)?;
+        // Users already there keep their address, whatever the policy says now
+        let decision = match before {
+            Some(_) => Decision::NotDisposable,
+            None => check_email_policy(state, &email).map_err(|message| AppError::Validation(ErrorCode::EmailRejected, message))?,
+        };
+        let (id, outcome) = repo::upsert_user_by_email(transaction, &upsert.name, &email)?;
+        let after = repo::find_user(transaction, id, false)?;
+        match outcome {
+            Upsert::Created => {
+                audit::record_with_details(transaction, &caller.identity(), "create", id, None, after.as_ref(), email_details(decision).as_ref())?;
+                email_verification::request(transaction, id, &token, state.verification_token_ttl)?;
+            }
+            Upsert::Updated => audit::record(transaction, &caller.identity(), "update", id, before.as_ref(), after.as_ref())?,
+            Upsert::Unchanged => {}
+        }
+        let user = after.ok_or_else(|| AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()))?;
+        Ok::<_, AppError>((outcome, decision, user))
+    }));
+
+    match result {
+        Ok((Upsert::Created, decision, user)) => {
+            let id = user.id.unwrap_or_default();
+            email_verification::deliver(state.mailer.as_ref(), id, &user.email, &token);
+            let status_line = format!("{}Location: /users/{}\r\nETag: {}\r\n", CREATED_RESPONSE, id, user.etag());
+            json_response(&with_email_header(&status_line, decision), &user)
+        }
+        Ok((_, _, user)) => json_response(&format!("{}ETag: {}\r\n", OK_RESPONSE, user.etag()), &user),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle GET /users/verify?token=...
+// Marks the user the token was mailed to as verified and sends back the updated user.
+// A token works once.
+// It's a GET so the mailed link can be followed as it is, but it changes the user, so
+// the cached responses for them are dropped like after any other write.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_verify_request(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    let Some(token) = get_query_param(request, "token") else {
+        return AppError::Validation(ErrorCode::InvalidQuery, "token is required".to_string()).into_response();
+    };
+
+    let result = with_client(state, |client| repo::with_transaction(client, |transaction| {
+        let invalid = || AppError::Validation(ErrorCode::VerificationTokenInvalid, "Invalid verification token".to_string());
+        let id = email_verification::take(transaction, &token)?.ok_or_else(invalid)?;
+        let current = repo::find_user_for_update(transaction, id, false)?.ok_or_else(invalid)?;
+        let updated = repo::set_verified(transaction, id)?.ok_or_else(invalid)?;
+        let details = serde_json::json!({ "email_verification": "confirmed" });
+        audit::record_with_details(transaction, &caller.identity(), "update", id, Some(&current), Some(&updated), Some(&details))?;
+        Ok::<_, AppError>(updated)
+    }));
+
+    match result {
+        Ok(updated) => {
+            if let Some(cache) = &state.response_cache {
+                cache.invalidate(&format!("/users/{}", updated.id.unwrap_or_default()));
+            }
+            json_response(&format!("{}ETag: {}\r\n", OK_RESPONSE, updated.etag()), &updated)
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle GET request (by ID)
+// Soft-deleted users are only returned to admins asking for `?include_deleted=true`.
+// The response carries an ETag; a matching `If-None-Match` gets 304 Not Modified.
+// `?include=posts` embeds the user's posts, fetched on the same connection, so clients
+// don't need a request per user. The ETag only covers the user, so expanded responses
+// don't get one.
+// `?as_of=2024-01-01T00:00:00Z` sends the user as it was then, rebuilt from the audit
+// log (see `audit::state_at`), without an ETag.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_get_request(request: &str, id: i32, state: &AppState, caller: &Caller) -> (String, String) {
+    let include_deleted = match include_deleted(request, caller) {
+        Ok(include_deleted) => include_deleted,
+        Err(response) => return response,
+    };
+    let include_posts = match include_posts(request) {
+        Ok(include_posts) => include_posts,
+        Err(response) => return response,
+    };
+
+    if let Some(as_of) = get_query_param(request, "as_of") {
+        // Posts have no history to rebuild them from
+        if include_posts {
+            return AppError::Validation(ErrorCode::InvalidQuery, "as_of can't be combined with include=posts".to_string()).into_response();
+        }
+        let deleted = |user: &serde_json::Value| user.get("deleted_at").is_some_and(|deleted_at| !deleted_at.is_null());
+        return match with_client(state, |client| Ok(audit::state_at(client, id, &as_of)?)) {
+            Ok(Some(user)) if include_deleted || !deleted(&user) => json_response(OK_RESPONSE, &user),
+            Ok(_) => AppError::NotFound(ErrorCode::UserNotFound, "User not found at that time".to_string()).into_response(),
+            Err(e) => list_error(e),
+        };
+    }
+
+    if include_posts {
+        let user = with_client(state, |client| {
+            let Some(user) = repo::find_user(client, id, include_deleted)? else {
+                return Ok(None);
+            };
+            let posts = resource::list_by::<Post>(client, "user_id", &id)?;
+            Ok(Some(UserWithPosts { user, posts }))
+        });
+        return match user {
+            Ok(Some(user)) => json_response(OK_RESPONSE, &user),
+            Ok(None) => AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()).into_response(),
+            Err(e) => e.into_response(),
+        };
+    }
+
+    match with_client(state, |client| Ok(repo::find_user(client, id, include_deleted)?)) {
+        Ok(Some(user)) => {
+            let etag = user.etag();
+            if get_header(request, "If-None-Match").is_some_and(|value| etag_matches(value, &etag)) {
+                return (format!("{}ETag: {}\r\n", NOT_MODIFIED, etag), String::new());
+            }
+            json_response(&format!("{}ETag: {}\r\n", OK_RESPONSE, etag), &user)
+        }
+        Ok(None) => AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle GET All request
+// Soft-deleted users are only listed for admins asking for `?include_deleted=true`.
+// `?created_after=` and `?created_before=` take ISO 8601 timestamps.
+// `?verified=true` or `?verified=false` only lists users who have or haven't verified
+// their email address.
+// `?fields=id,email` only selects and sends those fields of each user.
+// With `Accept: application/x-ndjson` the users come one per line instead of in an array.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_get_all_request(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    let filter = match list_filter(request, caller) {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+    let fields = match list_fields(request) {
+        Ok(fields) => fields,
+        Err(response) => return response,
+    };
+    let ndjson = wants_ndjson(request);
+    if let Some(fields) = fields {
+        return match with_client(state, |client| Ok(repo::list_user_fields(client, &filter, &fields)?)) {
+            Ok(users) if ndjson => (NDJSON_RESPONSE.to_string(), users.iter().map(|user| format!("{}\n", user)).collect()),
+            Ok(users) => (OK_RESPONSE.to_string(), format!("[{}]", users.join(","))),
+            Err(e) => list_error(e),
+        };
+    }
+    match with_client(state, |client| Ok(repo::list_users(client, &filter)?)) {
+        Ok(users) if ndjson => ndjson_response(&users),
+        Ok(users) => json_response(OK_RESPONSE, &users),
+        Err(e) => list_error(e),
+    }
+}
+
+// Handle GET /users/search?q=...
+// Live users whose name or email has words starting with every word of `q`, best
+// matches first. `?limit=` caps the results (20 by default, at most 100).
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_search_request(request: &str, state: &AppState) -> (String, String) {
+    let terms = get_query_param(request, "q").unwrap_or_default();
+    let Some(query) = repo::search_query(&terms) else {
+        return AppError::Validation(ErrorCode::InvalidQuery, "q needs at least one word to search for".to_string()).into_response();
+    };
+    let limit = match get_query_param(request, "limit").map(|limit| limit.parse::<i64>()) {
+        None => 20,
+        Some(Ok(limit)) if (1..=100).contains(&limit) => limit,
+        Some(_) => return AppError::Validation(ErrorCode::InvalidQuery, "limit must be between 1 and 100".to_string()).into_response(),
+    };
+    match with_client(state, |client| Ok(repo::search_users(client, &query, limit)?)) {
+        Ok(users) => json_response(OK_RESPONSE, &users),
+        Err(e) => list_error(e),
+    }
+}
+
+// Handle GET /users/checksum
+// Count and SHA-256 of the users the list would return (see `checksum`), taking the
+// list's filters, so sync consumers can check they're in step before a full export.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_checksum_request(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    let filter = match list_filter(request, caller) {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+    match with_client(state, |client| checksum::of_users(client, &filter)) {
+        Ok(summary) => json_response(OK_RESPONSE, &summary),
+        Err(e) => list_error(e),
+    }
+}
+
+// Handle GET /users/stats
+// Totals for dashboards, in one query instead of paging through every user: how many
+// users there are, by email domain, and how many were created each of the last 30
+// days. Takes the list's filters, such as `verified` or `include_deleted` (admins).
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_stats_request(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    let filter = match list_filter(request, caller) {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+    match with_client(state, |client| Ok(repo::user_stats(client, &filter)?)) {
+        Ok(stats) => json_response(OK_RESPONSE, &stats),
+        Err(e) => list_error(e),
+    }
+}
+
+// Handle POST /users/batch-get
+// Fetches the users with the IDs in the body in one query, instead of a GET for each:
+// `{"ids": [1, 5, 9]}` gets `{"users": [...], "missing": [9]}`, in the order asked for.
+// Soft-deleted users count as missing unless an admin sets `include_deleted`.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_batch_get_request(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    let include_deleted = match include_deleted(request, caller) {
+        Ok(include_deleted) => include_deleted,
+        Err(response) => return response,
+    };
+    let ids = match batch_ids(request) {
+        Ok(ids) => ids,
+        Err(e) => return e.into_response(),
+    };
+    match with_client(state, |client| Ok(repo::find_users(client, &ids, include_deleted)?)) {
+        Ok(found) => json_response(OK_RESPONSE, &BatchGetResult::new(&ids, found)),
+        Err(e) => e.into_response(),
+    }
+}
+
+// The IDs a batch get asks for, at most MAX_BATCH_IDS
+pub(crate) fn batch_ids(request: &str) -> Result<Vec<i32>, AppError> {
+    let batch: BatchGet = body::parse(request)?;
+    if batch.ids.len() > MAX_BATCH_IDS {
+        let message = format!("ids can list at most {} IDs", MAX_BATCH_IDS);
+        return Err(AppError::Validation(ErrorCode::InvalidBody, message));
+    }
+    Ok(batch.ids)
+}
+
+// Reads the `fields` sparse fieldset, None for whole users.
+// Returns the 400 response to send for unknown fields.
+pub(crate) fn list_fields(request: &str) -> Result<Option<Fields>, (String, String)> {
+    get_query_param(request, "fields")
+        .map(|list| Fields::parse(&list))
+        .transpose()
+        .map_err(|message| AppError::Validation(ErrorCode::InvalidQuery, message).into_response())
+}
+
+// Filter for the user list from the query string (`include_deleted`, `created_after`,
+// `created_before`), or the response to send if it isn't allowed
+pub(crate) fn list_filter(request: &str, caller: &Caller) -> Result<UserFilter, (String, String)> {
+    Ok(UserFilter {
+        include_deleted: include_deleted(request, caller)?,
+        created_after: get_query_param(request, "created_after"),
+        created_before: get_query_param(request, "created_before"),
+        verified: match get_query_param(request, "verified").as_deref() {
+            None => None,
+            Some("true") => Some(true),
+            Some("false") => Some(false),
+            Some(_) => return Err(AppError::Validation(ErrorCode::InvalidQuery, "verified must be true or false".to_string()).into_response()),
+        },
+        ..Default::default()
+    })
+}
+
+// Response for a failed user list query
+pub(crate) fn list_error(e: AppError) -> (String, String) {
+    match e {
+        AppError::Db(e) if e.code() == Some(&SqlState::INVALID_DATETIME_FORMAT)
+            || e.code() == Some(&SqlState::DATETIME_FIELD_OVERFLOW) =>
+        {
+            AppError::Validation(ErrorCode::InvalidQuery, "Invalid timestamp".to_string()).into_response()
+        }
+        AppError::Db(e) if e.code() == Some(&SqlState::CHARACTER_NOT_IN_REPERTOIRE) => {
+            AppError::Validation(ErrorCode::InvalidQuery, "Invalid query".to_string()).into_response()
+        }
+        e => e.into_response(),
+    }
+}
+
+// Reads the `include_deleted` query flag, which only admins may set.
+// Returns the 403 response to send when anyone else asks for it.
+pub(crate) fn include_deleted(request: &str, caller: &Caller) -> Result<bool, (String, String)> {
+    let include_deleted = get_query_param(request, "include_deleted").as_deref() == Some("true");
+    if include_deleted && !caller.is_admin() {
+        return Err(AppError::Auth.into_response());
+    }
+    Ok(include_deleted)
+}
+
+// Reads `include`, a comma-separated list of related records to embed; only `posts`
+// exists. Returns the 400 response to send for anything else.
+pub(crate) fn include_posts(request: &str) -> Result<bool, (String, String)> {
+    let Some(include) = get_query_param(request, "include") else {
+        return Ok(false);
+    };
+    for name in include.split(',').map(str::trim) {
+        if name != "posts" {
+            let message = format!("Unknown include: {}; only posts can be included", name);
+            return Err(AppError::Validation(ErrorCode::InvalidQuery, message).into_response());
+        }
+    }
+    Ok(true)
+}
+
+// Handle PUT and PATCH requests (by ID)
+// PUT replaces name and email, PATCH changes only the fields present in the body.
+// A `version` in the body must match the stored one, otherwise the update is a 409.
+// Both require `If-Match` with the user's current ETag so concurrent edits can't
+// silently overwrite each other: 428 if it's missing, 412 if it's stale.
+// A new email address only becomes pending (see `email_change`): the other fields are
+// updated, and `X-Email-Change: pending` says a confirmation token was sent.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_update_request(request: &str, id: i32, state: &AppState, caller: &Caller, partial: bool) -> (String, String) {
+    let if_match = match get_header(request, "If-Match") {
+        Some(value) => value,
+        None => return AppError::Validation(ErrorCode::PreconditionRequired, "If-Match header required".to_string()).into_response(),
+    };
+
+    let patch = if partial {
+        body::parse::<UserPatch>(request)
+    } else {
+        body::parse::<User>(request).map(|user| UserPatch {
+            name: Some(user.name),
+            email: Some(user.email),
+            version: user.version,
+        })
+    };
+    let patch = match patch {
+        Ok(patch) => patch,
+        Err(e) => return e.into_response(),
+    };
+    let decision = match &patch.email {
+        Some(email) => match check_email_policy(state, email) {
+            Ok(decision) => decision,
+            Err(message) => return AppError::Validation(ErrorCode::EmailRejected, message).into_response(),
+        },
+        None => Decision::NotDisposable,
+    };
+    let token = match tokens::new_token() {
+        Ok(token) => token,
+        Err(e) => return AppError::Token(e).into_response(),
+    };
+
+    let result = with_client(state, |client| repo::with_transaction(client, |transaction| {
+        let current = repo::find_user_for_update(transaction, id, false)?
+            .ok_or_else(|| AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()))?;
+        if !etag_matches(if_match, &current.etag()) {
+            return Err(AppError::Validation(ErrorCode::PreconditionFailed, "User was modified".to_string()));
+        }
+
+        let name = patch.name.as_deref().unwrap_or(&current.name);
+        let new_email = patch.email.as_deref().filter(|email| *email != current.email);
+        let pending_email = new_email.filter(|_| state.email_changes.verify);
+        let email = match pending_email {
+            Some(_) => &current.email,
+            None => new_email.unwrap_or(&current.email),
+        };
+        // The row is locked, so only a stale `version` in the body can miss here
+        let version = patch.version.or(current.version).unwrap_or_default();
+        let updated = repo::update_user(transaction, id, version, name, email)?
+            .ok_or_else(|| AppError::Validation(ErrorCode::VersionConflict, "Version conflict".to_string()))?;
+        audit::record_with_details(transaction, &caller.identity(), "update", id, Some(&current), Some(&updated), email_details(decision).as_ref())?;
+
+        let mut status_line = format!("{}ETag: {}\r\n", with_email_header(OK_RESPONSE, decision), updated.etag());
+        if let Some(pending_email) = pending_email {
+            email_change::request(transaction, id, pending_email, &token, state.email_changes.token_ttl)?;
+            status_line.push_str("X-Email-Change: pending\r\n");
+        }
+        Ok(((status_line, serde_json::to_string(&updated)?), pending_email.map(str::to_string)))
+    }));
+
+    match result {
+        Ok((response, pending_email)) => {
+            if let Some(pending_email) = pending_email {
+                email_change::deliver(state.mailer.as_ref(), id, &pending_email, &token);
+            }
+            response
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle PATCH /users?filter=...&confirm=true (admin only)
+// Applies the body to every live user matching the filter (see `bulk` for the syntax)
+// in one transaction. Without `confirm=true` nothing changes and the response says how
+// many users would be affected; filters matching more than BULK_UPDATE_MAX_ROWS are refused.
+// Moving users to another email domain can't skip verification: each new address
+// becomes a pending change with its own token, listed in `pending_email_ids`, and
+// users whose email was the only change aren't updated.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_bulk_update_request(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    let filter = match get_query_param(request, "filter") {
+        Some(filter) => filter,
+        None => return AppError::Validation(ErrorCode::InvalidQuery, "filter is required".to_string()).into_response(),
+    };
+    let predicates = match bulk::parse_filter(&filter) {
+        Ok(predicates) => predicates,
+        Err(message) => return AppError::Validation(ErrorCode::InvalidQuery, message).into_response(),
+    };
+    let patch: BulkPatch = match body::parse(request) {
+        Ok(patch) => patch,
+        Err(e) => return e.into_response(),
+    };
+    if patch.is_empty() {
+        return AppError::Validation(ErrorCode::InvalidBody, "Nothing to update".to_string()).into_response();
+    }
+    let confirmed = get_query_param(request, "confirm").as_deref() == Some("true");
+    let limit = state.bulk_update_limit;
+
+    let result = with_client(state, |client| repo::with_transaction(client, |transaction| {
+        let users = repo::lock_users_matching(transaction, &predicates, limit + 1)?;
+        if users.len() as i64 > limit {
+            let message = format!("Filter matches more than {} users; narrow it down", limit);
+            return Err(AppError::Validation(ErrorCode::BulkLimitExceeded, message));
+        }
+        if !confirmed {
+            let message = format!("Filter matches {} users; repeat with confirm=true to update them", users.len());
+            return Err(AppError::Validation(ErrorCode::ConfirmationRequired, message));
+        }
+
+        let details = serde_json::json!({ "bulk_filter": filter });
+        let mut updated_ids = Vec::new();
+        // (user ID, new email, token) to send once the transaction commits
+        let mut pending = Vec::new();
+        for user in &users {
+            let id = user.id.unwrap_or_default();
+            let (name, mut email) = patch.apply(user);
+            if email != user.email {
+                check_email_policy(state, &email)
+                    .map_err(|message| AppError::Validation(ErrorCode::EmailRejected, format!("User {}: {}", id, message)))?;
+                if state.email_changes.verify {
+                    let token = tokens::new_token().map_err(AppError::Token)?;
+                    email_change::request(transaction, id, &email, &token, state.email_changes.token_ttl)?;
+                    pending.push((id, email, token));
+                    email = user.email.clone();
+                }
+            }
+            if name == user.name && email == user.email {
+                continue;
+            }
+            let updated = repo::update_user(transaction, id, user.version.unwrap_or_default(), &name, &email)?
+                .ok_or_else(|| AppError::Validation(ErrorCode::VersionConflict, "Version conflict".to_string()))?;
+            audit::record_with_details(transaction, &caller.identity(), "update", id, Some(user), Some(&updated), Some(&details))?;
+            updated_ids.push(id);
+        }
+
+        let pending_ids: Vec<i32> = pending.iter().map(|(id, _, _)| *id).collect();
+        let body = serde_json::json!({ "updated": updated_ids.len(), "updated_ids": updated_ids, "pending_email_ids": pending_ids });
+        let updated = updated_ids.len() as u64;
+        let touched = vec![("users", updated), ("audit_log", updated), ("pending_email_changes", pending.len() as u64)];
+        Ok(((OK_RESPONSE.to_string(), body.to_string()), pending, touched))
+    }));
+
+    match result {
+        Ok((response, pending, touched)) => {
+            for (id, email, token) in pending {
+                email_change::deliver(state.mailer.as_ref(), id, &email, &token);
+            }
+            if let Some(fraction) = state.bulk_analyze_fraction {
+                storage::analyze_after_bulk_in_background(touched, fraction);
+            }
+            response
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+// Checks an `If-Match` / `If-None-Match` value, which may be `*` or a list of ETags
+pub(crate) fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(|candidate| candidate.trim().trim_start_matches("W/"))
+        .any(|candidate| candidate == "*" || candidate == etag)
+}
+
+// Validates an email address against the current domain policy and the disposable
+// provider list. Disposable addresses that aren't rejected come back as a decision
+// for the caller to surface.
+pub(crate) fn check_email_policy(state: &AppState, email: &str) -> Result<Decision, String> {
+    state.email_policy.read().unwrap_or_else(|e| e.into_inner()).check(email)?;
+    match state.disposable_emails.check(email) {
+        Decision::Rejected => Err("Disposable email addresses are not allowed".to_string()),
+        decision => Ok(decision),
+    }
+}
+
+// Adds `X-Disposable-Email: flagged|allowed` to a status line for disposable addresses
+pub(crate) fn with_email_header(status_line: &str, decision: Decision) -> String {
+    match decision.label() {
+        Some(label) => format!("{}X-Disposable-Email: {}\r\n", status_line, label),
+        None => status_line.to_string(),
+    }
+}
+
+// Audit log details recording the disposable email decision, if there was one
+pub(crate) fn email_details(decision: Decision) -> Option<serde_json::Value> {
+    decision
+        .label()
+        .map(|label| serde_json::json!({ "disposable_email": label }))
+}
+
+// Handle GET /admin/migrations (admin only)
+// Every schema migration with its status (see `migrations`): `applied`, `pending`,
+// `modified` when its SQL changed after it was applied, or `unknown` when a newer
+// server applied it. Checksums are SHA-256 of the SQL, this build's and the applied one.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_migrations_request(state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    match with_client(state, |client| Ok(migrations::status(client)?)) {
+        Ok(entries) => json_response(OK_RESPONSE, &entries),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle GET /admin/email-policy (admin only)
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_get_email_policy(state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    let policy = state.email_policy.read().unwrap_or_else(|e| e.into_inner());
+    json_response(OK_RESPONSE, &*policy)
+}
+
+// Handle PUT /admin/email-policy (admin only)
+// Replaces the policy for new registrations and email changes until the next restart,
+// which goes back to the environment's settings.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_put_email_policy(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    let policy = match body::parse::<EmailPolicy>(request) {
+        Ok(policy) => policy.normalized(),
+        Err(e) => return e.into_response(),
+    };
+
+    log!("Email policy replaced by {}: {:?}", caller.identity(), policy);
+    let response = json_response(OK_RESPONSE, &policy);
+    *state.email_policy.write().unwrap_or_else(|e| e.into_inner()) = policy;
+    response
+}
+
+// Handle POST /admin/impersonate/{user_id} (admin only)
+// Starts an impersonation session for a live user and returns its token, to be sent
+// as `Authorization: Bearer <token>` (see `impersonation`). The start is audited.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_start_impersonation(user_id: i32, state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    let token = match tokens::new_token() {
+        Ok(token) => token,
+        Err(e) => return AppError::Token(e).into_response(),
+    };
+    let ttl = state.impersonations.ttl.as_secs();
+
+    let started = with_client(state, |client| repo::with_transaction(client, |transaction| {
+        if repo::find_user(transaction, user_id, false)?.is_none() {
+            return Err(AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()));
+        }
+        let details = serde_json::json!({ "expires_in_secs": ttl });
+        audit::record_with_details(transaction, &caller.identity(), "impersonate", user_id, None, None, Some(&details))?;
+        Ok(())
+    }));
+    if let Err(e) = started {
+        return e.into_response();
+    }
+
+    let session = state.impersonations.start(token, user_id, caller.identity());
+    log!("{} started impersonating user {}", caller.identity(), user_id);
+    json_response(OK_RESPONSE, &serde_json::json!({ "token": session.token, "user_id": user_id, "expires_in_secs": ttl }))
+}
+
+// Handle POST /admin/impersonate/stop
+// Ends the session whose token the request carries; holding the token is what
+// authorizes this, so it works over TCP too.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_stop_impersonation(state: &AppState) -> (String, String) {
+    let Some(session) = impersonation::current() else {
+        return error(ErrorCode::Unauthorized, "Send the impersonation token as a bearer token");
+    };
+    let stopped = with_client(state, |client| {
+        Ok(audit::record(client, &session.admin, "stop_impersonation", session.user_id, None, None)?)
+    });
+    if let Err(e) = stopped {
+        return e.into_response();
+    }
+    state.impersonations.stop(&session.token);
+    log!("{} stopped impersonating user {}", session.admin, session.user_id);
+    (OK_RESPONSE.to_string(), "Impersonation Stopped".to_string())
+}
+
+// Handle GET /admin/chaos (admin only, in chaos mode)
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_get_chaos(state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    match &state.chaos {
+        Some(chaos) => json_response(OK_RESPONSE, &chaos.rules()),
+        None => error(ErrorCode::FeatureDisabled, "Chaos mode is off"),
+    }
+}
+
+// Handle PUT /admin/chaos (admin only, in chaos mode)
+// Replaces the latency and error rules; `{"rules": []}` turns injection off.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_put_chaos(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    let chaos = match &state.chaos {
+        Some(chaos) => chaos,
+        None => return error(ErrorCode::FeatureDisabled, "Chaos mode is off"),
+    };
+    let rules = match body::parse::<Rules>(request) {
+        Ok(rules) => rules,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(message) = chaos.set_rules(rules) {
+        return AppError::Validation(ErrorCode::ValidationFailed, message).into_response();
+    }
+
+    log!("Chaos rules replaced by {}: {:?}", caller.identity(), chaos.rules().rules);
+    json_response(OK_RESPONSE, &chaos.rules())
+}
+
+// Handle GET /admin/metrics/history?window=1h (admin only)
+// Request counts, error counts and latencies per minute over the window (default an hour).
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_metrics_history_request(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    let window = get_query_param(request, "window").unwrap_or_else(|| "1h".to_string());
+    let window_minutes = match metrics::parse_window(&window) {
+        Ok(minutes) => minutes,
+        Err(message) => return AppError::Validation(ErrorCode::InvalidQuery, message).into_response(),
+    };
+    let body = serde_json::json!({
+        "window_minutes": window_minutes,
+        "minutes": state.metrics.history(window_minutes),
+        // Over each route's latest requests rather than the window
+        "database": state.database_latencies.report(),
+    });
+    (OK_RESPONSE.to_string(), body.to_string())
+}
+
+// Handle GET /admin/slo (admin only)
+// Availability and latency SLIs per route and overall, with the error budget left over
+// the SLO window and burn rates over shorter ones.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_slo_request(state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    (OK_RESPONSE.to_string(), state.slo.report().to_string())
+}
+
+// Handle GET /admin/slow-requests (admin only)
+// The slowest requests of the recent window with their database timings, slowest first.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_slow_requests_request(state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    let body = serde_json::json!({
+        "window_minutes": state.slow_requests.window.as_secs() / 60,
+        "requests": state.slow_requests.slowest(),
+    });
+    (OK_RESPONSE.to_string(), body.to_string())
+}
+
+// Handle GET /admin/support-bundle (admin only)
+// The tarball `app support-bundle` writes, with this server's logs, metrics, pool and
+// jobs in it (see `support_bundle`).
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_support_bundle_request(state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    // Every file in it is JSON, so the whole archive is text
+    let tarball = support_bundle::tarball(&support_bundle::entries(Some(state)));
+    (
+        "HTTP/1.1 200 OK\r\nContent-Type: application/x-tar\r\nContent-Disposition: attachment; filename=\"support-bundle.tar\"\r\n".to_string(),
+        String::from_utf8_lossy(&tarball).into_owned(),
+    )
+}
+
+// Handle GET /admin/features (admin only)
+// Which optional query parameters, headers and body fields each route's requests use,
+// per API key, since the server started (see `features`).
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_features_request(state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    (OK_RESPONSE.to_string(), state.features.report().to_string())
+}
+
+// Handle GET /admin/data-quality (admin only)
+// Reports duplicate emails, empty names and malformed values among live users.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_data_quality_request(state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+
+    match with_client(state, |client| Ok(data_quality::report(client)?)) {
+        Ok(report) => (OK_RESPONSE.to_string(), report.to_string()),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle GET /admin/storage (admin only)
+// Table and index sizes, dead tuples and vacuum times (see `storage`).
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_storage_request(state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+
+    match with_client(state, |client| Ok(storage::report(client)?)) {
+        Ok(report) => (OK_RESPONSE.to_string(), report.to_string()),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle GET /readyz
+// 200 while the server can serve requests: its database answers a query (mock mode has
+// none to check). 503 NOT_READY otherwise, so orchestrators and the Docker HEALTHCHECK
+// (`app healthcheck`) can tell a wedged process from a working one.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_readyz_request(state: &AppState) -> (String, String) {
+    if state.mock.is_none() {
+        if let Err(e) = with_client(state, |client| Ok(client.simple_query("SELECT 1")?)) {
+            log!("Not ready: {}", e);
+            return error(ErrorCode::NotReady, "The database is unreachable");
+        }
+    }
+    (OK_RESPONSE.to_string(), serde_json::json!({ "status": "ready" }).to_string())
+}
+
+// Handle GET /metrics
+// The request latency histogram since the server started, in the OpenMetrics text format
+// Prometheus scrapes, with trace ID exemplars (see `metrics`). Open to all like /readyz,
+// since scrapers connect over TCP rather than the admin socket; it holds counts and
+// trace IDs only.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_metrics_request(state: &AppState) -> (String, String) {
+    (
+        format!("HTTP/1.1 200 OK\r\nContent-Type: {}\r\n", metrics::OPENMETRICS_CONTENT_TYPE),
+        state.metrics.openmetrics(),
+    )
+}
+
+// Handle GET /health/detail (admin only)
+// Each dependency's status, latency and last error, and the overall status (see
+// `health`). 503 when a critical dependency is down.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_health_detail_request(state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    let report = health::report(state);
+    let status_line = match report.status {
+        health::Status::Down => "HTTP/1.1 503 SERVICE UNAVAILABLE\r\nContent-Type: application/json\r\n",
+        _ => OK_RESPONSE,
+    };
+    json_response(status_line, &report)
+}
+
+// Handle GET /errors
+// Lists every error code with its status and what it means (see `errors`).
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_errors_request() -> (String, String) {
+    let errors: Vec<ErrorInfo> = ErrorCode::ALL.iter().map(|code| code.info()).collect();
+    (OK_RESPONSE.to_string(), serde_json::json!({ "errors": errors }).to_string())
+}
+
+// Handle GET /users/{id}/email-change
+// The new email address waiting for confirmation, if any.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_get_email_change(id: i32, state: &AppState) -> (String, String) {
+    match with_client(state, |client| Ok(email_change::pending(client, id)?)) {
+        Ok(Some(pending)) => json_response(OK_RESPONSE, &pending),
+        Ok(None) => no_pending_email_change().into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle DELETE /users/{id}/email-change
+// Cancels the pending email change; the user keeps the current address.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_cancel_email_change(id: i32, state: &AppState) -> (String, String) {
+    match with_client(state, |client| Ok(email_change::cancel(client, id)?)) {
+        Ok(true) => (OK_RESPONSE.to_string(), "Email Change Cancelled".to_string()),
+        Ok(false) => no_pending_email_change().into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle POST /users/{id}/email-change/confirm
+// Applies the pending email change if the body's token is the one that was sent.
+// The email policy is checked again, as it may have changed in the meantime.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_confirm_email_change(request: &str, id: i32, state: &AppState, caller: &Caller) -> (String, String) {
+    let confirmation: Confirmation = match body::parse(request) {
+        Ok(confirmation) => confirmation,
+        Err(e) => return e.into_response(),
+    };
+
+    in_transaction(state, |transaction| {
+        let current = repo::find_user_for_update(transaction, id, false)?
+            .ok_or_else(|| AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()))?;
+        if email_change::pending(transaction, id)?.is_none() {
+            return Err(no_pending_email_change());
+        }
+        let email = email_change::take(transaction, id, &confirmation.token)?
+            .ok_or_else(|| AppError::Validation(ErrorCode::EmailTokenInvalid, "Invalid confirmation token".to_string()))?;
+        let decision = check_email_policy(state, &email)
+            .map_err(|message| AppError::Validation(ErrorCode::EmailRejected, message))?;
+
+        let updated = repo::update_user(transaction, id, current.version.unwrap_or_default(), &current.name, &email)?
+            .ok_or_else(|| AppError::Validation(ErrorCode::VersionConflict, "Version conflict".to_string()))?;
+        let mut details = email_details(decision).unwrap_or_else(|| serde_json::json!({}));
+        details["email_change"] = "confirmed".into();
+        audit::record_with_details(transaction, &caller.identity(), "update", id, Some(&current), Some(&updated), Some(&details))?;
+
+        Ok((
+            format!("{}ETag: {}\r\n", with_email_header(OK_RESPONSE, decision), updated.etag()),
+            serde_json::to_string(&updated)?,
+        ))
+    })
+}
+
+fn no_pending_email_change() -> AppError {
+    AppError::NotFound(ErrorCode::NoPendingEmailChange, "No pending email change".to_string())
+}
+
+// Handle GET /users/{id}/notifications
+// The user's notification preferences (see `notifications`).
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_get_notifications(id: i32, state: &AppState) -> (String, String) {
+    let preferences = with_client(state, |client| {
+        if repo::find_user(client, id, false)?.is_none() {
+            return Err(AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()));
+        }
+        Ok(notifications::get(client, id)?)
+    });
+    match preferences {
+        Ok(preferences) => json_response(OK_RESPONSE, &preferences),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle PUT /users/{id}/notifications
+// Replaces the user's notification preferences, e.g. `{"digest": "weekly"}`.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_put_notifications(request: &str, id: i32, state: &AppState) -> (String, String) {
+    let preferences: Preferences = match body::parse(request) {
+        Ok(preferences) => preferences,
+        Err(e) => return e.into_response(),
+    };
+    in_transaction(state, |transaction| {
+        if repo::find_user_for_update(transaction, id, false)?.is_none() {
+            return Err(AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()));
+        }
+        notifications::set(transaction, id, &preferences)?;
+        Ok((OK_RESPONSE.to_string(), serde_json::to_string(&preferences)?))
+    })
+}
+
+// Handle GET /users/{id}/profile
+// The user's profile (see `user_profile`); a 404 until one is PUT.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_get_profile(id: i32, state: &AppState) -> (String, String) {
+    let profile = with_client(state, |client| {
+        if repo::find_user(client, id, false)?.is_none() {
+            return Err(AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()));
+        }
+        user_profile::get(client, id)?
+            .ok_or_else(|| AppError::NotFound(ErrorCode::RecordNotFound, "The user has no profile".to_string()))
+    });
+    match profile {
+        Ok(profile) => json_response(OK_RESPONSE, &profile),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle PUT /users/{id}/profile
+// Creates or replaces the user's profile, e.g. `{"bio": "...", "location": "Lisbon"}`.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_put_profile(request: &str, id: i32, state: &AppState) -> (String, String) {
+    let profile: Profile = match body::parse(request) {
+        Ok(profile) => profile,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(message) = profile.validate() {
+        return AppError::Validation(ErrorCode::ValidationFailed, message).into_response();
+    }
+    in_transaction(state, |transaction| {
+        if repo::find_user_for_update(transaction, id, false)?.is_none() {
+            return Err(AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()));
+        }
+        user_profile::put(transaction, id, &profile)?;
+        Ok((OK_RESPONSE.to_string(), serde_json::to_string(&profile)?))
+    })
+}
+
+// Handle POST /admin/digests/run (admin only)
+// Runs the digest job now instead of waiting for the scheduler.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_run_digests(state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    match with_client(state, |client| Ok(notifications::send_due_digests(client, state.mailer.as_ref())?)) {
+        Ok(sent) => json_response(OK_RESPONSE, &serde_json::json!({ "sent": sent })),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle GET /admin/archived-users?limit=... (admin only)
+// Lists archived users, most recently archived first; `limit` is 1-1000 (100).
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_archived_users_request(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    let limit = match get_query_param(request, "limit").map(|limit| limit.parse::<i64>()) {
+        None => 100,
+        Some(Ok(limit)) if (1..=1000).contains(&limit) => limit,
+        Some(_) => return AppError::Validation(ErrorCode::InvalidQuery, "limit must be between 1 and 1000".to_string()).into_response(),
+    };
+    match with_client(state, |client| Ok(archive::list(client, limit)?)) {
+        Ok(users) => json_response(OK_RESPONSE, &users),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle POST /admin/archive/run?inactive_days=... (admin only)
+// Archives inactive users now instead of waiting for the scheduler. `inactive_days`
+// defaults to ARCHIVE_INACTIVE_DAYS, and is required when that isn't set.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_run_archive(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    let days = match get_query_param(request, "inactive_days").map(|days| days.parse::<i32>()) {
+        None => archive::inactive_days_from_env(),
+        Some(Ok(days)) if days > 0 => Some(days),
+        Some(_) => None,
+    };
+    let Some(days) = days else {
+        return AppError::Validation(ErrorCode::InvalidQuery, "inactive_days must be a positive number of days".to_string()).into_response();
+    };
+    match with_client(state, |client| Ok(archive::archive_inactive(client, days)?)) {
+        Ok(archived) => json_response(OK_RESPONSE, &serde_json::json!({ "archived": archived })),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle POST /admin/archived-users/{id}/restore (admin only)
+// Moves an archived user back, with their posts, and returns them.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_unarchive_request(id: i32, state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    in_transaction(state, |transaction| {
+        match archive::restore(transaction, &caller.identity(), id)? {
+            Some(user) => Ok((OK_RESPONSE.to_string(), serde_json::to_string(&user)?)),
+            None => Err(AppError::NotFound(ErrorCode::UserNotFound, "Archived user not found".to_string())),
+        }
+    })
+}
+
+// Handle POST /admin/undo/{id} (admin only)
+// Reverts the change recorded in an audit entry, if nothing changed since (see `undo`),
+// and returns the user.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_undo_request(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    let Ok(audit_id) = get_id(request).parse::<i64>() else {
+        return error(ErrorCode::InvalidId, "Invalid audit entry ID");
+    };
+    in_transaction(state, |transaction| {
+        let user = undo::apply(transaction, &caller.identity(), audit_id)?;
+        Ok((OK_RESPONSE.to_string(), serde_json::to_string(&user)?))
+    })
+}
+
+// Handle GET /users/{id}/audit
+// Lists the recorded changes to a user, oldest first. History outlives soft deletes.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_audit_request(id: i32, state: &AppState) -> (String, String) {
+    match with_client(state, |client| Ok(audit::history(client, id)?)) {
+        Ok(entries) if entries.is_empty() => AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()).into_response(),
+        Ok(entries) => json_response(OK_RESPONSE, &entries),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle DELETE request
+// Users are soft-deleted: the row is kept with `deleted_at` set and can be restored.
+// Related records are dealt with by the delete policies (see `delete_policy`).
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_delete_request(id: i32, state: &AppState, caller: &Caller) -> (String, String) {
+    in_transaction(state, |transaction| {
+        let old = repo::find_user(transaction, id, false)?;
+        match repo::delete_user(transaction, id)? {
+            Deletion::Deleted => {}
+            Deletion::NotFound => return Err(AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string())),
+            Deletion::Restricted(dependents) => return Err(AppError::Restricted(dependents)),
+        }
+        let new = repo::find_user(transaction, id, true)?;
+        audit::record(transaction, &caller.identity(), "delete", id, old.as_ref(), new.as_ref())?;
+
+        Ok((OK_RESPONSE.to_string(), "User Deleted".to_string()))
+    })
+}
+
+// Handle POST /users/{id}/restore (admin only)
+// Undoes a soft delete.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+pub(crate) fn handle_restore_request(id: i32, state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+
+    in_transaction(state, |transaction| {
+        let old = repo::find_user(transaction, id, true)?;
+        if !repo::restore_user(transaction, id)? {
+            return Err(AppError::NotFound(ErrorCode::UserNotFound, "Deleted user not found".to_string()));
+        }
+        let new = repo::find_user(transaction, id, false)?;
+        audit::record(transaction, &caller.identity(), "restore", id, old.as_ref(), new.as_ref())?;
+
+        Ok((OK_RESPONSE.to_string(), "User Restored".to_string()))
+    })
+}
+
+// Deserializes a User from the request body (assumed to be JSON).
+// Returns `Result<User, AppError>`:
+// - `Ok(User)` on successful deserialization of the JSON into a `User` struct.
+// - `Err(AppError)` if the request body is not valid JSON or doesn't match the `User`
+//   structure, with a message saying where (see `body::parse`).
+pub(crate) fn get_user_from_request_body(request: &str) -> Result<User, AppError> {
+    body::parse(request)
+}
+
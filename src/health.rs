@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+use crate::db::with_client;
+use crate::mock;
+use crate::server::AppState;
+use crate::webhooks;
+
+// Dependency health for GET /health/detail (admin only), a closer look than /readyz.
+// Each dependency the server is configured with is checked on the spot:
+// - database: a query through the pool, at whichever database is current
+// - standby: a query on the standby, when STANDBY_DATABASE_URL is set
+// - redis: a PING, when REDIS_URL is set (`redis` feature)
+// - smtp: a connection to the mail server, with MAILER=smtp
+// Webhook receivers aren't called; they're judged by their latest delivery attempt.
+// Each comes with its latency and the last error it had, even if it's up again.
+// HEALTH_CRITICAL lists the dependencies that must be up (`database` by default;
+// `webhooks` covers every receiver). The overall status is `down`, a 503, when one of
+// them isn't, `degraded` when anything else isn't, and `up` otherwise.
+
+// Last errors by dependency, with when they happened
+static LAST_ERRORS: Mutex<BTreeMap<String, (String, String)>> = Mutex::new(BTreeMap::new());
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Degraded,
+    Down,
+    // Webhook receivers nothing was delivered to yet
+    Unknown,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Dependency {
+    pub name: String,
+    // What was checked, where there's more than one kind: `primary` or `standby` for
+    // the database, the URL for webhook receivers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub critical: bool,
+    pub status: Status,
+    pub latency_ms: Option<f64>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub status: Status,
+    pub dependencies: Vec<Dependency>,
+}
+
+pub fn critical_from_env() -> Vec<String> {
+    env::var("HEALTH_CRITICAL")
+        .unwrap_or_else(|_| "database".to_string())
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+// Checks every dependency
+pub fn report(state: &AppState) -> Report {
+    let critical = |name: &str| state.health_critical.iter().any(|critical| critical == name);
+    let mut dependencies = Vec::new();
+    if state.mock.is_none() {
+        let target = match &state.standby {
+            Some(standby) if standby.on_standby() => Some("standby".to_string()),
+            Some(_) => Some("primary".to_string()),
+            None => None,
+        };
+        let mut database = check("database", critical("database"), || {
+            with_client(state, |client| Ok(client.simple_query("SELECT 1")?)).map(|_| ()).map_err(|e| e.to_string())
+        });
+        database.target = target;
+        dependencies.push(database);
+    }
+    if let Some(standby) = &state.standby {
+        dependencies.push(check("standby", critical("standby"), || standby.check().map_err(|e| e.to_string())));
+    }
+    #[cfg(feature = "redis")]
+    if let Some(redis) = crate::redis::shared() {
+        dependencies.push(check("redis", critical("redis"), || redis.command(&[b"PING".as_slice()]).map(|_| ()).map_err(|e| e.to_string())));
+    }
+    let started = Instant::now();
+    if let Some(result) = state.mailer.check() {
+        dependencies.push(checked("smtp", critical("smtp"), started, result));
+    }
+    if state.mock.is_none() {
+        dependencies.extend(webhook_targets(state, critical("webhooks")));
+    }
+
+    let status = overall(&dependencies);
+    Report { status, dependencies }
+}
+
+// Runs a check, timing it and remembering its error
+fn check(name: &str, critical: bool, run: impl FnOnce() -> Result<(), String>) -> Dependency {
+    let started = Instant::now();
+    let result = run();
+    checked(name, critical, started, result)
+}
+
+// The outcome of a check that began at `started`
+fn checked(name: &str, critical: bool, started: Instant, result: Result<(), String>) -> Dependency {
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let mut last_errors = last_errors();
+    if let Err(e) = &result {
+        last_errors.insert(name.to_string(), (e.clone(), mock::now()));
+    }
+    let last_error = last_errors.get(name).cloned();
+    Dependency {
+        name: name.to_string(),
+        target: None,
+        critical,
+        status: if result.is_ok() { Status::Up } else { Status::Down },
+        latency_ms: Some(latency_ms),
+        last_error: last_error.as_ref().map(|(e, _)| e.clone()),
+        last_error_at: last_error.map(|(_, at)| at),
+    }
+}
+
+// A receiver per webhook. If they can't be read, that's the database's problem, which
+// its own check reports.
+fn webhook_targets(state: &AppState, critical: bool) -> Vec<Dependency> {
+    let Ok(targets) = with_client(state, |client| Ok(webhooks::target_health(client)?)) else {
+        return Vec::new();
+    };
+    targets
+        .into_iter()
+        .map(|target| Dependency {
+            name: format!("webhook {}", target.id),
+            target: Some(target.url),
+            critical,
+            status: match target.last_attempt {
+                Some((true, _)) => Status::Up,
+                Some((false, _)) => Status::Down,
+                None => Status::Unknown,
+            },
+            latency_ms: target.last_attempt.map(|(_, duration_ms)| duration_ms as f64),
+            last_error: target.last_failure.as_ref().map(|(e, _)| e.clone()),
+            last_error_at: target.last_failure.map(|(_, at)| at),
+        })
+        .collect()
+}
+
+fn overall(dependencies: &[Dependency]) -> Status {
+    let down: Vec<&Dependency> = dependencies.iter().filter(|dependency| dependency.status == Status::Down).collect();
+    if down.iter().any(|dependency| dependency.critical) {
+        Status::Down
+    } else if !down.is_empty() {
+        Status::Degraded
+    } else {
+        Status::Up
+    }
+}
+
+fn last_errors() -> MutexGuard<'static, BTreeMap<String, (String, String)>> {
+    LAST_ERRORS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dependency(name: &str, critical: bool, status: Status) -> Dependency {
+        Dependency {
+            name: name.to_string(),
+            target: None,
+            critical,
+            status,
+            latency_ms: None,
+            last_error: None,
+            last_error_at: None,
+        }
+    }
+
+    #[test]
+    fn only_critical_failures_take_the_service_down() {
+        let up = dependency("database", true, Status::Up);
+        assert_eq!(overall(&[]), Status::Up);
+        assert_eq!(overall(&[dependency("webhook 1", false, Status::Unknown)]), Status::Up);
+        let smtp_down = dependency("smtp", false, Status::Down);
+        assert_eq!(overall(&[up, smtp_down]), Status::Degraded);
+        assert_eq!(overall(&[dependency("database", true, Status::Down)]), Status::Down);
+
+        let failing = check("failing", false, || Err("refused".to_string()));
+        assert_eq!(failing.status, Status::Down);
+        let recovered = check("failing", false, || Ok(()));
+        assert_eq!(recovered.status, Status::Up);
+        assert_eq!(recovered.last_error.as_deref(), Some("refused"));
+    }
+}
@@ -0,0 +1,166 @@
+use std::fmt::Display;
+use std::io::{self, Write};
+
+// An HTTP response as it goes out on the wire. Handlers describe theirs as a status line
+// with any headers of their own, followed by a body (see `handlers`); the server parses
+// that into a `Response`, adds the headers every response gets, and writes it with
+// `write_to`. Content-Length is always worked out here from the body, never by hand.
+// Streamed responses, whose length isn't known up front, send `head` and then their body.
+
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) struct Response {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    // HEAD requests get the headers, Content-Length included, but not the body
+    omit_body: bool,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            reason: reason(status).to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            omit_body: false,
+        }
+    }
+
+    // From a status line followed by header lines, e.g. `HTTP/1.1 200 OK\r\nETag: "3"\r\n`
+    pub fn parse(head: &str, body: impl Into<Vec<u8>>) -> Self {
+        let mut lines = head.split("\r\n");
+        let status_line = lines.next().unwrap_or_default();
+        let mut parts = status_line.splitn(3, ' ').skip(1);
+        let status = parts.next().and_then(|code| code.parse().ok()).unwrap_or_default();
+        let mut response = Response {
+            reason: parts.next().unwrap_or_default().to_string(),
+            ..Response::new(status)
+        };
+        response.headers = header_lines(lines);
+        response.body = body.into();
+        response
+    }
+
+    // Adds a header. Headers are sent in the order they're added.
+    pub fn header(mut self, name: &str, value: impl Display) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    // Adds headers given as lines (`Name: value\r\n`), as `request_id::header_line` and
+    // `cors::header_lines` have them
+    pub fn header_lines(mut self, lines: &str) -> Self {
+        self.headers.extend(header_lines(lines.split("\r\n")));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn without_body(mut self) -> Self {
+        self.omit_body = true;
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    // The first header with this name, which is case-insensitive
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.body
+    }
+
+    // Status line and headers as they are, ending in the blank line
+    pub fn head(&self) -> String {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason);
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        head
+    }
+
+    // The whole response, with the body's Content-Length in place of any given one
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason);
+        for (name, value) in self.headers.iter().filter(|(name, _)| !name.eq_ignore_ascii_case("Content-Length")) {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str(&format!("Content-Length: {}\r\n\r\n", self.body.len()));
+        let mut response = response.into_bytes();
+        if !self.omit_body {
+            response.extend_from_slice(&self.body);
+        }
+        response
+    }
+
+    pub fn write_to(&self, stream: &mut impl Write) -> io::Result<()> {
+        stream.write_all(&self.to_bytes())
+    }
+}
+
+fn header_lines<'a>(lines: impl Iterator<Item = &'a str>) -> Vec<(String, String)> {
+    lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+// Reason phrases, written like the status lines in `handlers`
+fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "SWITCHING PROTOCOLS",
+        200 => "OK",
+        201 => "CREATED",
+        204 => "NO CONTENT",
+        304 => "NOT MODIFIED",
+        400 => "BAD REQUEST",
+        401 => "UNAUTHORIZED",
+        403 => "FORBIDDEN",
+        404 => "NOT FOUND",
+        405 => "METHOD NOT ALLOWED",
+        409 => "CONFLICT",
+        412 => "PRECONDITION FAILED",
+        422 => "UNPROCESSABLE ENTITY",
+        429 => "TOO MANY REQUESTS",
+        500 => "INTERNAL SERVER ERROR",
+        503 => "SERVICE UNAVAILABLE",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses_are_written_with_their_length() {
+        let response = Response::parse("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 99\r\n", "{}")
+            .header_lines("X-Request-Id: abc\r\n")
+            .header("Connection", "close");
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.get_header("content-type"), Some("application/json"));
+        assert_eq!(
+            String::from_utf8(response.to_bytes()).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nX-Request-Id: abc\r\n\
+             Connection: close\r\nContent-Length: 2\r\n\r\n{}"
+        );
+        assert!(response.clone().without_body().to_bytes().ends_with(b"Content-Length: 2\r\n\r\n"));
+
+        let response = Response::new(101).header("Upgrade", "websocket");
+        assert_eq!(response.head(), "HTTP/1.1 101 SWITCHING PROTOCOLS\r\nUpgrade: websocket\r\n\r\n");
+        assert_eq!(Response::parse("HTTP/1.1 500 INTERNAL_SERVER_ERROR\r\n", "").status(), 500);
+    }
+}
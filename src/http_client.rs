@@ -0,0 +1,185 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+// A minimal blocking HTTP/1.1 client used by the CLI tools that talk to a running server,
+// and to deliver webhooks. Only plain `http://` URLs are supported, plus
+// `unix:/path/to/socket` for the admin socket.
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Sends a single request on a fresh connection and reads the whole response.
+// `base_url` looks like `http://localhost:8080` or `unix:/run/app/admin.sock`; `path`
+// includes any query string.
+pub fn send(
+    base_url: &str,
+    method: &str,
+    path: &str,
+    headers: &[(String, String)],
+    body: Option<&str>,
+) -> io::Result<HttpResponse> {
+    send_with_timeout(base_url, method, path, headers, body, None)
+}
+
+// Like `send`, giving up when connecting, or any one read or write, takes longer than
+// `timeout`
+pub fn send_with_timeout(
+    base_url: &str,
+    method: &str,
+    path: &str,
+    headers: &[(String, String)],
+    body: Option<&str>,
+    timeout: Option<Duration>,
+) -> io::Result<HttpResponse> {
+    if let Some(socket) = base_url.strip_prefix("unix:") {
+        let stream = UnixStream::connect(socket)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        return exchange(stream, "localhost", method, path, headers, body);
+    }
+    let host = base_url
+        .strip_prefix("http://")
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only http:// and unix: URLs are supported",
+            )
+        })?
+        .trim_end_matches('/');
+
+    let stream = match timeout {
+        Some(timeout) => {
+            let address = host.to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("can't resolve {}", host))
+            })?;
+            TcpStream::connect_timeout(&address, timeout)?
+        }
+        None => TcpStream::connect(host)?,
+    };
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    exchange(stream, host, method, path, headers, body)
+}
+
+// Writes the request and reads the response until the server closes the connection
+fn exchange(
+    mut stream: impl Read + Write,
+    host: &str,
+    method: &str,
+    path: &str,
+    headers: &[(String, String)],
+    body: Option<&str>,
+) -> io::Result<HttpResponse> {
+    let body = body.unwrap_or_default();
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+        method, path, host
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    stream.write_all(request.as_bytes())?;
+
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw)?;
+    parse_response(&String::from_utf8_lossy(&raw))
+}
+
+fn parse_response(raw: &str) -> io::Result<HttpResponse> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response");
+
+    let (head, body) = raw.split_once("\r\n\r\n").ok_or_else(invalid)?;
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(invalid)?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let mut response = HttpResponse {
+        status,
+        headers,
+        body: body.to_string(),
+    };
+    if response
+        .header("Transfer-Encoding")
+        .is_some_and(|value| value.eq_ignore_ascii_case("chunked"))
+    {
+        let (body, trailers) = decode_chunked(body).ok_or_else(invalid)?;
+        response.body = body;
+        response.headers.extend(trailers);
+    }
+    Ok(response)
+}
+
+// Joins the chunks of a chunked body, and returns them with the trailer fields after
+// them; None if it's malformed or cut off
+fn decode_chunked(mut raw: &str) -> Option<(String, Vec<(String, String)>)> {
+    let mut body = String::new();
+    loop {
+        let (size, rest) = raw.split_once("\r\n")?;
+        // Chunk extensions after `;` are allowed and ignored
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            return Some((body, decode_trailers(rest)?));
+        }
+        body.push_str(rest.get(..size)?);
+        raw = rest.get(size..)?.strip_prefix("\r\n")?;
+    }
+}
+
+// The trailer fields up to the empty line that ends the message
+fn decode_trailers(mut raw: &str) -> Option<Vec<(String, String)>> {
+    let mut trailers = Vec::new();
+    loop {
+        let (line, rest) = raw.split_once("\r\n")?;
+        if line.is_empty() {
+            return Some(trailers);
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            trailers.push((key.trim().to_string(), value.trim().to_string()));
+        }
+        raw = rest;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunked_bodies_are_decoded() {
+        let raw = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                   1\r\n[\r\nA;ext=1\r\n0123456789\r\n1\r\n]\r\n0\r\n\r\n";
+        let response = parse_response(raw).unwrap();
+        assert_eq!(response.body, "[0123456789]");
+
+        let raw = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                   2\r\n[]\r\n0\r\nX-Row-Count: 0\r\n\r\n";
+        let response = parse_response(raw).unwrap();
+        assert_eq!(response.body, "[]");
+        assert_eq!(response.header("X-Row-Count"), Some("0"));
+
+        let truncated = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nab";
+        assert!(parse_response(truncated).is_err());
+    }
+}
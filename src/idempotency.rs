@@ -0,0 +1,131 @@
+use postgres::{Error as PostgresError, GenericClient};
+use std::env;
+use std::time::Duration;
+
+use crate::errors::{AppError, ErrorCode};
+use crate::query_log::Timed;
+use crate::router::get_header;
+
+// Retry-safe creation. A POST /users with an `Idempotency-Key` header is only applied
+// once per caller and key: the key is claimed in the transaction that creates the user
+// and stored with the response, and a retry with the same key and body gets that
+// response again, marked `Idempotent-Replayed: true`, instead of a second user. A retry
+// that arrives while the first attempt is still running waits for it. Reusing a key
+// with a different body is a 422. Failed requests don't keep their key. Keys are
+// kept for IDEMPOTENCY_KEY_TTL_HOURS (24), then a job deletes them.
+
+// How often expired keys are deleted
+pub const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const MAX_KEY_LENGTH: usize = 255;
+
+pub fn ttl_from_env() -> Duration {
+    let ttl_hours: u64 = env::var("IDEMPOTENCY_KEY_TTL_HOURS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(24);
+    Duration::from_secs(ttl_hours * 3600)
+}
+
+// The table, created by a migration (see `migrations`)
+pub(crate) const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS idempotency_keys (
+    caller TEXT NOT NULL,
+    key TEXT NOT NULL,
+    body_hash BYTEA NOT NULL,
+    status_line TEXT,
+    body TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (caller, key)
+);";
+
+// The request's Idempotency-Key, if it sent one
+pub fn key(request: &str) -> Result<Option<&str>, AppError> {
+    match get_header(request, "Idempotency-Key").map(str::trim) {
+        None => Ok(None),
+        Some(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => Ok(Some(key)),
+        Some(_) => Err(AppError::Validation(
+            ErrorCode::InvalidHeader,
+            format!("Idempotency-Key must be 1 to {} characters", MAX_KEY_LENGTH),
+        )),
+    }
+}
+
+pub enum Claim {
+    // The key is new (or expired): go ahead, then `save` the response
+    New,
+    // The response to the request that used the key first
+    Replay(String, String),
+}
+
+// Claims the key for a request with `body`. Call it in the request's transaction, so
+// the claim is dropped if the request fails.
+pub fn claim(
+    client: &mut impl GenericClient,
+    caller: &str,
+    key: &str,
+    body: &str,
+    ttl: Duration,
+) -> Result<Claim, AppError> {
+    // A conflicting insert waits until the transaction holding the key is done
+    let claimed = client.timed_query_opt(
+        "INSERT INTO idempotency_keys (caller, key, body_hash, expires_at)
+         VALUES ($1, $2, sha256(convert_to($3, 'UTF8')), now() + make_interval(secs => $4))
+         ON CONFLICT (caller, key) DO UPDATE SET body_hash = EXCLUDED.body_hash,
+             status_line = NULL, body = NULL, created_at = now(), expires_at = EXCLUDED.expires_at
+             WHERE idempotency_keys.expires_at <= now()
+         RETURNING true",
+        &[&caller, &key, &body, &ttl.as_secs_f64()],
+    )?;
+    if claimed.is_some() {
+        return Ok(Claim::New);
+    }
+
+    let row = client.timed_query_one(
+        "SELECT body_hash = sha256(convert_to($3, 'UTF8')), status_line, body
+         FROM idempotency_keys WHERE caller = $1 AND key = $2",
+        &[&caller, &key, &body],
+    )?;
+    if !row.try_get::<_, bool>(0)? {
+        return Err(AppError::Validation(
+            ErrorCode::IdempotencyKeyReused,
+            "Idempotency-Key was already used with a different body".to_string(),
+        ));
+    }
+    let status_line: String = row.try_get(1)?;
+    let body: String = row.try_get(2)?;
+    Ok(Claim::Replay(format!("{}Idempotent-Replayed: true\r\n", status_line), body))
+}
+
+// Stores the response to replay for a claimed key
+pub fn save(
+    client: &mut impl GenericClient,
+    caller: &str,
+    key: &str,
+    response: &(String, String),
+) -> Result<(), PostgresError> {
+    client.timed_execute(
+        "UPDATE idempotency_keys SET status_line = $3, body = $4 WHERE caller = $1 AND key = $2",
+        &[&caller, &key, &response.0, &response.1],
+    )?;
+    Ok(())
+}
+
+pub fn delete_expired(client: &mut impl GenericClient) -> Result<u64, PostgresError> {
+    client.timed_execute("DELETE FROM idempotency_keys WHERE expires_at <= now()", &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_come_from_the_header() {
+        let request = |header: &str| format!("POST /users HTTP/1.1\r\n{}\r\n\r\n{{}}", header);
+        assert_eq!(key(&request("Idempotency-Key: 8e03978e")).unwrap(), Some("8e03978e"));
+        assert_eq!(key(&request("Host: example.com")).unwrap(), None);
+        let too_long = format!("Idempotency-Key: {}", "k".repeat(MAX_KEY_LENGTH + 1));
+        assert_eq!(key(&request(&too_long)).unwrap_err().code(), ErrorCode::InvalidHeader);
+        assert_eq!(key(&request("Idempotency-Key: ")).unwrap_err().code(), ErrorCode::InvalidHeader);
+    }
+}
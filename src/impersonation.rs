@@ -0,0 +1,145 @@
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Impersonation ("login as") for support staff. An admin on the admin socket starts a
+// session with POST /admin/impersonate/{user_id} and gets a token that expires after
+// IMPERSONATION_TTL_MINUTES (30). Requests sending it as `Authorization: Bearer <token>`
+// act as that user, and every audit entry they cause carries an `impersonation` detail
+// naming the user and the admin. POST /admin/impersonate/stop with the token ends the
+// session early. Requests with an unknown or expired token are refused with a 401
+// rather than served without the session, so a lapsed one can't go unnoticed.
+// Sessions are kept in memory; a restart ends them all. Like the request ID, the
+// session of the request being handled is kept per thread.
+
+thread_local! {
+    static CURRENT: RefCell<Option<Session>> = const { RefCell::new(None) };
+}
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub token: String,
+    pub user_id: i32,
+    // Identity of the admin who started it
+    pub admin: String,
+    pub expires_at: Instant,
+}
+
+pub struct Impersonations {
+    // By token
+    sessions: Mutex<HashMap<String, Session>>,
+    pub ttl: Duration,
+}
+
+impl Impersonations {
+    pub fn new(ttl: Duration) -> Impersonations {
+        Impersonations {
+            sessions: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    pub fn from_env() -> Impersonations {
+        let minutes: u64 = env::var("IMPERSONATION_TTL_MINUTES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(30);
+        Impersonations::new(Duration::from_secs(minutes * 60))
+    }
+
+    // Starts a session for the user with a new token. Expired sessions are dropped
+    // here, so they don't pile up.
+    pub fn start(&self, token: String, user_id: i32, admin: String) -> Session {
+        let now = Instant::now();
+        let session = Session {
+            token: token.clone(),
+            user_id,
+            admin,
+            expires_at: now + self.ttl,
+        };
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(token, session.clone());
+        session
+    }
+
+    // The session a token belongs to, unless it has expired or was stopped
+    pub fn find(&self, token: &str) -> Option<Session> {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions
+            .get(token)
+            .filter(|session| session.expires_at > Instant::now())
+            .cloned()
+    }
+
+    // Ends a session; false if there was none with the token
+    pub fn stop(&self, token: &str) -> bool {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.remove(token).is_some()
+    }
+}
+
+// Sets (or with None, clears) the session of the request this thread is handling
+pub fn set_current(session: Option<Session>) {
+    CURRENT.with(|current| *current.borrow_mut() = session);
+}
+
+pub fn current() -> Option<Session> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// Audit log details for a change made by the current request: `details`, plus an
+// `impersonation` entry if the request was made under a session
+pub fn flag(details: Option<&Value>) -> Option<Value> {
+    let Some(session) = current() else {
+        return details.cloned();
+    };
+    let mut details = details.cloned().unwrap_or_else(|| serde_json::json!({}));
+    details["impersonation"] = serde_json::json!({
+        "user_id": session.user_id,
+        "admin": session.admin,
+    });
+    Some(details)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_last_until_stopped_or_expired() {
+        let impersonations = Impersonations::new(Duration::from_secs(60));
+        impersonations.start("abc".to_string(), 7, "uid=0 gid=0".to_string());
+        assert_eq!(impersonations.find("abc").map(|s| s.user_id), Some(7));
+        assert!(impersonations.find("xyz").is_none());
+        assert!(impersonations.stop("abc"));
+        assert!(!impersonations.stop("abc"));
+        assert!(impersonations.find("abc").is_none());
+
+        let expired = Impersonations::new(Duration::ZERO);
+        expired.start("abc".to_string(), 7, "uid=0 gid=0".to_string());
+        assert!(expired.find("abc").is_none());
+    }
+
+    #[test]
+    fn changes_under_a_session_are_flagged() {
+        let details = serde_json::json!({ "disposable_email": "flagged" });
+        assert_eq!(flag(Some(&details)), Some(details.clone()));
+        assert_eq!(flag(None), None);
+
+        let impersonations = Impersonations::new(Duration::from_secs(60));
+        set_current(Some(impersonations.start(
+            "abc".to_string(),
+            7,
+            "uid=0 gid=0".to_string(),
+        )));
+        let flagged = flag(Some(&details)).unwrap();
+        assert_eq!(flagged["disposable_email"], "flagged");
+        assert_eq!(flagged["impersonation"]["user_id"], 7);
+        assert_eq!(flagged["impersonation"]["admin"], "uid=0 gid=0");
+        set_current(None);
+    }
+}
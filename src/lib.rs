@@ -0,0 +1,98 @@
+// The user API server and its tools. `main.rs` is a thin binary over this crate;
+// integration tests can run the server in-process through `server::serve`.
+// The models, and with the `client` feature a typed client, are also shared with
+// other services so both ends agree on the JSON:
+//   rust_docker_pg_crud = { path = "...", features = ["client"] }
+
+#[macro_use]
+extern crate serde_derive;
+
+// Like `println!`, but prefixed with the ID of the request being handled (see `request_id`)
+macro_rules! log {
+    ($($arg:tt)*) => {
+        crate::request_id::log(format_args!($($arg)*))
+    };
+}
+
+mod access_log;
+pub mod admin_socket;
+mod admin_ui;
+mod archive;
+mod audit;
+mod audit_partitions;
+mod body;
+mod bulk;
+mod chaos;
+mod checksum;
+mod chunked;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod client_gen;
+pub mod collection_gen;
+mod compression;
+mod cors;
+pub mod config;
+mod data_quality;
+mod debug;
+mod delete_policy;
+pub mod db;
+mod disposable_email;
+mod email;
+mod email_change;
+mod email_policy;
+mod email_verification;
+mod errors;
+mod events;
+#[cfg(feature = "parquet")]
+mod export;
+mod features;
+mod health;
+mod idempotency;
+mod impersonation;
+pub mod handlers;
+mod http;
+pub mod http_client;
+pub mod loadtest;
+mod metrics;
+mod migrations;
+pub mod mock;
+pub mod models;
+mod notifications;
+mod openapi;
+pub mod ops;
+mod otel;
+pub mod output;
+pub mod pact;
+mod pool;
+mod query_log;
+mod posts;
+pub mod profile;
+mod rate_limit;
+#[cfg(feature = "redis")]
+mod redis;
+pub mod reload;
+pub mod repl;
+pub mod repo;
+pub mod resource;
+mod request_id;
+mod response_cache;
+mod retry;
+pub mod router;
+mod scheduler;
+mod scim;
+mod seed;
+pub mod server;
+mod shutdown;
+mod slo;
+mod slow_requests;
+mod sse;
+mod standby;
+mod statsd;
+mod storage;
+pub mod support_bundle;
+pub mod sync;
+mod tokens;
+mod undo;
+pub mod user_profile;
+mod webhooks;
+mod websocket;
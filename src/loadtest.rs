@@ -0,0 +1,266 @@
+use crate::http_client;
+use crate::output::{self, OutputFormat};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Operations the load generator can issue, with their share of the traffic mix
+#[derive(Clone, Copy)]
+enum Operation {
+    Get,
+    List,
+    Create,
+    Delete,
+}
+
+struct Options {
+    base_url: String,
+    duration: Duration,
+    concurrency: usize,
+    // Highest user id targeted by GET/DELETE requests
+    max_id: u64,
+    mix: Vec<(Operation, u32)>,
+}
+
+// Outcome of a single request: latency and whether it counts as an error
+// (transport failure or 5xx; a 404 for a random id is expected traffic)
+struct Sample {
+    latency: Duration,
+    error: bool,
+}
+
+// Entry point for `app loadtest [base-url] [--duration SECS] [--concurrency N]
+// [--max-id N] [--mix get=60,list=20,create=15,delete=5]`.
+// Drives CRUD traffic against a running server and prints throughput, latency
+// percentiles, and the error rate.
+pub fn run(args: &[String], format: OutputFormat) -> io::Result<()> {
+    let options = parse_options(args)?;
+    eprintln!(
+        "Running load test against {} for {}s with {} workers",
+        options.base_url,
+        options.duration.as_secs(),
+        options.concurrency
+    );
+
+    let options = Arc::new(options);
+    let stop = Arc::new(AtomicBool::new(false));
+    let started = Instant::now();
+
+    let workers: Vec<_> = (0..options.concurrency)
+        .map(|worker| {
+            let options = Arc::clone(&options);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || run_worker(&options, &stop, worker as u64 + 1))
+        })
+        .collect();
+
+    thread::sleep(options.duration);
+    stop.store(true, Ordering::Relaxed);
+
+    let mut samples = Vec::new();
+    for worker in workers {
+        samples.extend(worker.join().unwrap_or_default());
+    }
+
+    print_report(&samples, started.elapsed(), format);
+    Ok(())
+}
+
+fn parse_options(args: &[String]) -> io::Result<Options> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+    let mut options = Options {
+        base_url: "http://localhost:8080".to_string(),
+        duration: Duration::from_secs(10),
+        concurrency: 4,
+        max_id: 100,
+        mix: vec![
+            (Operation::Get, 60),
+            (Operation::List, 20),
+            (Operation::Create, 15),
+            (Operation::Delete, 5),
+        ],
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            options.base_url = arg.clone();
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| invalid(format!("missing value for {}", arg)))?;
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| invalid(format!("invalid number for {}: {}", arg, value)))
+        };
+
+        match arg.as_str() {
+            "--duration" => options.duration = Duration::from_secs(number()?),
+            "--concurrency" => options.concurrency = number()?.max(1) as usize,
+            "--max-id" => options.max_id = number()?.max(1),
+            "--mix" => options.mix = parse_mix(value).map_err(invalid)?,
+            _ => return Err(invalid(format!("unknown option {}", arg))),
+        }
+    }
+
+    Ok(options)
+}
+
+// Parses `get=60,list=20,create=15,delete=5` into weighted operations
+fn parse_mix(value: &str) -> Result<Vec<(Operation, u32)>, String> {
+    let mut mix = Vec::new();
+    for part in value.split(',') {
+        let (name, weight) = part
+            .split_once('=')
+            .ok_or_else(|| format!("invalid mix entry: {}", part))?;
+        let operation = match name.trim() {
+            "get" => Operation::Get,
+            "list" => Operation::List,
+            "create" => Operation::Create,
+            "delete" => Operation::Delete,
+            other => return Err(format!("unknown operation in mix: {}", other)),
+        };
+        let weight = weight
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid weight in mix: {}", part))?;
+        mix.push((operation, weight));
+    }
+
+    if mix.iter().all(|(_, weight)| *weight == 0) {
+        return Err("the mix needs at least one operation with a positive weight".to_string());
+    }
+    Ok(mix)
+}
+
+fn run_worker(options: &Options, stop: &AtomicBool, seed: u64) -> Vec<Sample> {
+    let total_weight: u64 = options.mix.iter().map(|(_, weight)| *weight as u64).sum();
+    let mut rng = XorShift(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1);
+    let mut samples = Vec::new();
+    let mut created = 0;
+
+    while !stop.load(Ordering::Relaxed) {
+        let mut pick = rng.next() % total_weight;
+        let operation = options
+            .mix
+            .iter()
+            .find(|(_, weight)| {
+                let found = pick < *weight as u64;
+                pick = pick.saturating_sub(*weight as u64);
+                found
+            })
+            .map(|(operation, _)| *operation)
+            .unwrap_or(Operation::List);
+
+        let id = rng.next() % options.max_id + 1;
+        let (method, path, body) = match operation {
+            Operation::Get => ("GET", format!("/users/{}", id), None),
+            Operation::List => ("GET", "/users".to_string(), None),
+            Operation::Create => {
+                created += 1;
+                let body = serde_json::json!({
+                    "name": format!("Load Test {}-{}", seed, created),
+                    "email": format!("loadtest-{}-{}@example.com", seed, created),
+                });
+                ("POST", "/users".to_string(), Some(body.to_string()))
+            }
+            Operation::Delete => ("DELETE", format!("/users/{}", id), None),
+        };
+
+        let started = Instant::now();
+        let result = http_client::send(&options.base_url, method, &path, &[], body.as_deref());
+        samples.push(Sample {
+            latency: started.elapsed(),
+            error: result.map_or(true, |response| response.status >= 500),
+        });
+    }
+
+    samples
+}
+
+fn print_report(samples: &[Sample], elapsed: Duration, format: OutputFormat) {
+    if samples.is_empty() {
+        eprintln!("No requests completed");
+        return;
+    }
+
+    let mut latencies: Vec<Duration> = samples.iter().map(|sample| sample.latency).collect();
+    latencies.sort();
+    let errors = samples.iter().filter(|sample| sample.error).count();
+    let millis = |latency: Duration| (latency.as_secs_f64() * 1000.0 * 1000.0).round() / 1000.0;
+
+    output::print_record(
+        format,
+        &[
+            ("requests", samples.len().into()),
+            (
+                "throughput_rps",
+                ((samples.len() as f64 / elapsed.as_secs_f64() * 10.0).round() / 10.0).into(),
+            ),
+            ("errors", errors.into()),
+            (
+                "error_rate_percent",
+                ((errors as f64 * 10000.0 / samples.len() as f64).round() / 100.0).into(),
+            ),
+            (
+                "latency_p50_ms",
+                millis(percentile(&latencies, 0.50)).into(),
+            ),
+            (
+                "latency_p90_ms",
+                millis(percentile(&latencies, 0.90)).into(),
+            ),
+            (
+                "latency_p99_ms",
+                millis(percentile(&latencies, 0.99)).into(),
+            ),
+            (
+                "latency_max_ms",
+                millis(latencies[latencies.len() - 1]).into(),
+            ),
+        ],
+    );
+}
+
+// Nearest-rank percentile of an already sorted, non-empty slice
+fn percentile(sorted: &[Duration], quantile: f64) -> Duration {
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+// Small deterministic PRNG so runs with the same settings issue the same request sequence
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 0.50), Duration::from_millis(50));
+        assert_eq!(percentile(&latencies, 0.99), Duration::from_millis(99));
+        assert_eq!(percentile(&latencies[..1], 0.99), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn mix_rejects_unknown_operations() {
+        assert!(parse_mix("get=1,list=2").is_ok());
+        assert!(parse_mix("get=1,patch=2").is_err());
+        assert!(parse_mix("get=0").is_err());
+    }
+}
@@ -1,207 +1,166 @@
-use postgres::{Client, NoTls, Error as PostgresError};
+use clap::{Args, Parser, Subcommand};
 use std::env;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-
-#[macro_use]
-extern crate serde_derive;
-
-// Model: User struct
-#[derive(Serialize, Deserialize)] // Fixed typo: Deserealize -> Deserialize
-struct User {
-    id: Option<i32>,
-    name: String,
-    email: String,
+use std::time::Duration;
+
+use rust_docker_pg_crud::{
+    client_gen, collection_gen, config, loadtest, ops, output, pact, profile, reload, repl, server, support_bundle,
+    sync,
+};
+
+// Subcommands run a one-off task instead of the server; `serve` (the default) runs it.
+// Every subcommand also accepts `--output table|json` (see `output`).
+#[derive(Parser)]
+#[command(about = "The user API server and its tools")]
+#[command(after_help = "Every command accepts --output table|json (default table).")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-// DB URL
-fn get_db_url() -> String {
-    env::var("DATABASE_URL").expect("DATABASE_URL must be set")
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server (the default), e.g. `serve --mock`
+    Serve(Passthrough),
+    /// Create or update the database schema, as the server does on startup
+    Migrate {
+        /// Print the SQL of the pending migrations, in order, without applying them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Create a verified user
+    CreateUser {
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        email: String,
+    },
+    /// List users, oldest first
+    ListUsers {
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+        #[arg(long, default_value_t = 0)]
+        offset: i64,
+        #[arg(long)]
+        include_deleted: bool,
+    },
+    /// Exit with 0 if the server at --url is ready, 1 otherwise
+    Healthcheck {
+        #[arg(long, default_value = "http://localhost:8080")]
+        url: String,
+        /// Seconds to wait for the answer
+        #[arg(long, default_value_t = 5)]
+        timeout: u64,
+    },
+    /// Write a tarball of the configuration, schema and server state for bug reports
+    SupportBundle(Passthrough),
+    /// Check the API (its mock mode) against recorded consumer contracts
+    VerifyPacts(Passthrough),
+    /// Send load to a running server and report latencies
+    Loadtest(Passthrough),
+    /// Interactive shell over the users table
+    Repl,
+    /// Import users from a paginated remote API
+    Sync(Passthrough),
+    /// Generate a typed API client from the OpenAPI document
+    GenerateClient(Passthrough),
+    /// Generate a Postman/Bruno collection from the OpenAPI document
+    GenerateCollection(Passthrough),
 }
 
-// Constants
-const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n";
-const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\n\r\n";
-const INTERNAL_SERVER_ERROR: &str = "HTTP/1.1 500 INTERNAL_SERVER_ERROR\r\n\r\n";
+// Arguments handed as they are to commands that parse their own
+#[derive(Args)]
+struct Passthrough {
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<String>,
+}
 
 fn main() {
-    // Set database
-    // This function returns a Result<(), PostgresError> because it performs an action (DB setup)
-    // that might fail, but doesn't need to return any data upon success.
-    // The `()` unit type signifies that on success, no specific value is returned.
-    if let Err(e) = set_database() {
-        println!("Error setting up database: {}", e);
-        return;
+    // Before anything reads the environment (see `reload` and `profile`)
+    if let Err(e) = reload::apply_file() {
+        eprintln!("Error reading CONFIG_FILE: {}", e);
+        std::process::exit(1);
     }
-
-    // Start server
-    let listener = TcpListener::bind("0.0.0.0:8080").unwrap(); // Fixed format! syntax
-    println!("Server started at port 8080");
-
-    // Handle the client
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                handle_client(stream);
-            }
-            Err(e) => {
-                println!("Error: {}", e);
-            }
-        }
+    profile::apply();
+    let args: Vec<String> = env::args().collect();
+    let (format, args) = output::take_output_format(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let command = Cli::parse_from(args)
+        .command
+        .unwrap_or(Command::Serve(Passthrough { args: Vec::new() }));
+    // Commands working on the database check the settings first, like the server does
+    if matches!(
+        command,
+        Command::Migrate { .. }
+            | Command::CreateUser { .. }
+            | Command::ListUsers { .. }
+            | Command::Repl
+            | Command::Sync(_)
+    ) {
+        config::exit_if_invalid(true);
     }
-}
 
-fn handle_client(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
-    
-    match stream.read(&mut buffer) {
-        // `stream.read` returns a `Result<usize, io::Error>`, indicating either
-        // the number of bytes read or an I/O error.
-        Ok(size) => {
-            let request = String::from_utf8_lossy(&buffer[..size]);
-
-            // Handlers return a `(String, String)` tuple, representing the HTTP status line
-            // and the response body. This is a custom choice for this simple server,
-            // not a standard `Result` type.
-            let (status_line, content) = match &*request {
-                r if r.starts_with("POST /users") => handle_post_request(r),
-                r if r.starts_with("GET /users/") => handle_get_request(r),
-                r if r.starts_with("GET /users") => handle_get_all_request(r),
-                r if r.starts_with("DELETE /users/") => handle_delete_request(r),
-                _ => (NOT_FOUND.to_string(), "Not Found".to_string()),
-            };
-
-            // `stream.write_all` returns a `Result<(), io::Error>`. We check for errors
-            // to ensure the response was sent successfully.
-            if let Err(e) = stream.write_all(format!("{}{}", status_line, content).as_bytes()) {
-                println!("Failed to send response: {}", e);
-            }
+    let result = match command {
+        Command::Serve(serve) => {
+            server::run(&serve.args);
+            return;
         }
-        Err(e) => {
-            println!("Error: {}", e);
+        Command::Migrate { dry_run } => ops::migrate(dry_run, format),
+        Command::CreateUser { name, email } => ops::create_user(&name, &email, format),
+        Command::ListUsers {
+            limit,
+            offset,
+            include_deleted,
+        } => ops::list_users(limit, offset, include_deleted, format),
+        Command::Healthcheck { url, timeout } => {
+            ops::healthcheck(&url, Duration::from_secs(timeout), format)
         }
-    }
-}
-
-// Handle POST request
-// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
-fn handle_post_request(request: &str) -> (String, String) {
-    match (get_user_from_request_body(request), Client::connect(&get_db_url(), NoTls)) {
-        (Ok(user), Ok(mut client)) => {
-            client
-                .execute(
-                    "INSERT INTO users (name, email) VALUES ($1, $2)",
-                    &[&user.name, &user.email],
-                )
-                .unwrap();
-            
-            (OK_RESPONSE.to_string(), "User Created".to_string())
-        }
-        _ => (INTERNAL_SERVER_ERROR.to_string(), "Error".to_string()),
-    }
-}
-
-// Handle GET request (by ID)
-// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
-fn handle_get_request(request: &str) -> (String, String) {
-    let id = get_id(request);
-    let id: i32 = match id.parse() {
-        Ok(n) => n,
-        Err(_) => return (INTERNAL_SERVER_ERROR.to_string(), "Invalid ID".to_string()),
+        Command::SupportBundle(command) => support_bundle::run(&command.args, format),
+        Command::VerifyPacts(command) => pact::run(&command.args, format),
+        Command::Loadtest(command) => loadtest::run(&command.args, format),
+        Command::Repl => repl::run(format),
+        Command::Sync(command) => sync::run(&command.args, format),
+        Command::GenerateClient(command) => client_gen::run(&command.args, format),
+        Command::GenerateCollection(command) => collection_gen::run(&command.args, format),
     };
-
-    match Client::connect(&get_db_url(), NoTls) {
-        Ok(mut client) => {
-            match client.query_one("SELECT id, name, email FROM users WHERE id = $1", &[&id]) {
-                Ok(row) => {
-                    let user = User {
-                        id: Some(row.get(0)),
-                        name: row.get(1),
-                        email: row.get(2),
-                    };
-                    (OK_RESPONSE.to_string(), serde_json::to_string(&user).unwrap())
-                }
-                Err(_) => (NOT_FOUND.to_string(), "User not found".to_string()),
-            }
-        }
-        Err(_) => (INTERNAL_SERVER_ERROR.to_string(), "Database error".to_string()),
-    }
-}
-
-// Handle GET All request
-// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
-fn handle_get_all_request(_request: &str) -> (String, String) {
-    match Client::connect(&get_db_url(), NoTls) {
-        Ok(mut client) => {
-            let mut users = Vec::new();
-            for row in client.query("SELECT id, name, email FROM users", &[]).unwrap() {
-                users.push(User {
-                    id: Some(row.get(0)),
-                    name: row.get(1),
-                    email: row.get(2),
-                });
-            }
-            (OK_RESPONSE.to_string(), serde_json::to_string(&users).unwrap())
-        }
-        Err(_) => (INTERNAL_SERVER_ERROR.to_string(), "Database error".to_string()),
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
     }
 }
 
-// Handle DELETE request
-// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
-fn handle_delete_request(request: &str) -> (String, String) {
-    let id = get_id(request);
-     let id: i32 = match id.parse() {
-        Ok(n) => n,
-        Err(_) => return (INTERNAL_SERVER_ERROR.to_string(), "Invalid ID".to_string()),
-    };
-
-    match Client::connect(&get_db_url(), NoTls) {
-        Ok(mut client) => {
-            let rows_affected = client.execute("DELETE FROM users WHERE id = $1", &[&id]).unwrap();
-            
-            if rows_affected == 0 {
-                return (NOT_FOUND.to_string(), "User not found".to_string());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn commands_parse() {
+        Cli::command().debug_assert();
+        let cli = Cli::parse_from([
+            "app",
+            "loadtest",
+            "http://localhost:8080",
+            "--duration",
+            "5",
+        ]);
+        match cli.command {
+            Some(Command::Loadtest(command)) => {
+                assert_eq!(command.args, ["http://localhost:8080", "--duration", "5"])
             }
-
-            (OK_RESPONSE.to_string(), "User Deleted".to_string())
+            _ => panic!("expected loadtest"),
         }
-        Err(_) => (INTERNAL_SERVER_ERROR.to_string(), "Database error".to_string()),
+        let cli = Cli::parse_from([
+            "app",
+            "create-user",
+            "--name",
+            "Ada",
+            "--email",
+            "ada@example.com",
+        ]);
+        assert!(matches!(cli.command, Some(Command::CreateUser { name, .. }) if name == "Ada"));
+        let cli = Cli::parse_from(["app", "migrate", "--dry-run"]);
+        assert!(matches!(cli.command, Some(Command::Migrate { dry_run: true })));
     }
 }
-
-// Sets up the database, creating the 'users' table if it doesn't exist.
-// Returns `Result<(), PostgresError>`:
-// - `Ok(())` on success, indicating no specific data is returned, only that the operation completed successfully.
-// - `Err(PostgresError)` if there's an error connecting to the database or executing the SQL.
-fn set_database() -> Result<(), PostgresError> {
-    // Connect to db
-    let mut client = Client::connect(&get_db_url(), NoTls)?;
-    client.execute(
-        "CREATE TABLE IF NOT EXISTS users (
-            id SERIAL PRIMARY KEY,
-            name VARCHAR NOT NULL,
-            email VARCHAR NOT NULL
-        )",
-        &[],
-    )?;
-    Ok(())
-}
-
-fn get_id(request: &str) -> &str {
-    request
-        .split("/")
-        .nth(2)
-        .unwrap_or_default()
-        .split_whitespace()
-        .next()
-        .unwrap_or_default()
-}
-
-// Deserializes a User from the request body (assumed to be JSON).
-// Returns `Result<User, serde_json::Error>`:
-// - `Ok(User)` on successful deserialization of the JSON into a `User` struct.
-// - `Err(serde_json::Error)` if the request body is not valid JSON or doesn't match the `User` structure.
-fn get_user_from_request_body(request: &str) -> Result<User, serde_json::Error> {
-    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
-}
@@ -0,0 +1,501 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::mock::format_timestamp;
+
+// Per-minute request aggregates for GET /admin/metrics/history, so operators get trends
+// without a metrics stack. The last METRICS_HISTORY_MINUTES (1440, a day) minutes are
+// kept in memory; the history starts over when the server restarts.
+// Each minute carries an exemplar: the request ID of its slowest request, so a latency
+// spike leads straight to that request's log lines and its entry in
+// GET /admin/slow-requests.
+// Latencies are also counted in a histogram per minute (see `LATENCY_BUCKETS_MS`). With
+// tracing on (see `otel`), each bucket carries an exemplar too: the trace ID of its latest
+// sampled request, to go from a spike in a bucket to the trace of a request in it.
+// The same histogram, counted since the server started, is served to Prometheus at
+// GET /metrics in the OpenMetrics text format, exemplars included, so Grafana can link
+// from a latency panel to the traces (see `MetricsHistory::openmetrics`).
+
+// Longest `window` accepted, whatever the history size
+const MAX_WINDOW_MINUTES: u64 = 7 * 24 * 60;
+
+// Upper bounds of the latency histogram's buckets; a last bucket takes anything slower
+const LATENCY_BUCKETS_MS: &[u64] = &[5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+#[derive(Clone, Default)]
+struct Minute {
+    // Minutes since the epoch
+    minute: u64,
+    requests: u64,
+    client_errors: u64,
+    server_errors: u64,
+    total_latency_micros: u64,
+    max_latency_micros: u64,
+    // The request that took `max_latency_micros`
+    max_latency_request_id: Option<String>,
+    // Requests per latency bucket, with the last one of each that was traced
+    buckets: Vec<(u64, Option<Exemplar>)>,
+}
+
+// A traced request counted in a bucket
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Exemplar {
+    pub trace_id: String,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct LatencyBucket {
+    // Upper bound; None for the last bucket, which has none
+    pub le_ms: Option<u64>,
+    pub requests: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exemplar: Option<Exemplar>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MinuteSummary {
+    pub start: String,
+    pub requests: u64,
+    pub client_errors: u64,
+    pub server_errors: u64,
+    pub avg_latency_ms: f64,
+    pub max_latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_latency_request_id: Option<String>,
+    pub latency_buckets: Vec<LatencyBucket>,
+}
+
+pub struct MetricsHistory {
+    // Oldest first, one entry per minute that had requests
+    minutes: Mutex<VecDeque<Minute>>,
+    capacity: usize,
+    // Since `started`, for GET /metrics
+    totals: Mutex<Totals>,
+    started: SystemTime,
+}
+
+#[derive(Default)]
+struct Totals {
+    requests: u64,
+    latency_micros: u64,
+    // Requests per latency bucket (each counted in its own bucket only), with the last
+    // one of each that was traced
+    buckets: Vec<(u64, Option<Exemplar>)>,
+}
+
+// The content type of `MetricsHistory::openmetrics`
+pub const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+impl MetricsHistory {
+    pub fn from_env() -> MetricsHistory {
+        let capacity = env::var("METRICS_HISTORY_MINUTES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(1440);
+        MetricsHistory::new(capacity)
+    }
+
+    pub fn new(capacity: usize) -> MetricsHistory {
+        MetricsHistory {
+            minutes: Mutex::new(VecDeque::new()),
+            capacity,
+            totals: Mutex::new(Totals::default()),
+            started: SystemTime::now(),
+        }
+    }
+
+    // Counts a response with this status that took `latency` to produce, for the
+    // request with this ID, in the trace with this ID if it was sampled
+    pub fn record(&self, status: u16, latency: Duration, request_id: Option<&str>, trace_id: Option<&str>) {
+        self.record_at(current_minute(), status, latency, request_id, trace_id);
+    }
+
+    fn record_at(&self, minute: u64, status: u16, latency: Duration, request_id: Option<&str>, trace_id: Option<&str>) {
+        let mut minutes = self.minutes.lock().unwrap_or_else(|e| e.into_inner());
+        if minutes.back().is_none_or(|last| last.minute < minute) {
+            minutes.push_back(Minute {
+                minute,
+                ..Default::default()
+            });
+        }
+        while minutes
+            .front()
+            .is_some_and(|first| first.minute + self.capacity as u64 <= minute)
+        {
+            minutes.pop_front();
+        }
+        // Clocks can step back; such requests count towards the latest minute
+        let Some(current) = minutes.back_mut() else {
+            return;
+        };
+        let exemplar = trace_id.map(|trace_id| Exemplar {
+            trace_id: trace_id.to_string(),
+            latency_ms: latency.as_micros() as f64 / 1000.0,
+            request_id: request_id.map(str::to_string),
+        });
+        count_in_bucket(&mut current.buckets, latency, exemplar.clone());
+        {
+            let mut totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+            totals.requests += 1;
+            totals.latency_micros += latency.as_micros() as u64;
+            count_in_bucket(&mut totals.buckets, latency, exemplar);
+        }
+
+        let latency = latency.as_micros() as u64;
+        current.requests += 1;
+        current.client_errors += u64::from((400..500).contains(&status));
+        current.server_errors += u64::from(status >= 500);
+        current.total_latency_micros += latency;
+        if latency >= current.max_latency_micros {
+            current.max_latency_micros = latency;
+            current.max_latency_request_id = request_id.map(str::to_string);
+        }
+    }
+
+    // The latency histogram since the server started, in the OpenMetrics text format:
+    // cumulative buckets, each with its latest traced request as an exemplar, e.g.
+    //   http_request_duration_seconds_bucket{le="0.05"} 12 # {trace_id="4bf9..."} 0.042
+    pub fn openmetrics(&self) -> String {
+        let totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+        let name = "http_request_duration_seconds";
+        let mut text = format!(
+            "# TYPE {name} histogram\n# UNIT {name} seconds\n# HELP {name} Time taken to respond to requests.\n"
+        );
+        let mut requests = 0;
+        for bucket in 0..=LATENCY_BUCKETS_MS.len() {
+            let (count, exemplar) = totals.buckets.get(bucket).cloned().unwrap_or_default();
+            requests += count;
+            let le = match LATENCY_BUCKETS_MS.get(bucket) {
+                Some(&le_ms) => format!("{:?}", le_ms as f64 / 1000.0),
+                None => "+Inf".to_string(),
+            };
+            let _ = write!(text, "{name}_bucket{{le=\"{le}\"}} {requests}");
+            if let Some(exemplar) = exemplar {
+                let _ = write!(text, " # {{trace_id=\"{}\"}} {:?}", exemplar.trace_id, exemplar.latency_ms / 1000.0);
+            }
+            text.push('\n');
+        }
+        let started = self.started.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let _ = write!(
+            text,
+            "{name}_count {}\n{name}_sum {:?}\n{name}_created {:?}\n# EOF\n",
+            totals.requests,
+            totals.latency_micros as f64 / 1_000_000.0,
+            started.as_secs_f64(),
+        );
+        text
+    }
+
+    // One summary per minute of the last `window_minutes`, including the current one,
+    // oldest first. Minutes without requests are included with zero counts.
+    pub fn history(&self, window_minutes: u64) -> Vec<MinuteSummary> {
+        self.history_at(current_minute(), window_minutes)
+    }
+
+    fn history_at(&self, now: u64, window_minutes: u64) -> Vec<MinuteSummary> {
+        let minutes = self.minutes.lock().unwrap_or_else(|e| e.into_inner());
+        let first = (now + 1).saturating_sub(window_minutes);
+        let mut recorded = minutes
+            .iter()
+            .filter(|minute| minute.minute >= first)
+            .peekable();
+        (first..=now)
+            .map(
+                |minute| match recorded.next_if(|recorded| recorded.minute == minute) {
+                    Some(recorded) => summary(recorded),
+                    None => summary(&Minute {
+                        minute,
+                        ..Default::default()
+                    }),
+                },
+            )
+            .collect()
+    }
+}
+
+// Time spent on the database per request, for each route: the total of the request's
+// units of database work (see `slow_requests::time_database`), over the route's last
+// METRICS_DATABASE_SAMPLES (1000) requests that did any. Reported with the history as
+// percentiles, to tell the routes whose queries are slow from those slow for other
+// reasons.
+pub struct DatabaseLatencies {
+    // Microseconds, oldest first
+    routes: Mutex<HashMap<&'static str, VecDeque<u64>>>,
+    samples: usize,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RouteDatabaseLatency {
+    pub route: &'static str,
+    pub requests: usize,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+impl DatabaseLatencies {
+    pub fn from_env() -> DatabaseLatencies {
+        let samples = env::var("METRICS_DATABASE_SAMPLES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(1000);
+        DatabaseLatencies::new(samples)
+    }
+
+    pub fn new(samples: usize) -> DatabaseLatencies {
+        DatabaseLatencies {
+            routes: Mutex::new(HashMap::new()),
+            samples,
+        }
+    }
+
+    pub fn record(&self, route: &'static str, database: Duration) {
+        let mut routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        let samples = routes.entry(route).or_default();
+        samples.push_back(database.as_micros() as u64);
+        while samples.len() > self.samples {
+            samples.pop_front();
+        }
+    }
+
+    // Percentiles for every route with samples, by route
+    pub fn report(&self) -> Vec<RouteDatabaseLatency> {
+        let routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        let mut report: Vec<RouteDatabaseLatency> = routes
+            .iter()
+            .filter(|(_, samples)| !samples.is_empty())
+            .map(|(route, samples)| {
+                let mut sorted: Vec<u64> = samples.iter().copied().collect();
+                sorted.sort_unstable();
+                // Nearest rank
+                let percentile = |p: f64| {
+                    let rank = (p * sorted.len() as f64).ceil() as usize;
+                    sorted[rank.clamp(1, sorted.len()) - 1] as f64 / 1000.0
+                };
+                RouteDatabaseLatency {
+                    route,
+                    requests: sorted.len(),
+                    p50_ms: percentile(0.5),
+                    p95_ms: percentile(0.95),
+                    p99_ms: percentile(0.99),
+                }
+            })
+            .collect();
+        report.sort_by_key(|latency| latency.route);
+        report
+    }
+}
+
+// Parses a window like `90m`, `1h` or `7d` into minutes
+pub fn parse_window(window: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid window '{}'; use e.g. 30m, 1h or 1d", window);
+    let digits = window
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (count, unit) = window.split_at(digits);
+    let count: u64 = count.parse().map_err(|_| invalid())?;
+    let minutes = match unit {
+        "m" => count,
+        "h" => count.saturating_mul(60),
+        "d" => count.saturating_mul(24 * 60),
+        _ => return Err(invalid()),
+    };
+    if minutes == 0 || minutes > MAX_WINDOW_MINUTES {
+        return Err(format!(
+            "window must be between 1m and {}d",
+            MAX_WINDOW_MINUTES / (24 * 60)
+        ));
+    }
+    Ok(minutes)
+}
+
+fn summary(minute: &Minute) -> MinuteSummary {
+    let millis = |micros: u64| micros as f64 / 1000.0;
+    MinuteSummary {
+        start: format_timestamp(minute.minute * 60, 0),
+        requests: minute.requests,
+        client_errors: minute.client_errors,
+        server_errors: minute.server_errors,
+        avg_latency_ms: millis(
+            minute
+                .total_latency_micros
+                .checked_div(minute.requests)
+                .unwrap_or_default(),
+        ),
+        max_latency_ms: millis(minute.max_latency_micros),
+        max_latency_request_id: minute.max_latency_request_id.clone(),
+        latency_buckets: (0..=LATENCY_BUCKETS_MS.len())
+            .map(|bucket| {
+                let (requests, exemplar) = minute.buckets.get(bucket).cloned().unwrap_or_default();
+                LatencyBucket {
+                    le_ms: LATENCY_BUCKETS_MS.get(bucket).copied(),
+                    requests,
+                    exemplar,
+                }
+            })
+            .collect(),
+    }
+}
+
+// Counts a request that took `latency` in its bucket, which keeps `exemplar` if there's one
+fn count_in_bucket(buckets: &mut Vec<(u64, Option<Exemplar>)>, latency: Duration, exemplar: Option<Exemplar>) {
+    let bucket = LATENCY_BUCKETS_MS
+        .iter()
+        .position(|&le_ms| latency <= Duration::from_millis(le_ms))
+        .unwrap_or(LATENCY_BUCKETS_MS.len());
+    if buckets.is_empty() {
+        *buckets = vec![(0, None); LATENCY_BUCKETS_MS.len() + 1];
+    }
+    let (requests, kept) = &mut buckets[bucket];
+    *requests += 1;
+    if exemplar.is_some() {
+        *kept = exemplar;
+    }
+}
+
+fn current_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 60
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minutes_are_aggregated_and_gaps_filled() {
+        let history = MetricsHistory::new(3);
+        history.record_at(100, 200, Duration::from_millis(10), Some("a"), None);
+        history.record_at(100, 404, Duration::from_millis(30), Some("b"), None);
+        history.record_at(100, 200, Duration::from_millis(20), Some("c"), None);
+        history.record_at(102, 503, Duration::from_millis(5), None, None);
+
+        let summaries = history.history_at(102, 3);
+        let requests: Vec<u64> = summaries.iter().map(|minute| minute.requests).collect();
+        assert_eq!(requests, [3, 0, 1]);
+        assert_eq!(summaries[0].start, "1970-01-01T01:40:00.000000+00:00");
+        assert_eq!(
+            (summaries[0].client_errors, summaries[0].server_errors),
+            (1, 0)
+        );
+        assert_eq!(
+            (summaries[0].avg_latency_ms, summaries[0].max_latency_ms),
+            (20.0, 30.0)
+        );
+        assert_eq!(summaries[0].max_latency_request_id.as_deref(), Some("b"));
+        assert_eq!(summaries[1].max_latency_request_id, None);
+        assert_eq!(summaries[2].server_errors, 1);
+
+        // Only the last 3 minutes are kept
+        history.record_at(103, 200, Duration::ZERO, None, None);
+        let requests: Vec<u64> = history
+            .history_at(103, 4)
+            .iter()
+            .map(|minute| minute.requests)
+            .collect();
+        assert_eq!(requests, [0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn latency_buckets_carry_the_latest_trace_as_exemplar() {
+        let history = MetricsHistory::new(3);
+        let trace = "4bf92f3577b34da6a3ce929d0e0e4736";
+        history.record_at(100, 200, Duration::from_millis(3), Some("a"), None);
+        history.record_at(100, 200, Duration::from_millis(40), Some("b"), Some(trace));
+        history.record_at(100, 200, Duration::from_millis(45), Some("c"), None);
+        history.record_at(100, 500, Duration::from_secs(30), None, Some("0af7651916cd43dd8448eb211c80319c"));
+
+        let buckets = &history.history_at(100, 1)[0].latency_buckets;
+        assert_eq!(buckets.len(), LATENCY_BUCKETS_MS.len() + 1);
+        let counts: Vec<(Option<u64>, u64)> = buckets
+            .iter()
+            .filter(|bucket| bucket.requests > 0)
+            .map(|bucket| (bucket.le_ms, bucket.requests))
+            .collect();
+        assert_eq!(counts, [(Some(5), 1), (Some(50), 2), (None, 1)]);
+        assert_eq!(buckets[0].exemplar, None);
+        assert_eq!(
+            buckets[3].exemplar,
+            Some(Exemplar {
+                trace_id: trace.to_string(),
+                latency_ms: 40.0,
+                request_id: Some("b".to_string()),
+            })
+        );
+        assert_eq!(buckets[11].exemplar.as_ref().map(|exemplar| exemplar.latency_ms), Some(30000.0));
+        assert!(history.history_at(101, 1)[0].latency_buckets.iter().all(|bucket| bucket.requests == 0));
+    }
+
+    #[test]
+    fn the_histogram_is_exposed_as_openmetrics_with_exemplars() {
+        let history = MetricsHistory::new(1);
+        let trace = "4bf92f3577b34da6a3ce929d0e0e4736";
+        history.record_at(100, 200, Duration::from_millis(3), None, None);
+        history.record_at(100, 200, Duration::from_millis(42), Some("b"), Some(trace));
+        // Totals outlast the minutes in the history
+        history.record_at(200, 200, Duration::from_secs(30), None, None);
+
+        let text = history.openmetrics();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "# TYPE http_request_duration_seconds histogram");
+        assert_eq!(lines[3], r#"http_request_duration_seconds_bucket{le="0.005"} 1"#);
+        assert_eq!(lines[5], r#"http_request_duration_seconds_bucket{le="0.025"} 1"#);
+        assert_eq!(
+            lines[6],
+            r#"http_request_duration_seconds_bucket{le="0.05"} 2 # {trace_id="4bf92f3577b34da6a3ce929d0e0e4736"} 0.042"#
+        );
+        assert_eq!(lines[10], r#"http_request_duration_seconds_bucket{le="1.0"} 2"#);
+        assert_eq!(lines[14], r#"http_request_duration_seconds_bucket{le="+Inf"} 3"#);
+        assert_eq!(lines[15], "http_request_duration_seconds_count 3");
+        assert_eq!(lines[16], "http_request_duration_seconds_sum 30.045");
+        assert!(lines[17].starts_with("http_request_duration_seconds_created "));
+        assert_eq!(lines.last(), Some(&"# EOF"));
+    }
+
+    #[test]
+    fn database_percentiles_cover_the_latest_samples() {
+        let latencies = DatabaseLatencies::new(100);
+        for millis in 1..=200 {
+            latencies.record("/users", Duration::from_millis(millis));
+        }
+        latencies.record("/users/{id}", Duration::from_micros(1500));
+        assert_eq!(
+            latencies.report(),
+            [
+                RouteDatabaseLatency {
+                    route: "/users",
+                    requests: 100,
+                    p50_ms: 150.0,
+                    p95_ms: 195.0,
+                    p99_ms: 199.0,
+                },
+                RouteDatabaseLatency {
+                    route: "/users/{id}",
+                    requests: 1,
+                    p50_ms: 1.5,
+                    p95_ms: 1.5,
+                    p99_ms: 1.5,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn windows_are_parsed_into_minutes() {
+        assert_eq!(parse_window("90m"), Ok(90));
+        assert_eq!(parse_window("1h"), Ok(60));
+        assert_eq!(parse_window("2d"), Ok(2880));
+        for invalid in ["", "h", "1", "1w", "-1h", "0m", "8d", "1é"] {
+            assert!(parse_window(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
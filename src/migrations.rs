@@ -0,0 +1,318 @@
+use postgres::{Client, Error as PostgresError, GenericClient};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+use crate::archive;
+use crate::idempotency;
+use crate::models::Post;
+use crate::query_log::Timed;
+use crate::resource;
+use crate::user_profile;
+use crate::webhooks;
+
+// Versioned schema changes, applied in order at startup (and by `app migrate`) and
+// recorded in `schema_migrations` with the SHA-256 of their SQL. `app migrate --dry-run`
+// prints the SQL of the pending ones; GET /admin/migrations lists them all.
+// Released migrations mustn't be edited, only followed by new ones: an edited one isn't
+// run again, startup warns about it, and it's listed as `modified`.
+// Every migration is idempotent, so databases set up before they were recorded apply
+// them all once and carry on.
+// The audit log's partitions and the delete policies' foreign keys follow the
+// configuration instead, and are brought in line on every start (see `db::set_database`).
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    sql: fn() -> String,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_users",
+        sql: || {
+            "CREATE TABLE IF NOT EXISTS users (
+                id SERIAL PRIMARY KEY,
+                name VARCHAR NOT NULL,
+                email VARCHAR NOT NULL
+            );"
+            .to_string()
+        },
+    },
+    // Soft delete marker
+    Migration {
+        version: 2,
+        name: "users_deleted_at",
+        sql: || "ALTER TABLE users ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;".to_string(),
+    },
+    // Timestamps; `updated_at` is kept current by a trigger so every write path gets it
+    Migration {
+        version: 3,
+        name: "users_timestamps",
+        sql: || {
+            "ALTER TABLE users ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+             ALTER TABLE users ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+             CREATE OR REPLACE FUNCTION set_updated_at() RETURNS trigger AS $$
+             BEGIN
+                 NEW.updated_at = now();
+                 RETURN NEW;
+             END;
+             $$ LANGUAGE plpgsql;
+             CREATE OR REPLACE TRIGGER users_set_updated_at BEFORE UPDATE ON users
+                 FOR EACH ROW EXECUTE FUNCTION set_updated_at();"
+                .to_string()
+        },
+    },
+    // Row version for optimistic concurrency control
+    Migration {
+        version: 4,
+        name: "users_version",
+        sql: || "ALTER TABLE users ADD COLUMN IF NOT EXISTS version INTEGER NOT NULL DEFAULT 1;".to_string(),
+    },
+    // Whether the email address was confirmed (see `email_verification`). Users from
+    // before verification existed count as verified; new ones start out unverified.
+    Migration {
+        version: 5,
+        name: "users_verified",
+        sql: || {
+            "ALTER TABLE users ADD COLUMN IF NOT EXISTS verified BOOLEAN NOT NULL DEFAULT true;
+             ALTER TABLE users ALTER COLUMN verified SET DEFAULT false;"
+                .to_string()
+        },
+    },
+    // Full-text search over name and email for GET /users/search. Email addresses are
+    // split into words (`ada`, `example`, `com`) so any part of them can be searched for.
+    Migration {
+        version: 6,
+        name: "users_search",
+        sql: || {
+            "ALTER TABLE users ADD COLUMN IF NOT EXISTS search tsvector GENERATED ALWAYS AS (
+                 setweight(to_tsvector('simple', name), 'A')
+                 || setweight(to_tsvector('simple', translate(email, '@.-_+', '     ')), 'B')
+             ) STORED;
+             CREATE INDEX IF NOT EXISTS users_search ON users USING GIN (search);"
+                .to_string()
+        },
+    },
+    // Live users have distinct emails, so PUT /users/by-email/{email} can upsert on
+    // them. Databases with duplicates (see /admin/data-quality) need them resolved first.
+    Migration {
+        version: 7,
+        name: "users_email_live",
+        sql: || {
+            "CREATE UNIQUE INDEX IF NOT EXISTS users_email_live ON users (email) WHERE deleted_at IS NULL;"
+                .to_string()
+        },
+    },
+    // New addresses waiting for confirmation, at most one per user (see `email_change`)
+    Migration {
+        version: 8,
+        name: "create_pending_email_changes",
+        sql: || {
+            "CREATE TABLE IF NOT EXISTS pending_email_changes (
+                user_id INTEGER PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+                new_email VARCHAR NOT NULL,
+                token_hash BYTEA NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                expires_at TIMESTAMPTZ NOT NULL
+            );"
+            .to_string()
+        },
+    },
+    // Tokens confirming new users' email addresses, at most one per user (see
+    // `email_verification`)
+    Migration {
+        version: 9,
+        name: "create_email_verifications",
+        sql: || {
+            "CREATE TABLE IF NOT EXISTS email_verifications (
+                user_id INTEGER PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+                token_hash BYTEA NOT NULL UNIQUE,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                expires_at TIMESTAMPTZ NOT NULL
+            );"
+            .to_string()
+        },
+    },
+    // Digest settings (see `notifications`); the first period starts when they're set
+    Migration {
+        version: 10,
+        name: "create_notification_preferences",
+        sql: || {
+            "CREATE TABLE IF NOT EXISTS notification_preferences (
+                user_id INTEGER PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+                digest TEXT NOT NULL DEFAULT 'off' CHECK (digest IN ('off', 'daily', 'weekly')),
+                last_digest_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            );"
+            .to_string()
+        },
+    },
+    // Public profiles, created by their first PUT (see `user_profile`)
+    Migration {
+        version: 11,
+        name: "create_profiles",
+        sql: || user_profile::SCHEMA.to_string(),
+    },
+    Migration {
+        version: 12,
+        name: "create_posts",
+        sql: || format!("{}\nCREATE INDEX IF NOT EXISTS posts_user_id ON posts (user_id);", resource::table_sql::<Post>()),
+    },
+    // Users moved to cold storage, with their posts and preferences (see `archive`)
+    Migration {
+        version: 13,
+        name: "create_users_archive",
+        sql: || archive::SCHEMA.to_string(),
+    },
+    // Registered webhooks and their queued deliveries (see `webhooks`)
+    Migration {
+        version: 14,
+        name: "create_webhooks",
+        sql: webhooks::schema,
+    },
+    // Keys of retry-safe POSTs and their responses (see `idempotency`)
+    Migration {
+        version: 15,
+        name: "create_idempotency_keys",
+        sql: || idempotency::SCHEMA.to_string(),
+    },
+];
+
+// Where applied migrations are recorded
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_migrations (
+    version INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    checksum TEXT NOT NULL,
+    applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);";
+
+impl Migration {
+    pub fn sql(&self) -> String {
+        (self.sql)()
+    }
+
+    // SHA-256 (hex) of the SQL
+    pub fn checksum(&self) -> String {
+        Sha256::digest(self.sql().as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Applied,
+    Pending,
+    // Applied, but its SQL has changed since
+    Modified,
+    // Applied by a newer version of the server
+    Unknown,
+}
+
+// A migration as GET /admin/migrations lists it
+#[derive(Serialize, Debug)]
+pub struct Entry {
+    pub version: i32,
+    pub name: String,
+    pub status: Status,
+    // Of the SQL in this build; None for unknown migrations
+    pub checksum: Option<String>,
+    pub applied_checksum: Option<String>,
+    pub applied_at: Option<String>,
+}
+
+// Every migration, known or applied, by version
+pub fn status(client: &mut impl GenericClient) -> Result<Vec<Entry>, PostgresError> {
+    let mut applied = applied(client)?;
+    let mut entries: Vec<Entry> = MIGRATIONS
+        .iter()
+        .map(|migration| {
+            let checksum = migration.checksum();
+            let recorded = applied.remove(&migration.version);
+            Entry {
+                version: migration.version,
+                name: migration.name.to_string(),
+                status: match &recorded {
+                    None => Status::Pending,
+                    Some((_, applied_checksum, _)) if *applied_checksum == checksum => Status::Applied,
+                    Some(_) => Status::Modified,
+                },
+                checksum: Some(checksum),
+                applied_checksum: recorded.as_ref().map(|(_, checksum, _)| checksum.clone()),
+                applied_at: recorded.map(|(_, _, applied_at)| applied_at),
+            }
+        })
+        .collect();
+    entries.extend(applied.into_iter().map(|(version, (name, checksum, applied_at))| Entry {
+        version,
+        name,
+        status: Status::Unknown,
+        checksum: None,
+        applied_checksum: Some(checksum),
+        applied_at: Some(applied_at),
+    }));
+    entries.sort_by_key(|entry| entry.version);
+    Ok(entries)
+}
+
+pub fn find(version: i32) -> Option<&'static Migration> {
+    MIGRATIONS.iter().find(|migration| migration.version == version)
+}
+
+// Applies the pending migrations in one transaction
+pub fn apply(client: &mut Client) -> Result<(), PostgresError> {
+    let mut transaction = client.transaction()?;
+    // Replicas starting together take turns, so only the first applies them
+    transaction.timed_execute("SELECT pg_advisory_xact_lock(hashtext('schema_migrations'))", &[])?;
+    transaction.batch_execute(CREATE_TABLE)?;
+    for entry in status(&mut transaction)? {
+        match entry.status {
+            Status::Modified => log!(
+                "Migration {} ({}) was edited after it was applied; add a new migration instead",
+                entry.version,
+                entry.name
+            ),
+            Status::Pending => {
+                let Some(migration) = find(entry.version) else { continue };
+                transaction.batch_execute(&migration.sql())?;
+                transaction.timed_execute(
+                    "INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3)",
+                    &[&migration.version, &migration.name, &entry.checksum],
+                )?;
+                log!("Applied migration {} ({})", migration.version, migration.name);
+            }
+            Status::Applied | Status::Unknown => {}
+        }
+    }
+    transaction.commit()
+}
+
+// Recorded migrations: name, checksum and when they were applied, by version. None yet
+// when the table doesn't exist.
+fn applied(client: &mut impl GenericClient) -> Result<BTreeMap<i32, (String, String, String)>, PostgresError> {
+    let exists: bool = client
+        .timed_query_one("SELECT to_regclass('schema_migrations') IS NOT NULL", &[])?
+        .try_get(0)?;
+    if !exists {
+        return Ok(BTreeMap::new());
+    }
+    let rows = client.timed_query(
+        "SELECT version, name, checksum, to_json(applied_at) #>> '{}' FROM schema_migrations",
+        &[],
+    )?;
+    rows.iter()
+        .map(|row| Ok((row.try_get(0)?, (row.try_get(1)?, row.try_get(2)?, row.try_get(3)?))))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_are_numbered_in_order() {
+        let versions: Vec<i32> = MIGRATIONS.iter().map(|migration| migration.version).collect();
+        assert_eq!(versions, (1..=MIGRATIONS.len() as i32).collect::<Vec<_>>());
+        assert!(MIGRATIONS.iter().all(|migration| migration.sql().trim_end().ends_with(';')));
+        assert_eq!(MIGRATIONS[0].checksum().len(), 64);
+    }
+}
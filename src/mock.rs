@@ -0,0 +1,923 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::body;
+use crate::checksum::Checksum;
+use crate::errors::{error, AppError, ErrorCode};
+use crate::handlers::{
+    batch_ids, check_email_policy, etag_matches, get_user_from_request_body, include_deleted, include_posts,
+    json_response, list_fields, list_filter, ndjson_response, wants_ndjson, NOT_MODIFIED, OK_RESPONSE,
+};
+use crate::models::{BatchGetResult, DayCount, DomainCount, User, UserPatch, UserStats, STATS_DAYS};
+use crate::repo::UserFilter;
+use crate::router::{get_header, get_path, get_query_param, parse_id};
+use crate::server::{AppState, Caller};
+
+// `serve --mock [--seed N] [--users N] [--data FILE [--wal]]`: the user API backed by
+// generated data instead of Postgres, so frontends can be built offline. The same seed
+// always produces the same users; changes are kept in memory until the server stops, or
+// with `--data` saved to FILE on shutdown and loaded from it on the next start instead
+// of generating users, so demo data survives container restarts. Routes that need the
+// database for more than CRUD (audit, bulk updates, admin reports) answer 501.
+// With `--wal` every change is also appended to `FILE.wal`, and synced to disk before
+// it's answered, so changes survive crashes too: at startup the log is replayed on top
+// of FILE (or the generated users), and a clean shutdown folds it into FILE and empties
+// it. That makes a durable store with no dependencies, for edge deployments where
+// Postgres isn't available. A change cut off by a crash while being logged is dropped.
+
+const USAGE: &str = "usage: serve [--mock [--seed N] [--users N] [--data FILE [--wal]]]";
+
+const FIRST_NAMES: &[&str] = &[
+    "Ada",
+    "Alan",
+    "Barbara",
+    "Claude",
+    "Donald",
+    "Edsger",
+    "Frances",
+    "Grace",
+    "Hedy",
+    "John",
+    "Katherine",
+    "Ken",
+    "Linus",
+    "Margaret",
+    "Niklaus",
+    "Radia",
+    "Shafi",
+    "Tim",
+    "Vint",
+    "Whitfield",
+];
+
+const LAST_NAMES: &[&str] = &[
+    "Lovelace",
+    "Turing",
+    "Liskov",
+    "Shannon",
+    "Knuth",
+    "Dijkstra",
+    "Allen",
+    "Hopper",
+    "Lamarr",
+    "McCarthy",
+    "Johnson",
+    "Thompson",
+    "Torvalds",
+    "Hamilton",
+    "Wirth",
+    "Perlman",
+    "Goldwasser",
+    "Berners-Lee",
+    "Cerf",
+    "Diffie",
+];
+
+const DOMAINS: &[&str] = &["example.com", "example.org", "example.net", "mail.test"];
+
+// Generated users were created between 2024-01-01 and 2025-12-31
+const FIRST_CREATED_AT: u64 = 1_704_067_200;
+const CREATED_AT_SPAN: u64 = 2 * 365 * 24 * 60 * 60;
+
+pub struct MockOptions {
+    pub seed: u64,
+    pub users: usize,
+    // Where users are loaded from and saved to
+    pub data: Option<PathBuf>,
+    // Whether changes are logged to `<data>.wal` as they're made
+    pub wal: bool,
+}
+
+// Parses the arguments after `serve`. None unless `--mock` is given.
+pub fn parse_options(args: &[String]) -> io::Result<Option<MockOptions>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, USAGE);
+    let mut mock = false;
+    let mut options = MockOptions {
+        seed: 42,
+        users: 50,
+        data: None,
+        wal: false,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--mock" => mock = true,
+            "--seed" => {
+                options.seed = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(invalid)?
+            }
+            "--users" => {
+                options.users = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(invalid)?
+            }
+            "--data" => options.data = Some(args.next().ok_or_else(invalid)?.into()),
+            "--wal" => options.wal = true,
+            _ => return Err(invalid()),
+        }
+    }
+    if options.wal && options.data.is_none() {
+        return Err(invalid());
+    }
+    Ok(mock.then_some(options))
+}
+
+pub struct MockStore {
+    // Ordered by id, like the database's listing. Readers take a snapshot and work on it
+    // without holding the lock; writers copy the list first if a snapshot is still in use.
+    users: Mutex<Arc<Vec<User>>>,
+    // Handed out once each, even to creates that run at the same time
+    next_id: AtomicI32,
+    data: Option<PathBuf>,
+    // Open for appending with `--wal`; written with the users' lock held, so the log
+    // has changes in the order they were made
+    wal: Option<Mutex<File>>,
+}
+
+// A line of the `--wal` log: the user as a change left them. Replaying a change twice
+// does no harm.
+#[derive(Serialize, Deserialize)]
+struct LoggedChange {
+    user: User,
+}
+
+// The `--data` file
+#[derive(Serialize, Deserialize)]
+struct SavedStore {
+    next_id: i32,
+    users: Vec<User>,
+}
+
+// `count` users with IDs from 1; the same seed generates the same users
+pub fn generate_users(seed: u64, count: usize) -> Vec<User> {
+    let mut rng = Rng(seed);
+    (1..=count as i32)
+        .map(|id| {
+            let first = FIRST_NAMES[rng.below(FIRST_NAMES.len() as u64) as usize];
+            let last = LAST_NAMES[rng.below(LAST_NAMES.len() as u64) as usize];
+            let domain = DOMAINS[rng.below(DOMAINS.len() as u64) as usize];
+            let created = FIRST_CREATED_AT + rng.below(CREATED_AT_SPAN);
+            let micros = rng.below(1_000_000) as u32;
+            let mut user = User::new(
+                &format!("{} {}", first, last),
+                &format!("{}.{}{}@{}", first, last, id, domain).to_lowercase(),
+            );
+            user.id = Some(id);
+            user.created_at = Some(format_timestamp(created, micros));
+            user.updated_at = user.created_at.clone();
+            user.version = Some(1);
+            // Some users have been edited since, and a few deleted
+            if rng.below(4) == 0 {
+                let updated = created + rng.below(90 * 24 * 60 * 60);
+                user.updated_at = Some(format_timestamp(updated, micros));
+                user.version = Some(2);
+                if rng.below(3) == 0 {
+                    user.deleted_at = user.updated_at.clone();
+                }
+            }
+            user
+        })
+        .collect()
+}
+
+impl MockStore {
+    pub fn generate(options: &MockOptions) -> MockStore {
+        let users = generate_users(options.seed, options.users);
+        MockStore {
+            users: Mutex::new(Arc::new(users)),
+            next_id: AtomicI32::new(options.users as i32 + 1),
+            data: options.data.clone(),
+            wal: None,
+        }
+    }
+
+    // A store of just these users, without a data file (see `pact`'s provider states)
+    pub(crate) fn from_users(mut users: Vec<User>) -> MockStore {
+        users.sort_by_key(|user| user.id);
+        let next_id = users.iter().filter_map(|user| user.id).max().unwrap_or(0) + 1;
+        MockStore {
+            users: Mutex::new(Arc::new(users)),
+            next_id: AtomicI32::new(next_id),
+            data: None,
+            wal: None,
+        }
+    }
+
+    // The users saved in the `--data` file if there is one, generated ones otherwise,
+    // with the changes in the `--wal` log replayed
+    pub fn load_or_generate(options: &MockOptions) -> io::Result<MockStore> {
+        let (mut users, next_id) = match options.data.as_ref().filter(|path| path.exists()) {
+            Some(path) => {
+                let saved: SavedStore = serde_json::from_str(&fs::read_to_string(path)?)?;
+                (saved.users, saved.next_id)
+            }
+            None => {
+                let generated = MockStore::generate(options);
+                let next_id = generated.next_id.load(Ordering::SeqCst);
+                (generated.snapshot().to_vec(), next_id)
+            }
+        };
+        let wal = match options.data.as_ref().filter(|_| options.wal) {
+            Some(path) => {
+                let path = wal_path(path);
+                let replayed = replay(&path, &mut users)?;
+                if replayed > 0 {
+                    log!(
+                        "Replayed {} logged change(s) from {}",
+                        replayed,
+                        path.display()
+                    );
+                }
+                let file = OpenOptions::new().create(true).append(true).open(&path)?;
+                Some(Mutex::new(file))
+            }
+            None => None,
+        };
+        users.sort_by_key(|user| user.id);
+        // IDs are never reused, even if the file was edited by hand
+        let next_id = users
+            .iter()
+            .filter_map(|user| user.id)
+            .map(|id| id + 1)
+            .fold(next_id, i32::max);
+        Ok(MockStore {
+            users: Mutex::new(Arc::new(users)),
+            next_id: AtomicI32::new(next_id),
+            data: options.data.clone(),
+            wal,
+        })
+    }
+
+    // Writes the users to the `--data` file, if there is one, and empties the `--wal` log
+    // now that its changes are in the file. The file is replaced in one step, so a crash
+    // while saving leaves the previous one (and the log to replay on it).
+    pub fn save(&self) -> io::Result<Option<usize>> {
+        let Some(path) = &self.data else {
+            return Ok(None);
+        };
+        // Changes wait, so none is logged after the snapshot and then lost with the log
+        let users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        let saved = SavedStore {
+            next_id: self.next_id.load(Ordering::SeqCst),
+            users: users.to_vec(),
+        };
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(&serde_json::to_vec_pretty(&saved)?)?;
+        file.sync_all()?;
+        fs::rename(&temporary, path)?;
+        if let Some(wal) = &self.wal {
+            wal.lock().unwrap_or_else(|e| e.into_inner()).set_len(0)?;
+        }
+        Ok(Some(users.len()))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.snapshot().len()
+    }
+
+    // The users as they are now; later changes don't affect it
+    fn snapshot(&self) -> Arc<Vec<User>> {
+        Arc::clone(&self.users.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    // Runs `change` on a copy of the user with the ID (None if there's no such user),
+    // with other writers waiting. If it returns Ok, the copy is logged and replaces the
+    // user. Either way its response is returned, unless logging fails.
+    fn modify(
+        &self,
+        id: i32,
+        include_deleted: bool,
+        change: impl FnOnce(Option<&mut User>) -> Result<(String, String), (String, String)>,
+    ) -> (String, String) {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        let mut user = users
+            .iter()
+            .find(|user| user.id == Some(id) && (include_deleted || user.deleted_at.is_none()))
+            .cloned();
+        match change(user.as_mut()) {
+            Ok(response) => match self.put(Arc::make_mut(&mut users), user) {
+                Ok(()) => response,
+                Err(e) => log_failed(e),
+            },
+            Err(response) => response,
+        }
+    }
+
+    fn allocate_id(&self) -> i32 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    // Adds a user, keeping the list ordered by id. IDs are allocated before the lock is
+    // taken, so users may arrive out of order.
+    fn insert(&self, user: User) -> io::Result<()> {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        self.put(Arc::make_mut(&mut users), Some(user))
+    }
+
+    // Logs the user, then stores it in place of the one with its ID
+    fn put(&self, users: &mut Vec<User>, user: Option<User>) -> io::Result<()> {
+        let Some(user) = user else {
+            return Ok(());
+        };
+        if let Some(wal) = &self.wal {
+            let mut line = serde_json::to_vec(&LoggedChange { user: user.clone() })?;
+            line.push(b'\n');
+            let mut wal = wal.lock().unwrap_or_else(|e| e.into_inner());
+            wal.write_all(&line)?;
+            wal.sync_data()?;
+        }
+        store(users, user);
+        Ok(())
+    }
+}
+
+// Replaces the user with the same ID, or adds it in ID order
+fn store(users: &mut Vec<User>, user: User) {
+    let index = users.partition_point(|other| other.id < user.id);
+    match users.get_mut(index) {
+        Some(existing) if existing.id == user.id => *existing = user,
+        _ => users.insert(index, user),
+    }
+}
+
+fn wal_path(data: &Path) -> PathBuf {
+    let mut path = data.to_path_buf().into_os_string();
+    path.push(".wal");
+    path.into()
+}
+
+// Applies the changes logged at `path` to the users (sorted by ID), returning how many
+// there were. A last line cut off by a crash is dropped from the log.
+fn replay(path: &Path, users: &mut Vec<User>) -> io::Result<usize> {
+    let log = match fs::read(path) {
+        Ok(log) => log,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    users.sort_by_key(|user| user.id);
+    let mut replayed = 0;
+    let mut complete = 0;
+    for line in log.split_inclusive(|&byte| byte == b'\n') {
+        let change = line
+            .strip_suffix(b"\n")
+            .and_then(|line| serde_json::from_slice::<LoggedChange>(line).ok());
+        let Some(change) = change else {
+            log!(
+                "Dropping the incomplete change at the end of {}",
+                path.display()
+            );
+            break;
+        };
+        store(users, change.user);
+        replayed += 1;
+        complete += line.len();
+    }
+    if complete < log.len() {
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(complete as u64)?;
+    }
+    Ok(replayed)
+}
+
+fn log_failed(e: io::Error) -> (String, String) {
+    log!("Error logging a mock change: {}", e);
+    error(ErrorCode::InternalError, "Internal Server Error")
+}
+
+// Routes a request in mock mode.
+// Returns a `(String, String)` tuple for HTTP status and body, like the other handlers.
+pub fn handle_request(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    let store = match &state.mock {
+        Some(store) => store,
+        None => return error(ErrorCode::RouteNotFound, "Not Found"),
+    };
+
+    let method = request.split_whitespace().next().unwrap_or_default();
+    let segments: Vec<&str> = get_path(request).trim_matches('/').split('/').collect();
+    let id = parse_id(request, state.id_policy);
+    match (method, segments.as_slice(), id) {
+        ("GET", ["users"], _) => list(request, store, caller),
+        ("GET", ["users", "checksum"], _) => checksum(request, store, caller),
+        ("POST", ["users", "batch-get"], _) => batch_get(request, store, caller),
+        ("GET", ["users", "stats"], _) => stats(request, store, caller),
+        ("GET", ["users", "search" | "events" | "verify"], _) => {
+            error(ErrorCode::NotImplemented, "Not available in mock mode")
+        }
+        ("POST", ["users"], _) => create(request, state, store),
+        ("GET", ["users", _], Ok(id)) => get(request, store, caller, id),
+        ("PUT", ["users", _], Ok(id)) => update(request, state, store, id, false),
+        ("PATCH", ["users", _], Ok(id)) => update(request, state, store, id, true),
+        ("DELETE", ["users", _], Ok(id)) => delete(store, id),
+        ("POST", ["users", _, "restore"], Ok(id)) => restore(store, caller, id),
+        ("GET" | "PUT" | "PATCH" | "DELETE", ["users", _], Err(response))
+        | ("POST", ["users", _, "restore"], Err(response)) => response,
+        (_, ["users", ..], _)
+        | (_, ["posts", ..], _)
+        | (_, ["admin", ..], _)
+        | (_, ["scim", ..], _)
+        | (_, ["webhooks", ..], _)
+        | (_, ["ws", ..], _) => error(ErrorCode::NotImplemented, "Not available in mock mode"),
+        _ => error(ErrorCode::RouteNotFound, "Not Found"),
+    }
+}
+
+// The users a list filter matches, in ID order.
+// Timestamps are compared as text, which works for the ISO 8601 format used here.
+// Mock users are never asked to verify their address, so they all count as verified.
+fn matching<'a>(snapshot: &'a [User], filter: &UserFilter) -> Vec<&'a User> {
+    snapshot
+        .iter()
+        .filter(|user| filter.include_deleted || user.deleted_at.is_none())
+        .filter(|user| {
+            filter
+                .created_after
+                .as_ref()
+                .is_none_or(|after| user.created_at.as_ref() > Some(after))
+        })
+        .filter(|user| {
+            filter
+                .created_before
+                .as_ref()
+                .is_none_or(|before| user.created_at.as_ref() < Some(before))
+        })
+        .filter(|user| {
+            filter
+                .verified
+                .is_none_or(|verified| user.verified.unwrap_or(true) == verified)
+        })
+        .collect()
+}
+
+fn list(request: &str, store: &MockStore, caller: &Caller) -> (String, String) {
+    let filter = match list_filter(request, caller) {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+    let snapshot = store.snapshot();
+    let users = matching(&snapshot, &filter);
+    let ndjson = wants_ndjson(request);
+    match list_fields(request) {
+        Ok(None) if ndjson => ndjson_response(&users),
+        Ok(None) => json_response(OK_RESPONSE, &users),
+        Ok(Some(fields)) => {
+            let users: Vec<serde_json::Value> = users
+                .iter()
+                .filter_map(|user| serde_json::to_value(user).ok())
+                .map(|mut user| {
+                    if let Some(user) = user.as_object_mut() {
+                        user.retain(|field, _| fields.contains(field));
+                    }
+                    user
+                })
+                .collect();
+            if ndjson {
+                ndjson_response(&users)
+            } else {
+                json_response(OK_RESPONSE, &users)
+            }
+        }
+        Err(response) => response,
+    }
+}
+
+fn checksum(request: &str, store: &MockStore, caller: &Caller) -> (String, String) {
+    let filter = match list_filter(request, caller) {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+    let mut checksum = Checksum::default();
+    matching(&store.snapshot(), &filter).into_iter().for_each(|user| checksum.add(user));
+    json_response(OK_RESPONSE, &checksum.finish())
+}
+
+fn stats(request: &str, store: &MockStore, caller: &Caller) -> (String, String) {
+    let filter = match list_filter(request, caller) {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+    let snapshot = store.snapshot();
+    let users = matching(&snapshot, &filter);
+    let mut domains = BTreeMap::new();
+    for user in &users {
+        let domain = user.email.split('@').nth(1).unwrap_or_default().to_lowercase();
+        *domains.entry(domain).or_insert(0) += 1;
+    }
+    let today = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86_400;
+    let created_per_day = (today + 1 - STATS_DAYS as u64..=today)
+        .map(|day| {
+            let date = format_timestamp(day * 86_400, 0)[..10].to_string();
+            let created = |user: &&&User| user.created_at.as_ref().is_some_and(|created_at| created_at.starts_with(&date));
+            DayCount { count: users.iter().filter(created).count() as i64, date }
+        })
+        .collect();
+    let by_domain = domains.into_iter().map(|(domain, count)| DomainCount { domain, count }).collect();
+    json_response(OK_RESPONSE, &UserStats::new(users.len() as i64, by_domain, created_per_day))
+}
+
+fn batch_get(request: &str, store: &MockStore, caller: &Caller) -> (String, String) {
+    let include_deleted = match include_deleted(request, caller) {
+        Ok(include_deleted) => include_deleted,
+        Err(response) => return response,
+    };
+    let ids = match batch_ids(request) {
+        Ok(ids) => ids,
+        Err(e) => return e.into_response(),
+    };
+    let found = store
+        .snapshot()
+        .iter()
+        .filter(|user| user.id.is_some_and(|id| ids.contains(&id)))
+        .filter(|user| include_deleted || user.deleted_at.is_none())
+        .cloned()
+        .collect();
+    json_response(OK_RESPONSE, &BatchGetResult::new(&ids, found))
+}
+
+fn get(request: &str, store: &MockStore, caller: &Caller, id: i32) -> (String, String) {
+    let include_deleted = match include_deleted(request, caller) {
+        Ok(include_deleted) => include_deleted,
+        Err(response) => return response,
+    };
+    // The mock store has no posts, nor an audit log to rebuild past states from
+    if get_query_param(request, "as_of").is_some() {
+        return error(ErrorCode::NotImplemented, "Not available in mock mode");
+    }
+    match include_posts(request) {
+        Ok(false) => {}
+        Ok(true) => return error(ErrorCode::NotImplemented, "Not available in mock mode"),
+        Err(response) => return response,
+    }
+    let snapshot = store.snapshot();
+    let user = snapshot
+        .iter()
+        .find(|user| user.id == Some(id) && (include_deleted || user.deleted_at.is_none()));
+    match user {
+        Some(user) => {
+            let etag = user.etag();
+            if get_header(request, "If-None-Match").is_some_and(|value| etag_matches(value, &etag))
+            {
+                return (format!("{}ETag: {}\r\n", NOT_MODIFIED, etag), String::new());
+            }
+            json_response(&format!("{}ETag: {}\r\n", OK_RESPONSE, etag), user)
+        }
+        None => AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string())
+            .into_response(),
+    }
+}
+
+fn create(request: &str, state: &AppState, store: &MockStore) -> (String, String) {
+    let user = match get_user_from_request_body(request) {
+        Ok(user) => user,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(message) = check_email_policy(state, &user.email) {
+        return AppError::Validation(ErrorCode::EmailRejected, message).into_response();
+    }
+
+    let now = now();
+    let mut created = User::new(&user.name, &user.email);
+    created.id = Some(store.allocate_id());
+    created.created_at = Some(now.clone());
+    created.updated_at = Some(now);
+    created.version = Some(1);
+    match store.insert(created) {
+        Ok(()) => (OK_RESPONSE.to_string(), "User Created".to_string()),
+        Err(e) => log_failed(e),
+    }
+}
+
+fn update(
+    request: &str,
+    state: &AppState,
+    store: &MockStore,
+    id: i32,
+    partial: bool,
+) -> (String, String) {
+    let if_match = match get_header(request, "If-Match") {
+        Some(value) => value,
+        None => {
+            return AppError::Validation(
+                ErrorCode::PreconditionRequired,
+                "If-Match header required".to_string(),
+            )
+            .into_response()
+        }
+    };
+    let patch = if partial {
+        body::parse::<UserPatch>(request)
+    } else {
+        body::parse::<User>(request).map(|user| UserPatch {
+            name: Some(user.name),
+            email: Some(user.email),
+            version: user.version,
+        })
+    };
+    let patch = match patch {
+        Ok(patch) => patch,
+        Err(e) => return e.into_response(),
+    };
+    if let Some(Err(message)) = patch
+        .email
+        .as_ref()
+        .map(|email| check_email_policy(state, email))
+    {
+        return AppError::Validation(ErrorCode::EmailRejected, message).into_response();
+    }
+
+    store.modify(id, false, |user| apply(user, if_match, patch))
+}
+
+// The rest of `update`, with the lock held so nothing changes between the checks and
+// the change
+fn apply(
+    user: Option<&mut User>,
+    if_match: &str,
+    patch: UserPatch,
+) -> Result<(String, String), (String, String)> {
+    let user = user.ok_or_else(|| {
+        AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string()).into_response()
+    })?;
+    let etag = user.etag();
+    if !etag_matches(if_match, &etag) {
+        return Err(AppError::Validation(
+            ErrorCode::PreconditionFailed,
+            "User was modified".to_string(),
+        )
+        .into_response());
+    }
+    if patch
+        .version
+        .is_some_and(|version| Some(version) != user.version)
+    {
+        return Err(AppError::Validation(
+            ErrorCode::VersionConflict,
+            "Version conflict".to_string(),
+        )
+        .into_response());
+    }
+
+    if let Some(name) = patch.name {
+        user.name = name;
+    }
+    if let Some(email) = patch.email {
+        user.email = email;
+    }
+    touch(user);
+    Ok(json_response(
+        &format!("{}ETag: {}\r\n", OK_RESPONSE, user.etag()),
+        user,
+    ))
+}
+
+fn delete(store: &MockStore, id: i32) -> (String, String) {
+    store.modify(id, false, |user| match user {
+        Some(user) => {
+            touch(user);
+            user.deleted_at = user.updated_at.clone();
+            Ok((OK_RESPONSE.to_string(), "User Deleted".to_string()))
+        }
+        None => Err(
+            AppError::NotFound(ErrorCode::UserNotFound, "User not found".to_string())
+                .into_response(),
+        ),
+    })
+}
+
+fn restore(store: &MockStore, caller: &Caller, id: i32) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    store.modify(id, true, |user| match user {
+        Some(user) if user.deleted_at.is_some() => {
+            touch(user);
+            user.deleted_at = None;
+            Ok((OK_RESPONSE.to_string(), "User Restored".to_string()))
+        }
+        _ => Err(AppError::NotFound(
+            ErrorCode::UserNotFound,
+            "Deleted user not found".to_string(),
+        )
+        .into_response()),
+    })
+}
+
+// Bumps the version and `updated_at`, as the database does on every change
+fn touch(user: &mut User) {
+    user.version = Some(user.version.unwrap_or_default() + 1);
+    user.updated_at = Some(now());
+}
+
+pub(crate) fn now() -> String {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format_timestamp(elapsed.as_secs(), elapsed.subsec_micros())
+}
+
+// Seconds since the epoch as `2024-05-01T12:34:56.789012+00:00`, the format Postgres uses
+pub fn format_timestamp(secs: u64, micros: u32) -> String {
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}+00:00",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        micros
+    )
+}
+
+// Calendar date for a day count since 1970-01-01 (Howard Hinnant's algorithm)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+// SplitMix64: tiny, fast and the same on every platform, which is all fake data needs
+// (and `chaos`'s coin flips)
+pub(crate) struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn the_same_seed_generates_the_same_users() {
+        let options = MockOptions {
+            seed: 7,
+            users: 20,
+            data: None,
+            wal: false,
+        };
+        let first = MockStore::generate(&options);
+        let second = MockStore::generate(&options);
+        assert_eq!(first.snapshot(), second.snapshot());
+        assert_eq!(first.len(), 20);
+        assert_eq!(first.allocate_id(), 21);
+
+        let other = MockStore::generate(&MockOptions {
+            seed: 8,
+            users: 20,
+            data: None,
+            wal: false,
+        });
+        assert_ne!(first.snapshot(), other.snapshot());
+        assert!(first.snapshot().iter().all(|user| user.email.contains('@')));
+    }
+
+    #[test]
+    fn concurrent_creates_get_distinct_ids_in_order() {
+        let store = MockStore::generate(&MockOptions {
+            seed: 1,
+            users: 3,
+            data: None,
+            wal: false,
+        });
+        let before = store.snapshot();
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..25 {
+                        let mut user = User::new("New User", "new@example.com");
+                        user.id = Some(store.allocate_id());
+                        store.insert(user).unwrap();
+                    }
+                });
+            }
+        });
+        let ids: Vec<i32> = store.snapshot().iter().filter_map(|user| user.id).collect();
+        assert_eq!(ids, (1..=203).collect::<Vec<_>>());
+        // Snapshots taken earlier don't see later changes
+        assert_eq!(before.len(), 3);
+    }
+
+    #[test]
+    fn users_survive_a_restart_with_a_data_file() {
+        let path = std::env::temp_dir().join(format!("mock-users-{}.json", std::process::id()));
+        let options = MockOptions {
+            seed: 3,
+            users: 4,
+            data: Some(path.clone()),
+            wal: false,
+        };
+        let store = MockStore::load_or_generate(&options).unwrap();
+        store.allocate_id();
+        delete(&store, 2);
+        assert_eq!(store.save().unwrap(), Some(4));
+
+        let loaded = MockStore::load_or_generate(&options).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.snapshot(), store.snapshot());
+        assert!(loaded.snapshot()[1].deleted_at.is_some());
+        // The ID given out before the restart isn't given out again
+        assert_eq!(loaded.allocate_id(), 6);
+    }
+
+    #[test]
+    fn logged_changes_survive_a_crash() {
+        let path = env::temp_dir().join(format!("mock-wal-{}.json", process::id()));
+        let options = MockOptions {
+            seed: 3,
+            users: 4,
+            data: Some(path.clone()),
+            wal: true,
+        };
+        let store = MockStore::load_or_generate(&options).unwrap();
+        let mut user = User::new("Logged", "logged@example.com");
+        user.id = Some(store.allocate_id());
+        store.insert(user).unwrap();
+        delete(&store, 2);
+        let changed = store.snapshot();
+        // A crash without saving, in the middle of logging another change
+        drop(store);
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(wal_path(&path))
+            .unwrap();
+        wal.write_all(br#"{"user": {"id": 3, "na"#).unwrap();
+
+        let replayed = MockStore::load_or_generate(&options).unwrap();
+        assert_eq!(replayed.snapshot(), changed);
+        assert_eq!(replayed.allocate_id(), 6);
+        assert_eq!(fs::read(wal_path(&path)).unwrap().last(), Some(&b'\n'));
+        // Saving folds the log into the data file
+        replayed.save().unwrap();
+        assert!(fs::read(wal_path(&path)).unwrap().is_empty());
+        assert_eq!(
+            MockStore::load_or_generate(&options).unwrap().snapshot(),
+            changed
+        );
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(wal_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn timestamps_use_the_postgres_format() {
+        assert_eq!(format_timestamp(0, 0), "1970-01-01T00:00:00.000000+00:00");
+        assert_eq!(
+            format_timestamp(1_709_210_096, 42),
+            "2024-02-29T12:34:56.000042+00:00"
+        );
+    }
+
+    #[test]
+    fn options_require_mock() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert!(parse_options(&[]).unwrap().is_none());
+        let options = parse_options(&args(&["--mock", "--seed", "9"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!((options.seed, options.users), (9, 50));
+        let options = parse_options(&args(&["--mock", "--data", "users.json"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.data, Some(PathBuf::from("users.json")));
+        assert!(parse_options(&args(&["--mock", "--data"])).is_err());
+        assert!(parse_options(&args(&["--mock", "--wal"])).is_err());
+        assert!(parse_options(&args(&["--mock", "--users", "many"])).is_err());
+    }
+}
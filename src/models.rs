@@ -0,0 +1,205 @@
+use postgres::types::ToSql;
+use postgres::{Error as PostgresError, Row};
+use std::collections::{HashMap, HashSet};
+
+use crate::resource::Resource;
+
+// Request and response bodies of the user API, shared by the server and the client
+
+// Model: User struct
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct User {
+    pub id: Option<i32>,
+    pub name: String,
+    pub email: String,
+    // Maintained by the database; ignored in request bodies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+    // Set when the user has been soft-deleted; only visible to admins
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<String>,
+    // Bumped on every change; updates carrying an older version are rejected with 409
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<i32>,
+    // Whether the user has confirmed their email address (see `email_verification`);
+    // ignored in request bodies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verified: Option<bool>,
+}
+
+impl User {
+    // A user to create; the database fills in the rest
+    pub fn new(name: &str, email: &str) -> User {
+        User {
+            id: None,
+            name: name.to_string(),
+            email: email.to_string(),
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+            version: None,
+            verified: None,
+        }
+    }
+
+    // Strong ETag derived from the user's JSON representation, which includes `updated_at`
+    pub fn etag(&self) -> String {
+        // 64-bit FNV-1a: stable across builds, unlike std's hasher
+        let hash = serde_json::to_string(self)
+            .unwrap_or_default()
+            .bytes()
+            .fold(0xcbf29ce484222325u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            });
+        format!("\"{:016x}\"", hash)
+    }
+}
+
+// Body of a PATCH request: only the fields present are changed
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct UserPatch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i32>,
+}
+
+// Body of PUT /users/by-email/{email}. The email is the path's; one in the body has to
+// be the same.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserUpsert {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+}
+
+// Body of POST /users/batch-get
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchGet {
+    pub ids: Vec<i32>,
+}
+
+// Response to POST /users/batch-get: the users found, in the order they were asked
+// for, and the IDs that weren't
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct BatchGetResult {
+    pub users: Vec<User>,
+    pub missing: Vec<i32>,
+}
+
+impl BatchGetResult {
+    // Puts the users found for `ids` in their order; IDs asked for twice count once
+    pub fn new(ids: &[i32], found: Vec<User>) -> BatchGetResult {
+        let mut found: HashMap<i32, User> = found
+            .into_iter()
+            .filter_map(|user| Some((user.id?, user)))
+            .collect();
+        let mut result = BatchGetResult { users: Vec::new(), missing: Vec::new() };
+        let mut seen = HashSet::new();
+        for &id in ids.iter().filter(|&&id| seen.insert(id)) {
+            match found.remove(&id) {
+                Some(user) => result.users.push(user),
+                None => result.missing.push(id),
+            }
+        }
+        result
+    }
+}
+
+// Response to GET /users/stats: how many users match, by email domain (most common
+// first), and how many were created on each of the last `STATS_DAYS` days (UTC, oldest
+// first, days without any included)
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct UserStats {
+    pub total: i64,
+    pub by_domain: Vec<DomainCount>,
+    pub created_per_day: Vec<DayCount>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct DomainCount {
+    pub domain: String,
+    pub count: i64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct DayCount {
+    // `2024-01-31`
+    pub date: String,
+    pub count: i64,
+}
+
+// Days GET /users/stats counts new users for, today included
+pub const STATS_DAYS: i32 = 30;
+
+impl UserStats {
+    // Puts the counts in order
+    pub fn new(total: i64, mut by_domain: Vec<DomainCount>, mut created_per_day: Vec<DayCount>) -> UserStats {
+        by_domain.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.domain.cmp(&b.domain)));
+        created_per_day.sort_by(|a, b| a.date.cmp(&b.date));
+        UserStats { total, by_domain, created_per_day }
+    }
+}
+
+// A user with related records embedded, for GET /users/{id}?include=posts
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct UserWithPosts {
+    #[serde(flatten)]
+    pub user: User,
+    pub posts: Vec<Post>,
+}
+
+// Model: a post written by a user, served at /users/{id}/posts and /posts/{id}
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Post {
+    #[serde(default)]
+    pub id: Option<i32>,
+    // Taken from the path when a post is created; a PUT may move it to another user.
+    // Null once its user was deleted under the orphan policy (see `delete_policy`).
+    #[serde(default)]
+    pub user_id: Option<i32>,
+    pub title: String,
+    pub body: String,
+    // Maintained by the database; ignored in request bodies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+}
+
+impl Resource for Post {
+    const TABLE: &'static str = "posts";
+    const NAME: &'static str = "Post";
+    // A user's posts go when the user row does, unless the delete policies say
+    // otherwise (see `delete_policy`)
+    const SCHEMA: &'static str = "user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        title VARCHAR NOT NULL,
+        body TEXT NOT NULL";
+    const COLUMNS: &'static [&'static str] = &["user_id", "title", "body"];
+
+    fn from_row(row: &Row) -> Result<Self, PostgresError> {
+        Ok(Post {
+            id: Some(row.try_get("id")?),
+            user_id: row.try_get("user_id")?,
+            title: row.try_get("title")?,
+            body: row.try_get("body")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+
+    fn values(&self) -> Vec<&(dyn ToSql + Sync)> {
+        vec![&self.user_id, &self.title, &self.body]
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match self.title.trim().is_empty() {
+            true => Err("Title can't be empty".to_string()),
+            false => Ok(()),
+        }
+    }
+}
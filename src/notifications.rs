@@ -0,0 +1,189 @@
+use postgres::{Client, Error as PostgresError, GenericClient};
+use std::env;
+use std::time::Duration;
+
+use crate::email::{Email, Mailer};
+use crate::query_log::Timed;
+
+// Notification preferences and the digest job. Users choose whether to get a digest
+// of the changes to their account daily, weekly or not at all (the default), at
+// GET/PUT /users/{id}/notifications. The changes come from the audit log, which
+// records every mutation as it commits, so nothing is lost across restarts and
+// replicas all see the same events.
+// The scheduler runs the job every DIGEST_CHECK_INTERVAL_SECS (300; 0 turns it off),
+// and admins can run it now with POST /admin/digests/run. Each run mails the users
+// whose period has passed since their last digest; users with no changes in it are
+// skipped, and their period starts over. Users being handled by one instance are
+// locked and skipped by the others, so replicas don't send the same digest twice.
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Digest {
+    Off,
+    Daily,
+    Weekly,
+}
+
+impl Digest {
+    fn as_str(self) -> &'static str {
+        match self {
+            Digest::Off => "off",
+            Digest::Daily => "daily",
+            Digest::Weekly => "weekly",
+        }
+    }
+
+    fn parse(value: &str) -> Digest {
+        match value {
+            "daily" => Digest::Daily,
+            "weekly" => Digest::Weekly,
+            _ => Digest::Off,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Preferences {
+    pub digest: Digest,
+}
+
+// How often the scheduler runs the digest job; None when it's turned off
+pub fn check_interval_from_env() -> Option<Duration> {
+    let secs: u64 = env::var("DIGEST_CHECK_INTERVAL_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(300);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+// The user's preferences; users who never set any get no digest
+pub fn get(client: &mut impl GenericClient, user_id: i32) -> Result<Preferences, PostgresError> {
+    let row = client.timed_query_opt(
+        "SELECT digest FROM notification_preferences WHERE user_id = $1",
+        &[&user_id],
+    )?;
+    let digest = match row {
+        Some(row) => Digest::parse(row.try_get(0)?),
+        None => Digest::Off,
+    };
+    Ok(Preferences { digest })
+}
+
+// Saves the user's preferences. Choosing a digest starts its first period now.
+pub fn set(
+    client: &mut impl GenericClient,
+    user_id: i32,
+    preferences: &Preferences,
+) -> Result<(), PostgresError> {
+    client.timed_execute(
+        "INSERT INTO notification_preferences (user_id, digest) VALUES ($1, $2)
+         ON CONFLICT (user_id) DO UPDATE SET digest = EXCLUDED.digest,
+             last_digest_at = CASE WHEN notification_preferences.digest = EXCLUDED.digest
+                 THEN notification_preferences.last_digest_at ELSE now() END",
+        &[&user_id, &preferences.digest.as_str()],
+    )?;
+    Ok(())
+}
+
+// Mails every digest that is due, returning how many were sent
+pub fn send_due_digests(client: &mut Client, mailer: &dyn Mailer) -> Result<usize, PostgresError> {
+    let mut transaction = client.transaction()?;
+    let due = transaction.timed_query(
+        "SELECT p.user_id, u.name, u.email, to_json(p.last_digest_at) #>> '{}'
+         FROM notification_preferences p JOIN users u ON u.id = p.user_id
+         WHERE u.deleted_at IS NULL
+           AND ((p.digest = 'daily' AND p.last_digest_at <= now() - interval '1 day')
+             OR (p.digest = 'weekly' AND p.last_digest_at <= now() - interval '7 days'))
+         ORDER BY p.user_id
+         FOR UPDATE OF p SKIP LOCKED",
+        &[],
+    )?;
+
+    let mut sent = 0;
+    for row in &due {
+        let user_id: i32 = row.try_get(0)?;
+        let name: String = row.try_get(1)?;
+        let email: String = row.try_get(2)?;
+        let since: String = row.try_get(3)?;
+        // `now()` is when the transaction started, so changes committed during the run
+        // fall into the next digest
+        let events = transaction.timed_query(
+            "SELECT action, to_json(created_at) #>> '{}' FROM audit_log
+             WHERE entity_id = $1 AND created_at > $2::text::timestamptz AND created_at <= now()
+             ORDER BY id",
+            &[&user_id, &since],
+        )?;
+        let events: Vec<(String, String)> = events
+            .iter()
+            .map(|event| Ok((event.try_get(0)?, event.try_get(1)?)))
+            .collect::<Result<_, PostgresError>>()?;
+
+        if !events.is_empty() {
+            if let Err(e) = mailer.send(&digest_email(&name, &email, &since, &events)) {
+                // Its period isn't restarted, so the next run tries again
+                log!("Digest for user {} not sent: {}", user_id, e);
+                continue;
+            }
+            sent += 1;
+        }
+        transaction.timed_execute(
+            "UPDATE notification_preferences SET last_digest_at = now() WHERE user_id = $1",
+            &[&user_id],
+        )?;
+    }
+    transaction.commit()?;
+    Ok(sent)
+}
+
+// The digest of changes (action, time) to a user's account since `since`
+fn digest_email(name: &str, email: &str, since: &str, events: &[(String, String)]) -> Email {
+    let mut body = format!(
+        "Hi {},\n\nYour account changed {} time(s) since {}:\n",
+        name,
+        events.len(),
+        since
+    );
+    for (action, at) in events {
+        body.push_str(&format!("- {} at {}\n", action, at));
+    }
+    Email {
+        to: email.to_string(),
+        subject: "Your account activity".to_string(),
+        body,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests_list_the_changes() {
+        let events = [
+            (
+                "update".to_string(),
+                "2024-05-01T10:00:00+00:00".to_string(),
+            ),
+            (
+                "delete".to_string(),
+                "2024-05-02T10:00:00+00:00".to_string(),
+            ),
+        ];
+        let email = digest_email("Ada", "ada@example.com", "2024-04-30", &events);
+        assert_eq!(email.to, "ada@example.com");
+        assert!(email
+            .body
+            .starts_with("Hi Ada,\n\nYour account changed 2 time(s) since 2024-04-30:\n"));
+        assert!(email
+            .body
+            .ends_with("- delete at 2024-05-02T10:00:00+00:00\n"));
+    }
+
+    #[test]
+    fn preferences_only_accept_known_digests() {
+        let preferences: Preferences = serde_json::from_str(r#"{"digest": "weekly"}"#).unwrap();
+        assert_eq!(preferences.digest, Digest::Weekly);
+        assert!(serde_json::from_str::<Preferences>(r#"{"digest": "hourly"}"#).is_err());
+    }
+}
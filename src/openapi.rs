@@ -0,0 +1,544 @@
+use serde_json::{json, Value};
+
+// OpenAPI 3.0 description of the public user API (the TCP routes under /users, and
+// /posts for users' posts).
+// Admin-socket and SCIM routes are left out: they have their own clients.
+// `generate-client` builds typed clients from this, so route or schema changes
+// belong here as well as in the handlers.
+
+pub fn spec() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "rust-docker-pg-crud",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/users": {
+                "get": {
+                    "operationId": "listUsers",
+                    "summary": "List users",
+                    "parameters": [
+                        query("include_deleted", "boolean", "Include soft-deleted users (admin only)"),
+                        query("created_after", "string", "Only users created after this ISO 8601 timestamp"),
+                        query("created_before", "string", "Only users created before this ISO 8601 timestamp"),
+                        query("verified", "boolean", "Only users who have (true) or haven't (false) verified their email address"),
+                        query("fields", "string", "Comma-separated fields to send, e.g. id,email; the rest are left out"),
+                    ],
+                    "responses": {
+                        "200": json_response("The users", array_of("User")),
+                        "default": error_response(),
+                    },
+                },
+                "post": {
+                    "operationId": "createUser",
+                    "summary": "Create a user",
+                    "parameters": [
+                        header("Idempotency-Key", false, "Retries with the same key get the first response back instead of creating another user"),
+                    ],
+                    "requestBody": json_body("User"),
+                    "responses": {
+                        "200": text_response("User Created"),
+                        "default": error_response(),
+                    },
+                },
+                "patch": {
+                    "operationId": "bulkUpdateUsers",
+                    "summary": "Update every user matching a filter (admin only)",
+                    "parameters": [
+                        required(query("filter", "string", "e.g. email_domain eq \"old-corp.com\"")),
+                        query("confirm", "boolean", "Apply the change; without it only the match count is reported"),
+                    ],
+                    "requestBody": json_body("BulkPatch"),
+                    "responses": {
+                        "200": json_response("Updated users", reference("BulkUpdateResult")),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/users/verify": {
+                "get": {
+                    "operationId": "verifyEmail",
+                    "summary": "Verify a new user's email address with the token mailed to it",
+                    "parameters": [required(query("token", "string", "The token from the verification email"))],
+                    "responses": {
+                        "200": json_response("The verified user", reference("User")),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/users/{id}": {
+                "parameters": [id_parameter()],
+                "get": {
+                    "operationId": "getUser",
+                    "summary": "Get a user",
+                    "parameters": [
+                        query("include", "string", "posts: embed the user's posts (the response then has no ETag)"),
+                        query("as_of", "string", "ISO 8601 timestamp; the user as it was then, from the audit log (no ETag)"),
+                        header("If-None-Match", false, "ETag from an earlier response; 304 if unchanged"),
+                    ],
+                    "responses": {
+                        "200": json_response("The user", reference("User")),
+                        "default": error_response(),
+                    },
+                },
+                "put": {
+                    "operationId": "replaceUser",
+                    "summary": "Replace a user's name and email",
+                    "parameters": [header("If-Match", true, "The user's current ETag")],
+                    "requestBody": json_body("User"),
+                    "responses": {
+                        "200": json_response("The updated user", reference("User")),
+                        "default": error_response(),
+                    },
+                },
+                "patch": {
+                    "operationId": "patchUser",
+                    "summary": "Change some of a user's fields",
+                    "parameters": [header("If-Match", true, "The user's current ETag")],
+                    "requestBody": json_body("UserPatch"),
+                    "responses": {
+                        "200": json_response("The updated user", reference("User")),
+                        "default": error_response(),
+                    },
+                },
+                "delete": {
+                    "operationId": "deleteUser",
+                    "summary": "Soft-delete a user; a 409 lists related records the delete policies keep it from leaving behind",
+                    "responses": {
+                        "200": text_response("User Deleted"),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/users/{id}/restore": {
+                "parameters": [id_parameter()],
+                "post": {
+                    "operationId": "restoreUser",
+                    "summary": "Restore a soft-deleted user (admin only)",
+                    "responses": {
+                        "200": text_response("User Restored"),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/users/search": {
+                "get": {
+                    "operationId": "searchUsers",
+                    "summary": "Find users by words in their name or email, best matches first",
+                    "parameters": [
+                        required(query("q", "string", "Words to search for; each matches the start of a word")),
+                        query("limit", "integer", "Most users to return, 1 to 100 (default 20)"),
+                    ],
+                    "responses": {
+                        "200": json_response("The matching users", array_of("User")),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/users/checksum": {
+                "get": {
+                    "operationId": "getUsersChecksum",
+                    "summary": "Count and SHA-256 of the users the list would return, to check a synced copy",
+                    "parameters": [
+                        query("include_deleted", "boolean", "Include soft-deleted users (admin only)"),
+                        query("created_after", "string", "Only users created after this ISO 8601 timestamp"),
+                        query("created_before", "string", "Only users created before this ISO 8601 timestamp"),
+                        query("verified", "boolean", "Only users who have (true) or haven't (false) verified their email address"),
+                    ],
+                    "responses": {
+                        "200": json_response("Count and checksum", reference("Checksum")),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/users/stats": {
+                "get": {
+                    "operationId": "getUserStats",
+                    "summary": "User counts: total, by email domain, and created per day over the last 30 days",
+                    "parameters": [
+                        query("include_deleted", "boolean", "Include soft-deleted users (admin only)"),
+                        query("created_after", "string", "Only users created after this ISO 8601 timestamp"),
+                        query("created_before", "string", "Only users created before this ISO 8601 timestamp"),
+                        query("verified", "boolean", "Only users who have (true) or haven't (false) verified their email address"),
+                    ],
+                    "responses": {
+                        "200": json_response("User counts", reference("UserStats")),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/users/batch-get": {
+                "post": {
+                    "operationId": "batchGetUsers",
+                    "summary": "Fetch several users by ID at once, with the IDs that weren't found",
+                    "parameters": [query("include_deleted", "boolean", "Include soft-deleted users (admin only)")],
+                    "requestBody": json_body("BatchGet"),
+                    "responses": {
+                        "200": json_response("The users found, in the order asked for", reference("BatchGetResult")),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/users/by-email/{email}": {
+                "parameters": [{ "name": "email", "in": "path", "required": true, "schema": { "type": "string" } }],
+                "put": {
+                    "operationId": "upsertUserByEmail",
+                    "summary": "Create the live user with this email, or rename it if it exists",
+                    "requestBody": json_body("UserUpsert"),
+                    "responses": {
+                        "200": json_response("The user was there already", reference("User")),
+                        "201": json_response("The user was created", reference("User")),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/users/{id}/audit": {
+                "parameters": [id_parameter()],
+                "get": {
+                    "operationId": "getUserAudit",
+                    "summary": "Audit trail of a user, oldest first",
+                    "responses": {
+                        "200": json_response("Audit entries", array_of("AuditEntry")),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/users/{id}/email-change": {
+                "parameters": [id_parameter()],
+                "get": {
+                    "operationId": "getEmailChange",
+                    "summary": "The new email address waiting for confirmation",
+                    "responses": {
+                        "200": json_response("Pending email change", reference("PendingEmailChange")),
+                        "default": error_response(),
+                    },
+                },
+                "delete": {
+                    "operationId": "cancelEmailChange",
+                    "summary": "Cancel the pending email change",
+                    "responses": {
+                        "200": text_response("Email Change Cancelled"),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/users/{id}/email-change/confirm": {
+                "parameters": [id_parameter()],
+                "post": {
+                    "operationId": "confirmEmailChange",
+                    "summary": "Apply the pending email change with the token sent to the new address",
+                    "requestBody": json_body("EmailConfirmation"),
+                    "responses": {
+                        "200": json_response("The updated user", reference("User")),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/users/{id}/notifications": {
+                "parameters": [id_parameter()],
+                "get": {
+                    "operationId": "getNotificationPreferences",
+                    "summary": "A user's notification preferences",
+                    "responses": {
+                        "200": json_response("The preferences", reference("NotificationPreferences")),
+                        "default": error_response(),
+                    },
+                },
+                "put": {
+                    "operationId": "setNotificationPreferences",
+                    "summary": "Replace a user's notification preferences",
+                    "requestBody": json_body("NotificationPreferences"),
+                    "responses": {
+                        "200": json_response("The saved preferences", reference("NotificationPreferences")),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/users/{id}/profile": {
+                "parameters": [id_parameter()],
+                "get": {
+                    "operationId": "getProfile",
+                    "summary": "A user's profile; 404 until one is set",
+                    "responses": {
+                        "200": json_response("The profile", reference("Profile")),
+                        "default": error_response(),
+                    },
+                },
+                "put": {
+                    "operationId": "setProfile",
+                    "summary": "Create or replace a user's profile",
+                    "requestBody": json_body("Profile"),
+                    "responses": {
+                        "200": json_response("The saved profile", reference("Profile")),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/users/{id}/posts": {
+                "parameters": [id_parameter()],
+                "get": {
+                    "operationId": "listUserPosts",
+                    "summary": "List a user's posts",
+                    "responses": {
+                        "200": json_response("The posts", array_of("Post")),
+                        "default": error_response(),
+                    },
+                },
+                "post": {
+                    "operationId": "createUserPost",
+                    "summary": "Create a post for a user",
+                    "requestBody": json_body("Post"),
+                    "responses": {
+                        "200": json_response("The new post", reference("Post")),
+                        "default": error_response(),
+                    },
+                },
+            },
+            "/posts/{id}": {
+                "parameters": [id_parameter()],
+                "get": {
+                    "operationId": "getPost",
+                    "summary": "Get a post",
+                    "responses": {
+                        "200": json_response("The post", reference("Post")),
+                        "default": error_response(),
+                    },
+                },
+                "put": {
+                    "operationId": "replacePost",
+                    "summary": "Replace a post's title and body, or move it to another user",
+                    "requestBody": json_body("Post"),
+                    "responses": {
+                        "200": json_response("The updated post", reference("Post")),
+                        "default": error_response(),
+                    },
+                },
+                "delete": {
+                    "operationId": "deletePost",
+                    "summary": "Delete a post",
+                    "responses": {
+                        "204": { "description": "Post deleted" },
+                        "default": error_response(),
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": {
+                "User": {
+                    "type": "object",
+                    "required": ["name", "email"],
+                    "properties": {
+                        "id": { "type": "integer" },
+                        "name": { "type": "string" },
+                        "email": { "type": "string" },
+                        "created_at": { "type": "string", "format": "date-time" },
+                        "updated_at": { "type": "string", "format": "date-time" },
+                        "deleted_at": { "type": "string", "format": "date-time" },
+                        "version": { "type": "integer" },
+                        "verified": { "type": "boolean" },
+                    },
+                },
+                "Post": {
+                    "type": "object",
+                    "required": ["title", "body"],
+                    "properties": {
+                        "id": { "type": "integer" },
+                        "user_id": { "type": "integer" },
+                        "title": { "type": "string" },
+                        "body": { "type": "string" },
+                        "created_at": { "type": "string", "format": "date-time" },
+                        "updated_at": { "type": "string", "format": "date-time" },
+                    },
+                },
+                "UserPatch": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "email": { "type": "string" },
+                        "version": { "type": "integer" },
+                    },
+                },
+                "BulkPatch": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "email_domain": { "type": "string" },
+                    },
+                },
+                "BulkUpdateResult": {
+                    "type": "object",
+                    "required": ["updated", "updated_ids", "pending_email_ids"],
+                    "properties": {
+                        "updated": { "type": "integer" },
+                        "updated_ids": { "type": "array", "items": { "type": "integer" } },
+                        "pending_email_ids": { "type": "array", "items": { "type": "integer" } },
+                    },
+                },
+                "PendingEmailChange": {
+                    "type": "object",
+                    "required": ["new_email", "requested_at", "expires_at"],
+                    "properties": {
+                        "new_email": { "type": "string" },
+                        "requested_at": { "type": "string", "format": "date-time" },
+                        "expires_at": { "type": "string", "format": "date-time" },
+                    },
+                },
+                "EmailConfirmation": {
+                    "type": "object",
+                    "required": ["token"],
+                    "properties": {
+                        "token": { "type": "string" },
+                    },
+                },
+                "NotificationPreferences": {
+                    "type": "object",
+                    "required": ["digest"],
+                    "properties": {
+                        "digest": { "type": "string", "enum": ["off", "daily", "weekly"] },
+                    },
+                },
+                "Checksum": {
+                    "type": "object",
+                    "required": ["count", "sha256"],
+                    "properties": {
+                        "count": { "type": "integer" },
+                        "sha256": {
+                            "type": "string",
+                            "description": "Hex SHA-256 of a line `<id>\\t<name>\\t<email>\\n` per user, in ID order",
+                        },
+                    },
+                },
+                "UserStats": {
+                    "type": "object",
+                    "required": ["total", "by_domain", "created_per_day"],
+                    "properties": {
+                        "total": { "type": "integer" },
+                        "by_domain": {
+                            "type": "array",
+                            "description": "Most common first",
+                            "items": {
+                                "type": "object",
+                                "required": ["domain", "count"],
+                                "properties": { "domain": { "type": "string" }, "count": { "type": "integer" } },
+                            },
+                        },
+                        "created_per_day": {
+                            "type": "array",
+                            "description": "The last 30 days (UTC), oldest first",
+                            "items": {
+                                "type": "object",
+                                "required": ["date", "count"],
+                                "properties": { "date": { "type": "string", "format": "date" }, "count": { "type": "integer" } },
+                            },
+                        },
+                    },
+                },
+                "UserUpsert": {
+                    "type": "object",
+                    "required": ["name"],
+                    "properties": {
+                        "name": { "type": "string" },
+                        "email": { "type": "string", "description": "Must match the path if given" },
+                    },
+                },
+                "BatchGet": {
+                    "type": "object",
+                    "required": ["ids"],
+                    "properties": {
+                        "ids": { "type": "array", "maxItems": 1000, "items": { "type": "integer" } },
+                    },
+                },
+                "BatchGetResult": {
+                    "type": "object",
+                    "required": ["users", "missing"],
+                    "properties": {
+                        "users": array_of("User"),
+                        "missing": { "type": "array", "items": { "type": "integer" } },
+                    },
+                },
+                "Profile": {
+                    "type": "object",
+                    "properties": {
+                        "bio": { "type": "string", "nullable": true, "maxLength": 1000 },
+                        "avatar_url": { "type": "string", "nullable": true, "format": "uri" },
+                        "location": { "type": "string", "nullable": true, "maxLength": 100 },
+                    },
+                },
+                "Error": {
+                    "type": "object",
+                    "required": ["code", "message"],
+                    "properties": {
+                        "code": { "type": "string", "description": "Stable error code; GET /errors lists them" },
+                        "message": { "type": "string" },
+                        "request_id": { "type": "string" },
+                    },
+                },
+                "AuditEntry": {
+                    "type": "object",
+                    "required": ["id", "actor", "action", "entity_id", "old", "new", "created_at"],
+                    "properties": {
+                        "id": { "type": "integer" },
+                        "actor": { "type": "string" },
+                        "action": { "type": "string" },
+                        "entity_id": { "type": "integer" },
+                        "old": { "nullable": true, "allOf": [reference("User")] },
+                        "new": { "nullable": true, "allOf": [reference("User")] },
+                        "details": { "type": "object" },
+                        "created_at": { "type": "string", "format": "date-time" },
+                    },
+                },
+            },
+        },
+    })
+}
+
+fn reference(schema: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", schema) })
+}
+
+fn array_of(schema: &str) -> Value {
+    json!({ "type": "array", "items": reference(schema) })
+}
+
+fn id_parameter() -> Value {
+    json!({ "name": "id", "in": "path", "required": true, "schema": { "type": "integer" } })
+}
+
+fn query(name: &str, kind: &str, description: &str) -> Value {
+    json!({ "name": name, "in": "query", "description": description, "schema": { "type": kind } })
+}
+
+fn header(name: &str, required: bool, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "header",
+        "required": required,
+        "description": description,
+        "schema": { "type": "string" },
+    })
+}
+
+fn required(mut parameter: Value) -> Value {
+    parameter["required"] = json!(true);
+    parameter
+}
+
+fn json_body(schema: &str) -> Value {
+    json!({ "required": true, "content": { "application/json": { "schema": reference(schema) } } })
+}
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({ "description": description, "content": { "application/json": { "schema": schema } } })
+}
+
+// Any error, with its machine-readable code (see `errors`)
+fn error_response() -> Value {
+    json_response("Error", reference("Error"))
+}
+
+// Plain-text confirmation such as `User Created`
+fn text_response(description: &str) -> Value {
+    json!({ "description": description, "content": { "text/plain": { "schema": { "type": "string" } } } })
+}
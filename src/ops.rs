@@ -0,0 +1,142 @@
+use postgres::{Client, NoTls};
+use serde_json::Value;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::audit;
+use crate::config::get_db_url;
+use crate::db::set_database;
+use crate::http_client;
+use crate::migrations::{self, Migration, Status};
+use crate::models::User;
+use crate::output::{self, OutputFormat};
+use crate::repl::print_users;
+use crate::repo::{self, UserFilter};
+use crate::retry::{self, RetryPolicy};
+use crate::support_bundle::redact_url;
+
+// Operational subcommands for use inside the container (`docker exec <container> app
+// list-users`) without curl or psql. All but `healthcheck` work directly on the
+// database at DATABASE_URL.
+
+// Actor recorded in the audit log for changes made from the command line
+const ACTOR: &str = "cli";
+
+// Entry point for `app migrate`: creates or updates the schema, as the server does on
+// startup, waiting up to WAIT_FOR_DB_SECS for the database like it. With `--dry-run`
+// it applies nothing and prints the SQL of the pending migrations instead.
+pub fn migrate(dry_run: bool, format: OutputFormat) -> io::Result<()> {
+    if dry_run {
+        return print_migration_plan(format);
+    }
+    RetryPolicy::startup_from_env()
+        .run(set_database, retry::is_transient)
+        .map_err(io::Error::other)?;
+    output::print_record(
+        format,
+        &[
+            ("database", redact_url(&get_db_url()).into()),
+            ("schema", "up to date".into()),
+        ],
+    );
+    Ok(())
+}
+
+// The pending migrations in the order they'd run: a SQL script, or a JSON array
+fn print_migration_plan(format: OutputFormat) -> io::Result<()> {
+    let entries = migrations::status(&mut connect()?).map_err(io::Error::other)?;
+    for entry in entries.iter().filter(|entry| entry.status == Status::Modified) {
+        eprintln!("Migration {} ({}) was edited after it was applied; it won't run again", entry.version, entry.name);
+    }
+    let pending: Vec<&Migration> = entries
+        .iter()
+        .filter(|entry| entry.status == Status::Pending)
+        .filter_map(|entry| migrations::find(entry.version))
+        .collect();
+    if format == OutputFormat::Json {
+        let rows: Vec<Vec<Value>> = pending
+            .iter()
+            .map(|migration| vec![migration.version.into(), migration.name.into(), migration.checksum().into(), migration.sql().into()])
+            .collect();
+        output::print_table(format, &["version", "name", "checksum", "sql"], &rows);
+        return Ok(());
+    }
+    if pending.is_empty() {
+        println!("-- No pending migrations");
+    }
+    for migration in pending {
+        println!("-- {} {} (sha256 {})", migration.version, migration.name, migration.checksum());
+        println!("{}\n", migration.sql());
+    }
+    println!("-- Then, as on every start, the audit log partitions and the delete policies' foreign keys are brought in line with the configuration");
+    Ok(())
+}
+
+// Entry point for `app create-user --name NAME --email EMAIL`. The operator vouches for
+// the address, so the user starts out verified and no email is sent.
+pub fn create_user(name: &str, email: &str, format: OutputFormat) -> io::Result<()> {
+    let user = User {
+        id: None,
+        name: name.to_string(),
+        email: email.to_string(),
+        created_at: None,
+        updated_at: None,
+        deleted_at: None,
+        version: None,
+        verified: None,
+    };
+    let mut client = connect()?;
+    let created = repo::with_transaction(&mut client, |transaction| {
+        let id = repo::create_user(transaction, &user)?;
+        let created = repo::set_verified(transaction, id)?;
+        audit::record(transaction, ACTOR, "create", id, None, created.as_ref())?;
+        Ok::<_, postgres::Error>(created)
+    })
+    .map_err(io::Error::other)?;
+    print_users(created.as_slice(), format);
+    Ok(())
+}
+
+// Entry point for `app list-users`
+pub fn list_users(
+    limit: i64,
+    offset: i64,
+    include_deleted: bool,
+    format: OutputFormat,
+) -> io::Result<()> {
+    let filter = UserFilter {
+        include_deleted,
+        limit: Some(limit),
+        offset,
+        ..Default::default()
+    };
+    let users = repo::list_users(&mut connect()?, &filter).map_err(io::Error::other)?;
+    print_users(&users, format);
+    Ok(())
+}
+
+// Entry point for `app healthcheck [--url URL] [--timeout SECS]`, the Docker
+// HEALTHCHECK: fails unless the server at `url` answers GET /readyz with a 200 within
+// `timeout`. A process that's up but wedged, or has lost its database, fails too.
+pub fn healthcheck(url: &str, timeout: Duration, format: OutputFormat) -> io::Result<()> {
+    let started = Instant::now();
+    let response = http_client::send_with_timeout(url, "GET", "/readyz", &[], None, Some(timeout))?;
+    if response.status != 200 {
+        return Err(io::Error::other(format!(
+            "not ready ({}): {}",
+            response.status, response.body
+        )));
+    }
+    output::print_record(
+        format,
+        &[
+            ("status", "ready".into()),
+            ("ms", (started.elapsed().as_millis() as u64).into()),
+        ],
+    );
+    Ok(())
+}
+
+fn connect() -> io::Result<Client> {
+    Client::connect(&get_db_url(), NoTls).map_err(io::Error::other)
+}
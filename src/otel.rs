@@ -0,0 +1,513 @@
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::env;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::http_client;
+use crate::request_id;
+
+// OpenTelemetry traces, exported over OTLP/HTTP with JSON bodies to a collector. Each
+// request is a server span named after its route (`GET /users/{id}`), with a client span
+// for every database statement it runs; each run of a background job (see `scheduler`)
+// is an internal span with its statements under it.
+// A request carrying a W3C `traceparent` header, as set by proxies and other services,
+// joins the caller's trace; otherwise it starts a new one.
+// Configured with the standard variables: tracing is on once OTEL_EXPORTER_OTLP_ENDPOINT
+// (spans go to its `/v1/traces`) or OTEL_EXPORTER_OTLP_TRACES_ENDPOINT (the full URL) is
+// set, unless OTEL_TRACES_EXPORTER=none or OTEL_SDK_DISABLED=true. Only `http://`
+// endpoints and the `http/json` protocol are supported, so run a collector nearby.
+// OTEL_EXPORTER_OTLP_HEADERS (`key=value,...`) are sent with every export, which gives up
+// after OTEL_EXPORTER_OTLP_TIMEOUT ms (10000). OTEL_SERVICE_NAME (`rust-docker-pg-crud`)
+// and OTEL_RESOURCE_ATTRIBUTES (`key=value,...`) describe the service.
+// OTEL_TRACES_SAMPLER picks which traces are kept: `parentbased_always_on` (the default)
+// follows the caller's decision and keeps every new trace; `traceidratio` keeps the
+// OTEL_TRACES_SAMPLER_ARG fraction of them.
+// Spans are queued (at most OTEL_BSP_MAX_QUEUE_SIZE, 2048) and sent in batches of up to
+// OTEL_BSP_MAX_EXPORT_BATCH_SIZE (512) at least every OTEL_BSP_SCHEDULE_DELAY ms (5000)
+// by a background thread. Tracing never blocks or fails a request: spans that don't fit
+// in the queue, or that the collector doesn't take, are lost.
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+thread_local! {
+    // The request or job span this thread is in
+    static CURRENT: RefCell<Option<ActiveSpan>> = const { RefCell::new(None) };
+}
+
+struct Exporter {
+    spans: SyncSender<Span>,
+    sampler: Sampler,
+}
+
+// Which traces are recorded
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Sampler {
+    // Fraction of new traces kept
+    ratio: f64,
+    // Whether a caller's sampling decision is followed
+    parent_based: bool,
+}
+
+impl Sampler {
+    fn from_env() -> Sampler {
+        let ratio = env::var("OTEL_TRACES_SAMPLER_ARG")
+            .ok()
+            .and_then(|arg| arg.parse::<f64>().ok())
+            .map_or(1.0, |ratio| ratio.clamp(0.0, 1.0));
+        let (ratio, parent_based) = match env::var("OTEL_TRACES_SAMPLER").as_deref() {
+            Ok("always_on") => (1.0, false),
+            Ok("always_off") => (0.0, false),
+            Ok("traceidratio") => (ratio, false),
+            Ok("parentbased_always_off") => (0.0, true),
+            Ok("parentbased_traceidratio") => (ratio, true),
+            _ => (1.0, true),
+        };
+        Sampler { ratio, parent_based }
+    }
+
+    fn sampled(&self, trace_id: &str, parent: Option<&SpanContext>) -> bool {
+        match parent {
+            Some(parent) if self.parent_based => parent.sampled,
+            // The last 16 hex digits of the ID are random, so comparing them keeps the
+            // same traces on every service with the same ratio
+            _ => {
+                let random = u64::from_str_radix(&trace_id[16..], 16).unwrap_or_default();
+                self.ratio >= 1.0 || (random as f64) < self.ratio * u64::MAX as f64
+            }
+        }
+    }
+}
+
+// The trace and span IDs, in hex, that `traceparent` carries
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: String,
+    pub span_id: String,
+    pub sampled: bool,
+}
+
+// A W3C `traceparent` header, e.g.
+//   00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01
+// None if it's malformed or has all-zero IDs
+pub fn parse_traceparent(header: &str) -> Option<SpanContext> {
+    let parts: Vec<&str> = header.trim().split('-').collect();
+    let hex = |part: &str, len: usize| {
+        part.len() == len && part.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    let (version, trace_id, span_id, flags) = match parts[..] {
+        [version, trace_id, span_id, flags, ..] => (version, trace_id, span_id, flags),
+        _ => return None,
+    };
+    // Later versions may add fields after these; version 00 has none
+    if !hex(version, 2) || version == "ff" || (version == "00" && parts.len() != 4) {
+        return None;
+    }
+    if !hex(trace_id, 32) || !hex(span_id, 16) || !hex(flags, 2) {
+        return None;
+    }
+    if trace_id.bytes().all(|byte| byte == b'0') || span_id.bytes().all(|byte| byte == b'0') {
+        return None;
+    }
+    Some(SpanContext {
+        trace_id: trace_id.to_string(),
+        span_id: span_id.to_string(),
+        sampled: u8::from_str_radix(flags, 16).is_ok_and(|flags| flags & 1 == 1),
+    })
+}
+
+// OTLP span kinds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+// A span that hasn't ended yet
+struct ActiveSpan {
+    context: SpanContext,
+    parent_span_id: Option<String>,
+    kind: SpanKind,
+    started: SystemTime,
+    clock: Instant,
+}
+
+// A finished span, as exported
+#[derive(Debug, PartialEq)]
+struct Span {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    name: String,
+    kind: SpanKind,
+    start_unix_nanos: u128,
+    end_unix_nanos: u128,
+    attributes: Vec<(&'static str, Value)>,
+    error: bool,
+}
+
+// Starts exporting spans if it's configured (see above)
+pub fn init() {
+    let disabled = env::var("OTEL_SDK_DISABLED").is_ok_and(|value| value == "true")
+        || env::var("OTEL_TRACES_EXPORTER").is_ok_and(|value| value == "none");
+    let Some(url) = traces_url().filter(|_| !disabled) else {
+        return;
+    };
+    if !url.starts_with("http://") {
+        log!("Trace export disabled: only http:// endpoints are supported, not {}", url);
+        return;
+    }
+    let setting = |name: &str, default: u64| {
+        env::var(name)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    };
+    let (spans, queue) = mpsc::sync_channel(setting("OTEL_BSP_MAX_QUEUE_SIZE", 2048) as usize);
+    let batch = Batching {
+        max_size: setting("OTEL_BSP_MAX_EXPORT_BATCH_SIZE", 512).max(1) as usize,
+        delay: Duration::from_millis(setting("OTEL_BSP_SCHEDULE_DELAY", 5000)),
+        timeout: Duration::from_millis(setting("OTEL_EXPORTER_OTLP_TIMEOUT", 10000)),
+    };
+    let exporter = Exporter {
+        spans,
+        sampler: Sampler::from_env(),
+    };
+    if EXPORTER.set(exporter).is_err() {
+        return;
+    }
+    log!("Exporting traces to {}", url);
+    let resource = resource(&env::var("OTEL_RESOURCE_ATTRIBUTES").unwrap_or_default());
+    let headers = key_values(&env::var("OTEL_EXPORTER_OTLP_HEADERS").unwrap_or_default());
+    thread::spawn(move || export_batches(queue, &url, &headers, &resource, batch));
+}
+
+// Where spans are sent, from the OTLP endpoint settings
+fn traces_url() -> Option<String> {
+    let set = |name| env::var(name).ok().filter(|value| !value.is_empty());
+    set("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").or_else(|| {
+        set("OTEL_EXPORTER_OTLP_ENDPOINT").map(|base| format!("{}/v1/traces", base.trim_end_matches('/')))
+    })
+}
+
+// Whether spans are being exported
+pub fn is_enabled() -> bool {
+    EXPORTER.get().is_some()
+}
+
+// The trace of the span this thread is in, if it's being exported: for exemplars
+// linking metrics to it (see `metrics`)
+pub fn current_trace_id() -> Option<String> {
+    CURRENT.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .filter(|span| span.context.sampled)
+            .map(|span| span.context.trace_id.clone())
+    })
+}
+
+// Starts the span of a request, in the trace of its `traceparent` if it has one
+pub fn start_request(traceparent: Option<&str>) {
+    start(SpanKind::Server, traceparent.and_then(parse_traceparent));
+}
+
+// Ends the current request's span. It's named after the route the request matched.
+pub fn end_request(method: &str, route: Option<&str>, path: &str, status: u16) {
+    let name = match route {
+        Some(route) => format!("{} {}", method, route),
+        None => method.to_string(),
+    };
+    let mut attributes = vec![
+        ("http.request.method", json!(method)),
+        ("url.path", json!(path)),
+        ("http.response.status_code", json!(status)),
+    ];
+    if let Some(route) = route {
+        attributes.push(("http.route", json!(route)));
+    }
+    if let Some(request_id) = request_id::current() {
+        attributes.push(("http.request.header.x-request-id", json!([request_id])));
+    }
+    end(name, attributes, status >= 500);
+}
+
+// Runs background work in a span of its own, in a new trace
+pub fn in_span<T, E>(name: &str, work: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    start(SpanKind::Internal, None);
+    let result = work();
+    end(name.to_string(), Vec::new(), result.is_err());
+    result
+}
+
+fn start(kind: SpanKind, parent: Option<SpanContext>) {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    let trace_id = match &parent {
+        Some(parent) => parent.trace_id.clone(),
+        None => format!("{}{}", request_id::generate(), request_id::generate()),
+    };
+    let sampled = exporter.sampler.sampled(&trace_id, parent.as_ref());
+    let span = ActiveSpan {
+        context: SpanContext {
+            trace_id,
+            span_id: request_id::generate(),
+            sampled,
+        },
+        parent_span_id: parent.map(|parent| parent.span_id),
+        kind,
+        started: SystemTime::now(),
+        clock: Instant::now(),
+    };
+    CURRENT.with(|current| *current.borrow_mut() = Some(span));
+}
+
+fn end(name: String, attributes: Vec<(&'static str, Value)>, error: bool) {
+    let Some(span) = CURRENT.with(|current| current.take()) else {
+        return;
+    };
+    if !span.context.sampled {
+        return;
+    }
+    let start_unix_nanos = unix_nanos(span.started);
+    send(Span {
+        trace_id: span.context.trace_id,
+        span_id: span.context.span_id,
+        parent_span_id: span.parent_span_id,
+        name,
+        kind: span.kind,
+        start_unix_nanos,
+        end_unix_nanos: start_unix_nanos + span.clock.elapsed().as_nanos(),
+        attributes,
+        error,
+    });
+}
+
+// Records work that started at `started` and just finished, such as a database
+// statement, as a child of the current span
+pub fn record(name: String, kind: SpanKind, started: Instant, attributes: Vec<(&'static str, Value)>) {
+    let Some((trace_id, parent_span_id)) = CURRENT.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .filter(|span| span.context.sampled)
+            .map(|span| (span.context.trace_id.clone(), span.context.span_id.clone()))
+    }) else {
+        return;
+    };
+    let duration = started.elapsed();
+    let end_unix_nanos = unix_nanos(SystemTime::now());
+    send(Span {
+        trace_id,
+        span_id: request_id::generate(),
+        parent_span_id: Some(parent_span_id),
+        name,
+        kind,
+        start_unix_nanos: end_unix_nanos.saturating_sub(duration.as_nanos()),
+        end_unix_nanos,
+        attributes,
+        error: false,
+    });
+}
+
+fn send(span: Span) {
+    if let Some(exporter) = EXPORTER.get() {
+        // A full queue costs this span
+        let _ = exporter.spans.try_send(span);
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+struct Batching {
+    max_size: usize,
+    delay: Duration,
+    timeout: Duration,
+}
+
+// Sends queued spans in batches, until the queue is dropped
+fn export_batches(queue: Receiver<Span>, url: &str, headers: &[(String, String)], resource: &Value, batching: Batching) {
+    let (base_url, path) = match url.strip_prefix("http://").and_then(|rest| rest.find('/').map(|at| at + "http://".len())) {
+        Some(at) => url.split_at(at),
+        None => (url, "/"),
+    };
+    let mut headers = headers.to_vec();
+    headers.push(("Content-Type".to_string(), "application/json".to_string()));
+    let mut batch = Vec::new();
+    let mut deadline = Instant::now() + batching.delay;
+    loop {
+        let disconnected = match queue.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(span) => {
+                batch.push(span);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        let due = Instant::now() >= deadline;
+        if !batch.is_empty() && (batch.len() >= batching.max_size || due || disconnected) {
+            let body = export_request(resource, &batch).to_string();
+            match http_client::send_with_timeout(base_url, "POST", path, &headers, Some(&body), Some(batching.timeout)) {
+                Ok(response) if (200..300).contains(&response.status) => {}
+                Ok(response) => log!("Trace export failed: the collector answered {}", response.status),
+                Err(e) => log!("Trace export failed: {}", e),
+            }
+            batch.clear();
+        }
+        if disconnected {
+            return;
+        }
+        if due || batch.is_empty() {
+            deadline = Instant::now() + batching.delay;
+        }
+    }
+}
+
+// The OTLP/JSON body for a batch of spans
+fn export_request(resource: &Value, spans: &[Span]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let mut value = json!({
+                "traceId": span.trace_id,
+                "spanId": span.span_id,
+                "name": span.name,
+                "kind": span.kind as u8,
+                // 64-bit integers are strings in OTLP/JSON
+                "startTimeUnixNano": span.start_unix_nanos.to_string(),
+                "endTimeUnixNano": span.end_unix_nanos.to_string(),
+                "attributes": attributes(&span.attributes),
+                "status": { "code": if span.error { 2 } else { 0 } },
+            });
+            if let Some(parent) = &span.parent_span_id {
+                value["parentSpanId"] = json!(parent);
+            }
+            value
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": resource,
+            "scopeSpans": [{ "scope": { "name": env!("CARGO_PKG_NAME") }, "spans": spans }],
+        }],
+    })
+}
+
+// The service's resource: OTEL_RESOURCE_ATTRIBUTES, and its name
+fn resource(resource_attributes: &str) -> Value {
+    let mut pairs = key_values(resource_attributes);
+    let name = env::var("OTEL_SERVICE_NAME")
+        .ok()
+        .filter(|name| !name.is_empty())
+        .or_else(|| pairs.iter().find(|(key, _)| key == "service.name").map(|(_, value)| value.clone()))
+        .unwrap_or_else(|| "rust-docker-pg-crud".to_string());
+    pairs.retain(|(key, _)| key != "service.name");
+    let mut attributes = vec![json!({ "key": "service.name", "value": { "stringValue": name } })];
+    attributes.extend(pairs.into_iter().map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } })));
+    json!({ "attributes": attributes })
+}
+
+fn attributes(attributes: &[(&str, Value)]) -> Vec<Value> {
+    attributes
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": any_value(value) }))
+        .collect()
+}
+
+fn any_value(value: &Value) -> Value {
+    match value {
+        Value::Bool(value) => json!({ "boolValue": value }),
+        Value::Number(number) => match number.as_i64() {
+            Some(number) => json!({ "intValue": number.to_string() }),
+            None => json!({ "doubleValue": number }),
+        },
+        Value::Array(values) => json!({ "arrayValue": { "values": values.iter().map(any_value).collect::<Vec<_>>() } }),
+        Value::String(value) => json!({ "stringValue": value }),
+        value => json!({ "stringValue": value.to_string() }),
+    }
+}
+
+// `key=value` pairs separated by commas, as in OTEL_RESOURCE_ATTRIBUTES
+fn key_values(list: &str) -> Vec<(String, String)> {
+    list.split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .filter(|(key, _)| !key.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparent_headers_are_parsed() {
+        assert_eq!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            Some(SpanContext {
+                trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+                span_id: "00f067aa0ba902b7".to_string(),
+                sampled: true,
+            })
+        );
+        let unsampled = parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00");
+        assert!(unsampled.is_some_and(|context| !context.sampled));
+        // Later versions may have more fields
+        assert!(parse_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-xyz").is_some());
+
+        assert_eq!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-xyz"), None);
+        assert_eq!(parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"), None);
+        assert_eq!(parse_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"), None);
+        assert_eq!(parse_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"), None);
+        assert_eq!(parse_traceparent("garbage"), None);
+    }
+
+    #[test]
+    fn samplers_follow_the_parent_or_the_ratio() {
+        let parent = SpanContext {
+            trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+            span_id: "00f067aa0ba902b7".to_string(),
+            sampled: false,
+        };
+        let low = "4bf92f3577b34da60000000000000001";
+        let high = "4bf92f3577b34da6ffffffffffffff00";
+        let parent_based = Sampler { ratio: 1.0, parent_based: true };
+        assert!(!parent_based.sampled(&parent.trace_id, Some(&parent)));
+        assert!(parent_based.sampled(high, None));
+
+        let ratio = Sampler { ratio: 0.5, parent_based: false };
+        assert!(ratio.sampled(low, Some(&parent)));
+        assert!(!ratio.sampled(high, None));
+    }
+
+    #[test]
+    fn spans_are_exported_as_otlp_json() {
+        let span = Span {
+            trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_string(),
+            span_id: "00f067aa0ba902b7".to_string(),
+            parent_span_id: None,
+            name: "GET /users/{id}".to_string(),
+            kind: SpanKind::Server,
+            start_unix_nanos: 1_000,
+            end_unix_nanos: 2_000,
+            attributes: vec![("http.response.status_code", json!(500))],
+            error: true,
+        };
+        let body = export_request(&resource("deployment.environment=prod,service.name=users"), &[span]);
+        let resource = &body["resourceSpans"][0]["resource"]["attributes"];
+        assert_eq!(resource[0]["key"], "service.name");
+        assert_eq!(resource[1]["value"]["stringValue"], "prod");
+        let span = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["kind"], 2);
+        assert_eq!(span["startTimeUnixNano"], "1000");
+        assert_eq!(span["attributes"][0]["value"]["intValue"], "500");
+        assert_eq!(span["status"]["code"], 2);
+        assert!(span.get("parentSpanId").is_none());
+    }
+}
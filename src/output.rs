@@ -0,0 +1,145 @@
+use serde_json::{Map, Value};
+use std::io;
+
+// Output formatting shared by the CLI subcommands.
+// Every subcommand accepts `--output table` (the default, for humans) or
+// `--output json` (for scripts). Progress messages go to stderr so stdout
+// only ever carries the result.
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+// Removes `--output <format>` from the arguments and returns the chosen format
+// along with the remaining arguments.
+pub fn take_output_format(args: &[String]) -> io::Result<(OutputFormat, Vec<String>)> {
+    let mut format = OutputFormat::Table;
+    let mut rest = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg != "--output" {
+            rest.push(arg.clone());
+            continue;
+        }
+        format = match args.next().map(String::as_str) {
+            Some("table") => OutputFormat::Table,
+            Some("json") => OutputFormat::Json,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("--output must be json or table, got {:?}", other),
+                ))
+            }
+        };
+    }
+
+    Ok((format, rest))
+}
+
+// Prints a single record: aligned `key: value` lines, or one JSON object
+pub fn print_record(format: OutputFormat, fields: &[(&str, Value)]) {
+    match format {
+        OutputFormat::Json => {
+            let object: Map<String, Value> = fields
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect();
+            println!("{}", Value::Object(object));
+        }
+        OutputFormat::Table => {
+            let width = fields.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+            for (key, value) in fields {
+                println!("{:width$}  {}", key, cell(value), width = width);
+            }
+        }
+    }
+}
+
+// Prints rows under a header with aligned columns, or a JSON array of objects
+pub fn print_table(format: OutputFormat, columns: &[&str], rows: &[Vec<Value>]) {
+    match format {
+        OutputFormat::Json => {
+            let objects: Vec<Value> = rows
+                .iter()
+                .map(|row| {
+                    Value::Object(
+                        columns
+                            .iter()
+                            .zip(row)
+                            .map(|(column, value)| (column.to_string(), value.clone()))
+                            .collect(),
+                    )
+                })
+                .collect();
+            println!("{}", Value::Array(objects));
+        }
+        OutputFormat::Table => {
+            let cells: Vec<Vec<String>> = rows
+                .iter()
+                .map(|row| row.iter().map(cell).collect())
+                .collect();
+            let widths: Vec<usize> = columns
+                .iter()
+                .enumerate()
+                .map(|(i, column)| {
+                    cells
+                        .iter()
+                        .filter_map(|row| row.get(i).map(String::len))
+                        .chain([column.len()])
+                        .max()
+                        .unwrap_or(0)
+                })
+                .collect();
+
+            let header: Vec<String> = columns.iter().map(|c| c.to_uppercase()).collect();
+            println!("{}", format_row(&header, &widths));
+            for row in &cells {
+                println!("{}", format_row(row, &widths));
+            }
+        }
+    }
+}
+
+fn format_row(cells: &[String], widths: &[usize]) -> String {
+    cells
+        .iter()
+        .zip(widths)
+        .map(|(cell, width)| format!("{:width$}", cell, width = width))
+        .collect::<Vec<_>>()
+        .join("  ")
+        .trim_end()
+        .to_string()
+}
+
+// Strings are shown without quotes in tables; everything else as JSON
+fn cell(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Null => "-".to_string(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_flag_is_removed_from_the_arguments() {
+        let args: Vec<String> = ["file.json", "--output", "json", "http://localhost:8080"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+
+        let (format, rest) = take_output_format(&args).unwrap();
+        assert!(format == OutputFormat::Json);
+        assert_eq!(rest, vec!["file.json", "http://localhost:8080"]);
+
+        let (format, _) = take_output_format(&[]).unwrap();
+        assert!(format == OutputFormat::Table);
+        assert!(take_output_format(&["--output".to_string(), "yaml".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,770 @@
+use crate::http::Response;
+use crate::mock::{self, MockStore};
+use crate::models::User;
+use crate::output::{self, OutputFormat};
+use crate::router;
+use crate::server::{AppState, Caller};
+use regex_lite::Regex;
+use serde_json::{Map, Value};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "usage: verify-pacts <file-or-dir> [--seed N] [--users N]";
+
+// Entry point for `app verify-pacts <file-or-dir> [--seed N] [--users N]`.
+// Replays every interaction from the consumer contracts (Pact v2 and v3) against the
+// in-memory store of `serve --mock`, in this process, and checks that the responses
+// still satisfy them. Fails if any interaction doesn't match.
+// Each interaction starts from the same generated users (`--seed` and `--users`, as for
+// `serve --mock`), changed by its provider states (see `set_up`), and its response is
+// compared using its matching rules (see `Rules`). Provider states and matchers this
+// doesn't know fail the interaction rather than being skipped.
+pub fn run(args: &[String], format: OutputFormat) -> io::Result<()> {
+    let mut source = None;
+    let mut seed = 42;
+    let mut count = 50;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => seed = number(args.next())?,
+            "--users" => count = number(args.next())?,
+            _ if source.is_none() && !arg.starts_with("--") => source = Some(arg.clone()),
+            _ => return Err(invalid()),
+        }
+    }
+    let source = source.ok_or_else(invalid)?;
+    let users = mock::generate_users(seed, count as usize);
+    let mut state = AppState::from_env(None);
+
+    let mut failures = 0;
+    let mut results = Vec::new();
+    for path in pact_files(Path::new(&source))? {
+        let pact: Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        let consumer = pact["consumer"]["name"]
+            .as_str()
+            .unwrap_or("unknown consumer");
+        eprintln!("Verifying {} ({})", consumer, path.display());
+
+        for interaction in pact["interactions"].as_array().into_iter().flatten() {
+            let description = interaction["description"]
+                .as_str()
+                .unwrap_or("unnamed interaction");
+            let verified = set_up(interaction, users.clone()).and_then(|store| {
+                state.mock = Some(store);
+                verify_interaction(&state, interaction)
+            });
+            let (result, reason) = match verified {
+                Ok(()) => ("ok", Value::Null),
+                Err(reason) => {
+                    failures += 1;
+                    ("FAIL", reason.into())
+                }
+            };
+            results.push(vec![
+                consumer.into(),
+                description.into(),
+                result.into(),
+                reason,
+            ]);
+        }
+    }
+
+    output::print_table(
+        format,
+        &["consumer", "interaction", "result", "reason"],
+        &results,
+    );
+
+    if failures > 0 {
+        return Err(io::Error::other(format!(
+            "{} interaction(s) failed verification",
+            failures
+        )));
+    }
+    Ok(())
+}
+
+// A single pact file, or every `*.json` file in a directory
+fn pact_files(source: &Path) -> io::Result<Vec<PathBuf>> {
+    if source.is_file() {
+        return Ok(vec![source.to_path_buf()]);
+    }
+
+    let mut files: Vec<PathBuf> = fs::read_dir(source)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+// The store an interaction runs against: the generated users, changed by each of its
+// provider states in turn (`providerState` in v2, `providerStates` with params in v3):
+// - `users exist`: left as they are
+// - `no users exist`: none at all
+// - `a user with id N exists`, or `a user exists` with an `id` param: that user is
+//   there and not deleted, with the `name` and `email` params if given
+// - `no user with id N exists`, or `no user exists` with an `id` param: it isn't there
+fn set_up(interaction: &Value, mut users: Vec<User>) -> Result<MockStore, String> {
+    let states: Vec<(&str, Map<String, Value>)> = match &interaction["providerStates"] {
+        Value::Array(states) => states
+            .iter()
+            .map(|state| {
+                let params = state["params"].as_object().cloned().unwrap_or_default();
+                (state["name"].as_str().unwrap_or_default(), params)
+            })
+            .collect(),
+        _ => interaction["providerState"]
+            .as_str()
+            .map(|name| vec![(name, Map::new())])
+            .unwrap_or_default(),
+    };
+
+    for (name, params) in states {
+        let param_id = params
+            .get("id")
+            .and_then(|id| id.as_i64().or_else(|| id.as_str()?.parse().ok()))
+            .and_then(|id| i32::try_from(id).ok());
+        let id_in_name = |prefix: &str| {
+            name.strip_prefix(prefix)?
+                .strip_suffix(" exists")?
+                .parse::<i32>()
+                .ok()
+        };
+        let param = |key: &str| params.get(key).and_then(Value::as_str).map(str::to_string);
+
+        if name == "users exist" {
+            continue;
+        } else if name == "no users exist" {
+            users.clear();
+        } else if let Some(id) = id_in_name("a user with id ")
+            .or(param_id.filter(|_| name == "a user exists"))
+        {
+            let mut user = match users.iter().position(|user| user.id == Some(id)) {
+                Some(index) => users.remove(index),
+                None => {
+                    let mut user = User::new(&format!("User {}", id), &format!("user{}@example.com", id));
+                    user.id = Some(id);
+                    user.created_at = Some(mock::now());
+                    user.updated_at = user.created_at.clone();
+                    user.version = Some(1);
+                    user
+                }
+            };
+            user.name = param("name").unwrap_or(user.name);
+            user.email = param("email").unwrap_or(user.email);
+            user.deleted_at = None;
+            users.push(user);
+        } else if let Some(id) = id_in_name("no user with id ")
+            .or(param_id.filter(|_| name == "no user exists"))
+        {
+            users.retain(|user| user.id != Some(id));
+        } else {
+            return Err(format!("unknown provider state {:?}", name));
+        }
+    }
+    Ok(MockStore::from_users(users))
+}
+
+fn verify_interaction(state: &AppState, interaction: &Value) -> Result<(), String> {
+    let request = &interaction["request"];
+    let expected = &interaction["response"];
+    let rules = Rules::parse(&expected["matchingRules"])?;
+
+    let method = request["method"].as_str().unwrap_or("GET").to_uppercase();
+    let mut path = request["path"].as_str().unwrap_or("/").to_string();
+    let query = query_string(&request["query"])?;
+    if !query.is_empty() {
+        path = format!("{}?{}", path, query);
+    }
+    let body = match &request["body"] {
+        Value::Null => None,
+        Value::String(text) => Some(text.clone()),
+        json => Some(json.to_string()),
+    };
+
+    // HEAD is answered like GET, without the body, as the server does
+    let head = method == "HEAD";
+    let mut raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", if head { "GET" } else { &method }, path);
+    for (name, value) in request["headers"].as_object().into_iter().flatten() {
+        raw.push_str(&format!("{}: {}\r\n", name, header_value(value)));
+    }
+    if let Some(body) = &body {
+        raw.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    raw.push_str("\r\n");
+    raw.push_str(body.as_deref().unwrap_or_default());
+
+    let caller = Caller::Remote {
+        ip: "127.0.0.1".to_string(),
+    };
+    let (head_lines, content) = router::route(&raw, state, &caller);
+    let response = Response::parse(&head_lines, content);
+    let response_body = match head {
+        true => String::new(),
+        false => String::from_utf8_lossy(response.content()).into_owned(),
+    };
+
+    if let Some(status) = expected["status"].as_u64() {
+        if status != response.status() as u64 {
+            return Err(format!(
+                "expected status {}, got {}",
+                status,
+                response.status()
+            ));
+        }
+    }
+
+    for (name, value) in expected["headers"].as_object().into_iter().flatten() {
+        let expected_value = header_value(value);
+        let matched = match (response.get_header(name), rules.header(name)) {
+            (Some(actual), Some(rule)) => rule.check(&expected_value.as_str().into(), &actual.into()).is_ok(),
+            (Some(actual), None) => actual == expected_value,
+            (None, _) => false,
+        };
+        if !matched {
+            return Err(format!(
+                "expected header {}: {}, got {:?}",
+                name,
+                expected_value,
+                response.get_header(name)
+            ));
+        }
+    }
+
+    match &expected["body"] {
+        Value::Null => Ok(()),
+        Value::String(text) if text != &response_body => {
+            Err(format!("expected body {:?}, got {:?}", text, response_body))
+        }
+        Value::String(_) => Ok(()),
+        expected_body => {
+            let actual: Value = serde_json::from_str(&response_body)
+                .map_err(|_| format!("expected a JSON body, got {:?}", response_body))?;
+            rules.body_matches(expected_body, &actual, &Field::root(), false)
+        }
+    }
+}
+
+// The request's query: a string in v2, an object of lists of values from v3 on
+fn query_string(query: &Value) -> Result<String, String> {
+    let params = match query {
+        Value::Null => return Ok(String::new()),
+        Value::String(query) => return Ok(query.clone()),
+        Value::Object(params) => params,
+        query => return Err(format!("unsupported query {}", query)),
+    };
+    let mut pairs = Vec::new();
+    for (name, values) in params {
+        let values = match values {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            pairs.push(format!("{}={}", encode(name), encode(&value)));
+        }
+    }
+    Ok(pairs.join("&"))
+}
+
+// Percent-encodes everything but the characters URLs leave as they are
+fn encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// A header's value: a string, or a list of them from v4 on
+fn header_value(value: &Value) -> String {
+    match value {
+        Value::Array(values) => values
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join(", "),
+        value => value.as_str().unwrap_or_default().to_string(),
+    }
+}
+
+// Where a value is in the response body: as it's shown (`$[0].name`), and as segments
+// to find its matching rule by
+struct Field {
+    path: String,
+    segments: Vec<String>,
+}
+
+impl Field {
+    fn root() -> Field {
+        Field {
+            path: "$".to_string(),
+            segments: Vec::new(),
+        }
+    }
+
+    fn child(&self, shown: String, segment: String) -> Field {
+        let mut segments = self.segments.clone();
+        segments.push(segment);
+        Field {
+            path: format!("{}{}", self.path, shown),
+            segments,
+        }
+    }
+
+    fn key(&self, key: &str) -> Field {
+        self.child(format!(".{}", key), key.to_string())
+    }
+
+    fn index(&self, index: usize) -> Field {
+        self.child(format!("[{}]", index), index.to_string())
+    }
+}
+
+// A response's matching rules. v2 keeps them in one object keyed by paths like
+// `$.body.items[*].id` or `$.headers.Content-Type`, with a matcher each; v3 groups them
+// by category (`body`, `header`), each path with a list of matchers and how they combine.
+// Without a rule, Pact's default matching applies: objects may contain extra keys,
+// arrays must match element by element, everything else must be equal.
+#[derive(Default)]
+struct Rules {
+    // By path segments, where `*` is any key or index
+    body: Vec<(Vec<String>, Rule)>,
+    headers: Vec<(String, Rule)>,
+}
+
+struct Rule {
+    matchers: Vec<Matcher>,
+    // `"combine": "OR"`: any one matcher is enough, rather than all of them
+    any: bool,
+}
+
+enum Matcher {
+    // Same type as the expected value; arrays have between `min` and `max` items, each
+    // like the first expected one
+    Type { min: Option<usize>, max: Option<usize> },
+    Equality,
+    // The whole value must match, so the pattern is compiled anchored
+    Regex { pattern: String, regex: Regex },
+    Include(String),
+    Integer,
+    Decimal,
+    Number,
+    Boolean,
+    Null,
+}
+
+impl Rules {
+    fn parse(rules: &Value) -> Result<Rules, String> {
+        let mut parsed = Rules::default();
+        for (key, value) in rules.as_object().into_iter().flatten() {
+            if let Some(path) = key.strip_prefix("$.body") {
+                parsed.body.push((segments(path)?, Rule::parse_v2(value)?));
+            } else if let Some(name) = key.strip_prefix("$.headers.") {
+                parsed.headers.push((name.to_string(), Rule::parse_v2(value)?));
+            } else if key == "body" {
+                for (path, rule) in value.as_object().into_iter().flatten() {
+                    let path = path
+                        .strip_prefix('$')
+                        .ok_or_else(|| format!("unsupported matching rule path {}", path))?;
+                    parsed.body.push((segments(path)?, Rule::parse_v3(rule)?));
+                }
+            } else if key == "header" {
+                for (name, rule) in value.as_object().into_iter().flatten() {
+                    parsed.headers.push((name.clone(), Rule::parse_v3(rule)?));
+                }
+            } else {
+                return Err(format!("unsupported matching rules for {}", key));
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn header(&self, name: &str) -> Option<&Rule> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, rule)| rule)
+    }
+
+    // The most specific rule for a field, i.e. the one with the fewest wildcards
+    fn body_rule(&self, field: &Field) -> Option<&Rule> {
+        self.body
+            .iter()
+            .filter(|(segments, _)| {
+                segments.len() == field.segments.len()
+                    && segments
+                        .iter()
+                        .zip(&field.segments)
+                        .all(|(rule, segment)| rule == "*" || rule == segment)
+            })
+            .max_by_key(|(segments, _)| segments.iter().filter(|segment| *segment != "*").count())
+            .map(|(_, rule)| rule)
+    }
+
+    // Whether the actual body satisfies the expected one. `by_type` is set inside a value
+    // with a `type` matcher, which covers everything in it that has no rule of its own.
+    fn body_matches(&self, expected: &Value, actual: &Value, field: &Field, by_type: bool) -> Result<(), String> {
+        let rule = self.body_rule(field);
+        if let Some(rule) = rule {
+            rule.check(expected, actual)
+                .map_err(|wanted| format!("expected {} at {}, got {}", wanted, field.path, actual))?;
+        } else if by_type && !same_type(expected, actual) {
+            return Err(format!("expected {} at {}, got {}", type_name(expected), field.path, actual));
+        }
+        let by_type = by_type || rule.is_some_and(|rule| rule.matchers.iter().any(|matcher| matches!(matcher, Matcher::Type { .. })));
+
+        match (expected, actual) {
+            (Value::Object(expected), Value::Object(actual)) => {
+                for (key, value) in expected {
+                    let field = field.key(key);
+                    match actual.get(key) {
+                        Some(actual_value) => self.body_matches(value, actual_value, &field, by_type)?,
+                        None => return Err(format!("missing field {}", field.path)),
+                    }
+                }
+                Ok(())
+            }
+            (Value::Array(expected), Value::Array(actual)) if by_type => match expected.first() {
+                Some(first) => actual
+                    .iter()
+                    .enumerate()
+                    .try_for_each(|(index, actual)| self.body_matches(first, actual, &field.index(index), true)),
+                None => Ok(()),
+            },
+            (Value::Array(expected), Value::Array(actual)) => {
+                if expected.len() != actual.len() {
+                    return Err(format!(
+                        "expected {} items at {}, got {}",
+                        expected.len(),
+                        field.path,
+                        actual.len()
+                    ));
+                }
+                for (index, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+                    self.body_matches(expected, actual, &field.index(index), false)?;
+                }
+                Ok(())
+            }
+            // Already checked by its rule, or by type
+            _ if by_type || rule.is_some() => Ok(()),
+            (expected, actual) if expected == actual => Ok(()),
+            (expected, actual) => Err(format!("expected {} at {}, got {}", expected, field.path, actual)),
+        }
+    }
+}
+
+impl Rule {
+    // `{"match": "type", "min": 1}`, or `{"regex": "..."}` without the `match`
+    fn parse_v2(rule: &Value) -> Result<Rule, String> {
+        Ok(Rule {
+            matchers: vec![Matcher::parse(rule)?],
+            any: false,
+        })
+    }
+
+    // `{"matchers": [{"match": "type"}, ...], "combine": "AND"}`
+    fn parse_v3(rule: &Value) -> Result<Rule, String> {
+        let matchers = rule["matchers"]
+            .as_array()
+            .ok_or_else(|| format!("unsupported matching rule {}", rule))?
+            .iter()
+            .map(Matcher::parse)
+            .collect::<Result<_, _>>()?;
+        let any = match rule["combine"].as_str() {
+            None | Some("AND") => false,
+            Some("OR") => true,
+            Some(combine) => return Err(format!("unsupported combine {:?}", combine)),
+        };
+        Ok(Rule { matchers, any })
+    }
+
+    // Ok, or what the value should have been
+    fn check(&self, expected: &Value, actual: &Value) -> Result<(), String> {
+        let failures: Vec<String> = self
+            .matchers
+            .iter()
+            .filter_map(|matcher| matcher.check(expected, actual).err())
+            .collect();
+        let passed = match self.any {
+            true => failures.len() < self.matchers.len(),
+            false => failures.is_empty(),
+        };
+        match passed {
+            true => Ok(()),
+            false => Err(failures.join(if self.any { " or " } else { " and " })),
+        }
+    }
+}
+
+impl Matcher {
+    fn parse(matcher: &Value) -> Result<Matcher, String> {
+        let size = |key: &str| matcher[key].as_u64().map(|size| size as usize);
+        let kind = match matcher["match"].as_str() {
+            Some(kind) => kind,
+            None if matcher["regex"].is_string() => "regex",
+            None if matcher["min"].is_u64() || matcher["max"].is_u64() => "type",
+            None => return Err(format!("unsupported matcher {}", matcher)),
+        };
+        Ok(match kind {
+            "type" => Matcher::Type {
+                min: size("min"),
+                max: size("max"),
+            },
+            "equality" => Matcher::Equality,
+            "regex" => {
+                let pattern = matcher["regex"].as_str().unwrap_or_default().to_string();
+                let regex = Regex::new(&format!("^(?:{})$", pattern))
+                    .map_err(|e| format!("invalid regex {:?}: {}", pattern, e))?;
+                Matcher::Regex { pattern, regex }
+            }
+            "include" => Matcher::Include(matcher["value"].as_str().unwrap_or_default().to_string()),
+            "integer" => Matcher::Integer,
+            "decimal" => Matcher::Decimal,
+            "number" => Matcher::Number,
+            "boolean" => Matcher::Boolean,
+            "null" => Matcher::Null,
+            kind => return Err(format!("unsupported matcher {:?}", kind)),
+        })
+    }
+
+    // Ok, or what the value should have been
+    fn check(&self, expected: &Value, actual: &Value) -> Result<(), String> {
+        let text = match actual {
+            Value::String(text) => Some(text.clone()),
+            Value::Number(number) => Some(number.to_string()),
+            Value::Bool(value) => Some(value.to_string()),
+            _ => None,
+        };
+        let (matched, wanted) = match self {
+            Matcher::Type { min, max } => {
+                let items = actual.as_array().map_or(0, Vec::len);
+                let matched = same_type(expected, actual)
+                    && (!actual.is_array() || (min.is_none_or(|min| items >= min) && max.is_none_or(|max| items <= max)));
+                let wanted = match (min, max) {
+                    (Some(min), Some(max)) => format!("{} to {} items", min, max),
+                    (Some(min), None) => format!("at least {} items", min),
+                    (None, Some(max)) => format!("at most {} items", max),
+                    (None, None) => type_name(expected).to_string(),
+                };
+                (matched, wanted)
+            }
+            Matcher::Equality => (expected == actual, expected.to_string()),
+            Matcher::Regex { pattern, regex } => (
+                text.is_some_and(|text| regex.is_match(&text)),
+                format!("a match for /{}/", pattern),
+            ),
+            Matcher::Include(part) => (
+                text.is_some_and(|text| text.contains(part.as_str())),
+                format!("a value including {:?}", part),
+            ),
+            Matcher::Integer => (actual.is_i64() || actual.is_u64(), "an integer".to_string()),
+            Matcher::Decimal => (actual.is_f64(), "a decimal".to_string()),
+            Matcher::Number => (actual.is_number(), "a number".to_string()),
+            Matcher::Boolean => (actual.is_boolean(), "a boolean".to_string()),
+            Matcher::Null => (actual.is_null(), "null".to_string()),
+        };
+        match matched {
+            true => Ok(()),
+            false => Err(wanted),
+        }
+    }
+}
+
+// The segments of a rule's path after `$` (or `$.body` in v2): `.name`, `['name']`,
+// `[0]`, and `.*` or `[*]` for any
+fn segments(path: &str) -> Result<Vec<String>, String> {
+    let unsupported = || format!("unsupported matching rule path ${}", path);
+    let mut segments = Vec::new();
+    let mut rest = path;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            segments.push(after[..end].to_string());
+            rest = &after[end..];
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(unsupported)?;
+            segments.push(after[..end].trim_matches('\'').to_string());
+            rest = &after[end + 1..];
+        } else {
+            return Err(unsupported());
+        }
+    }
+    if segments.iter().any(String::is_empty) {
+        return Err(unsupported());
+    }
+    Ok(segments)
+}
+
+fn same_type(expected: &Value, actual: &Value) -> bool {
+    type_name(expected) == type_name(actual)
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+fn number(arg: Option<&String>) -> io::Result<u64> {
+    arg.and_then(|arg| arg.parse().ok()).ok_or_else(invalid)
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, USAGE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn body_matches(expected: &Value, actual: &Value, rules: Value) -> Result<(), String> {
+        Rules::parse(&rules)?.body_matches(expected, actual, &Field::root(), false)
+    }
+
+    #[test]
+    fn extra_fields_in_the_response_are_allowed() {
+        let expected = json!({ "name": "Ada" });
+        let actual = json!({ "id": 1, "name": "Ada", "email": "ada@example.com" });
+        assert!(body_matches(&expected, &actual, Value::Null).is_ok());
+    }
+
+    #[test]
+    fn mismatches_report_the_field_path() {
+        let expected = json!([{ "name": "Ada" }]);
+        let actual = json!([{ "name": "Grace" }]);
+        assert_eq!(
+            body_matches(&expected, &actual, Value::Null),
+            Err("expected \"Ada\" at $[0].name, got \"Grace\"".to_string())
+        );
+    }
+
+    #[test]
+    fn arrays_must_have_the_same_length() {
+        let expected = json!([1, 2]);
+        let actual = json!([1]);
+        assert!(body_matches(&expected, &actual, Value::Null).is_err());
+    }
+
+    #[test]
+    fn matching_rules_loosen_the_comparison() {
+        let expected = json!({ "users": [{ "id": 1, "email": "ada@example.com" }], "total": 1 });
+        let actual = json!({
+            "users": [{ "id": 7, "email": "grace@example.com" }, { "id": 8, "email": "alan@example.net" }],
+            "total": 2,
+        });
+        let v2 = json!({
+            "$.body.users": { "match": "type", "min": 1 },
+            "$.body.users[*].email": { "regex": ".+@.+" },
+            "$.body.total": { "match": "integer" },
+        });
+        assert_eq!(body_matches(&expected, &actual, v2), Ok(()));
+
+        let v3 = json!({ "body": {
+            "$.users": { "matchers": [{ "match": "type", "min": 3 }] },
+            "$.total": { "matchers": [{ "match": "integer" }] },
+        } });
+        assert_eq!(
+            body_matches(&expected, &actual, v3),
+            Err(format!("expected at least 3 items at $.users, got {}", actual["users"]))
+        );
+
+        // A type rule covers everything inside it, except where a rule of its own applies
+        let v3 = json!({ "body": {
+            "$": { "matchers": [{ "match": "type" }] },
+            "$.users[*].email": {
+                "matchers": [{ "match": "regex", "regex": "\\w+@example\\.com" }, { "match": "include", "value": "grace" }],
+                "combine": "OR",
+            },
+        } });
+        assert_eq!(
+            body_matches(&expected, &actual, v3),
+            Err("expected a match for /\\w+@example\\.com/ or a value including \"grace\" at $.users[1].email, got \"alan@example.net\"".to_string())
+        );
+        let actual = json!({ "users": [{ "id": "7", "email": "ada@example.com" }], "total": 1 });
+        assert_eq!(
+            body_matches(&expected, &actual, json!({ "$.body": { "match": "type" } })),
+            Err("expected a number at $.users[0].id, got \"7\"".to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_matchers_and_categories_are_rejected() {
+        let error = |rules: Value| Rules::parse(&rules).err();
+        assert_eq!(
+            error(json!({ "$.body.created_at": { "match": "timestamp" } })),
+            Some("unsupported matcher \"timestamp\"".to_string())
+        );
+        assert_eq!(
+            error(json!({ "path": { "$": { "matchers": [{ "match": "type" }] } } })),
+            Some("unsupported matching rules for path".to_string())
+        );
+        assert!(error(json!({ "$.body.name": { "regex": "(" } })).is_some());
+    }
+
+    #[test]
+    fn v3_queries_are_encoded() {
+        let query = json!({ "email": ["ada+1@example.com"], "tag": ["a", "b c"] });
+        assert_eq!(
+            query_string(&query),
+            Ok("email=ada%2B1%40example.com&tag=a&tag=b%20c".to_string())
+        );
+        assert_eq!(query_string(&json!("limit=5")), Ok("limit=5".to_string()));
+    }
+
+    #[test]
+    fn provider_states_set_up_the_store() {
+        let users = mock::generate_users(42, 5);
+        let size = |interaction: Value| set_up(&interaction, users.clone()).map(|store| store.len());
+        assert_eq!(size(json!({})), Ok(5));
+        assert_eq!(size(json!({ "providerState": "no users exist" })), Ok(0));
+        assert_eq!(size(json!({ "providerState": "no user with id 2 exists" })), Ok(4));
+        assert_eq!(
+            size(json!({ "providerStates": [{ "name": "no users exist" }, { "name": "a user exists", "params": { "id": 99 } }] })),
+            Ok(1)
+        );
+        assert_eq!(
+            size(json!({ "providerState": "the moon is full" })),
+            Err("unknown provider state \"the moon is full\"".to_string())
+        );
+    }
+
+    #[test]
+    fn interactions_are_replayed_against_the_mock_store() {
+        let mut state = AppState::from_env(None);
+        let interaction = json!({
+            "providerStates": [{ "name": "a user exists", "params": { "id": 99, "name": "Ada", "email": "ada@example.com" } }],
+            "request": { "method": "GET", "path": "/users/99", "query": { "fields": ["id,name,version"] } },
+            "response": {
+                "status": 200,
+                "body": { "id": 99, "name": "Ada", "version": 7 },
+                "matchingRules": { "body": { "$.version": { "matchers": [{ "match": "integer" }] } } },
+            },
+        });
+        state.mock = Some(set_up(&interaction, Vec::new()).unwrap());
+        assert_eq!(verify_interaction(&state, &interaction), Ok(()));
+
+        let interaction = json!({
+            "providerState": "no user with id 99 exists",
+            "request": { "method": "HEAD", "path": "/users/99" },
+            "response": { "status": 200 },
+        });
+        state.mock = Some(set_up(&interaction, Vec::new()).unwrap());
+        assert_eq!(
+            verify_interaction(&state, &interaction),
+            Err("expected status 200, got 404".to_string())
+        );
+    }
+}
@@ -0,0 +1,592 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// A blocking connection pool.
+//
+// The bookkeeping lives in `PoolCore`, which never blocks, never does I/O and takes
+// the current time as an argument, so the simulation tests below can drive it with
+// virtual time. `Pool` wraps it with a mutex, a condvar and the real clock.
+
+pub enum PoolError<E> {
+    // No connection became available within the configured wait time
+    Timeout,
+    // Opening a new connection failed
+    Connect(E),
+}
+
+impl<E: fmt::Display> fmt::Display for PoolError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolError::Timeout => write!(f, "timed out waiting for a database connection"),
+            PoolError::Connect(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Debug for PoolError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PoolStats {
+    pub max_size: usize,
+    // Checked out, idle, or being opened
+    pub open: usize,
+    pub idle: usize,
+    // Checked out or being opened
+    pub in_use: usize,
+    // Checkouts waiting for a connection
+    pub waiting: usize,
+}
+
+// What a waiting checkout should do next
+#[derive(Debug, PartialEq)]
+enum Poll<C> {
+    // Take this idle connection
+    Ready(C),
+    // A slot was reserved: open a new connection outside the lock
+    Connect,
+    // Not this waiter's turn yet, or nothing available
+    Wait,
+    // The waiter's deadline passed; it has been removed from the queue
+    TimedOut,
+}
+
+struct Waiter {
+    ticket: u64,
+    deadline: Instant,
+}
+
+struct PoolCore<C> {
+    max_size: usize,
+    max_wait: Duration,
+    idle: Vec<C>,
+    // Connections that exist or are being opened: checked out + idle + connecting
+    open: usize,
+    // Checkouts are served strictly in arrival order
+    waiters: VecDeque<Waiter>,
+    next_ticket: u64,
+    // Connections from an earlier generation are closed instead of reused
+    generation: u64,
+}
+
+impl<C> PoolCore<C> {
+    fn new(max_size: usize, max_wait: Duration) -> Self {
+        PoolCore {
+            max_size: max_size.max(1),
+            max_wait,
+            idle: Vec::new(),
+            open: 0,
+            waiters: VecDeque::new(),
+            next_ticket: 0,
+            generation: 0,
+        }
+    }
+
+    fn enqueue(&mut self, now: Instant) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.waiters.push_back(Waiter {
+            ticket,
+            deadline: now + self.max_wait,
+        });
+        ticket
+    }
+
+    fn poll(&mut self, ticket: u64, now: Instant) -> Poll<C> {
+        let position = match self.waiters.iter().position(|w| w.ticket == ticket) {
+            Some(position) => position,
+            None => return Poll::TimedOut,
+        };
+        if now >= self.waiters[position].deadline {
+            self.waiters.remove(position);
+            return Poll::TimedOut;
+        }
+        if position != 0 {
+            return Poll::Wait;
+        }
+
+        if let Some(conn) = self.idle.pop() {
+            self.waiters.pop_front();
+            Poll::Ready(conn)
+        } else if self.open < self.max_size {
+            self.open += 1;
+            self.waiters.pop_front();
+            Poll::Connect
+        } else {
+            Poll::Wait
+        }
+    }
+
+    fn deadline(&self, ticket: u64) -> Option<Instant> {
+        self.waiters
+            .iter()
+            .find(|w| w.ticket == ticket)
+            .map(|w| w.deadline)
+    }
+
+    // A reserved slot whose connection attempt failed, or a broken connection, is freed
+    fn discard(&mut self) {
+        self.open -= 1;
+    }
+
+    fn release(&mut self, conn: C) {
+        self.idle.push(conn);
+    }
+
+    // Moves to a newer generation, handing back the idle connections to close
+    fn advance(&mut self, generation: u64) -> Vec<C> {
+        if generation <= self.generation {
+            return Vec::new();
+        }
+        self.generation = generation;
+        self.open -= self.idle.len();
+        std::mem::take(&mut self.idle)
+    }
+}
+
+type Connector<C, E> = Box<dyn Fn() -> Result<C, E> + Send + Sync>;
+type HealthCheck<C> = Box<dyn Fn(&C) -> bool + Send + Sync>;
+
+pub struct Pool<C, E> {
+    core: Mutex<PoolCore<C>>,
+    changed: Condvar,
+    connect: Connector<C, E>,
+    // Connections failing this check are dropped instead of returned to the pool
+    is_healthy: HealthCheck<C>,
+}
+
+impl<C, E> Pool<C, E> {
+    pub fn new(
+        max_size: usize,
+        max_wait: Duration,
+        connect: impl Fn() -> Result<C, E> + Send + Sync + 'static,
+        is_healthy: impl Fn(&C) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Pool {
+            core: Mutex::new(PoolCore::new(max_size, max_wait)),
+            changed: Condvar::new(),
+            connect: Box::new(connect),
+            is_healthy: Box::new(is_healthy),
+        }
+    }
+
+    // Checks out a connection, waiting up to the pool's max wait for one to become available
+    pub fn get(&self) -> Result<PooledConnection<'_, C, E>, PoolError<E>> {
+        let mut core = self.lock();
+        let ticket = core.enqueue(Instant::now());
+
+        loop {
+            let generation = core.generation;
+            match core.poll(ticket, Instant::now()) {
+                Poll::Ready(conn) => {
+                    // The next waiter may be able to proceed too
+                    self.changed.notify_all();
+                    return Ok(self.wrap(conn, generation));
+                }
+                Poll::Connect => {
+                    self.changed.notify_all();
+                    drop(core);
+                    return match (self.connect)() {
+                        Ok(conn) => Ok(self.wrap(conn, generation)),
+                        Err(e) => {
+                            self.lock().discard();
+                            self.changed.notify_all();
+                            Err(PoolError::Connect(e))
+                        }
+                    };
+                }
+                Poll::TimedOut => {
+                    self.changed.notify_all();
+                    return Err(PoolError::Timeout);
+                }
+                Poll::Wait => {
+                    let deadline = core.deadline(ticket).unwrap_or_else(Instant::now);
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    core = self
+                        .changed
+                        .wait_timeout(core, timeout)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0;
+                }
+            }
+        }
+    }
+
+    // Closes the idle connections and those checked out now once they're returned, when
+    // `generation` is newer than the pool's: after switching databases, for example
+    pub fn advance(&self, generation: u64) {
+        let stale = self.lock().advance(generation);
+        if !stale.is_empty() {
+            self.changed.notify_all();
+        }
+    }
+
+    // How many connections there are and how busy they are, as of now
+    pub fn stats(&self) -> PoolStats {
+        let core = self.lock();
+        PoolStats {
+            max_size: core.max_size,
+            open: core.open,
+            idle: core.idle.len(),
+            in_use: core.open - core.idle.len(),
+            waiting: core.waiters.len(),
+        }
+    }
+
+    fn wrap(&self, conn: C, generation: u64) -> PooledConnection<'_, C, E> {
+        PooledConnection {
+            pool: self,
+            conn: Some(conn),
+            generation,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolCore<C>> {
+        self.core.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// A checked-out connection, returned to the pool when dropped
+pub struct PooledConnection<'a, C, E> {
+    pool: &'a Pool<C, E>,
+    conn: Option<C>,
+    generation: u64,
+}
+
+impl<C, E> PooledConnection<'_, C, E> {
+    // Closes the connection instead of returning it to the pool, for connections that
+    // still look healthy but shouldn't be reused
+    pub fn discard(mut self) {
+        if self.conn.take().is_some() {
+            self.pool.lock().discard();
+            self.pool.changed.notify_all();
+        }
+    }
+}
+
+impl<C, E> Deref for PooledConnection<'_, C, E> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        self.conn
+            .as_ref()
+            .expect("connection is present until drop")
+    }
+}
+
+impl<C, E> DerefMut for PooledConnection<'_, C, E> {
+    fn deref_mut(&mut self) -> &mut C {
+        self.conn
+            .as_mut()
+            .expect("connection is present until drop")
+    }
+}
+
+impl<C, E> Drop for PooledConnection<'_, C, E> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            let healthy = (self.pool.is_healthy)(&conn);
+            let mut core = self.pool.lock();
+            if healthy && self.generation == core.generation {
+                core.release(conn);
+            } else {
+                core.discard();
+            }
+            drop(core);
+            self.pool.changed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // Deterministic simulation of many clients sharing a small pool.
+    //
+    // Time is virtual and advances in fixed steps; a seeded PRNG decides when clients
+    // arrive, how long they hold a connection (some checkouts are very slow) and when
+    // opening a connection fails. After every step the invariants are checked.
+
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn chance(&mut self, percent: u64) -> bool {
+            self.next() % 100 < percent
+        }
+    }
+
+    enum Client {
+        Waiting { ticket: u64, arrived: Instant },
+        Connecting { ready_at: Instant },
+        Holding { conn: u32, release_at: Instant },
+    }
+
+    struct Simulation {
+        core: PoolCore<u32>,
+        clients: Vec<Client>,
+        now: Instant,
+        rng: Rng,
+        next_conn_id: u32,
+        // Tickets in the order their checkout was granted
+        granted: Vec<u64>,
+        timeouts: usize,
+        connect_failures: usize,
+        completed: usize,
+    }
+
+    const STEP: Duration = Duration::from_millis(10);
+    const MAX_SIZE: usize = 4;
+    const MAX_WAIT: Duration = Duration::from_millis(500);
+
+    impl Simulation {
+        fn new(seed: u64) -> Self {
+            Simulation {
+                core: PoolCore::new(MAX_SIZE, MAX_WAIT),
+                clients: Vec::new(),
+                now: Instant::now(),
+                rng: Rng(seed),
+                next_conn_id: 0,
+                granted: Vec::new(),
+                timeouts: 0,
+                connect_failures: 0,
+                completed: 0,
+            }
+        }
+
+        fn hold_time(&mut self) -> Duration {
+            // One in twenty checkouts is slow, holding its connection for up to a second
+            if self.rng.chance(5) {
+                Duration::from_millis(200 + self.rng.next() % 800)
+            } else {
+                Duration::from_millis(10 + self.rng.next() % 50)
+            }
+        }
+
+        fn step(&mut self) {
+            self.now += STEP;
+
+            for _ in 0..self.rng.next() % 3 {
+                let ticket = self.core.enqueue(self.now);
+                self.clients.push(Client::Waiting {
+                    ticket,
+                    arrived: self.now,
+                });
+            }
+
+            let mut clients = std::mem::take(&mut self.clients);
+            // Clients are polled in a shuffled order: fairness must not depend on who asks first
+            for i in (1..clients.len()).rev() {
+                let j = (self.rng.next() % (i as u64 + 1)) as usize;
+                clients.swap(i, j);
+            }
+
+            for client in clients {
+                if let Some(client) = self.advance(client) {
+                    self.clients.push(client);
+                }
+            }
+        }
+
+        // Moves one client forward; `None` once it's done with the pool
+        fn advance(&mut self, client: Client) -> Option<Client> {
+            match client {
+                Client::Waiting { ticket, arrived } => match self.core.poll(ticket, self.now) {
+                    Poll::Ready(conn) => {
+                        self.granted.push(ticket);
+                        assert!(self.now - arrived < MAX_WAIT, "waited past the deadline");
+                        let release_at = self.now + self.hold_time();
+                        Some(Client::Holding { conn, release_at })
+                    }
+                    Poll::Connect => {
+                        self.granted.push(ticket);
+                        let delay = Duration::from_millis(self.rng.next() % 100);
+                        Some(Client::Connecting {
+                            ready_at: self.now + delay,
+                        })
+                    }
+                    Poll::Wait => Some(Client::Waiting { ticket, arrived }),
+                    Poll::TimedOut => {
+                        assert!(self.now - arrived >= MAX_WAIT, "timed out too early");
+                        self.timeouts += 1;
+                        None
+                    }
+                },
+                Client::Connecting { ready_at } if self.now >= ready_at => {
+                    if self.rng.chance(10) {
+                        self.connect_failures += 1;
+                        self.core.discard();
+                        return None;
+                    }
+                    self.next_conn_id += 1;
+                    let release_at = self.now + self.hold_time();
+                    Some(Client::Holding {
+                        conn: self.next_conn_id,
+                        release_at,
+                    })
+                }
+                Client::Holding { conn, release_at } if self.now >= release_at => {
+                    self.completed += 1;
+                    // Occasionally a connection breaks while in use
+                    if self.rng.chance(3) {
+                        self.core.discard();
+                    } else {
+                        self.core.release(conn);
+                    }
+                    None
+                }
+                client => Some(client),
+            }
+        }
+
+        fn check_invariants(&self) {
+            let mut held = HashSet::new();
+            let mut connecting = 0;
+            for client in &self.clients {
+                match client {
+                    Client::Holding { conn, .. } => {
+                        assert!(held.insert(*conn), "connection {} checked out twice", conn);
+                        assert!(
+                            !self.core.idle.contains(conn),
+                            "connection {} is idle and held",
+                            conn
+                        );
+                    }
+                    Client::Connecting { .. } => connecting += 1,
+                    Client::Waiting { .. } => {}
+                }
+            }
+
+            let mut idle = HashSet::new();
+            for conn in &self.core.idle {
+                assert!(idle.insert(*conn), "connection {} is idle twice", conn);
+            }
+
+            assert_eq!(self.core.open, held.len() + idle.len() + connecting);
+            assert!(self.core.open <= MAX_SIZE);
+        }
+    }
+
+    fn simulate(seed: u64) -> Simulation {
+        let mut simulation = Simulation::new(seed);
+        for _ in 0..3_000 {
+            simulation.step();
+            simulation.check_invariants();
+        }
+        simulation
+    }
+
+    #[test]
+    fn simulation_upholds_invariants() {
+        for seed in 1..=10 {
+            let simulation = simulate(seed);
+
+            // Checkouts are granted in arrival order
+            let mut sorted = simulation.granted.clone();
+            sorted.sort();
+            assert_eq!(
+                simulation.granted, sorted,
+                "seed {}: grants out of order",
+                seed
+            );
+
+            // The scenario actually exercised the interesting paths
+            assert!(simulation.completed > 500, "seed {}", seed);
+            assert!(simulation.timeouts > 0, "seed {}", seed);
+            assert!(simulation.connect_failures > 0, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn simulation_is_deterministic() {
+        let first = simulate(42);
+        let second = simulate(42);
+        assert_eq!(first.granted, second.granted);
+        assert_eq!(first.timeouts, second.timeouts);
+    }
+
+    #[test]
+    fn pool_reuses_connections() {
+        let opened = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counter = std::sync::Arc::clone(&opened);
+        let pool: Pool<u32, String> = Pool::new(
+            2,
+            Duration::from_millis(50),
+            move || Ok(counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst)),
+            |_| true,
+        );
+
+        let first = *pool.get().unwrap();
+        let second = *pool.get().unwrap();
+        assert_eq!(first, second);
+
+        let a = pool.get().unwrap();
+        let b = pool.get().unwrap();
+        assert_ne!(*a, *b);
+        assert!(matches!(pool.get(), Err(PoolError::Timeout)));
+
+        drop(b);
+        assert_eq!(
+            pool.stats(),
+            PoolStats { max_size: 2, open: 2, idle: 1, in_use: 1, waiting: 0 }
+        );
+    }
+
+    #[test]
+    fn discarded_connections_are_replaced() {
+        let opened = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counter = std::sync::Arc::clone(&opened);
+        let pool: Pool<u32, String> = Pool::new(
+            1,
+            Duration::from_millis(50),
+            move || Ok(counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst)),
+            |_| true,
+        );
+
+        let first = pool.get().unwrap();
+        assert_eq!(*first, 0);
+        first.discard();
+        assert_eq!(*pool.get().unwrap(), 1);
+    }
+
+    #[test]
+    fn connections_from_older_generations_are_closed() {
+        let opened = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counter = std::sync::Arc::clone(&opened);
+        let pool: Pool<u32, String> = Pool::new(
+            2,
+            Duration::from_millis(50),
+            move || Ok(counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst)),
+            |_| true,
+        );
+
+        let checked_out = pool.get().unwrap();
+        let idle = pool.get().unwrap();
+        assert_eq!((*checked_out, *idle), (0, 1));
+        drop(idle);
+
+        pool.advance(1);
+        drop(checked_out);
+        let a = pool.get().unwrap();
+        let b = pool.get().unwrap();
+        assert_eq!((*a, *b), (2, 3));
+        // Going back to an older generation changes nothing
+        drop(a);
+        pool.advance(1);
+        assert_eq!(*pool.get().unwrap(), 2);
+    }
+}
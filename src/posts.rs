@@ -0,0 +1,80 @@
+use postgres::GenericClient;
+
+use crate::db::{in_transaction, with_client};
+use crate::errors::{AppError, ErrorCode};
+use crate::handlers::{json_response, OK_RESPONSE};
+use crate::models::Post;
+use crate::repo;
+use crate::resource;
+use crate::server::AppState;
+
+// Posts belong to a user: they're listed and created under `/users/{id}/posts`, and
+// fetched, replaced and deleted at `/posts/{id}` (GET and DELETE use the generic
+// resource handlers). Posts can only be written for live users; a missing or
+// soft-deleted user is a 404. Removing a user row removes its posts with it.
+
+// Handle GET /users/{id}/posts
+// Returns a `(String, String)` tuple for HTTP status and body, like the other handlers.
+pub(crate) fn handle_list(user_id: i32, state: &AppState) -> (String, String) {
+    let posts = with_client(state, |client| {
+        require_user(client, user_id)?;
+        Ok(resource::list_by::<Post>(client, "user_id", &user_id)?)
+    });
+    match posts {
+        Ok(posts) => json_response(OK_RESPONSE, &posts),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle POST /users/{id}/posts
+// The post is written for the user in the path; a `user_id` in the body is ignored.
+// Returns a `(String, String)` tuple for HTTP status and body, like the other handlers.
+pub(crate) fn handle_create(request: &str, user_id: i32, state: &AppState) -> (String, String) {
+    let mut post = match resource::body::<Post>(request) {
+        Ok(post) => post,
+        Err(e) => return e.into_response(),
+    };
+    post.user_id = Some(user_id);
+    in_transaction(state, |transaction| {
+        require_user(transaction, user_id)?;
+        let created = resource::insert(transaction, &post)?;
+        Ok((OK_RESPONSE.to_string(), serde_json::to_string(&created)?))
+    })
+}
+
+// Handle PUT /posts/{id}
+// Replaces title and body. A `user_id` in the body moves the post to that user,
+// which must exist; without one the post stays with its user.
+// Returns a `(String, String)` tuple for HTTP status and body, like the other handlers.
+pub(crate) fn handle_update(request: &str, id: i32, state: &AppState) -> (String, String) {
+    let post = match resource::body::<Post>(request) {
+        Ok(post) => post,
+        Err(e) => return e.into_response(),
+    };
+    in_transaction(state, |transaction| {
+        let current =
+            resource::find::<Post>(transaction, id)?.ok_or_else(resource::not_found::<Post>)?;
+        let user_id = post.user_id.or(current.user_id);
+        if user_id != current.user_id {
+            require_user(transaction, user_id.unwrap_or_default())?;
+        }
+        let post = Post {
+            user_id,
+            ..post.clone()
+        };
+        let updated =
+            resource::update(transaction, id, &post)?.ok_or_else(resource::not_found::<Post>)?;
+        Ok((OK_RESPONSE.to_string(), serde_json::to_string(&updated)?))
+    })
+}
+
+// Fails with a 404 unless the user exists and isn't soft-deleted
+fn require_user(client: &mut impl GenericClient, user_id: i32) -> Result<(), AppError> {
+    match repo::find_user(client, user_id, false)? {
+        Some(_) => Ok(()),
+        None => Err(AppError::NotFound(
+            ErrorCode::UserNotFound,
+            "User not found".to_string(),
+        )),
+    }
+}
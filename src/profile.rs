@@ -0,0 +1,72 @@
+use std::env;
+
+// Deployment profiles. APP_ENV=dev, staging or prod picks defaults for the settings
+// that usually differ between environments, so each deployment only sets what's
+// particular to it:
+//
+//   setting               dev    staging  prod
+//   LOG_FORMAT            text   json     json
+//   CORS_ALLOWED_ORIGINS  *
+//   DEBUG_HEADER          on     on       off
+//   SEED_USERS            50
+//
+// Settings given explicitly always win. Without APP_ENV every setting keeps its own
+// default.
+
+const PROFILES: &[(&str, &[(&str, &str)])] = &[
+    (
+        "dev",
+        &[
+            ("LOG_FORMAT", "text"),
+            ("CORS_ALLOWED_ORIGINS", "*"),
+            ("DEBUG_HEADER", "on"),
+            ("SEED_USERS", "50"),
+        ],
+    ),
+    ("staging", &[("LOG_FORMAT", "json"), ("DEBUG_HEADER", "on")]),
+    ("prod", &[("LOG_FORMAT", "json"), ("DEBUG_HEADER", "off")]),
+];
+
+// Sets the APP_ENV profile's defaults for the settings that aren't set. Changing the
+// environment isn't safe while other threads may read it, so call this first thing.
+pub fn apply() {
+    let Ok(profile) = env::var("APP_ENV") else {
+        return;
+    };
+    let is_set = |name: &str| env::var(name).is_ok_and(|value| !value.is_empty());
+    for (name, value) in defaults(&profile, is_set) {
+        env::set_var(name, value);
+    }
+}
+
+// The settings the profile provides that `is_set` says aren't set yet. Nothing for
+// unknown profiles, which the configuration check reports.
+fn defaults(profile: &str, is_set: impl Fn(&str) -> bool) -> Vec<(&'static str, &'static str)> {
+    PROFILES
+        .iter()
+        .find(|(name, _)| *name == profile)
+        .map_or(&[][..], |(_, defaults)| defaults)
+        .iter()
+        .filter(|(name, _)| !is_set(name))
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explicit_settings_win_over_the_profile() {
+        assert_eq!(
+            defaults("dev", |name| name == "DEBUG_HEADER"),
+            [
+                ("LOG_FORMAT", "text"),
+                ("CORS_ALLOWED_ORIGINS", "*"),
+                ("SEED_USERS", "50")
+            ]
+        );
+        assert_eq!(defaults("prod", |_| false)[1], ("DEBUG_HEADER", "off"));
+        assert_eq!(defaults("production", |_| false), []);
+    }
+}
@@ -0,0 +1,139 @@
+use postgres::types::ToSql;
+use postgres::{Error as PostgresError, GenericClient, Row};
+use serde_json::json;
+use std::env;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::otel::{self, SpanKind};
+
+// Slow query log. Statements that take SLOW_QUERY_MS (500) or longer are logged with
+// their duration, e.g.
+//   Slow query (812.4ms): SELECT id FROM users WHERE email = $1 AND deleted_at < ?
+// Parameters are sent apart from the SQL and never logged, and literals written into the
+// SQL itself are replaced with `?`, so no user data ends up in the log.
+// SLOW_QUERY_MS=0 logs every statement.
+// With tracing on (see `otel`), every statement is also a span, with the same redacted SQL.
+
+static THRESHOLD: OnceLock<Duration> = OnceLock::new();
+
+// The statement methods of `GenericClient`, timed for the slow query log
+pub(crate) trait Timed: GenericClient {
+    fn timed_query(
+        &mut self,
+        sql: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, PostgresError> {
+        timed(sql, || self.query(sql, params))
+    }
+
+    fn timed_query_one(
+        &mut self,
+        sql: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Row, PostgresError> {
+        timed(sql, || self.query_one(sql, params))
+    }
+
+    fn timed_query_opt(
+        &mut self,
+        sql: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Option<Row>, PostgresError> {
+        timed(sql, || self.query_opt(sql, params))
+    }
+
+    fn timed_execute(
+        &mut self,
+        sql: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<u64, PostgresError> {
+        timed(sql, || self.execute(sql, params))
+    }
+}
+
+impl<C: GenericClient> Timed for C {}
+
+// Runs a statement, logging it if it's slow
+fn timed<T>(sql: &str, run: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = run();
+    let elapsed = started.elapsed();
+    let threshold = THRESHOLD.get_or_init(|| {
+        Duration::from_millis(
+            env::var("SLOW_QUERY_MS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(500),
+        )
+    });
+    if otel::is_enabled() {
+        let sql = redact(sql);
+        let operation = sql.split_whitespace().next().unwrap_or_default().to_uppercase();
+        let attributes = vec![
+            ("db.system.name", json!("postgresql")),
+            ("db.operation.name", json!(operation)),
+            ("db.query.text", json!(sql)),
+        ];
+        otel::record(operation, SpanKind::Client, started, attributes);
+    }
+    if elapsed >= *threshold {
+        log!(
+            "Slow query ({}ms): {}",
+            elapsed.as_micros() as f64 / 1000.0,
+            redact(sql)
+        );
+    }
+    result
+}
+
+// The statement on one line, with string and number literals replaced by `?` and
+// comments left out. Placeholders like `$1` and identifiers like `users_2024` stay.
+fn redact(sql: &str) -> String {
+    let mut redacted = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    let mut previous = ' ';
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                // '' inside a literal is an escaped quote
+                while let Some(c) = chars.next() {
+                    if c == '\'' && chars.next_if_eq(&'\'').is_none() {
+                        break;
+                    }
+                }
+                redacted.push('?');
+            }
+            '-' if chars.peek() == Some(&'-') => while chars.next_if(|&c| c != '\n').is_some() {},
+            c if c.is_ascii_digit() && !(previous.is_alphanumeric() || "_$".contains(previous)) => {
+                while chars.next_if(|c| c.is_ascii_digit() || *c == '.').is_some() {}
+                redacted.push('?');
+            }
+            c if c.is_whitespace() => {
+                if !redacted.ends_with(' ') {
+                    redacted.push(' ');
+                }
+            }
+            c => redacted.push(c),
+        }
+        previous = redacted.chars().next_back().unwrap_or(' ');
+    }
+    redacted.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals_are_redacted() {
+        assert_eq!(
+            redact(
+                "SELECT id FROM users_2024 -- recent\n    WHERE email = $1\n      \
+                 AND name = 'O''Brien' AND age > 42.5 LIMIT 10"
+            ),
+            "SELECT id FROM users_2024 WHERE email = $1 AND name = ? AND age > ? LIMIT ?"
+        );
+        assert_eq!(redact("now() - INTERVAL '30 days'"), "now() - INTERVAL ?");
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+#[cfg(feature = "redis")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "redis")]
+use crate::redis::{self, Redis, Reply};
+
+// Buckets beyond this count trigger a sweep of the ones that have refilled completely.
+// Should most of them still be in use, the next sweep waits until there are twice as
+// many, so requests don't each pay for a sweep that frees next to nothing.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+// Slowest refill allowed, one token a day. Lower rates, 0 included, and ones that aren't
+// finite are replaced with it, so the waits worked out from them stay finite.
+const MIN_RATE: f64 = 1.0 / 86_400.0;
+
+// Token-bucket rate limiter. Every client key gets `burst` tokens that refill at
+// `rate` tokens per second; each request spends one token.
+// Clients are told apart by IP address. `X-Api-Key` is the client's word alone, so
+// only the keys listed in RATE_LIMIT_API_KEYS get buckets of their own (see
+// `client_key`); with any other key a new key per request would never run out.
+// With Redis (see `redis`) the buckets are kept there, so the limits hold across
+// replicas; while Redis can't be reached, each replica limits on its own.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    // Keys whose clients are limited per key instead of per IP
+    api_keys: HashSet<String>,
+    buckets: Mutex<Buckets>,
+    #[cfg(feature = "redis")]
+    redis: Option<Arc<Redis>>,
+}
+
+// Spends a token from the bucket in KEYS[1] with ARGV[1] (rate) and ARGV[2] (burst),
+// as `check_at` does, returning 0 or the milliseconds until a token is available.
+// Redis' clock is used so that all replicas agree on it.
+#[cfg(feature = "redis")]
+const TOKEN_BUCKET_SCRIPT: &str = "
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(bucket[1]) or burst
+local updated = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated) * rate)
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.ceil((1 - tokens) / rate * 1000)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', tostring(now))
+redis.call('PEXPIRE', KEYS[1], math.ceil(burst / rate * 1000) + 1000)
+return wait
+";
+
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    // The count that triggers the next sweep
+    sweep_at: usize,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64, api_keys: HashSet<String>) -> Self {
+        RateLimiter {
+            rate: if rate.is_finite() { rate.max(MIN_RATE) } else { MIN_RATE },
+            burst: if burst.is_finite() { burst.max(1.0) } else { 1.0 },
+            api_keys,
+            buckets: Mutex::new(Buckets {
+                by_key: HashMap::new(),
+                sweep_at: MAX_TRACKED_CLIENTS,
+            }),
+            #[cfg(feature = "redis")]
+            redis: None,
+        }
+    }
+
+    // Reads RATE_LIMIT_RPS, RATE_LIMIT_BURST, RATE_LIMIT_BY_API_KEY and
+    // RATE_LIMIT_API_KEYS (comma-separated).
+    // Returns `None` (no limiting) unless RATE_LIMIT_RPS is set to a positive number.
+    pub fn from_env() -> Option<Self> {
+        RateLimiter::from_settings(|name| env::var(name).ok())
+    }
+
+    // Like `from_env`, with the settings looked up by `setting` (see `reload`)
+    pub fn from_settings(setting: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let rate: f64 = setting("RATE_LIMIT_RPS")?.parse().ok()?;
+        if rate <= 0.0 {
+            return None;
+        }
+        let burst = setting("RATE_LIMIT_BURST")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(rate);
+        let api_keys = match setting("RATE_LIMIT_BY_API_KEY").as_deref() {
+            Some("true") => setting("RATE_LIMIT_API_KEYS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|key| key.trim().to_string())
+                .filter(|key| !key.is_empty())
+                .collect(),
+            _ => HashSet::new(),
+        };
+
+        #[allow(unused_mut)]
+        let mut limiter = RateLimiter::new(rate, burst, api_keys);
+        #[cfg(feature = "redis")]
+        {
+            limiter.redis = redis::shared();
+        }
+        Some(limiter)
+    }
+
+    // The bucket for a client at `ip` that sent `api_key`, if any
+    pub fn client_key(&self, api_key: Option<&str>, ip: &str) -> String {
+        match api_key {
+            Some(api_key) if self.api_keys.contains(api_key) => format!("key:{}", api_key),
+            _ => format!("ip:{}", ip),
+        }
+    }
+
+    // Spends a token for `key`. On rejection returns how long until a token is available.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            match self.check_shared(redis, key) {
+                Ok(result) => return result,
+                Err(e) => log!("Rate limiting locally: {}", e),
+            }
+        }
+        self.check_at(key, Instant::now())
+    }
+
+    #[cfg(feature = "redis")]
+    fn check_shared(&self, redis: &Redis, key: &str) -> std::io::Result<Result<(), Duration>> {
+        let key = redis.key(&format!("rate:{}", key));
+        let (rate, burst) = (self.rate.to_string(), self.burst.to_string());
+        let reply = redis.command(&[
+            b"EVAL",
+            TOKEN_BUCKET_SCRIPT.as_bytes(),
+            b"1",
+            key.as_bytes(),
+            rate.as_bytes(),
+            burst.as_bytes(),
+        ])?;
+        match reply {
+            Reply::Integer(0) => Ok(Ok(())),
+            Reply::Integer(wait) => Ok(Err(Duration::from_millis(wait as u64))),
+            reply => Err(std::io::Error::other(format!(
+                "unexpected reply {:?}",
+                reply
+            ))),
+        }
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        if buckets.by_key.len() >= buckets.sweep_at {
+            let full_after = Duration::try_from_secs_f64(self.burst / self.rate).unwrap_or(Duration::MAX);
+            buckets.by_key.retain(|_, bucket| now.duration_since(bucket.updated) < full_after);
+            buckets.sweep_at = (buckets.by_key.len() * 2).max(MAX_TRACKED_CLIENTS);
+        }
+
+        let bucket = buckets.by_key.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::try_from_secs_f64((1.0 - bucket.tokens) / self.rate).unwrap_or(Duration::MAX))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_a_burst_then_rejects() {
+        let limiter = RateLimiter::new(1.0, 3.0, HashSet::new());
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at("ip:1.2.3.4", now).is_ok());
+        }
+        let retry_after = limiter.check_at("ip:1.2.3.4", now).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+
+        // Other clients have their own bucket
+        assert!(limiter.check_at("ip:5.6.7.8", now).is_ok());
+    }
+
+    #[test]
+    fn tokens_refill_over_time() {
+        let limiter = RateLimiter::new(2.0, 1.0, HashSet::new());
+        let now = Instant::now();
+
+        assert!(limiter.check_at("client", now).is_ok());
+        assert!(limiter.check_at("client", now).is_err());
+        assert!(limiter
+            .check_at("client", now + Duration::from_millis(500))
+            .is_ok());
+    }
+
+    #[test]
+    fn only_known_api_keys_get_their_own_bucket() {
+        let settings = |name: &str| match name {
+            "RATE_LIMIT_RPS" => Some("1".to_string()),
+            "RATE_LIMIT_BY_API_KEY" => Some("true".to_string()),
+            "RATE_LIMIT_API_KEYS" => Some("partner-a, partner-b".to_string()),
+            _ => None,
+        };
+        let limiter = RateLimiter::from_settings(settings).unwrap();
+        assert_eq!(limiter.client_key(Some("partner-b"), "1.2.3.4"), "key:partner-b");
+        assert_eq!(limiter.client_key(Some("made-up"), "1.2.3.4"), "ip:1.2.3.4");
+        assert_eq!(limiter.client_key(None, "1.2.3.4"), "ip:1.2.3.4");
+    }
+
+    #[test]
+    fn sweeps_wait_while_most_clients_are_active() {
+        let limiter = RateLimiter::new(1.0, 1.0, HashSet::new());
+        let now = Instant::now();
+        for client in 0..MAX_TRACKED_CLIENTS {
+            assert!(limiter.check_at(&client.to_string(), now).is_ok());
+        }
+        // Nothing has refilled, so the sweep frees nothing and the next one waits
+        assert!(limiter.check_at("another", now).is_ok());
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.by_key.len(), MAX_TRACKED_CLIENTS + 1);
+        assert_eq!(buckets.sweep_at, 2 * MAX_TRACKED_CLIENTS);
+        drop(buckets);
+
+        // Once they have, they're swept
+        let later = now + Duration::from_secs(2);
+        for client in MAX_TRACKED_CLIENTS..2 * MAX_TRACKED_CLIENTS {
+            assert!(limiter.check_at(&client.to_string(), later).is_ok());
+        }
+        assert!(limiter.check_at("last", later).is_ok());
+        assert_eq!(limiter.buckets.lock().unwrap().by_key.len(), MAX_TRACKED_CLIENTS + 1);
+    }
+
+    #[test]
+    fn rates_of_zero_still_give_a_wait() {
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let limiter = RateLimiter::new(rate, 1.0, HashSet::new());
+            let now = Instant::now();
+            assert!(limiter.check_at("client", now).is_ok());
+            assert!(limiter.check_at("client", now).unwrap_err() <= Duration::from_secs(86_400));
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+// State shared by every replica through Redis (the `redis` feature), for deployments
+// running several containers behind a load balancer: the rate limits (see
+// `rate_limit`) and the response cache (see `response_cache`) use it instead of their
+// in-process state when REDIS_URL (`redis://[:password@]host[:port][/db]`) is set.
+// Keys start with REDIS_KEY_PREFIX (`rust_docker_pg_crud`), so several apps can share
+// a Redis. Commands time out after REDIS_TIMEOUT_MS (500). Redis being unreachable
+// never fails a request: callers fall back to their local state and log why.
+// This is a minimal RESP client: just enough for the few commands used here.
+
+// Connections kept open for reuse; more are opened when they're all busy
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+pub struct Redis {
+    host: String,
+    port: u16,
+    password: Option<String>,
+    db: u32,
+    timeout: Duration,
+    // Prepended to every key, with a `:`
+    prefix: String,
+    idle: Mutex<Vec<BufReader<TcpStream>>>,
+}
+
+// A reply from Redis
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Status(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn into_string(self) -> Option<String> {
+        match self {
+            Reply::Status(value) => Some(value),
+            Reply::Bulk(value) => value.and_then(|value| String::from_utf8(value).ok()),
+            Reply::Integer(value) => Some(value.to_string()),
+            Reply::Array(_) => None,
+        }
+    }
+}
+
+// The client configured by REDIS_URL, shared by everything that uses Redis.
+// None when it isn't set or can't be parsed (which is logged once).
+pub fn shared() -> Option<Arc<Redis>> {
+    static SHARED: OnceLock<Option<Arc<Redis>>> = OnceLock::new();
+    SHARED
+        .get_or_init(|| {
+            let url = env::var("REDIS_URL").ok().filter(|url| !url.is_empty())?;
+            let timeout = env::var("REDIS_TIMEOUT_MS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(500);
+            let prefix =
+                env::var("REDIS_KEY_PREFIX").unwrap_or_else(|_| "rust_docker_pg_crud".to_string());
+            match Redis::new(&url, Duration::from_millis(timeout), prefix) {
+                Ok(redis) => {
+                    log!("Sharing rate limits and cached responses through Redis");
+                    Some(Arc::new(redis))
+                }
+                Err(e) => {
+                    log!("Not using Redis: invalid REDIS_URL: {}", e);
+                    None
+                }
+            }
+        })
+        .clone()
+}
+
+impl Redis {
+    pub fn new(url: &str, timeout: Duration, prefix: String) -> Result<Redis, String> {
+        let rest = url
+            .strip_prefix("redis://")
+            .ok_or("expected redis://[:password@]host[:port][/db]")?;
+        let (authority, db) = rest.split_once('/').unwrap_or((rest, ""));
+        let (password, address) = match authority.rsplit_once('@') {
+            Some((credentials, address)) => {
+                // `user:password` or `:password`; only the password is used
+                let password = credentials.rsplit(':').next().unwrap_or(credentials);
+                (Some(password.to_string()), address)
+            }
+            None => (None, authority),
+        };
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| "invalid port")?),
+            None => (address, 6379),
+        };
+        if host.is_empty() {
+            return Err("missing host".to_string());
+        }
+        let db = match db {
+            "" => 0,
+            db => db.parse().map_err(|_| "invalid database number")?,
+        };
+        Ok(Redis {
+            host: host.to_string(),
+            port,
+            password,
+            db,
+            timeout,
+            prefix,
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    // A key in this app's namespace
+    pub fn key(&self, key: &str) -> String {
+        format!("{}:{}", self.prefix, key)
+    }
+
+    // Runs a command, e.g. `["INCR", "counter"]`. An error reply is an `io::Error` too.
+    pub fn command(&self, args: &[&[u8]]) -> io::Result<Reply> {
+        let mut connection = match self.take_idle() {
+            Some(connection) => connection,
+            None => self.connect()?,
+        };
+        match send(&mut connection, args) {
+            Ok(reply) => {
+                self.put_idle(connection);
+                Ok(reply)
+            }
+            // Error replies leave the connection usable; anything else drops it
+            Err(e) if e.kind() == io::ErrorKind::Other => {
+                self.put_idle(connection);
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn take_idle(&self) -> Option<BufReader<TcpStream>> {
+        self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop()
+    }
+
+    fn put_idle(&self, connection: BufReader<TcpStream>) {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(connection);
+        }
+    }
+
+    fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for host"))?;
+        let stream = TcpStream::connect_timeout(&address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_nodelay(true)?;
+        let mut connection = BufReader::new(stream);
+        if let Some(password) = &self.password {
+            send(&mut connection, &[b"AUTH", password.as_bytes()])?;
+        }
+        if self.db != 0 {
+            send(
+                &mut connection,
+                &[b"SELECT", self.db.to_string().as_bytes()],
+            )?;
+        }
+        Ok(connection)
+    }
+}
+
+// Sends one command and reads its reply
+fn send(connection: &mut BufReader<TcpStream>, args: &[&[u8]]) -> io::Result<Reply> {
+    connection.get_mut().write_all(&encode(args))?;
+    read_reply(connection)
+}
+
+// A command as a RESP array of bulk strings
+fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    command
+}
+
+fn read_reply(reader: &mut impl BufRead) -> io::Result<Reply> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let line = line.trim_end_matches("\r\n");
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed reply from Redis");
+    let mut chars = line.chars();
+    let kind = chars.next().ok_or_else(invalid)?;
+    let rest = chars.as_str();
+    let length = || rest.parse::<i64>().map_err(|_| invalid());
+    match kind {
+        '+' => Ok(Reply::Status(rest.to_string())),
+        // Error replies, like failing scripts, are reported as `ErrorKind::Other`
+        '-' => Err(io::Error::other(format!("Redis: {}", rest))),
+        ':' => Ok(Reply::Integer(length()?)),
+        '$' if length()? < 0 => Ok(Reply::Bulk(None)),
+        '$' => {
+            let mut value = vec![0; length()? as usize + 2];
+            reader.read_exact(&mut value)?;
+            value.truncate(value.len() - 2);
+            Ok(Reply::Bulk(Some(value)))
+        }
+        '*' if length()? < 0 => Ok(Reply::Array(Vec::new())),
+        '*' => (0..length()?)
+            .map(|_| read_reply(reader))
+            .collect::<io::Result<_>>()
+            .map(Reply::Array),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn urls_are_parsed() {
+        let redis = Redis::new(
+            "redis://:secret@cache.internal:6380/2",
+            Duration::from_millis(500),
+            "app".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            (redis.host.as_str(), redis.port, redis.db),
+            ("cache.internal", 6380, 2)
+        );
+        assert_eq!(redis.password.as_deref(), Some("secret"));
+        assert_eq!(redis.key("rate:ip:1.2.3.4"), "app:rate:ip:1.2.3.4");
+
+        let redis = Redis::new("redis://localhost", Duration::ZERO, String::new()).unwrap();
+        assert_eq!((redis.port, redis.db, redis.password), (6379, 0, None));
+        for invalid in [
+            "localhost:6379",
+            "redis://",
+            "redis://host:port",
+            "redis://host/x",
+        ] {
+            assert!(
+                Redis::new(invalid, Duration::ZERO, String::new()).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn replies_are_read() {
+        let mut reader: &[u8] = b"*3\r\n:7\r\n$5\r\nhello\r\n$-1\r\n+OK\r\n-ERR nope\r\n";
+        assert_eq!(
+            read_reply(&mut reader).unwrap(),
+            Reply::Array(vec![
+                Reply::Integer(7),
+                Reply::Bulk(Some(b"hello".to_vec())),
+                Reply::Bulk(None),
+            ])
+        );
+        assert_eq!(
+            read_reply(&mut reader).unwrap(),
+            Reply::Status("OK".to_string())
+        );
+        assert_eq!(
+            read_reply(&mut reader).unwrap_err().kind(),
+            io::ErrorKind::Other
+        );
+    }
+
+    #[test]
+    fn commands_are_sent_as_resp_arrays() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://:pw@{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let expected = [
+                "*2\r\n$4\r\nAUTH\r\n$2\r\npw\r\n",
+                "*2\r\n$4\r\nINCR\r\n$5\r\napp:n\r\n",
+            ];
+            let replies = ["+OK\r\n", ":1\r\n"];
+            for (expected, reply) in expected.iter().zip(replies) {
+                let mut request = vec![0; expected.len()];
+                stream.read_exact(&mut request).unwrap();
+                assert_eq!(String::from_utf8(request).unwrap(), *expected);
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+
+        let redis = Redis::new(&url, Duration::from_secs(5), "app".to_string()).unwrap();
+        let key = redis.key("n");
+        assert_eq!(
+            redis.command(&[b"INCR", key.as_bytes()]).unwrap(),
+            Reply::Integer(1)
+        );
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{self, Problem};
+use crate::cors::Cors;
+use crate::rate_limit::RateLimiter;
+use crate::server::AppState;
+
+// Settings changed without a restart. CONFIG_FILE names a file of KEY=VALUE lines (the
+// format of `docker run --env-file`, `#` starts a comment) whose settings override the
+// environment's. On SIGHUP (`docker kill --signal HUP <container>`) the server rereads
+// it and switches to its rate limits (RATE_LIMIT_*) and CORS settings (CORS_*) while
+// connections stay open and requests in flight finish as they were.
+//
+// The reloaded settings are checked like at startup; when anything is wrong the old ones
+// stay. Rate limit buckets start over with the new limits. Other settings changed in the
+// file take effect on the next restart, and settings removed from it keep their values
+// until then. There are no log levels to reload: everything is always logged.
+
+// How often the watcher thread checks whether a SIGHUP arrived
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Prefixes of the settings a reload applies
+const RELOADABLE: &[&str] = &["RATE_LIMIT_", "CORS_"];
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Sets the settings in CONFIG_FILE, if set, in the environment. Changing the environment
+// isn't safe while other threads may read it, so call this first thing.
+pub fn apply_file() -> io::Result<()> {
+    let Some(path) = env::var("CONFIG_FILE").ok().filter(|path| !path.is_empty()) else {
+        return Ok(());
+    };
+    for (name, value) in read(&path)? {
+        env::set_var(name, value);
+    }
+    Ok(())
+}
+
+// Installs the SIGHUP handler and starts the thread that reloads the settings of `state`
+pub(crate) fn install(state: Arc<AppState>) {
+    // Signal handlers may only do async-signal-safe things, so this one just raises a flag
+    extern "C" fn request(_signal: libc::c_int) {
+        REQUESTED.store(true, Ordering::SeqCst);
+    }
+    let handler = request as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGHUP, handler);
+    }
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        if REQUESTED.swap(false, Ordering::SeqCst) {
+            reload(&state);
+        }
+    });
+}
+
+fn reload(state: &AppState) {
+    let Some(path) = env::var("CONFIG_FILE").ok().filter(|path| !path.is_empty()) else {
+        log!("Not reloading the settings: CONFIG_FILE isn't set");
+        return;
+    };
+    let file = match read(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            log!("Not reloading the settings: error reading {}: {}", path, e);
+            return;
+        }
+    };
+    let reloaded = match load(&file, |name| env::var(name).ok(), state.mock.is_none()) {
+        Ok(reloaded) => reloaded,
+        Err(problems) => {
+            log!(
+                "Not reloading the settings: {} has {} problem(s)",
+                path,
+                problems.len()
+            );
+            for problem in problems {
+                log!(
+                    "  {}: {} (hint: {})",
+                    problem.setting,
+                    problem.message,
+                    problem.hint
+                );
+            }
+            return;
+        }
+    };
+    *state
+        .rate_limiter
+        .write()
+        .unwrap_or_else(|e| e.into_inner()) = reloaded.rate_limiter;
+    *state.cors.write().unwrap_or_else(|e| e.into_inner()) = reloaded.cors;
+    log!("Reloaded the rate limits and CORS settings from {}", path);
+    if !reloaded.pending.is_empty() {
+        log!(
+            "Changed settings that take effect on restart: {}",
+            reloaded.pending.join(", ")
+        );
+    }
+}
+
+struct Reloaded {
+    rate_limiter: Option<RateLimiter>,
+    cors: Option<Cors>,
+    // Settings in the file, other than the reloadable ones, that differ from the
+    // environment's
+    pending: Vec<String>,
+}
+
+// The settings in `file` over those `env` looks up, or what's wrong with them
+fn load(
+    file: &HashMap<String, String>,
+    env: impl Fn(&str) -> Option<String>,
+    needs_database: bool,
+) -> Result<Reloaded, Vec<Problem>> {
+    let setting = |name: &str| file.get(name).cloned().or_else(|| env(name));
+    let problems = config::problems(setting, needs_database);
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    let mut pending: Vec<String> = file
+        .iter()
+        .filter(|(name, _)| !RELOADABLE.iter().any(|prefix| name.starts_with(prefix)))
+        .filter(|(name, value)| env(name).as_ref() != Some(*value))
+        .map(|(name, _)| name.clone())
+        .collect();
+    pending.sort();
+    Ok(Reloaded {
+        rate_limiter: RateLimiter::from_settings(setting),
+        cors: Cors::from_settings(setting),
+        pending,
+    })
+}
+
+fn read(path: &str) -> io::Result<HashMap<String, String>> {
+    parse(&fs::read_to_string(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Parses KEY=VALUE lines. Blank lines and lines starting with `#` are skipped; values
+// are taken as they are, quotes included.
+fn parse(contents: &str) -> Result<HashMap<String, String>, String> {
+    let mut settings = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => {
+                settings.insert(name.trim().to_string(), value.trim().to_string());
+            }
+            _ => return Err(format!("line {}: expected KEY=VALUE", number + 1)),
+        }
+    }
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_files_are_parsed() {
+        let file = parse("# Limits\nRATE_LIMIT_RPS = 5\n\nCORS_ALLOWED_ORIGINS=\n").unwrap();
+        assert_eq!(file["RATE_LIMIT_RPS"], "5");
+        assert_eq!(file["CORS_ALLOWED_ORIGINS"], "");
+        assert_eq!(
+            parse("RATE_LIMIT_RPS=5\nRATE_LIMIT_BURST").unwrap_err(),
+            "line 2: expected KEY=VALUE"
+        );
+        assert!(parse("=5").is_err());
+    }
+
+    #[test]
+    fn the_file_overrides_the_environment() {
+        let env = |name: &str| match name {
+            "RATE_LIMIT_RPS" => Some("1".to_string()),
+            "CORS_ALLOWED_ORIGINS" => Some("*".to_string()),
+            "DB_POOL_SIZE" => Some("10".to_string()),
+            _ => None,
+        };
+        let file = parse("RATE_LIMIT_BURST=3\nCORS_ALLOWED_ORIGINS=\nDB_POOL_SIZE=20").unwrap();
+        let reloaded = load(&file, env, false).ok().unwrap();
+        let limiter = reloaded.rate_limiter.unwrap();
+        assert!((0..3).all(|_| limiter.check("ip:127.0.0.1").is_ok()));
+        assert!(limiter.check("ip:127.0.0.1").is_err());
+        assert!(reloaded.cors.is_none());
+        assert_eq!(reloaded.pending, ["DB_POOL_SIZE"]);
+
+        // Nothing changes when the file has mistakes
+        let file = parse("RATE_LIMIT_RPS=fast").unwrap();
+        let problems = load(&file, env, false).err().unwrap();
+        assert_eq!(problems[0].setting, "RATE_LIMIT_RPS");
+    }
+}
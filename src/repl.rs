@@ -0,0 +1,136 @@
+use postgres::{Client, NoTls};
+use serde_json::Value;
+use std::io::{self, BufRead, Write};
+
+use crate::output::{self, OutputFormat};
+use crate::repo::{self, Deletion, UserFilter};
+use crate::config::get_db_url;
+use crate::models::User;
+
+const HELP: &str = "Commands:
+  list users [--limit N] [--include-deleted] [--created-after TS] [--created-before TS]
+  get user ID
+  delete user ID
+  restore user ID
+  help
+  quit";
+
+// Entry point for `app repl`: a small interactive shell over the repository layer,
+// handy inside the container with `docker exec -it <container> app repl`.
+pub fn run(format: OutputFormat) -> io::Result<()> {
+    let mut client = Client::connect(&get_db_url(), NoTls).map_err(io::Error::other)?;
+    eprintln!("Connected. Type `help` for commands.");
+
+    let stdin = io::stdin();
+    loop {
+        eprint!("> ");
+        io::stderr().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => continue,
+            ["quit"] | ["exit"] => return Ok(()),
+            ["help"] => eprintln!("{}", HELP),
+            words => {
+                if let Err(message) = execute(&mut client, words, format) {
+                    eprintln!("{}", message);
+                }
+            }
+        }
+    }
+}
+
+fn execute(client: &mut Client, words: &[&str], format: OutputFormat) -> Result<(), String> {
+    match words {
+        ["list", "users", options @ ..] => {
+            let mut filter = UserFilter::default();
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                match *option {
+                    "--limit" => {
+                        let value = options.next().ok_or("--limit needs a number")?;
+                        filter.limit = Some(value.parse().map_err(|_| "--limit needs a number")?);
+                    }
+                    "--include-deleted" => filter.include_deleted = true,
+                    "--created-after" => {
+                        let value = options.next().ok_or("--created-after needs a timestamp")?;
+                        filter.created_after = Some(value.to_string());
+                    }
+                    "--created-before" => {
+                        let value = options.next().ok_or("--created-before needs a timestamp")?;
+                        filter.created_before = Some(value.to_string());
+                    }
+                    other => return Err(format!("Unknown option {}", other)),
+                }
+            }
+
+            let users = repo::list_users(client, &filter).map_err(|e| e.to_string())?;
+            print_users(&users, format);
+        }
+        ["get", "user", id] => {
+            let id = parse_id(id)?;
+            match repo::find_user(client, id, true).map_err(|e| e.to_string())? {
+                Some(user) => print_users(&[user], format),
+                None => return Err(format!("User {} not found", id)),
+            }
+        }
+        ["delete", "user", id] => {
+            let id = parse_id(id)?;
+            let deletion = repo::with_transaction(client, |transaction| repo::delete_user(transaction, id));
+            match deletion.map_err(|e| e.to_string())? {
+                Deletion::Deleted => {}
+                Deletion::NotFound => return Err(format!("User {} not found", id)),
+                Deletion::Restricted(dependents) => {
+                    return Err(format!("User {} has related records: {:?}", id, dependents))
+                }
+            }
+            eprintln!("User {} deleted", id);
+        }
+        ["restore", "user", id] => {
+            let id = parse_id(id)?;
+            if !repo::restore_user(client, id).map_err(|e| e.to_string())? {
+                return Err(format!("Deleted user {} not found", id));
+            }
+            eprintln!("User {} restored", id);
+        }
+        _ => return Err("Unknown command. Type `help` for commands.".to_string()),
+    }
+    Ok(())
+}
+
+fn parse_id(id: &str) -> Result<i32, String> {
+    id.parse().map_err(|_| format!("Invalid ID: {}", id))
+}
+
+pub(crate) fn print_users(users: &[User], format: OutputFormat) {
+    let rows: Vec<Vec<Value>> = users
+        .iter()
+        .map(|user| {
+            vec![
+                user.id.into(),
+                user.name.clone().into(),
+                user.email.clone().into(),
+                user.created_at.clone().into(),
+                user.updated_at.clone().into(),
+                user.deleted_at.clone().into(),
+            ]
+        })
+        .collect();
+    output::print_table(
+        format,
+        &[
+            "id",
+            "name",
+            "email",
+            "created_at",
+            "updated_at",
+            "deleted_at",
+        ],
+        &rows,
+    );
+}
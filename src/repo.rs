@@ -0,0 +1,595 @@
+use postgres::types::ToSql;
+use postgres::{Client, Error as PostgresError, GenericClient, Row, Transaction};
+
+use crate::delete_policy::{self, Dependents};
+use crate::events;
+use crate::models::{DayCount, DomainCount, User, UserStats, STATS_DAYS};
+use crate::query_log::Timed;
+
+// Queries for the `users` table, shared by the HTTP handlers and the CLI tools.
+// Soft-deleted users are skipped unless `include_deleted` is set.
+
+// Runs `work` in a transaction: committed if it returns Ok, rolled back if it returns
+// Err, so a failure halfway through never leaves partial changes behind.
+// `E` can be any error that database errors convert into, letting callers abort
+// with their own errors too.
+pub fn with_transaction<T, E>(
+    client: &mut Client,
+    work: impl FnOnce(&mut Transaction) -> Result<T, E>,
+) -> Result<T, E>
+where
+    E: From<PostgresError>,
+{
+    // Change events are published only if the transaction commits (see `events`)
+    events::discard_staged();
+    let mut transaction = client.transaction()?;
+    match work(&mut transaction) {
+        Ok(value) => {
+            let committed = transaction.commit();
+            if committed.is_ok() {
+                events::publish_staged();
+            }
+            committed?;
+            Ok(value)
+        }
+        Err(e) => {
+            events::discard_staged();
+            // The original error matters more than a failed rollback, and dropping
+            // the transaction rolls back anyway
+            let _ = transaction.rollback();
+            Err(e)
+        }
+    }
+}
+
+// Columns selected for a `User`, in the order `user_from_row` expects.
+// Rows are read with `try_get`, so an unexpected column type is an error, not a panic.
+// Timestamps are rendered as ISO 8601 strings by Postgres.
+const USER_COLUMNS: &str = "id, name, email, to_json(created_at) #>> '{}', \
+    to_json(updated_at) #>> '{}', to_json(deleted_at) #>> '{}', version, verified";
+
+fn user_from_row(row: &Row) -> Result<User, PostgresError> {
+    Ok(User {
+        id: Some(row.try_get(0)?),
+        name: row.try_get(1)?,
+        email: row.try_get(2)?,
+        created_at: row.try_get(3)?,
+        updated_at: row.try_get(4)?,
+        deleted_at: row.try_get(5)?,
+        version: Some(row.try_get(6)?),
+        verified: Some(row.try_get(7)?),
+    })
+}
+
+// Fields `?fields=` can select, with the SQL each is read with (as in `USER_COLUMNS`)
+const USER_FIELDS: &[(&str, &str)] = &[
+    ("id", "id"),
+    ("name", "name"),
+    ("email", "email"),
+    ("created_at", "to_json(created_at) #>> '{}'"),
+    ("updated_at", "to_json(updated_at) #>> '{}'"),
+    ("deleted_at", "to_json(deleted_at) #>> '{}'"),
+    ("version", "version"),
+    ("verified", "verified"),
+];
+
+// A sparse fieldset: the user fields a client asked for, kept in the order of a full
+// `User`. Postgres builds each user's JSON object from just those columns, leaving out
+// nulls like `User` does.
+#[derive(Debug, PartialEq)]
+pub struct Fields(Vec<&'static str>);
+
+impl Fields {
+    // Parses a comma-separated list like `id,email`
+    pub fn parse(list: &str) -> Result<Fields, String> {
+        let requested: Vec<&str> = list.split(',').map(str::trim).filter(|name| !name.is_empty()).collect();
+        if requested.is_empty() {
+            return Err("fields needs at least one field".to_string());
+        }
+        if let Some(unknown) = requested.iter().find(|name| !USER_FIELDS.iter().any(|(field, _)| field == *name)) {
+            return Err(format!("Unknown field: {}", unknown));
+        }
+        Ok(Fields(
+            USER_FIELDS
+                .iter()
+                .map(|(field, _)| *field)
+                .filter(|field| requested.contains(field))
+                .collect(),
+        ))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains(&name)
+    }
+
+    // A single column with each user as a JSON object
+    fn select_list(&self) -> String {
+        let pairs: Vec<String> = USER_FIELDS
+            .iter()
+            .filter(|(field, _)| self.contains(field))
+            .map(|(field, sql)| format!("'{}', {}", field, sql))
+            .collect();
+        format!("json_strip_nulls(json_build_object({}))::text", pairs.join(", "))
+    }
+}
+
+// Narrows down `list_users`. Timestamps are passed through to Postgres as text,
+// so any format it accepts works; a malformed one fails with `INVALID_DATETIME_FORMAT`.
+#[derive(Default)]
+pub struct UserFilter {
+    pub include_deleted: bool,
+    pub limit: Option<i64>,
+    pub offset: i64,
+    pub created_after: Option<String>,
+    pub created_before: Option<String>,
+    // Exact matches; email is compared case-insensitively
+    pub email: Option<String>,
+    pub name: Option<String>,
+    // Only users who have (or haven't) confirmed their email address
+    pub verified: Option<bool>,
+}
+
+// WHERE clause shared by `list_users` and `count_users`, using parameters $1 to $6
+const USER_FILTER: &str = "($1 OR deleted_at IS NULL)
+    AND ($2::text IS NULL OR created_at > $2::text::timestamptz)
+    AND ($3::text IS NULL OR created_at < $3::text::timestamptz)
+    AND ($4::text IS NULL OR lower(email) = lower($4))
+    AND ($5::text IS NULL OR name = $5)
+    AND ($6::bool IS NULL OR verified = $6)";
+
+fn list_query(select_list: &str) -> String {
+    format!(
+        "SELECT {} FROM users WHERE {} ORDER BY id LIMIT $7 OFFSET $8",
+        select_list, USER_FILTER
+    )
+}
+
+fn list_params(filter: &UserFilter) -> [&(dyn ToSql + Sync); 8] {
+    [
+        &filter.include_deleted,
+        &filter.created_after,
+        &filter.created_before,
+        &filter.email,
+        &filter.name,
+        &filter.verified,
+        &filter.limit,
+        &filter.offset,
+    ]
+}
+
+pub fn list_users(
+    client: &mut impl GenericClient,
+    filter: &UserFilter,
+) -> Result<Vec<User>, PostgresError> {
+    let rows = client.timed_query(&list_query(USER_COLUMNS), &list_params(filter))?;
+    rows.iter().map(user_from_row).collect()
+}
+
+// Like `list_users`, with each user as a JSON object of just the selected fields
+pub fn list_user_fields(
+    client: &mut impl GenericClient,
+    filter: &UserFilter,
+    fields: &Fields,
+) -> Result<Vec<String>, PostgresError> {
+    let rows = client.timed_query(&list_query(&fields.select_list()), &list_params(filter))?;
+    rows.iter().map(|row| row.try_get(0)).collect()
+}
+
+// Like `list_users`, but reads the rows through a portal (a server-side cursor) and
+// hands them to `visit` at most `batch_size` at a time, so memory use stays flat no
+// matter how many users match. Portals only live as long as their transaction.
+pub fn for_each_user_batch<E: From<PostgresError>>(
+    transaction: &mut Transaction,
+    filter: &UserFilter,
+    batch_size: i32,
+    visit: impl FnMut(Vec<User>) -> Result<(), E>,
+) -> Result<(), E> {
+    for_each_batch(transaction, &list_query(USER_COLUMNS), filter, batch_size, user_from_row, visit)
+}
+
+// Like `for_each_user_batch`, with each user as a JSON object of just the selected fields
+pub fn for_each_user_fields_batch<E: From<PostgresError>>(
+    transaction: &mut Transaction,
+    filter: &UserFilter,
+    fields: &Fields,
+    batch_size: i32,
+    visit: impl FnMut(Vec<String>) -> Result<(), E>,
+) -> Result<(), E> {
+    let query = list_query(&fields.select_list());
+    for_each_batch(transaction, &query, filter, batch_size, |row| row.try_get(0), visit)
+}
+
+fn for_each_batch<T, E: From<PostgresError>>(
+    transaction: &mut Transaction,
+    query: &str,
+    filter: &UserFilter,
+    batch_size: i32,
+    from_row: impl Fn(&Row) -> Result<T, PostgresError>,
+    mut visit: impl FnMut(Vec<T>) -> Result<(), E>,
+) -> Result<(), E> {
+    let portal = transaction.bind(query, &list_params(filter))?;
+    loop {
+        let rows = transaction.query_portal(&portal, batch_size)?;
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let last = rows.len() < batch_size as usize;
+        visit(rows.iter().map(&from_row).collect::<Result<_, _>>()?)?;
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+// Turns what a user typed into a prefix query matching every word in it, so `ada lov`
+// finds Ada Lovelace. None if there are no words to search for.
+pub fn search_query(terms: &str) -> Option<String> {
+    let words: Vec<String> = terms
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("{}:*", word.to_lowercase()))
+        .collect();
+    (!words.is_empty()).then(|| words.join(" & "))
+}
+
+// Live users matching a `search_query` on name and email, best matches first. Uses
+// the GIN index on the `search` column; a name match ranks above an email match.
+pub fn search_users(
+    client: &mut impl GenericClient,
+    query: &str,
+    limit: i64,
+) -> Result<Vec<User>, PostgresError> {
+    let query_sql = format!(
+        "SELECT {} FROM users, to_tsquery('simple', $1) query
+         WHERE deleted_at IS NULL AND search @@ query
+         ORDER BY ts_rank(search, query) DESC, id LIMIT $2",
+        USER_COLUMNS
+    );
+    let rows = client.timed_query(&query_sql, &[&query, &limit])?;
+    rows.iter().map(user_from_row).collect()
+}
+
+// Counts of the users matching the filter for GET /users/stats, ignoring its limit
+// and offset, in one query: a row for the total, one per domain, and one per day
+pub fn user_stats(client: &mut impl GenericClient, filter: &UserFilter) -> Result<UserStats, PostgresError> {
+    let query = format!(
+        "WITH matching AS (
+             SELECT lower(split_part(email, '@', 2)) AS domain,
+                    (created_at AT TIME ZONE 'UTC')::date AS day
+             FROM users WHERE {}
+         ), days AS (
+             SELECT day::date FROM generate_series(
+                 (now() AT TIME ZONE 'UTC')::date - ($7 - 1),
+                 (now() AT TIME ZONE 'UTC')::date,
+                 interval '1 day'
+             ) AS day
+         )
+         SELECT 'total', NULL, count(*) FROM matching
+         UNION ALL
+         SELECT 'domain', domain, count(*) FROM matching GROUP BY domain
+         UNION ALL
+         SELECT 'day', to_char(days.day, 'YYYY-MM-DD'), count(matching.day)
+         FROM days LEFT JOIN matching ON matching.day = days.day GROUP BY days.day",
+        USER_FILTER
+    );
+    let rows = client.timed_query(
+        &query,
+        &[
+            &filter.include_deleted,
+            &filter.created_after,
+            &filter.created_before,
+            &filter.email,
+            &filter.name,
+            &filter.verified,
+            &STATS_DAYS,
+        ],
+    )?;
+    let (mut total, mut by_domain, mut created_per_day) = (0, Vec::new(), Vec::new());
+    for row in &rows {
+        let (kind, key, count): (&str, Option<String>, i64) = (row.try_get(0)?, row.try_get(1)?, row.try_get(2)?);
+        let key = key.unwrap_or_default();
+        match kind {
+            "domain" => by_domain.push(DomainCount { domain: key, count }),
+            "day" => created_per_day.push(DayCount { date: key, count }),
+            _ => total = count,
+        }
+    }
+    Ok(UserStats::new(total, by_domain, created_per_day))
+}
+
+// Number of users matching the filter, ignoring its limit and offset
+pub fn count_users(
+    client: &mut impl GenericClient,
+    filter: &UserFilter,
+) -> Result<i64, PostgresError> {
+    let query = format!("SELECT count(*) FROM users WHERE {}", USER_FILTER);
+    let row = client.timed_query_one(
+        &query,
+        &[
+            &filter.include_deleted,
+            &filter.created_after,
+            &filter.created_before,
+            &filter.email,
+            &filter.name,
+            &filter.verified,
+        ],
+    )?;
+    row.try_get(0)
+}
+
+pub fn find_user(
+    client: &mut impl GenericClient,
+    id: i32,
+    include_deleted: bool,
+) -> Result<Option<User>, PostgresError> {
+    let query = format!(
+        "SELECT {} FROM users WHERE id = $1 AND ($2 OR deleted_at IS NULL)",
+        USER_COLUMNS
+    );
+    let row = client.timed_query_opt(&query, &[&id, &include_deleted])?;
+    row.as_ref().map(user_from_row).transpose()
+}
+
+// Columns a bulk update can select users by
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Field {
+    Name,
+    Email,
+    // The part after `@`, compared case-insensitively
+    EmailDomain,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Op {
+    Eq,
+    Ne,
+    StartsWith,
+    EndsWith,
+    Contains,
+}
+
+// One `field op value` condition; conditions are combined with AND
+#[derive(PartialEq, Debug)]
+pub struct Predicate {
+    pub field: Field,
+    pub op: Op,
+    pub value: String,
+}
+
+impl Predicate {
+    // SQL for this condition with its value bound to parameter `$n`
+    fn sql(&self, n: usize) -> String {
+        let column = match self.field {
+            Field::Name => "name",
+            Field::Email => "email",
+            Field::EmailDomain => "lower(split_part(email, '@', 2))",
+        };
+        let value = match self.field {
+            Field::EmailDomain => format!("lower(${})", n),
+            _ => format!("${}", n),
+        };
+        match self.op {
+            Op::Eq => format!("{} = {}", column, value),
+            Op::Ne => format!("{} <> {}", column, value),
+            Op::StartsWith => format!("starts_with({}, {})", column, value),
+            Op::EndsWith => format!("right({}, length({})) = {}", column, value, value),
+            Op::Contains => format!("strpos({}, {}) > 0", column, value),
+        }
+    }
+}
+
+// Live users matching every predicate, locked for update, at most `limit` of them
+pub fn lock_users_matching(
+    client: &mut impl GenericClient,
+    predicates: &[Predicate],
+    limit: i64,
+) -> Result<Vec<User>, PostgresError> {
+    let conditions: Vec<String> = predicates
+        .iter()
+        .enumerate()
+        .map(|(i, predicate)| predicate.sql(i + 2))
+        .collect();
+    let query = format!(
+        "SELECT {} FROM users WHERE deleted_at IS NULL AND {} ORDER BY id LIMIT $1 FOR UPDATE",
+        USER_COLUMNS,
+        conditions.join(" AND ")
+    );
+
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&limit];
+    params.extend(
+        predicates
+            .iter()
+            .map(|predicate| &predicate.value as &(dyn ToSql + Sync)),
+    );
+    let rows = client.timed_query(&query, &params)?;
+    rows.iter().map(user_from_row).collect()
+}
+
+// The users with any of the IDs, in ID order; IDs with no user are left out
+pub fn find_users(
+    client: &mut impl GenericClient,
+    ids: &[i32],
+    include_deleted: bool,
+) -> Result<Vec<User>, PostgresError> {
+    let query = format!(
+        "SELECT {} FROM users WHERE id = ANY($1) AND ($2 OR deleted_at IS NULL) ORDER BY id",
+        USER_COLUMNS
+    );
+    let rows = client.timed_query(&query, &[&ids, &include_deleted])?;
+    rows.iter().map(user_from_row).collect()
+}
+
+// Like `find_user`, but locks the row until the surrounding transaction ends
+pub fn find_user_for_update(
+    client: &mut impl GenericClient,
+    id: i32,
+    include_deleted: bool,
+) -> Result<Option<User>, PostgresError> {
+    let query = format!(
+        "SELECT {} FROM users WHERE id = $1 AND ($2 OR deleted_at IS NULL) FOR UPDATE",
+        USER_COLUMNS
+    );
+    let row = client.timed_query_opt(&query, &[&id, &include_deleted])?;
+    row.as_ref().map(user_from_row).transpose()
+}
+
+// Overwrites name and email of a live user, returning the updated row.
+// Only applies if the row is still at `version`; None means it's gone or was
+// changed by someone else in the meantime.
+pub fn update_user(
+    client: &mut impl GenericClient,
+    id: i32,
+    version: i32,
+    name: &str,
+    email: &str,
+) -> Result<Option<User>, PostgresError> {
+    let query = format!(
+        "UPDATE users SET name = $3, email = $4, version = version + 1
+         WHERE id = $1 AND version = $2 AND deleted_at IS NULL RETURNING {}",
+        USER_COLUMNS
+    );
+    let row = client.timed_query_opt(&query, &[&id, &version, &name, &email])?;
+    row.as_ref().map(user_from_row).transpose()
+}
+
+// Marks a live user's email address as confirmed, returning the updated row; None if
+// there's no such user
+pub fn set_verified(client: &mut impl GenericClient, id: i32) -> Result<Option<User>, PostgresError> {
+    let query = format!(
+        "UPDATE users SET verified = true, version = version + 1
+         WHERE id = $1 AND deleted_at IS NULL RETURNING {}",
+        USER_COLUMNS
+    );
+    let row = client.timed_query_opt(&query, &[&id])?;
+    row.as_ref().map(user_from_row).transpose()
+}
+
+// Inserts the user and returns its new id
+pub fn create_user(client: &mut impl GenericClient, user: &User) -> Result<i32, PostgresError> {
+    let row = client.timed_query_one(
+        "INSERT INTO users (name, email) VALUES ($1, $2) RETURNING id",
+        &[&user.name, &user.email],
+    )?;
+    row.try_get(0)
+}
+
+// What `upsert_user_by_email` did with a record
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Upsert {
+    Created,
+    Updated,
+    Unchanged,
+}
+
+// Matches the live user with the email: renames it if the name differs, or inserts a
+// new one. An `INSERT ... ON CONFLICT` on the `users_email_live` index, so concurrent
+// upserts of the same email don't race. Returns the user's ID with what was done.
+pub fn upsert_user_by_email(
+    client: &mut impl GenericClient,
+    name: &str,
+    email: &str,
+) -> Result<(i32, Upsert), PostgresError> {
+    // `xmax` is 0 for rows the statement inserted
+    let row = client.timed_query_opt(
+        "INSERT INTO users (name, email) VALUES ($1, $2)
+         ON CONFLICT (email) WHERE deleted_at IS NULL
+         DO UPDATE SET name = EXCLUDED.name, version = users.version + 1
+             WHERE users.name <> EXCLUDED.name
+         RETURNING id, xmax = 0",
+        &[&name, &email],
+    )?;
+    match row {
+        Some(row) if row.try_get(1)? => Ok((row.try_get(0)?, Upsert::Created)),
+        Some(row) => Ok((row.try_get(0)?, Upsert::Updated)),
+        // The name was already right; the conflicting row is locked all the same
+        None => {
+            let row = client.timed_query_one(
+                "SELECT id FROM users WHERE email = $1 AND deleted_at IS NULL",
+                &[&email],
+            )?;
+            Ok((row.try_get(0)?, Upsert::Unchanged))
+        }
+    }
+}
+
+// The live user with the email, locked until the surrounding transaction ends
+pub fn find_user_by_email_for_update(
+    client: &mut impl GenericClient,
+    email: &str,
+) -> Result<Option<User>, PostgresError> {
+    let query = format!(
+        "SELECT {} FROM users WHERE email = $1 AND deleted_at IS NULL FOR UPDATE",
+        USER_COLUMNS
+    );
+    let row = client.timed_query_opt(&query, &[&email])?;
+    row.as_ref().map(user_from_row).transpose()
+}
+
+// What `delete_user` did
+#[derive(PartialEq, Debug)]
+pub enum Deletion {
+    Deleted,
+    // No live user with the ID
+    NotFound,
+    // Nothing changed: the delete policies keep these records from being left behind
+    Restricted(Dependents),
+}
+
+// Soft-deletes a live user, applying the delete policies to its related records (see
+// `delete_policy`)
+pub fn delete_user(client: &mut impl GenericClient, id: i32) -> Result<Deletion, PostgresError> {
+    if find_user_for_update(client, id, false)?.is_none() {
+        return Ok(Deletion::NotFound);
+    }
+    let dependents = delete_policy::dependents(client, id)?;
+    if !dependents.is_empty() {
+        return Ok(Deletion::Restricted(dependents));
+    }
+    delete_policy::orphan(client, id)?;
+    soft_delete_user(client, id)?;
+    Ok(Deletion::Deleted)
+}
+
+// Returns false if there was no live user with that id. Doesn't look at related
+// records, so it's only for users just created; others go through `delete_user`.
+pub fn soft_delete_user(client: &mut impl GenericClient, id: i32) -> Result<bool, PostgresError> {
+    let rows_affected = client.timed_execute(
+        "UPDATE users SET deleted_at = now(), version = version + 1 WHERE id = $1 AND deleted_at IS NULL",
+        &[&id],
+    )?;
+    Ok(rows_affected > 0)
+}
+
+// Returns false if there was no soft-deleted user with that id
+pub fn restore_user(client: &mut impl GenericClient, id: i32) -> Result<bool, PostgresError> {
+    let rows_affected = client.timed_execute(
+        "UPDATE users SET deleted_at = NULL, version = version + 1 WHERE id = $1 AND deleted_at IS NOT NULL",
+        &[&id],
+    )?;
+    Ok(rows_affected > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fieldsets_keep_the_user_field_order() {
+        let fields = Fields::parse("email, id,email").unwrap();
+        assert_eq!(fields, Fields(vec!["id", "email"]));
+        assert_eq!(
+            fields.select_list(),
+            "json_strip_nulls(json_build_object('id', id, 'email', email))::text"
+        );
+        assert_eq!(Fields::parse("id,password").unwrap_err(), "Unknown field: password");
+        assert!(Fields::parse(" , ").is_err());
+    }
+
+    #[test]
+    fn searches_match_word_prefixes() {
+        assert_eq!(search_query("Ada  lov").as_deref(), Some("ada:* & lov:*"));
+        assert_eq!(search_query("ada@example.com").as_deref(), Some("ada:* & example:* & com:*"));
+        assert_eq!(search_query("x' | !y").as_deref(), Some("x:* & y:*"));
+        assert_eq!(search_query(" &!:* "), None);
+    }
+}
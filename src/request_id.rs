@@ -0,0 +1,163 @@
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::env;
+use std::fmt;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::mock::format_timestamp;
+
+// Request IDs for correlating a request across services and log lines.
+// A request keeps the caller's `X-Request-Id` when it's usable, otherwise it gets a
+// new one. The ID is sent back in the `X-Request-Id` response header and prefixed to
+// every `log!` line written while the request is handled. Connections are served on
+// their own thread one request at a time, so the current ID is kept per thread.
+// With LOG_FORMAT=json each line is written as a JSON object for log shippers,
+//   {"time": "...", "request_id": "...", "message": "..."}
+// instead of the plain `[request_id] message`.
+// The latest lines are also kept in memory for support bundles (see `recent_logs`).
+
+// Longest incoming ID that's accepted
+const MAX_LENGTH: usize = 128;
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// The caller's ID if it's 1 to 128 visible ASCII characters (so it can't break the
+// response headers or forge log lines), otherwise a new one
+pub fn from_header(incoming: Option<&str>) -> String {
+    match incoming {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_LENGTH
+                && id.bytes().all(|byte| byte.is_ascii_graphic()) =>
+        {
+            id.to_string()
+        }
+        _ => generate(),
+    }
+}
+
+// 16 hex digits, unique per process and very unlikely to repeat across processes
+pub fn generate() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    // SplitMix64 finalizer, so consecutive IDs don't look alike
+    let mut z = nanos ^ (process::id() as u64) << 32 ^ count.wrapping_mul(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    format!("{:016x}", z ^ (z >> 31))
+}
+
+// Sets (or with None, clears) the ID of the request this thread is handling
+pub fn set_current(id: Option<String>) {
+    CURRENT.with(|current| *current.borrow_mut() = id);
+}
+
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// `X-Request-Id` header line for the current request, if there is one
+pub fn header_line() -> String {
+    match current() {
+        Some(id) => format!("X-Request-Id: {}\r\n", id),
+        None => String::new(),
+    }
+}
+
+static JSON_LOGS: OnceLock<bool> = OnceLock::new();
+
+// Lines kept for `recent_logs`
+const RECENT_LINES: usize = 200;
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+// Used by `log!`
+pub fn log(message: fmt::Arguments) {
+    let json =
+        *JSON_LOGS.get_or_init(|| env::var("LOG_FORMAT").is_ok_and(|format| format == "json"));
+    let line = if json {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        json_line(
+            &format_timestamp(now.as_secs(), now.subsec_micros()),
+            current(),
+            &message.to_string(),
+        )
+    } else {
+        match current() {
+            Some(id) => format!("[{}] {}", id, message),
+            None => message.to_string(),
+        }
+    };
+    println!("{}", line);
+
+    let mut recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    if recent.len() == RECENT_LINES {
+        recent.pop_front();
+    }
+    recent.push_back(line);
+}
+
+// The latest lines logged, oldest first
+pub fn recent_logs() -> Vec<String> {
+    RECENT.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+}
+
+fn json_line(time: &str, request_id: Option<String>, message: &str) -> String {
+    let mut line = Map::new();
+    line.insert("time".to_string(), time.into());
+    if let Some(id) = request_id {
+        line.insert("request_id".to_string(), id.into());
+    }
+    line.insert("message".to_string(), message.into());
+    Value::Object(line).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usable_incoming_ids_are_kept() {
+        assert_eq!(from_header(Some("abc-123")), "abc-123");
+        for unusable in [None, Some(""), Some("two words"), Some("a\r\nX-Evil: 1")] {
+            let id = from_header(unusable);
+            assert_eq!(id.len(), 16, "{:?}", unusable);
+        }
+        assert_ne!(from_header(Some(&"x".repeat(129))), "x".repeat(129));
+        assert_ne!(generate(), generate());
+    }
+
+    #[test]
+    fn the_current_id_is_per_thread() {
+        set_current(Some("outer".to_string()));
+        std::thread::spawn(|| assert_eq!(current(), None))
+            .join()
+            .unwrap();
+        assert_eq!(header_line(), "X-Request-Id: outer\r\n");
+        set_current(None);
+        assert_eq!(header_line(), "");
+    }
+
+    #[test]
+    fn json_lines_carry_the_request_id() {
+        let time = "2026-01-01T00:00:00.000000+00:00";
+        assert_eq!(
+            json_line(time, Some("abc".to_string()), "said \"hi\"\n"),
+            r#"{"message":"said \"hi\"\n","request_id":"abc","time":"2026-01-01T00:00:00.000000+00:00"}"#
+        );
+        assert!(!json_line(time, None, "").contains("request_id"));
+    }
+}
@@ -0,0 +1,332 @@
+use postgres::types::ToSql;
+use postgres::{Error as PostgresError, GenericClient, Row};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::body;
+use crate::db::with_client;
+use crate::errors::{AppError, ErrorCode};
+use crate::handlers::{json_response, NO_CONTENT, OK_RESPONSE};
+use crate::query_log::Timed;
+use crate::router::{get_path, parse_id};
+use crate::server::AppState;
+
+// Generic CRUD for entities beyond users. A resource is a table with a SERIAL `id`,
+// `created_at` and `updated_at` columns plus its own, served at `/<table>` and
+// `/<table>/{id}`:
+//   GET /<table>          lists all rows, by ID
+//   POST /<table>         creates one from the JSON body, answering with the new row
+//   GET /<table>/{id}     fetches one
+//   PUT /<table>/{id}     replaces its columns with the body
+//   DELETE /<table>/{id}  deletes it for good
+// Users have their own handlers, for soft deletes, ETags, audit and email policies.
+// Resources that belong to a user, like posts, are listed and created under the user
+// instead (see `posts`) and use the generic handlers for the rest.
+//
+// To register an entity:
+// 1. define its model and `impl Resource` for it, say in `models`,
+// 2. add `/<table>` and `/<table>/{id}` to `router::ROUTES` with `COLLECTION_METHODS`
+//    and `ITEM_METHODS`, and route both to `resource::handle::<Model>`,
+// 3. add a migration creating its table with `resource::table_sql::<Model>` to
+//    `migrations::MIGRATIONS`.
+
+// Methods of the `/<table>` and `/<table>/{id}` routes
+pub const COLLECTION_METHODS: &[&str] = &["GET", "HEAD", "POST", "OPTIONS"];
+pub const ITEM_METHODS: &[&str] = &["GET", "HEAD", "PUT", "DELETE", "OPTIONS"];
+
+pub trait Resource: Serialize + DeserializeOwned {
+    // Table name, also the first path segment
+    const TABLE: &'static str;
+    // Singular name for messages, e.g. "Post"
+    const NAME: &'static str;
+    // Column definitions besides `id` and the timestamps, for CREATE TABLE
+    const SCHEMA: &'static str;
+    // Columns clients write, in the order `values` returns them
+    const COLUMNS: &'static [&'static str];
+
+    // Reads a row selected with `select_list`; columns can be read by name
+    fn from_row(row: &Row) -> Result<Self, PostgresError>;
+
+    // Values for `COLUMNS`, to insert or update
+    fn values(&self) -> Vec<&(dyn ToSql + Sync)>;
+
+    // Checks a body before it's written; the message is sent back with a 422
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// Creates the resource's table if it doesn't exist
+pub fn create_table<R: Resource>(client: &mut impl GenericClient) -> Result<(), PostgresError> {
+    client.batch_execute(&table_sql::<R>())
+}
+
+// SQL creating the resource's table, with `updated_at` kept current by the trigger
+// function the users migration defines (see `migrations`)
+pub fn table_sql<R: Resource>() -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS {table} (
+            id SERIAL PRIMARY KEY,
+            {schema},
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+        CREATE OR REPLACE TRIGGER {table}_set_updated_at BEFORE UPDATE ON {table}
+            FOR EACH ROW EXECUTE FUNCTION set_updated_at();",
+        table = R::TABLE,
+        schema = R::SCHEMA,
+    )
+}
+
+// Columns selected for a resource. Timestamps are rendered as ISO 8601 strings.
+fn select_list<R: Resource>() -> String {
+    format!(
+        "id, {}, to_json(created_at) #>> '{{}}' AS created_at, to_json(updated_at) #>> '{{}}' AS updated_at",
+        R::COLUMNS.join(", ")
+    )
+}
+
+fn insert_query<R: Resource>() -> String {
+    let placeholders: Vec<String> = (1..=R::COLUMNS.len()).map(|i| format!("${}", i)).collect();
+    format!(
+        "INSERT INTO {} ({}) VALUES ({}) RETURNING {}",
+        R::TABLE,
+        R::COLUMNS.join(", "),
+        placeholders.join(", "),
+        select_list::<R>()
+    )
+}
+
+fn update_query<R: Resource>() -> String {
+    let assignments: Vec<String> = R::COLUMNS
+        .iter()
+        .enumerate()
+        .map(|(i, column)| format!("{} = ${}", column, i + 1))
+        .collect();
+    format!(
+        "UPDATE {} SET {} WHERE id = ${} RETURNING {}",
+        R::TABLE,
+        assignments.join(", "),
+        R::COLUMNS.len() + 1,
+        select_list::<R>()
+    )
+}
+
+pub fn list<R: Resource>(client: &mut impl GenericClient) -> Result<Vec<R>, PostgresError> {
+    let query = format!(
+        "SELECT {} FROM {} ORDER BY id",
+        select_list::<R>(),
+        R::TABLE
+    );
+    client
+        .timed_query(&query, &[])?
+        .iter()
+        .map(R::from_row)
+        .collect()
+}
+
+// Rows whose `column` equals the value, by ID; for resources that belong to another
+pub fn list_by<R: Resource>(
+    client: &mut impl GenericClient,
+    column: &str,
+    value: &(dyn ToSql + Sync),
+) -> Result<Vec<R>, PostgresError> {
+    let query = format!(
+        "SELECT {} FROM {} WHERE {} = $1 ORDER BY id",
+        select_list::<R>(),
+        R::TABLE,
+        column
+    );
+    client
+        .timed_query(&query, &[value])?
+        .iter()
+        .map(R::from_row)
+        .collect()
+}
+
+pub fn find<R: Resource>(
+    client: &mut impl GenericClient,
+    id: i32,
+) -> Result<Option<R>, PostgresError> {
+    let query = format!(
+        "SELECT {} FROM {} WHERE id = $1",
+        select_list::<R>(),
+        R::TABLE
+    );
+    client
+        .timed_query_opt(&query, &[&id])?
+        .as_ref()
+        .map(R::from_row)
+        .transpose()
+}
+
+pub fn insert<R: Resource>(
+    client: &mut impl GenericClient,
+    resource: &R,
+) -> Result<R, PostgresError> {
+    R::from_row(&client.timed_query_one(&insert_query::<R>(), &resource.values())?)
+}
+
+// The updated row, or None if there's none with the ID
+pub fn update<R: Resource>(
+    client: &mut impl GenericClient,
+    id: i32,
+    resource: &R,
+) -> Result<Option<R>, PostgresError> {
+    let mut params = resource.values();
+    params.push(&id);
+    client
+        .timed_query_opt(&update_query::<R>(), &params)?
+        .as_ref()
+        .map(R::from_row)
+        .transpose()
+}
+
+// False if there's no row with the ID
+pub fn delete<R: Resource>(
+    client: &mut impl GenericClient,
+    id: i32,
+) -> Result<bool, PostgresError> {
+    let query = format!("DELETE FROM {} WHERE id = $1", R::TABLE);
+    Ok(client.timed_execute(&query, &[&id])? > 0)
+}
+
+// Handles a request to `/<table>` or `/<table>/{id}`; the router has already checked
+// the method against the route's.
+// Returns a `(String, String)` tuple for HTTP status and body, like the other handlers.
+pub fn handle<R: Resource>(request: &str, state: &AppState) -> (String, String) {
+    let method = request.split_whitespace().next().unwrap_or_default();
+    if get_path(request).trim_matches('/').split('/').count() == 1 {
+        return match method {
+            "POST" => handle_create::<R>(request, state),
+            _ => handle_list::<R>(state),
+        };
+    }
+    let id = match parse_id(request, state.id_policy) {
+        Ok(id) => id,
+        Err(response) => return response,
+    };
+    match method {
+        "PUT" => handle_update::<R>(request, id, state),
+        "DELETE" => handle_delete::<R>(id, state),
+        _ => handle_get::<R>(id, state),
+    }
+}
+
+fn handle_list<R: Resource>(state: &AppState) -> (String, String) {
+    match with_client(state, |client| Ok(list::<R>(client)?)) {
+        Ok(rows) => json_response(OK_RESPONSE, &rows),
+        Err(e) => e.into_response(),
+    }
+}
+
+fn handle_get<R: Resource>(id: i32, state: &AppState) -> (String, String) {
+    match with_client(state, |client| Ok(find::<R>(client, id)?)) {
+        Ok(Some(row)) => json_response(OK_RESPONSE, &row),
+        Ok(None) => not_found::<R>().into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+fn handle_create<R: Resource>(request: &str, state: &AppState) -> (String, String) {
+    let resource = match body::<R>(request) {
+        Ok(resource) => resource,
+        Err(e) => return e.into_response(),
+    };
+    match with_client(state, |client| Ok(insert(client, &resource)?)) {
+        Ok(created) => json_response(OK_RESPONSE, &created),
+        Err(e) => e.into_response(),
+    }
+}
+
+fn handle_update<R: Resource>(request: &str, id: i32, state: &AppState) -> (String, String) {
+    let resource = match body::<R>(request) {
+        Ok(resource) => resource,
+        Err(e) => return e.into_response(),
+    };
+    match with_client(state, |client| Ok(update(client, id, &resource)?)) {
+        Ok(Some(updated)) => json_response(OK_RESPONSE, &updated),
+        Ok(None) => not_found::<R>().into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+fn handle_delete<R: Resource>(id: i32, state: &AppState) -> (String, String) {
+    match with_client(state, |client| Ok(delete::<R>(client, id)?)) {
+        Ok(true) => (NO_CONTENT.to_string(), String::new()),
+        Ok(false) => not_found::<R>().into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+// The request body as a resource, validated
+pub(crate) fn body<R: Resource>(request: &str) -> Result<R, AppError> {
+    let resource: R = body::parse(request)?;
+    resource
+        .validate()
+        .map_err(|message| AppError::Validation(ErrorCode::ValidationFailed, message))?;
+    Ok(resource)
+}
+
+pub(crate) fn not_found<R: Resource>() -> AppError {
+    AppError::NotFound(ErrorCode::RecordNotFound, format!("{} not found", R::NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Note {
+        #[serde(default)]
+        id: Option<i32>,
+        title: String,
+        pinned: bool,
+    }
+
+    impl Resource for Note {
+        const TABLE: &'static str = "notes";
+        const NAME: &'static str = "Note";
+        const SCHEMA: &'static str = "title TEXT NOT NULL, pinned BOOLEAN NOT NULL";
+        const COLUMNS: &'static [&'static str] = &["title", "pinned"];
+
+        fn from_row(row: &Row) -> Result<Self, PostgresError> {
+            Ok(Note {
+                id: Some(row.try_get("id")?),
+                title: row.try_get("title")?,
+                pinned: row.try_get("pinned")?,
+            })
+        }
+
+        fn values(&self) -> Vec<&(dyn ToSql + Sync)> {
+            vec![&self.title, &self.pinned]
+        }
+    }
+
+    #[test]
+    fn queries_cover_the_resources_columns() {
+        let select = "id, title, pinned, to_json(created_at) #>> '{}' AS created_at, \
+                      to_json(updated_at) #>> '{}' AS updated_at";
+        assert_eq!(
+            insert_query::<Note>(),
+            format!(
+                "INSERT INTO notes (title, pinned) VALUES ($1, $2) RETURNING {}",
+                select
+            )
+        );
+        assert_eq!(
+            update_query::<Note>(),
+            format!(
+                "UPDATE notes SET title = $1, pinned = $2 WHERE id = $3 RETURNING {}",
+                select
+            )
+        );
+    }
+
+    #[test]
+    fn missing_rows_are_named_after_the_resource() {
+        let (status_line, body) = not_found::<Note>().into_response();
+        assert!(status_line.starts_with("HTTP/1.1 404"));
+        assert!(body.contains(r#""code":"RECORD_NOT_FOUND","message":"Note not found""#));
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+#[cfg(feature = "redis")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "redis")]
+use crate::redis::{self, Redis, Reply};
+
+// In-process cache of GET /users and GET /users/{id} responses, to absorb read-heavy
+// traffic. Responses are kept for RESPONSE_CACHE_TTL_SECS (off unless set) and at most
+// RESPONSE_CACHE_MAX_ENTRIES (1000) of them, least recently used first out. They're
+// keyed by path and query (in any parameter order) and by whether the caller is an
+// admin, so admin-only views never reach anyone else.
+// Any other request to the API invalidates what it may have changed: a request for a
+// user drops that user's entries and every list, anything else drops everything.
+// Changes made outside this process (the CLI tools, other replicas) are only seen once
+// entries expire, so keep the TTL short when there are any.
+// Lists are served from memory rather than streamed while the cache is on, and bodies
+// over `MAX_BODY_BYTES` aren't kept. With Redis (see `redis`), entries are shared by
+// all replicas and so are invalidations.
+
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+struct Entry {
+    response: (String, String),
+    // The user for `/users/{id}`, None for lists
+    user_id: Option<i32>,
+    expires_at: Instant,
+    // Position in `Entries::recency`
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<String, Entry>,
+    // Keys by when they were last used, oldest first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    // Bumped by every invalidation; see `Slot`
+    generation: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.by_key.remove(key) {
+            self.recency.remove(&entry.used);
+        }
+    }
+}
+
+pub struct ResponseCache {
+    backend: Backend,
+    ttl: Duration,
+    max_entries: usize,
+}
+
+enum Backend {
+    Local(Mutex<Entries>),
+    // Entries shared by all replicas (see `redis`). Rather than deleting entries,
+    // invalidation bumps generation counters that are part of their keys, so stale
+    // ones are never read again and expire. Redis evicts entries by its own policy.
+    #[cfg(feature = "redis")]
+    Shared(Arc<Redis>),
+}
+
+pub enum Lookup {
+    Hit((String, String)),
+    // Where to `put` the response once it's been produced
+    Miss(Slot),
+}
+
+// A cache entry as of the `get` that missed it. Invalidations since then make `put`
+// store nothing, as the response may have been read before their changes committed.
+pub struct Slot {
+    key: String,
+    // Local only; shared keys include their generations
+    generation: u64,
+}
+
+impl ResponseCache {
+    // None unless both a TTL and room for entries are configured
+    pub fn from_env() -> Option<ResponseCache> {
+        let setting = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        let ttl = setting("RESPONSE_CACHE_TTL_SECS", 0);
+        let max_entries = setting("RESPONSE_CACHE_MAX_ENTRIES", 1000) as usize;
+        if ttl == 0 || max_entries == 0 {
+            return None;
+        }
+        #[allow(unused_mut)]
+        let mut cache = ResponseCache::new(Duration::from_secs(ttl), max_entries);
+        #[cfg(feature = "redis")]
+        if let Some(redis) = redis::shared() {
+            cache.backend = Backend::Shared(redis);
+        }
+        Some(cache)
+    }
+
+    pub fn new(ttl: Duration, max_entries: usize) -> ResponseCache {
+        ResponseCache {
+            backend: Backend::Local(Mutex::new(Entries::default())),
+            ttl,
+            max_entries,
+        }
+    }
+
+    // The cached response for `key` if there's a fresh one, or where to put one
+    pub fn get(&self, key: &str) -> Lookup {
+        match &self.backend {
+            Backend::Local(entries) => {
+                let mut entries = entries.lock().unwrap_or_else(|e| e.into_inner());
+                let entries = &mut *entries;
+                let miss = Lookup::Miss(Slot {
+                    key: key.to_string(),
+                    generation: entries.generation,
+                });
+                let Some(entry) = entries.by_key.get_mut(key) else {
+                    return miss;
+                };
+                if entry.expires_at <= Instant::now() {
+                    entries.remove(key);
+                    return miss;
+                }
+                entries.tick += 1;
+                entries.recency.remove(&entry.used);
+                entry.used = entries.tick;
+                entries.recency.insert(entry.used, key.to_string());
+                Lookup::Hit(entry.response.clone())
+            }
+            #[cfg(feature = "redis")]
+            Backend::Shared(redis) => match get_shared(redis, key) {
+                Ok(lookup) => lookup,
+                Err(e) => {
+                    log!("Response cache unavailable: {}", e);
+                    Lookup::Miss(Slot {
+                        key: String::new(),
+                        generation: 0,
+                    })
+                }
+            },
+        }
+    }
+
+    // Stores a successful response in the slot a `get` missed
+    pub fn put(&self, slot: Slot, response: &(String, String)) {
+        if !response.0.starts_with("HTTP/1.1 200 ") || response.1.len() > MAX_BODY_BYTES {
+            return;
+        }
+        match &self.backend {
+            Backend::Local(entries) => {
+                let mut entries = entries.lock().unwrap_or_else(|e| e.into_inner());
+                if entries.generation != slot.generation {
+                    return;
+                }
+                let key = slot.key;
+                entries.remove(&key);
+                while entries.by_key.len() >= self.max_entries {
+                    let Some((_, oldest)) = entries.recency.pop_first() else {
+                        break;
+                    };
+                    entries.by_key.remove(&oldest);
+                }
+                entries.tick += 1;
+                let used = entries.tick;
+                entries.recency.insert(used, key.clone());
+                let user_id = user_id(&key);
+                entries.by_key.insert(
+                    key,
+                    Entry {
+                        response: response.clone(),
+                        user_id,
+                        expires_at: Instant::now() + self.ttl,
+                        used,
+                    },
+                );
+            }
+            #[cfg(feature = "redis")]
+            Backend::Shared(redis) => {
+                // Redis failed when the slot was looked up
+                if slot.key.is_empty() {
+                    return;
+                }
+                let Ok(value) = serde_json::to_vec(response) else {
+                    return;
+                };
+                let ttl = self.ttl.as_millis().to_string();
+                let command: [&[u8]; 5] =
+                    [b"SET", slot.key.as_bytes(), &value, b"PX", ttl.as_bytes()];
+                if let Err(e) = redis.command(&command) {
+                    log!("Response not cached: {}", e);
+                }
+            }
+        }
+    }
+
+    // Drops the entries a request to `path` may have made stale
+    pub fn invalidate(&self, path: &str) {
+        let user = match path.trim_matches('/').split('/').collect::<Vec<_>>()[..] {
+            ["users", id, ..] => id.parse::<i32>().ok(),
+            _ => None,
+        };
+        match &self.backend {
+            Backend::Local(entries) => {
+                let mut entries = entries.lock().unwrap_or_else(|e| e.into_inner());
+                entries.generation += 1;
+                let Some(user) = user else {
+                    entries.by_key.clear();
+                    entries.recency.clear();
+                    return;
+                };
+                let stale: Vec<String> = entries
+                    .by_key
+                    .iter()
+                    .filter(|(_, entry)| entry.user_id.is_none_or(|id| id == user))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in stale {
+                    entries.remove(&key);
+                }
+            }
+            #[cfg(feature = "redis")]
+            Backend::Shared(redis) => {
+                let counters = match user {
+                    Some(user) => vec![generation_key(Some(user)), generation_key(None)],
+                    None => vec!["cache:generation".to_string()],
+                };
+                for counter in counters {
+                    let counter = redis.key(&counter);
+                    if let Err(e) = redis.command(&[b"INCR", counter.as_bytes()]) {
+                        // Other replicas may serve the stale entries until they expire
+                        log!("Cached responses not invalidated: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The counter bumped when a user changes, or for lists (None) when any user changes
+#[cfg(feature = "redis")]
+fn generation_key(user: Option<i32>) -> String {
+    match user {
+        Some(user) => format!("cache:generation:user:{}", user),
+        None => "cache:generation:lists".to_string(),
+    }
+}
+
+#[cfg(feature = "redis")]
+fn get_shared(redis: &Redis, key: &str) -> std::io::Result<Lookup> {
+    let counters = [
+        redis.key("cache:generation"),
+        redis.key(&generation_key(user_id(key))),
+    ];
+    let generations =
+        match redis.command(&[b"MGET", counters[0].as_bytes(), counters[1].as_bytes()])? {
+            Reply::Array(values) => values
+                .into_iter()
+                .map(|value| {
+                    value
+                        .into_string()
+                        .and_then(|value| value.parse().ok())
+                        .unwrap_or(0)
+                })
+                .collect::<Vec<u64>>(),
+            _ => vec![0, 0],
+        };
+    let key = redis.key(&format!(
+        "cache:{}:{}:{}",
+        generations[0], generations[1], key
+    ));
+    let cached = redis.command(&[b"GET", key.as_bytes()])?;
+    let response = match cached {
+        Reply::Bulk(Some(value)) => serde_json::from_slice(&value).ok(),
+        _ => None,
+    };
+    Ok(match response {
+        Some(response) => Lookup::Hit(response),
+        None => Lookup::Miss(Slot { key, generation: 0 }),
+    })
+}
+
+// The cache key for a GET of `path` with `query`, or None if it isn't cached
+pub fn key(path: &str, query: Option<&str>, admin: bool) -> Option<String> {
+    match path.trim_matches('/').split('/').collect::<Vec<_>>()[..] {
+        ["users"] => {}
+        ["users", id] if id.parse::<i32>().is_ok() => {}
+        _ => return None,
+    }
+    let mut pairs: Vec<&str> = query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .collect();
+    pairs.sort_unstable();
+    let caller = if admin { "admin" } else { "user" };
+    Some(format!("{} {}?{}", caller, path, pairs.join("&")))
+}
+
+fn user_id(key: &str) -> Option<i32> {
+    let (_, path) = key.split_once(' ')?;
+    let path = path.split('?').next()?;
+    path.strip_prefix("/users/")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(body: &str) -> (String, String) {
+        ("HTTP/1.1 200 OK\r\n".to_string(), body.to_string())
+    }
+
+    #[test]
+    fn keys_ignore_parameter_order_but_not_the_caller() {
+        assert_eq!(
+            key("/users", Some("b=2&a=1"), false),
+            key("/users", Some("a=1&b=2"), false)
+        );
+        assert_ne!(key("/users/7", None, true), key("/users/7", None, false));
+        assert_eq!(key("/users/search", Some("q=ada"), false), None);
+        assert_eq!(key("/users/7/audit", None, false), None);
+    }
+
+    fn cached(cache: &ResponseCache, key: &str) -> Option<(String, String)> {
+        match cache.get(key) {
+            Lookup::Hit(response) => Some(response),
+            Lookup::Miss(_) => None,
+        }
+    }
+
+    fn miss(cache: &ResponseCache, key: &str) -> Slot {
+        match cache.get(key) {
+            Lookup::Hit(_) => panic!("{} is cached", key),
+            Lookup::Miss(slot) => slot,
+        }
+    }
+
+    #[test]
+    fn least_recently_used_entries_go_first() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 2);
+        cache.put(miss(&cache, "user /users/1?"), &ok("one"));
+        cache.put(miss(&cache, "user /users/2?"), &ok("two"));
+        assert!(cached(&cache, "user /users/1?").is_some());
+        cache.put(miss(&cache, "user /users/3?"), &ok("three"));
+        assert!(cached(&cache, "user /users/2?").is_none());
+        assert_eq!(cached(&cache, "user /users/1?"), Some(ok("one")));
+
+        let not_found = ("HTTP/1.1 404 NOT FOUND\r\n".to_string(), String::new());
+        cache.put(miss(&cache, "user /users/4?"), &not_found);
+        assert!(cached(&cache, "user /users/4?").is_none());
+    }
+
+    #[test]
+    fn changes_drop_what_they_may_have_changed() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 10);
+        for key in ["user /users/1?", "user /users/2?", "user /users?"] {
+            cache.put(miss(&cache, key), &ok(key));
+        }
+        cache.invalidate("/users/1/restore");
+        assert!(cached(&cache, "user /users/1?").is_none());
+        assert!(cached(&cache, "user /users?").is_none());
+        assert!(cached(&cache, "user /users/2?").is_some());
+
+        // Responses read before the change aren't stored after it
+        let slot = miss(&cache, "user /users?");
+        cache.invalidate("/users/2");
+        cache.put(slot, &ok("stale"));
+        assert!(cached(&cache, "user /users?").is_none());
+
+        cache.put(miss(&cache, "user /users/1?"), &ok("one"));
+        cache.invalidate("/posts/5");
+        assert!(cached(&cache, "user /users/1?").is_none());
+
+        let expired = ResponseCache::new(Duration::ZERO, 10);
+        expired.put(miss(&expired, "user /users?"), &ok("list"));
+        assert!(cached(&expired, "user /users?").is_none());
+    }
+}
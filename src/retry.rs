@@ -0,0 +1,229 @@
+use postgres::error::SqlState;
+use postgres::Error as PostgresError;
+use std::env;
+use std::error::Error;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Retrying of database work that failed for reasons that go away on their own:
+// Postgres restarting (common under docker-compose), dropped connections, and
+// serialization failures or deadlocks between concurrent transactions.
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    // Total tries including the first one
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    // Stop retrying once this much time has passed since the first try
+    pub deadline: Option<Duration>,
+}
+
+impl RetryPolicy {
+    // Policy for requests: DB_RETRY_ATTEMPTS (3), DB_RETRY_BASE_MS (50), DB_RETRY_MAX_MS (1000)
+    pub fn from_env() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: setting("DB_RETRY_ATTEMPTS", 3).max(1) as u32,
+            base_delay: Duration::from_millis(setting("DB_RETRY_BASE_MS", 50)),
+            max_delay: Duration::from_millis(setting("DB_RETRY_MAX_MS", 1000)),
+            deadline: None,
+        }
+    }
+
+    // Policy for waiting on the database at startup, which may still be booting (e.g.
+    // under docker-compose): keep trying for WAIT_FOR_DB_SECS (30) seconds, backing off
+    // from 500ms up to 5s. 0 gives up after the first failure.
+    pub fn startup_from_env() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: u32::MAX,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(5),
+            deadline: Some(Duration::from_secs(setting("WAIT_FOR_DB_SECS", 30))),
+        }
+    }
+
+    // Calls `operation` until it succeeds, fails with an error `is_transient` rejects,
+    // or runs out of attempts or time. The last error is returned.
+    pub fn run<T, E: std::fmt::Display>(
+        &self,
+        mut operation: impl FnMut() -> Result<T, E>,
+        is_transient: impl Fn(&E) -> bool,
+    ) -> Result<T, E> {
+        let started = Instant::now();
+        let mut attempt = 1;
+        loop {
+            match operation() {
+                Err(e) if self.may_retry(attempt, started.elapsed()) && is_transient(&e) => {
+                    let elapsed = started.elapsed();
+                    let mut delay = self.delay(attempt);
+                    let progress = match self.deadline {
+                        Some(deadline) => {
+                            delay = delay.min(deadline.saturating_sub(elapsed));
+                            format!(
+                                "attempt {}, {}s of {}s",
+                                attempt,
+                                elapsed.as_secs(),
+                                deadline.as_secs()
+                            )
+                        }
+                        None => format!("attempt {}/{}", attempt, self.max_attempts),
+                    };
+                    log!(
+                        "Transient database error ({}), retrying in {}ms: {}",
+                        progress,
+                        delay.as_millis(),
+                        e
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn may_retry(&self, attempt: u32, elapsed: Duration) -> bool {
+        attempt < self.max_attempts && self.deadline.is_none_or(|deadline| elapsed < deadline)
+    }
+
+    // Exponential backoff capped at `max_delay`, with the upper half randomized so
+    // clients that failed together don't all retry at the same moment
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let capped = exponential.min(self.max_delay);
+        let half = capped / 2;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.subsec_nanos())
+            .unwrap_or_default();
+        half + half.mul_f64(nanos as f64 / 1_000_000_000.0)
+    }
+}
+
+// Whether retrying the same work may succeed
+pub fn is_transient(e: &PostgresError) -> bool {
+    if e.is_closed() || e.source().is_some_and(|source| source.is::<io::Error>()) {
+        return true;
+    }
+    matches!(
+        e.code(),
+        Some(code) if *code == SqlState::T_R_SERIALIZATION_FAILURE
+            || *code == SqlState::T_R_DEADLOCK_DETECTED
+            || *code == SqlState::ADMIN_SHUTDOWN
+            || *code == SqlState::CRASH_SHUTDOWN
+            || *code == SqlState::CANNOT_CONNECT_NOW
+            || *code == SqlState::CONNECTION_EXCEPTION
+            || *code == SqlState::CONNECTION_FAILURE
+            // After a failover the old primary may come back as a standby
+            || *code == SqlState::READ_ONLY_SQL_TRANSACTION
+    )
+}
+
+fn setting(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(4),
+            deadline: None,
+        }
+    }
+
+    #[test]
+    fn delays_grow_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            deadline: None,
+        };
+        for (attempt, full) in [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1000),
+            (30, 1000),
+        ] {
+            let delay = policy.delay(attempt);
+            assert!(
+                delay >= Duration::from_millis(full / 2),
+                "attempt {}",
+                attempt
+            );
+            assert!(delay <= Duration::from_millis(full), "attempt {}", attempt);
+        }
+    }
+
+    #[test]
+    fn only_transient_errors_are_retried() {
+        let mut calls = 0;
+        let result: Result<(), &str> = policy(3).run(
+            || {
+                calls += 1;
+                Err("transient")
+            },
+            |e| *e == "transient",
+        );
+        assert_eq!((result, calls), (Err("transient"), 3));
+
+        let mut calls = 0;
+        let result: Result<(), &str> = policy(3).run(
+            || {
+                calls += 1;
+                Err("fatal")
+            },
+            |e| *e == "transient",
+        );
+        assert_eq!((result, calls), (Err("fatal"), 1));
+
+        let mut calls = 0;
+        let result = policy(3).run(
+            || {
+                calls += 1;
+                if calls < 2 {
+                    Err("transient")
+                } else {
+                    Ok(calls)
+                }
+            },
+            |e| *e == "transient",
+        );
+        assert_eq!(result, Ok(2));
+    }
+
+    #[test]
+    fn retries_stop_at_the_deadline() {
+        let policy = RetryPolicy {
+            max_attempts: u32::MAX,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+            deadline: Some(Duration::from_millis(50)),
+        };
+        let started = Instant::now();
+        let mut calls = 0;
+        let result: Result<(), &str> = policy.run(
+            || {
+                calls += 1;
+                Err("transient")
+            },
+            |_| true,
+        );
+        assert_eq!(result, Err("transient"));
+        assert!(calls > 1);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,421 @@
+use crate::handlers::{
+    handle_archived_users_request, handle_audit_request, handle_batch_get_request, handle_bulk_update_request, handle_cancel_email_change,
+    handle_confirm_email_change, handle_data_quality_request, handle_delete_request,
+    handle_errors_request, handle_features_request, handle_get_all_request, handle_get_chaos,
+    handle_get_email_change, handle_get_email_policy, handle_get_notifications, handle_get_profile, handle_get_request, handle_health_detail_request,
+    handle_metrics_history_request, handle_metrics_request, handle_migrations_request, handle_post_request, handle_put_chaos,
+    handle_put_email_policy, handle_put_notifications, handle_put_profile, handle_readyz_request, handle_restore_request, handle_run_archive,
+    handle_checksum_request, handle_run_digests, handle_search_request, handle_stats_request, handle_verify_request, handle_slo_request, handle_slow_requests_request,
+    handle_storage_request, handle_start_impersonation, handle_support_bundle_request, handle_stop_impersonation,
+    handle_unarchive_request, handle_undo_request, handle_update_request, handle_upsert_by_email_request, NO_CONTENT,
+};
+use crate::config::IdPolicy;
+use crate::errors::{error, ErrorCode};
+use crate::mock;
+use crate::models::Post;
+use crate::posts;
+use crate::resource;
+use crate::scim;
+use crate::server::{AppState, Caller};
+use crate::sse;
+use crate::webhooks;
+use crate::websocket;
+
+// Maps requests to handlers, plus the helpers handlers use to read a request: its path,
+// ID segment, query parameters and headers.
+
+// Every route: its path pattern and the methods it supports. `{id}` segments match
+// any one segment, `{email}` segments only ones with an `@` (which may be `%40`). Where patterns overlap, the more specific one (a literal where the
+// other has `{id}`) wins, so `/users/count` would take precedence over `/users/{id}`.
+// `check_routes` rejects tables where that doesn't settle it; see there.
+pub(crate) const ROUTES: &[Route] = &[
+    Route { pattern: "/users", methods: &["GET", "HEAD", "POST", "PATCH", "OPTIONS"] },
+    Route { pattern: "/users/search", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/users/checksum", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/users/stats", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/users/batch-get", methods: &["POST", "OPTIONS"] },
+    Route { pattern: "/users/events", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/users/verify", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/users/export", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/users/by-email/{email}", methods: &["PUT", "OPTIONS"] },
+    Route { pattern: "/users/{id}", methods: &["GET", "HEAD", "PUT", "PATCH", "DELETE", "OPTIONS"] },
+    Route { pattern: "/users/{id}/restore", methods: &["POST", "OPTIONS"] },
+    Route { pattern: "/users/{id}/audit", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/users/{id}/email-change", methods: &["GET", "HEAD", "DELETE", "OPTIONS"] },
+    Route { pattern: "/users/{id}/email-change/confirm", methods: &["POST", "OPTIONS"] },
+    Route { pattern: "/users/{id}/posts", methods: resource::COLLECTION_METHODS },
+    Route { pattern: "/users/{id}/notifications", methods: &["GET", "HEAD", "PUT", "OPTIONS"] },
+    Route { pattern: "/users/{id}/profile", methods: &["GET", "HEAD", "PUT", "OPTIONS"] },
+    Route { pattern: "/posts/{id}", methods: resource::ITEM_METHODS },
+    Route { pattern: "/admin/data-quality", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/admin/storage", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/admin/migrations", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/admin/metrics/history", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/admin/slow-requests", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/admin/slo", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/admin/features", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/admin/support-bundle", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/admin/email-policy", methods: &["GET", "HEAD", "PUT", "OPTIONS"] },
+    Route { pattern: "/admin/chaos", methods: &["GET", "HEAD", "PUT", "OPTIONS"] },
+    Route { pattern: "/admin/impersonate/{id}", methods: &["POST", "OPTIONS"] },
+    Route { pattern: "/admin/impersonate/stop", methods: &["POST", "OPTIONS"] },
+    Route { pattern: "/admin/digests/run", methods: &["POST", "OPTIONS"] },
+    Route { pattern: "/admin/archive/run", methods: &["POST", "OPTIONS"] },
+    Route { pattern: "/admin/exports/{id}", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/admin/archived-users", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/admin/archived-users/{id}/restore", methods: &["POST", "OPTIONS"] },
+    Route { pattern: "/admin/webhooks/run", methods: &["POST", "OPTIONS"] },
+    Route { pattern: "/admin/undo/{id}", methods: &["POST", "OPTIONS"] },
+    Route { pattern: "/admin/ui", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/admin/ui/{id}", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/webhooks", methods: resource::COLLECTION_METHODS },
+    Route { pattern: "/webhooks/{id}", methods: resource::ITEM_METHODS },
+    Route { pattern: "/webhooks/{id}/deliveries", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/ws/users", methods: &["GET", "OPTIONS"] },
+    Route { pattern: "/errors", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/readyz", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/metrics", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/health/detail", methods: &["GET", "HEAD", "OPTIONS"] },
+    Route { pattern: "/scim/v2/Users", methods: &["GET", "HEAD", "POST", "OPTIONS"] },
+    Route { pattern: "/scim/v2/Users/{id}", methods: &["GET", "HEAD", "PATCH", "DELETE", "OPTIONS"] },
+];
+
+pub(crate) struct Route {
+    pub pattern: &'static str,
+    pub methods: &'static [&'static str],
+}
+
+// Picks the handler for a request. Unknown paths are a 404, known paths with a
+// method they don't support a 405.
+pub fn route(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    let method = request.split_whitespace().next().unwrap_or_default();
+    let path = get_path(request);
+    if method == "OPTIONS" {
+        return handle_options_request(request);
+    }
+    // SCIM answers unknown paths and methods itself, in the error format of its spec
+    if path.starts_with("/scim/") && state.mock.is_none() {
+        return scim::handle_request(request, state);
+    }
+    let pattern = match find_route(path) {
+        Some(route) if !route.methods.contains(&method) => return method_not_allowed(path),
+        Some(route) => route.pattern,
+        None => return error(ErrorCode::RouteNotFound, "Not Found"),
+    };
+
+    match (method, pattern) {
+        ("GET", "/errors") => handle_errors_request(),
+        ("GET", "/readyz") => handle_readyz_request(state),
+        ("GET", "/metrics") => handle_metrics_request(state),
+        ("GET", "/health/detail") => handle_health_detail_request(state, caller),
+        ("GET", "/admin/chaos") => handle_get_chaos(state, caller),
+        ("PUT", "/admin/chaos") => handle_put_chaos(request, state, caller),
+        ("GET", "/admin/metrics/history") => handle_metrics_history_request(request, state, caller),
+        ("GET", "/admin/slow-requests") => handle_slow_requests_request(state, caller),
+        ("GET", "/admin/slo") => handle_slo_request(state, caller),
+        ("GET", "/admin/features") => handle_features_request(state, caller),
+        _ if state.mock.is_some() => mock::handle_request(request, state, caller),
+        ("GET", "/admin/data-quality") => handle_data_quality_request(state, caller),
+        ("GET", "/admin/storage") => handle_storage_request(state, caller),
+        ("GET", "/admin/migrations") => handle_migrations_request(state, caller),
+        ("GET", "/admin/support-bundle") => handle_support_bundle_request(state, caller),
+        ("GET", "/admin/email-policy") => handle_get_email_policy(state, caller),
+        ("PUT", "/admin/email-policy") => handle_put_email_policy(request, state, caller),
+        ("POST", "/admin/impersonate/{id}") => with_id(request, state, |id| handle_start_impersonation(id, state, caller)),
+        ("POST", "/admin/impersonate/stop") => handle_stop_impersonation(state),
+        ("POST", "/admin/digests/run") => handle_run_digests(state, caller),
+        ("POST", "/admin/archive/run") => handle_run_archive(request, state, caller),
+        ("GET", "/admin/archived-users") => handle_archived_users_request(request, state, caller),
+        ("POST", "/admin/archived-users/{id}/restore") => with_id(request, state, |id| handle_unarchive_request(id, state, caller)),
+        ("POST", "/admin/webhooks/run") => webhooks::handle_run(state, caller),
+        ("POST", "/admin/undo/{id}") => handle_undo_request(request, state, caller),
+        (_, "/webhooks" | "/webhooks/{id}") => webhooks::handle(request, state, caller),
+        ("GET", "/webhooks/{id}/deliveries") => with_id(request, state, |id| webhooks::handle_deliveries(request, id, state, caller)),
+        ("GET", "/users") => handle_get_all_request(request, state, caller),
+        ("POST", "/users") => handle_post_request(request, state, caller),
+        ("PATCH", "/users") => handle_bulk_update_request(request, state, caller),
+        ("GET", "/users/search") => handle_search_request(request, state),
+        ("GET", "/users/checksum") => handle_checksum_request(request, state, caller),
+        ("GET", "/users/stats") => handle_stats_request(request, state, caller),
+        ("POST", "/users/batch-get") => handle_batch_get_request(request, state, caller),
+        ("GET", "/users/events") => sse::handle_request(request),
+        ("GET", "/users/verify") => handle_verify_request(request, state, caller),
+        ("PUT", "/users/by-email/{email}") => handle_upsert_by_email_request(request, state, caller),
+        ("GET", "/ws/users") => websocket::handle_request(),
+        ("GET", "/users/{id}") => with_id(request, state, |id| handle_get_request(request, id, state, caller)),
+        ("PUT", "/users/{id}") => with_id(request, state, |id| handle_update_request(request, id, state, caller, false)),
+        ("PATCH", "/users/{id}") => with_id(request, state, |id| handle_update_request(request, id, state, caller, true)),
+        ("DELETE", "/users/{id}") => with_id(request, state, |id| handle_delete_request(id, state, caller)),
+        ("POST", "/users/{id}/restore") => with_id(request, state, |id| handle_restore_request(id, state, caller)),
+        ("GET", "/users/{id}/audit") => with_id(request, state, |id| handle_audit_request(id, state)),
+        ("GET", "/users/{id}/email-change") => with_id(request, state, |id| handle_get_email_change(id, state)),
+        ("DELETE", "/users/{id}/email-change") => with_id(request, state, |id| handle_cancel_email_change(id, state)),
+        ("POST", "/users/{id}/email-change/confirm") => with_id(request, state, |id| handle_confirm_email_change(request, id, state, caller)),
+        ("GET", "/users/{id}/notifications") => with_id(request, state, |id| handle_get_notifications(id, state)),
+        ("PUT", "/users/{id}/notifications") => with_id(request, state, |id| handle_put_notifications(request, id, state)),
+        ("GET", "/users/{id}/profile") => with_id(request, state, |id| handle_get_profile(id, state)),
+        ("PUT", "/users/{id}/profile") => with_id(request, state, |id| handle_put_profile(request, id, state)),
+        ("GET", "/users/{id}/posts") => with_id(request, state, |id| posts::handle_list(id, state)),
+        ("POST", "/users/{id}/posts") => with_id(request, state, |id| posts::handle_create(request, id, state)),
+        ("PUT", "/posts/{id}") => with_id(request, state, |id| posts::handle_update(request, id, state)),
+        ("GET" | "DELETE", "/posts/{id}") => resource::handle::<Post>(request, state),
+        _ => error(ErrorCode::RouteNotFound, "Not Found"),
+    }
+}
+
+// Handle OPTIONS
+// Lists the methods a path supports in the `Allow` header; `OPTIONS *` lists every method
+// the server supports.
+// Returns a `(String, String)` tuple for HTTP status and body, as explained above.
+fn handle_options_request(request: &str) -> (String, String) {
+    let methods = match get_path(request) {
+        "*" => &["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"][..],
+        path => match allowed_methods(path) {
+            Some(methods) => methods,
+            None => return error(ErrorCode::RouteNotFound, "Not Found"),
+        },
+    };
+    (format!("{}Allow: {}\r\n", NO_CONTENT, methods.join(", ")), String::new())
+}
+
+// The route a path belongs to, or None for unknown paths
+pub(crate) fn find_route(path: &str) -> Option<&'static Route> {
+    find_in(ROUTES, path)
+}
+
+// The most specific of the routes matching a path. `check_routes` makes sure the
+// routes matching any one path are ordered by how many literal segments they have.
+fn find_in<'a>(routes: &'a [Route], path: &str) -> Option<&'a Route> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    routes
+        .iter()
+        .filter(|route| {
+            let pattern = pattern_segments(route.pattern);
+            pattern.len() == segments.len() && pattern.iter().zip(&segments).all(|(expected, segment)| segment_matches(expected, segment))
+        })
+        .max_by_key(|route| pattern_segments(route.pattern).iter().filter(|segment| !is_param(segment)).count())
+}
+
+// Methods each route supports, or None for unknown paths
+pub(crate) fn allowed_methods(path: &str) -> Option<&'static [&'static str]> {
+    find_route(path).map(|route| route.methods)
+}
+
+// Checks the route table, so a new route that clashes with an existing one stops the
+// server at startup instead of quietly taking (or losing) requests. Overlapping
+// patterns are fine when one is more specific at every segment where they differ;
+// they're rejected when
+// - they're the same pattern,
+// - each has a literal where the other has `{id}`, so neither clearly wins (say
+//   `/users/{id}/audit` and `/users/count/{id}`), or
+// - the literal would be a valid ID (`/users/0`), hiding that user.
+pub fn check_routes() -> Result<(), String> {
+    check(ROUTES)
+}
+
+fn check(routes: &[Route]) -> Result<(), String> {
+    for (i, a) in routes.iter().enumerate() {
+        for b in &routes[i + 1..] {
+            let (a_segments, b_segments) = (pattern_segments(a.pattern), pattern_segments(b.pattern));
+            if a_segments.len() != b_segments.len() {
+                continue;
+            }
+            let pairs: Vec<(&str, &str)> = a_segments.into_iter().zip(b_segments).collect();
+            // A literal the other pattern's segment can't match: no path matches both
+            let disjoint = |x: &str, y: &str| !is_param(x) && !segment_matches(y, x);
+            if pairs.iter().any(|(x, y)| disjoint(x, y) || disjoint(y, x)) {
+                continue;
+            }
+            if pairs.iter().all(|(x, y)| x == y || is_param(x) && is_param(y)) {
+                return Err(format!("routes {} and {} are the same", a.pattern, b.pattern));
+            }
+            let a_wins = pairs.iter().any(|(x, y)| !is_param(x) && is_param(y));
+            let b_wins = pairs.iter().any(|(x, y)| is_param(x) && !is_param(y));
+            if a_wins && b_wins {
+                return Err(format!("routes {} and {} are ambiguous: both match some paths and neither is more specific", a.pattern, b.pattern));
+            }
+            let shadowed_id = pairs.iter().find_map(|(x, y)| match (is_param(x), is_param(y)) {
+                (true, false) => Some(*y),
+                (false, true) => Some(*x),
+                _ => None,
+            });
+            if let Some(literal) = shadowed_id.filter(|literal| literal.trim_start_matches('-').bytes().all(|byte| byte.is_ascii_digit())) {
+                return Err(format!("route segment {} in {} or {} would hide the ID {}", literal, a.pattern, b.pattern, literal));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn pattern_segments(pattern: &str) -> Vec<&str> {
+    pattern.trim_matches('/').split('/').collect()
+}
+
+fn is_param(segment: &str) -> bool {
+    segment.starts_with('{') && segment.ends_with('}')
+}
+
+// Whether a path segment matches a pattern segment
+fn segment_matches(expected: &str, segment: &str) -> bool {
+    match expected {
+        "{email}" => segment.contains('@') || segment.contains("%40"),
+        _ => is_param(expected) || expected == segment,
+    }
+}
+
+// 405 response listing the methods the path does support
+fn method_not_allowed(path: &str) -> (String, String) {
+    let (status_line, body) = error(ErrorCode::MethodNotAllowed, "Method Not Allowed");
+    (format!("{}{}", status_line, allow_header(path)), body)
+}
+
+// `Allow` header with the methods a known path supports
+pub(crate) fn allow_header(path: &str) -> String {
+    format!("Allow: {}\r\n", allowed_methods(path).unwrap_or_default().join(", "))
+}
+
+// Returns the value of the first header matching `name` (case-insensitive).
+pub(crate) fn get_header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request
+        .split("\r\n\r\n")
+        .next()
+        .unwrap_or_default()
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+// The `{id}` segment of the path, unparsed: wherever the route has it, e.g. `7` in
+// `/admin/impersonate/7`, and otherwise the second segment as in `/users/{id}`
+pub(crate) fn get_id(request: &str) -> &str {
+    let path = get_path(request);
+    let index = find_route(path)
+        .and_then(|route| pattern_segments(route.pattern).iter().position(|segment| is_param(segment)))
+        .unwrap_or(1);
+    path.trim_matches('/').split('/').nth(index).unwrap_or_default()
+}
+
+// The decoded `{email}` segment. A `+` is itself here, as it is in addresses.
+pub(crate) fn get_email(request: &str) -> String {
+    percent_decode(&get_id(request).replace('+', "%2B"))
+}
+
+// Parses the `{id}` segment: digits, optionally after a `-`, that fit a user ID.
+// Anything else is a 400 response, and so are zero and negative IDs unless the policy
+// says to look them up (see `IdPolicy`). A 404 then always means a well-formed ID
+// that doesn't exist.
+pub(crate) fn parse_id(request: &str, policy: IdPolicy) -> Result<i32, (String, String)> {
+    let segment = get_id(request);
+    let digits = segment.strip_prefix('-').unwrap_or(segment);
+    let id = match digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        true => segment.parse::<i32>().ok(),
+        false => None,
+    };
+    match id {
+        Some(id) if id > 0 || policy == IdPolicy::Lookup => Ok(id),
+        Some(_) => Err(error(ErrorCode::InvalidId, "User IDs are positive numbers")),
+        None => Err(error(ErrorCode::InvalidId, "Invalid ID")),
+    }
+}
+
+// Runs a handler for a `/users/{id}` route with the parsed ID, or answers with the
+// parse error
+fn with_id(request: &str, state: &AppState, handler: impl FnOnce(i32) -> (String, String)) -> (String, String) {
+    match parse_id(request, state.id_policy) {
+        Ok(id) => handler(id),
+        Err(response) => response,
+    }
+}
+
+// The request path without its query string, e.g. `/users/5/restore`
+pub(crate) fn get_path(request: &str) -> &str {
+    request
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+}
+
+// Returns the decoded value of a query string parameter, e.g. `include_deleted` in `/users?include_deleted=true`
+pub(crate) fn get_query_param(request: &str, name: &str) -> Option<String> {
+    let target = request.split_whitespace().nth(1)?;
+    let (_, query) = target.split_once('?')?;
+    query
+        .split('&')
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .find(|(key, _)| percent_decode(key) == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+// Decodes `%XX` escapes and `+` (space) in a query string component
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes(patterns: &[&'static str]) -> Vec<Route> {
+        patterns.iter().map(|&pattern| Route { pattern, methods: &["GET"] }).collect()
+    }
+
+    #[test]
+    fn the_route_table_has_no_conflicts() {
+        assert_eq!(check_routes(), Ok(()));
+    }
+
+    #[test]
+    fn literal_segments_take_precedence_over_ids() {
+        let table = routes(&["/users/{id}", "/users/count", "/users/{id}/audit"]);
+        assert_eq!(check(&table), Ok(()));
+        assert_eq!(find_in(&table, "/users/count").map(|route| route.pattern), Some("/users/count"));
+        assert_eq!(find_in(&table, "/users/7").map(|route| route.pattern), Some("/users/{id}"));
+        assert_eq!(find_in(&table, "/users/count/audit").map(|route| route.pattern), Some("/users/{id}/audit"));
+        assert!(find_in(&table, "/users/7/history").is_none());
+    }
+
+    #[test]
+    fn email_segments_only_match_addresses() {
+        let table = routes(&["/users/{id}/audit", "/users/by-email/{email}"]);
+        assert_eq!(check(&table), Ok(()));
+        assert_eq!(find_in(&table, "/users/by-email/ada%40example.com").map(|route| route.pattern), Some("/users/by-email/{email}"));
+        assert_eq!(find_in(&table, "/users/by-email/audit").map(|route| route.pattern), Some("/users/{id}/audit"));
+        let request = "PUT /users/by-email/ada+news%40example.com HTTP/1.1\r\n\r\n";
+        assert_eq!(get_email(request), "ada+news@example.com");
+    }
+
+    #[test]
+    fn conflicting_routes_are_rejected() {
+        let duplicate = check(&routes(&["/users/{id}", "/users/{user_id}"]));
+        assert!(duplicate.unwrap_err().contains("the same"));
+        let ambiguous = check(&routes(&["/users/{id}/audit", "/users/count/{id}"]));
+        assert!(ambiguous.unwrap_err().contains("ambiguous"));
+        let hides_id = check(&routes(&["/users/{id}", "/users/0"]));
+        assert!(hides_id.unwrap_err().contains("would hide the ID 0"));
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use crate::mock;
+use crate::otel;
+
+// Background jobs, each run on its own thread, either once or at a fixed interval. A
+// job that fails logs why; a recurring one is tried again at the next run. Jobs must be safe to run on several
+// instances at once, since every replica schedules its own. Each run is traced as a span
+// of its own (see `otel`), and counted by name for support bundles (see `jobs`).
+
+// What's known about a job since the server started
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Job {
+    pub name: &'static str,
+    // None for jobs run once at a time, on demand
+    pub interval_secs: Option<u64>,
+    pub running: bool,
+    pub runs: u64,
+    pub failures: u64,
+    pub last_started_at: Option<String>,
+    pub last_finished_at: Option<String>,
+    pub last_error: Option<String>,
+}
+
+static JOBS: Mutex<BTreeMap<&'static str, Job>> = Mutex::new(BTreeMap::new());
+
+// Runs `job` once, now, in the background
+pub fn once(name: &'static str, job: impl FnOnce() -> Result<(), String> + Send + 'static) {
+    thread::spawn(move || run(name, job));
+}
+
+// Runs `job` every `interval`, starting one interval from now
+pub fn every(
+    name: &'static str,
+    interval: Duration,
+    mut job: impl FnMut() -> Result<(), String> + Send + 'static,
+) {
+    log!("Scheduled {} every {}s", name, interval.as_secs());
+    jobs_lock().entry(name).or_insert_with(|| Job { name, ..Job::default() }).interval_secs = Some(interval.as_secs());
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        run(name, &mut job);
+    });
+}
+
+// The jobs scheduled or run so far, by name
+pub fn jobs() -> Vec<Job> {
+    jobs_lock().values().cloned().collect()
+}
+
+fn run(name: &'static str, job: impl FnOnce() -> Result<(), String>) {
+    {
+        let mut jobs = jobs_lock();
+        let entry = jobs.entry(name).or_insert_with(|| Job { name, ..Job::default() });
+        entry.running = true;
+        entry.runs += 1;
+        entry.last_started_at = Some(mock::now());
+    }
+    let result = otel::in_span(name, job);
+    let mut jobs = jobs_lock();
+    let entry = jobs.entry(name).or_insert_with(|| Job { name, ..Job::default() });
+    entry.running = false;
+    entry.last_finished_at = Some(mock::now());
+    if let Err(e) = result {
+        log!("Job {} failed: {}", name, e);
+        entry.failures += 1;
+        entry.last_error = Some(e);
+    }
+}
+
+fn jobs_lock() -> MutexGuard<'static, BTreeMap<&'static str, Job>> {
+    JOBS.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -0,0 +1,632 @@
+use postgres::{Error as PostgresError, GenericClient};
+use serde_json::{json, Value};
+
+use crate::audit;
+use crate::delete_policy::Dependents;
+use crate::repo::{self, Deletion, UserFilter};
+use crate::db::with_client;
+use crate::errors::AppError;
+use crate::handlers::check_email_policy;
+use crate::models::User;
+use crate::router::{allow_header, get_header, get_path, get_query_param};
+use crate::server::AppState;
+
+// A subset of SCIM 2.0 (RFC 7643/7644) so identity providers like Okta and Azure AD
+// can provision users: create, get, list with `eq` filters, PATCH and DELETE on
+// `/scim/v2/Users`. SCIM users map onto the users table as
+//   userName / primary email  ->  email
+//   displayName / name        ->  name
+//   active                    ->  not soft-deleted
+// Requests must carry `Authorization: Bearer <SCIM_TOKEN>`.
+
+const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+const LIST_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+const PATCH_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:PatchOp";
+
+// Audit log actor for changes made by the identity provider
+const ACTOR: &str = "scim";
+
+const DEFAULT_COUNT: i64 = 100;
+const MAX_COUNT: i64 = 1000;
+
+// Changes requested by a PATCH; attributes we don't store are ignored so IdPs that
+// send extension attributes (title, department, ...) aren't rejected
+#[derive(Default, Debug, PartialEq)]
+struct Changes {
+    name: Option<String>,
+    given_name: Option<String>,
+    family_name: Option<String>,
+    email: Option<String>,
+    active: Option<bool>,
+}
+
+impl Changes {
+    // displayName wins over name parts, which are joined when that's all we got
+    fn name(&self) -> Option<String> {
+        self.name.clone().or_else(|| {
+            let parts: Vec<&str> = [&self.given_name, &self.family_name]
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+                .collect();
+            (!parts.is_empty()).then(|| parts.join(" "))
+        })
+    }
+}
+
+// Routes a request under `/scim/` after checking the bearer token.
+// Returns a `(String, String)` tuple for HTTP status and body, like the other handlers.
+pub fn handle_request(request: &str, state: &AppState) -> (String, String) {
+    let token = match &state.scim_token {
+        Some(token) => token,
+        None => return error(404, None, "SCIM provisioning is not enabled"),
+    };
+    let authorized = get_header(request, "Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.trim().as_bytes(), token.as_bytes()));
+    if !authorized {
+        let (status, body) = error(401, None, "Invalid or missing bearer token");
+        return (format!("{}WWW-Authenticate: Bearer\r\n", status), body);
+    }
+
+    let method = request.split_whitespace().next().unwrap_or_default();
+    let id = match get_path(request).strip_prefix("/scim/v2/Users") {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest.trim_matches('/'),
+        _ => return error(404, None, "Unknown SCIM resource"),
+    };
+
+    let id: Option<i32> = match id {
+        "" => None,
+        id => match id.parse() {
+            Ok(id) => Some(id),
+            Err(_) => return error(404, None, "User not found"),
+        },
+    };
+    if !matches!(
+        (method, id),
+        ("POST" | "GET", None) | ("GET" | "PATCH" | "DELETE", Some(_))
+    ) {
+        let (status, body) = error(405, None, "Method not allowed");
+        return (format!("{}{}", status, allow_header(get_path(request))), body);
+    }
+
+    let body = request.split("\r\n\r\n").last().unwrap_or_default();
+
+    // Each request runs in one transaction, together with its audit log entries,
+    // and is rolled back unless it succeeded
+    let result = with_client(state, |client| {
+        repo::with_transaction(client, |client| {
+            let (status, body) = match (method, id) {
+                ("POST", None) => create_user(client, state, body)?,
+                ("GET", None) => list_users(client, request)?,
+                ("GET", Some(id)) => get_user(client, id)?,
+                ("PATCH", Some(id)) => patch_user(client, state, id, body)?,
+                (_, Some(id)) => delete_user(client, id)?,
+                _ => error(405, None, "Method not allowed"),
+            };
+            if status.starts_with("HTTP/1.1 2") {
+                Ok((status, body))
+            } else {
+                Err(AppError::Response(status, body))
+            }
+        })
+    });
+
+    match result {
+        Ok(response) => response,
+        Err(AppError::Response(status, body)) => (status, body),
+        Err(e) => {
+            log!("SCIM error: {}", e);
+            error(500, None, "Database error")
+        }
+    }
+}
+
+fn create_user(
+    client: &mut impl GenericClient,
+    state: &AppState,
+    body: &str,
+) -> Result<(String, String), PostgresError> {
+    let resource: Value = match serde_json::from_str(body) {
+        Ok(resource) => resource,
+        Err(_) => return Ok(error(400, Some("invalidSyntax"), "Body is not valid JSON")),
+    };
+    let (name, email, active) = match user_from_resource(&resource) {
+        Ok(user) => user,
+        Err(detail) => return Ok(error(400, Some("invalidValue"), &detail)),
+    };
+    if let Err(detail) = check_email_policy(state, &email) {
+        return Ok(error(400, Some("invalidValue"), &detail));
+    }
+
+    let filter = UserFilter {
+        include_deleted: true,
+        email: Some(email.clone()),
+        ..Default::default()
+    };
+    if repo::count_users(client, &filter)? > 0 {
+        return Ok(error(409, Some("uniqueness"), "userName is already taken"));
+    }
+
+    let user = User {
+        id: None,
+        name,
+        email,
+        created_at: None,
+        updated_at: None,
+        deleted_at: None,
+        version: None,
+        verified: None,
+    };
+    let id = repo::create_user(client, &user)?;
+    // The identity provider owns the address, so there's nothing to verify
+    repo::set_verified(client, id)?;
+    if !active {
+        repo::soft_delete_user(client, id)?;
+    }
+
+    let created = repo::find_user(client, id, true)?;
+    audit::record(client, ACTOR, "create", id, None, created.as_ref())?;
+    Ok(match created {
+        Some(user) => (
+            format!("{}Location: /scim/v2/Users/{}\r\n", status_line(201), id),
+            to_resource(&user).to_string(),
+        ),
+        None => error(500, None, "Created user disappeared"),
+    })
+}
+
+fn get_user(client: &mut impl GenericClient, id: i32) -> Result<(String, String), PostgresError> {
+    // Deactivated users stay visible so the IdP can reactivate them
+    Ok(match repo::find_user(client, id, true)? {
+        Some(user) => (status_line(200), to_resource(&user).to_string()),
+        None => error(404, None, "User not found"),
+    })
+}
+
+fn list_users(
+    client: &mut impl GenericClient,
+    request: &str,
+) -> Result<(String, String), PostgresError> {
+    let mut filter = UserFilter {
+        include_deleted: true,
+        ..Default::default()
+    };
+    if let Some(expression) = get_query_param(request, "filter") {
+        match parse_filter(&expression) {
+            Ok(("email", value)) => filter.email = Some(value),
+            Ok((_, value)) => filter.name = Some(value),
+            Err(detail) => return Ok(error(400, Some("invalidFilter"), &detail)),
+        }
+    }
+
+    // startIndex is 1-based; out-of-range values are clamped as RFC 7644 asks
+    let start_index = get_query_param(request, "startIndex")
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(1)
+        .max(1);
+    let count = get_query_param(request, "count")
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_COUNT)
+        .clamp(0, MAX_COUNT);
+    filter.offset = start_index - 1;
+    filter.limit = Some(count);
+
+    let total = repo::count_users(client, &filter)?;
+    let users = repo::list_users(client, &filter)?;
+    let body = json!({
+        "schemas": [LIST_SCHEMA],
+        "totalResults": total,
+        "startIndex": start_index,
+        "itemsPerPage": users.len(),
+        "Resources": users.iter().map(to_resource).collect::<Vec<_>>(),
+    });
+    Ok((status_line(200), body.to_string()))
+}
+
+fn patch_user(
+    client: &mut impl GenericClient,
+    state: &AppState,
+    id: i32,
+    body: &str,
+) -> Result<(String, String), PostgresError> {
+    let patch: Value = match serde_json::from_str(body) {
+        Ok(patch) => patch,
+        Err(_) => return Ok(error(400, Some("invalidSyntax"), "Body is not valid JSON")),
+    };
+    let changes = match parse_patch(&patch) {
+        Ok(changes) => changes,
+        Err(detail) => return Ok(error(400, Some("invalidValue"), &detail)),
+    };
+    if let Some(email) = &changes.email {
+        if let Err(detail) = check_email_policy(state, email) {
+            return Ok(error(400, Some("invalidValue"), &detail));
+        }
+    }
+
+    let current = match repo::find_user(client, id, true)? {
+        Some(user) => user,
+        None => return Ok(error(404, None, "User not found")),
+    };
+
+    let mut active = current.deleted_at.is_none();
+    if changes.active == Some(true) && !active {
+        repo::restore_user(client, id)?;
+        active = true;
+    }
+    let name = changes.name();
+    if name.is_some() || changes.email.is_some() {
+        if !active {
+            return Ok(error(
+                400,
+                Some("mutability"),
+                "Reactivate the user before changing it",
+            ));
+        }
+        repo::update_user(
+            client,
+            id,
+            current.version.unwrap_or_default(),
+            name.as_deref().unwrap_or(&current.name),
+            changes.email.as_deref().unwrap_or(&current.email),
+        )?;
+    }
+    if changes.active == Some(false) && active {
+        if let Deletion::Restricted(dependents) = repo::delete_user(client, id)? {
+            return Ok(restricted(&dependents));
+        }
+    }
+
+    let updated = repo::find_user(client, id, true)?;
+    audit::record(
+        client,
+        ACTOR,
+        "update",
+        id,
+        Some(&current),
+        updated.as_ref(),
+    )?;
+    Ok(match updated {
+        Some(user) => (status_line(200), to_resource(&user).to_string()),
+        None => error(404, None, "User not found"),
+    })
+}
+
+// Deprovisioning soft-deletes, so an admin can still restore the user
+fn delete_user(
+    client: &mut impl GenericClient,
+    id: i32,
+) -> Result<(String, String), PostgresError> {
+    let old = repo::find_user(client, id, false)?;
+    match repo::delete_user(client, id)? {
+        Deletion::Deleted => {}
+        Deletion::NotFound => return Ok(error(404, None, "User not found")),
+        Deletion::Restricted(dependents) => return Ok(restricted(&dependents)),
+    }
+    let new = repo::find_user(client, id, true)?;
+    audit::record(client, ACTOR, "delete", id, old.as_ref(), new.as_ref())?;
+    Ok((status_line(204), String::new()))
+}
+
+fn to_resource(user: &User) -> Value {
+    let id = user.id.unwrap_or_default();
+    json!({
+        "schemas": [USER_SCHEMA],
+        "id": id.to_string(),
+        "userName": user.email,
+        "displayName": user.name,
+        "name": { "formatted": user.name },
+        "emails": [{ "value": user.email, "primary": true }],
+        "active": user.deleted_at.is_none(),
+        "meta": {
+            "resourceType": "User",
+            "created": user.created_at,
+            "lastModified": user.updated_at,
+            "location": format!("/scim/v2/Users/{}", id),
+        },
+    })
+}
+
+// Extracts (name, email, active) from a SCIM User resource
+fn user_from_resource(resource: &Value) -> Result<(String, String, bool), String> {
+    let user_name = resource["userName"].as_str();
+    let emails = resource["emails"].as_array();
+    let email = emails
+        .and_then(|emails| {
+            emails
+                .iter()
+                .find(|email| parse_bool(&email["primary"]) == Some(true))
+                .or_else(|| emails.first())
+        })
+        .and_then(|email| email["value"].as_str())
+        .or(user_name.filter(|user_name| user_name.contains('@')))
+        .ok_or("userName or emails must contain an email address")?;
+
+    let mut changes = Changes {
+        name: resource["displayName"]
+            .as_str()
+            .or_else(|| resource["name"]["formatted"].as_str())
+            .map(str::to_string),
+        ..Default::default()
+    };
+    changes.given_name = resource["name"]["givenName"].as_str().map(str::to_string);
+    changes.family_name = resource["name"]["familyName"].as_str().map(str::to_string);
+    let name = changes
+        .name()
+        .or(user_name.map(str::to_string))
+        .ok_or("displayName or name is required")?;
+
+    let active = match &resource["active"] {
+        Value::Null => true,
+        value => parse_bool(value).ok_or("active must be a boolean")?,
+    };
+    Ok((name, email.to_string(), active))
+}
+
+// Collects the changes from a PatchOp request. Operation names are matched
+// case-insensitively because Azure AD sends `Replace`/`Add`.
+fn parse_patch(patch: &Value) -> Result<Changes, String> {
+    let operations = patch["Operations"]
+        .as_array()
+        .ok_or_else(|| format!("Expected a {} request with Operations", PATCH_SCHEMA))?;
+
+    let mut changes = Changes::default();
+    for operation in operations {
+        let op = operation["op"].as_str().unwrap_or_default().to_lowercase();
+        let value = &operation["value"];
+        match (op.as_str(), operation["path"].as_str()) {
+            ("add" | "replace", Some(path)) => apply_attribute(&mut changes, path, value)?,
+            // Without a path, the value is an object of attributes to set
+            ("add" | "replace", None) => {
+                let attributes = value
+                    .as_object()
+                    .ok_or("value must be an object when path is omitted")?;
+                for (attribute, value) in attributes {
+                    apply_attribute(&mut changes, attribute, value)?;
+                }
+            }
+            ("remove", Some(path)) => {
+                if matches!(
+                    attribute_name(path).as_str(),
+                    "username" | "emails" | "displayname" | "active"
+                ) {
+                    return Err(format!("{} is required and can't be removed", path));
+                }
+            }
+            _ => return Err(format!("Unsupported operation: {}", operation)),
+        }
+    }
+    Ok(changes)
+}
+
+fn apply_attribute(changes: &mut Changes, path: &str, value: &Value) -> Result<(), String> {
+    let text = || {
+        value
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| format!("{} must be a string", path))
+    };
+    match attribute_name(path).as_str() {
+        "active" => changes.active = Some(parse_bool(value).ok_or("active must be a boolean")?),
+        "displayname" | "name.formatted" => changes.name = Some(text()?),
+        "name.givenname" => changes.given_name = Some(text()?),
+        "name.familyname" => changes.family_name = Some(text()?),
+        "username" => changes.email = Some(text()?),
+        "name" => {
+            changes.name = value["formatted"]
+                .as_str()
+                .map(str::to_string)
+                .or(changes.name.take());
+            changes.given_name = value["givenName"].as_str().map(str::to_string);
+            changes.family_name = value["familyName"].as_str().map(str::to_string);
+        }
+        "emails" => {
+            // Either `emails[type eq "work"].value` with a string, or the whole array
+            changes.email = match value {
+                Value::String(email) => Some(email.clone()),
+                Value::Array(emails) => emails
+                    .iter()
+                    .find(|email| parse_bool(&email["primary"]) == Some(true))
+                    .or_else(|| emails.first())
+                    .and_then(|email| email["value"].as_str())
+                    .map(str::to_string),
+                _ => return Err("emails must be a string or an array".to_string()),
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Lowercased attribute path with any value filter dropped:
+// `emails[type eq "work"].value` -> `emails`, `name.givenName` -> `name.givenname`
+fn attribute_name(path: &str) -> String {
+    let path = path.to_lowercase();
+    if path.starts_with("emails") {
+        return "emails".to_string();
+    }
+    path.trim_start_matches("urn:ietf:params:scim:schemas:core:2.0:user:")
+        .to_string()
+}
+
+// Parses `attribute eq "value"`, the only filter IdPs need for provisioning.
+// Returns which local column to match ("email" or "name") and the value.
+fn parse_filter(expression: &str) -> Result<(&'static str, String), String> {
+    let unsupported = || format!("Unsupported filter: {}", expression);
+    let mut parts = expression.trim().splitn(3, ' ');
+    let (attribute, operator, value) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(attribute), Some(operator), Some(value)) => (attribute, operator, value),
+        _ => return Err(unsupported()),
+    };
+    if !operator.eq_ignore_ascii_case("eq") {
+        return Err(unsupported());
+    }
+    let value = value
+        .trim()
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .ok_or_else(unsupported)?
+        .replace("\\\"", "\"");
+
+    match attribute.to_lowercase().as_str() {
+        "username" | "emails" | "emails.value" => Ok(("email", value)),
+        "displayname" => Ok(("name", value)),
+        _ => Err(unsupported()),
+    }
+}
+
+// Booleans may arrive as strings; Azure AD sends "True"/"False"
+fn parse_bool(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(value) => Some(*value),
+        Value::String(value) if value.eq_ignore_ascii_case("true") => Some(true),
+        Value::String(value) if value.eq_ignore_ascii_case("false") => Some(false),
+        _ => None,
+    }
+}
+
+// Compares tokens without bailing out at the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn status_line(status: u16) -> String {
+    let reason = match status {
+        200 => "OK",
+        201 => "CREATED",
+        204 => "NO CONTENT",
+        400 => "BAD REQUEST",
+        401 => "UNAUTHORIZED",
+        404 => "NOT FOUND",
+        405 => "METHOD NOT ALLOWED",
+        409 => "CONFLICT",
+        _ => "INTERNAL SERVER ERROR",
+    };
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/scim+json\r\n",
+        status, reason
+    )
+}
+
+// A 409 for a user the delete policies keep from being deprovisioned
+fn restricted(dependents: &Dependents) -> (String, String) {
+    let records: Vec<String> = dependents
+        .iter()
+        .map(|(table, keys)| {
+            let keys: Vec<String> = keys.iter().map(i32::to_string).collect();
+            format!("{} {}", table, keys.join(", "))
+        })
+        .collect();
+    error(409, None, &format!("The user has related records: {}", records.join("; ")))
+}
+
+fn error(status: u16, scim_type: Option<&str>, detail: &str) -> (String, String) {
+    let mut body = json!({
+        "schemas": [ERROR_SCHEMA],
+        "status": status.to_string(),
+        "detail": detail,
+    });
+    if let Some(scim_type) = scim_type {
+        body["scimType"] = scim_type.into();
+    }
+    (status_line(status), body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn okta_create_payload_is_mapped() {
+        let resource = json!({
+            "schemas": [USER_SCHEMA],
+            "userName": "ada@example.com",
+            "name": { "givenName": "Ada", "familyName": "Lovelace" },
+            "emails": [{ "primary": true, "value": "ada@example.com", "type": "work" }],
+            "displayName": "Ada Lovelace",
+            "locale": "en-US",
+            "active": true
+        });
+        assert_eq!(
+            user_from_resource(&resource).unwrap(),
+            (
+                "Ada Lovelace".to_string(),
+                "ada@example.com".to_string(),
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn azure_ad_create_payload_is_mapped() {
+        let resource = json!({
+            "schemas": [USER_SCHEMA, "urn:ietf:params:scim:schemas:extension:enterprise:2.0:User"],
+            "externalId": "0a21f0f2-8d2a-4f8e-bf98-7363c4aed4ef",
+            "userName": "Test_User_ab6490ee@contoso.com",
+            "active": "False",
+            "emails": [{ "primary": "True", "type": "work", "value": "Test_User_fd0ea19b@contoso.com" }],
+            "name": { "givenName": "Grace", "familyName": "Hopper" },
+            "urn:ietf:params:scim:schemas:extension:enterprise:2.0:User": { "department": "Eng" }
+        });
+        assert_eq!(
+            user_from_resource(&resource).unwrap(),
+            (
+                "Grace Hopper".to_string(),
+                "Test_User_fd0ea19b@contoso.com".to_string(),
+                false
+            )
+        );
+        assert!(user_from_resource(&json!({ "userName": "no-email" })).is_err());
+    }
+
+    #[test]
+    fn okta_deactivation_patch_is_parsed() {
+        let patch = json!({
+            "schemas": [PATCH_SCHEMA],
+            "Operations": [{ "op": "replace", "value": { "active": false } }]
+        });
+        assert_eq!(
+            parse_patch(&patch).unwrap(),
+            Changes {
+                active: Some(false),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn azure_ad_patch_operations_are_parsed() {
+        let patch = json!({
+            "schemas": [PATCH_SCHEMA],
+            "Operations": [
+                { "op": "Replace", "path": "emails[type eq \"work\"].value", "value": "new@contoso.com" },
+                { "op": "Replace", "path": "name.givenName", "value": "Grace" },
+                { "op": "Replace", "path": "name.familyName", "value": "Hopper" },
+                { "op": "Add", "path": "urn:ietf:params:scim:schemas:extension:enterprise:2.0:User:department", "value": "Eng" },
+                { "op": "Replace", "path": "active", "value": "True" }
+            ]
+        });
+        let changes = parse_patch(&patch).unwrap();
+        assert_eq!(changes.email.as_deref(), Some("new@contoso.com"));
+        assert_eq!(changes.name().as_deref(), Some("Grace Hopper"));
+        assert_eq!(changes.active, Some(true));
+
+        let remove = json!({ "Operations": [{ "op": "remove", "path": "userName" }] });
+        assert!(parse_patch(&remove).is_err());
+    }
+
+    #[test]
+    fn equality_filters_are_parsed() {
+        assert_eq!(
+            parse_filter("userName eq \"ada@example.com\"").unwrap(),
+            ("email", "ada@example.com".to_string())
+        );
+        assert_eq!(
+            parse_filter("displayName EQ \"Ada \\\"The Countess\\\"\"").unwrap(),
+            ("name", "Ada \"The Countess\"".to_string())
+        );
+        assert!(parse_filter("userName sw \"ada\"").is_err());
+        assert!(parse_filter("title eq \"x\"").is_err());
+    }
+}
@@ -0,0 +1,44 @@
+use postgres::{Client, Error as PostgresError};
+use std::env;
+
+use crate::mock;
+use crate::query_log::Timed;
+use crate::repo;
+
+// Sample users for development databases. With SEED_USERS=N (50 in the dev profile,
+// see `profile`) a database without any users gets N generated ones at startup, the
+// same ones `serve --mock` serves, so a fresh environment isn't blank. Databases that
+// have users, even deleted ones, are left alone.
+
+// Seed of the generated users, the same as `serve --mock`'s default
+const SEED: u64 = 42;
+
+pub fn count_from_env() -> usize {
+    env::var("SEED_USERS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+// Adds `count` sample users if there are no users yet; returns how many were added
+pub fn seed_if_empty(client: &mut Client, count: usize) -> Result<usize, PostgresError> {
+    if count == 0 {
+        return Ok(0);
+    }
+    repo::with_transaction(client, |transaction| {
+        // Two instances starting together mustn't both seed
+        transaction.timed_execute("LOCK TABLE users IN EXCLUSIVE MODE", &[])?;
+        let row = transaction.timed_query_one("SELECT EXISTS (SELECT 1 FROM users)", &[])?;
+        if row.try_get(0)? {
+            return Ok(0);
+        }
+        for user in mock::generate_users(SEED, count) {
+            let id = repo::create_user(transaction, &user)?;
+            repo::set_verified(transaction, id)?;
+            if user.deleted_at.is_some() {
+                repo::soft_delete_user(transaction, id)?;
+            }
+        }
+        Ok(count)
+    })
+}
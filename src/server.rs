@@ -0,0 +1,1152 @@
+use sha2::{Digest, Sha256};
+use std::env;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::admin_socket;
+use crate::archive;
+use crate::body;
+use crate::events;
+#[cfg(feature = "parquet")]
+use crate::export::{self, Exports};
+use crate::audit_partitions;
+use crate::chaos::Chaos;
+use crate::chunked::ChunkedWriter;
+use crate::access_log::AccessLog;
+use crate::admin_ui::AdminUi;
+use crate::compression::{self, Compression};
+use crate::cors::{self, Cors};
+use crate::config::{self, get_db_url, get_id_policy, get_max_connections, get_timeouts, IdPolicy, Timeouts};
+use crate::debug::{self, Debugging};
+use crate::db::{self, create_db_pool, set_database, with_client, DbPool};
+use crate::disposable_email::DisposableEmails;
+use crate::email::{self, Mailer};
+use crate::email_change::EmailChanges;
+use crate::email_policy::EmailPolicy;
+use crate::email_verification;
+use crate::features::FeatureMetrics;
+use crate::health;
+use crate::http::Response;
+use crate::idempotency;
+use crate::impersonation::{self, Impersonations};
+use crate::errors::{error, error_with_status, AppError, ErrorCode};
+use crate::handlers::{list_error, list_fields, list_filter, wants_ndjson, NDJSON_RESPONSE, OK_RESPONSE};
+use crate::metrics::{DatabaseLatencies, MetricsHistory};
+use crate::mock::{self, MockStore};
+use crate::notifications;
+use crate::otel;
+use crate::rate_limit::RateLimiter;
+use crate::reload;
+use crate::repo;
+use crate::request_id;
+use crate::seed;
+use crate::slo::Slo;
+use crate::response_cache::{self, Lookup, ResponseCache};
+use crate::retry::{self, RetryPolicy};
+use crate::router::{self, get_header, get_path};
+use crate::scheduler;
+use crate::shutdown;
+use crate::slow_requests::{self, SlowRequest, SlowRequests};
+use crate::sse;
+use crate::standby::Standby;
+use crate::statsd::StatsD;
+use crate::storage;
+use crate::webhooks;
+use crate::websocket;
+
+// The HTTP/1.1 server: accepts connections, reads requests off them (keep-alive and
+// pipelining included), hands each to the router and writes the response.
+
+// State shared by all connection threads
+pub struct AppState {
+    pub(crate) db: DbPool,
+    // Where connections go while the primary database is down, when configured
+    pub(crate) standby: Option<Arc<Standby>>,
+    // Replaced when the settings are reloaded (see `reload`)
+    pub(crate) rate_limiter: RwLock<Option<RateLimiter>>,
+    // Bearer token for the SCIM endpoints; they are disabled when it's not set
+    pub(crate) scim_token: Option<String>,
+    // Replaceable at runtime through the admin endpoint
+    pub(crate) email_policy: RwLock<EmailPolicy>,
+    pub(crate) disposable_emails: DisposableEmails,
+    // Whether new email addresses wait for confirmation
+    pub(crate) email_changes: EmailChanges,
+    // How long the token mailed to new users stays valid
+    pub(crate) verification_token_ttl: Duration,
+    // How long an Idempotency-Key and its response are kept
+    pub(crate) idempotency_key_ttl: Duration,
+    // Dependencies that must be up for GET /health/detail to report the service up
+    pub(crate) health_critical: Vec<String>,
+    pub(crate) mailer: Box<dyn Mailer>,
+    // Support staff acting as users (see `impersonation`)
+    pub(crate) impersonations: Impersonations,
+    // Most users a single bulk update may touch
+    pub(crate) bulk_update_limit: i64,
+    // Share of a table a bulk change must touch to be analyzed (see `storage`)
+    pub(crate) bulk_analyze_fraction: Option<f64>,
+    // Retries for transient database errors
+    pub(crate) retry: RetryPolicy,
+    pub(crate) compression: Compression,
+    // Recent GET /users responses, when RESPONSE_CACHE_TTL_SECS is set
+    pub(crate) response_cache: Option<ResponseCache>,
+    // Generated users served instead of the database by `serve --mock`
+    pub(crate) mock: Option<MockStore>,
+    // Injected latency and errors, in mock mode or with CHAOS=on
+    pub(crate) chaos: Option<Chaos>,
+    // Per-minute request counts and latencies for GET /admin/metrics/history
+    pub(crate) metrics: MetricsHistory,
+    // Database time per route, reported alongside
+    pub(crate) database_latencies: DatabaseLatencies,
+    // The same metrics pushed to a StatsD agent, when STATSD_ADDR is set
+    pub(crate) statsd: Option<StatsD>,
+    // Availability and latency SLIs per route, for GET /admin/slo
+    pub(crate) slo: Slo,
+    // The slowest recent requests, for GET /admin/slow-requests
+    pub(crate) slow_requests: SlowRequests,
+    pub(crate) features: FeatureMetrics,
+    pub(crate) id_policy: IdPolicy,
+    // Cross-origin access for browser apps, when CORS_ALLOWED_ORIGINS is set. Replaced
+    // when the settings are reloaded.
+    pub(crate) cors: RwLock<Option<Cors>>,
+    // `X-Debug: true` responses for admins, when enabled (see `debug`)
+    pub(crate) debugging: Option<Debugging>,
+    // The admin UI bundle, when ADMIN_UI_DIR is set
+    pub(crate) admin_ui: Option<AdminUi>,
+    // A line per request in ACCESS_LOG_FILE, when it's set
+    pub(crate) access_log: Option<AccessLog>,
+    // Parquet exports being written or kept (see `export`)
+    #[cfg(feature = "parquet")]
+    pub(crate) exports: Exports,
+}
+
+impl AppState {
+    // Settings come from the environment; with a mock store the database is never used
+    pub fn from_env(mock: Option<MockStore>) -> AppState {
+        let standby = Standby::from_env().map(Arc::new);
+        AppState {
+            db: create_db_pool(standby.clone()),
+            standby,
+            rate_limiter: RwLock::new(RateLimiter::from_env()),
+            scim_token: env::var("SCIM_TOKEN").ok().filter(|token| !token.is_empty()),
+            email_policy: RwLock::new(EmailPolicy::from_env()),
+            disposable_emails: DisposableEmails::from_env(),
+            email_changes: EmailChanges::from_env(),
+            verification_token_ttl: email_verification::token_ttl_from_env(),
+            idempotency_key_ttl: idempotency::ttl_from_env(),
+            health_critical: health::critical_from_env(),
+            mailer: email::from_env(),
+            impersonations: Impersonations::from_env(),
+            bulk_update_limit: env::var("BULK_UPDATE_MAX_ROWS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(1000),
+            bulk_analyze_fraction: storage::bulk_analyze_fraction_from_env(),
+            retry: RetryPolicy::from_env(),
+            compression: Compression::from_env(),
+            response_cache: ResponseCache::from_env(),
+            chaos: Chaos::from_env(mock.is_some()),
+            metrics: MetricsHistory::from_env(),
+            database_latencies: DatabaseLatencies::from_env(),
+            statsd: StatsD::from_env(),
+            slo: Slo::from_env(),
+            slow_requests: SlowRequests::from_env(),
+            features: FeatureMetrics::from_env(),
+            id_policy: get_id_policy(),
+            cors: RwLock::new(Cors::from_env()),
+            debugging: Debugging::from_env(),
+            admin_ui: AdminUi::from_env(),
+            access_log: AccessLog::from_env(),
+            #[cfg(feature = "parquet")]
+            exports: Exports::from_env(),
+            mock,
+        }
+    }
+}
+
+// Who is on the other end of a connection
+pub enum Caller {
+    // A client connected over TCP, identified by its IP address
+    Remote { ip: String },
+    // A local process on the admin socket, authenticated by its peer credentials
+    LocalAdmin { uid: u32, gid: u32 },
+}
+
+impl Caller {
+    pub fn is_admin(&self) -> bool {
+        matches!(self, Caller::LocalAdmin { .. })
+    }
+
+    // Describes the caller for logs
+    pub fn identity(&self) -> String {
+        match self {
+            Caller::Remote { ip } => format!("ip={}", ip),
+            Caller::LocalAdmin { uid, gid } => format!("uid={} gid={}", uid, gid),
+        }
+    }
+}
+
+// Sockets the server can serve requests on
+pub(crate) trait Connection: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+// `serve [--mock ...]`: sets up the database and serves the API on port 8080 until killed
+pub fn run(args: &[String]) {
+    if let Err(e) = router::check_routes() {
+        eprintln!("Error in the route table: {}", e);
+        std::process::exit(1);
+    }
+
+    let mock = match mock::parse_options(args) {
+        Ok(mock) => mock.map(|options| {
+            let saved = options.data.as_ref().filter(|data| data.exists());
+            let store = MockStore::load_or_generate(&options).unwrap_or_else(|e| {
+                eprintln!("Error loading mock data: {}", e);
+                std::process::exit(1);
+            });
+            match saved {
+                Some(data) => log!("Mock mode: serving {} users from {} without a database", store.len(), data.display()),
+                None => log!("Mock mode: serving {} generated users (seed {}) without a database", store.len(), options.seed),
+            }
+            store
+        }),
+        Err(e) => {
+            eprintln!("Error running serve: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // Every setting is checked up front; mock mode doesn't need a database
+    config::exit_if_invalid(mock.is_none());
+    if let Ok(profile) = env::var("APP_ENV") {
+        log!("Using the {} profile", profile);
+    }
+    // Trace export, when an OTLP endpoint is configured (see `otel`)
+    otel::init();
+
+    // Set database
+    // This function returns a Result<(), PostgresError> because it performs an action (DB setup)
+    // that might fail, but doesn't need to return any data upon success.
+    // The `()` unit type signifies that on success, no specific value is returned.
+    // The database may still be starting (e.g. under docker-compose), so connection
+    // errors are retried with backoff for up to WAIT_FOR_DB_SECS before giving up.
+    if mock.is_none() {
+        if let Err(e) = RetryPolicy::startup_from_env().run(set_database, retry::is_transient) {
+            log!("Error setting up database: {}", e);
+            std::process::exit(1);
+        }
+        // Sample users for an empty database (see `seed`)
+        let count = seed::count_from_env();
+        match db::connect(None).and_then(|mut client| seed::seed_if_empty(&mut client, count)) {
+            Ok(0) => {}
+            Ok(seeded) => log!("Seeded the database with {} sample users", seeded),
+            Err(e) => log!("Error seeding the database: {}", e),
+        }
+    }
+
+    // Start server
+    let listener = TcpListener::bind("0.0.0.0:8080").unwrap(); // Fixed format! syntax
+    log!("Server started at port 8080");
+
+    let state = Arc::new(AppState::from_env(mock));
+
+    // Stop cleanly on SIGTERM and SIGINT, saving mock data first (see `shutdown`)
+    shutdown::install();
+    if state.mock.is_some() {
+        let state = Arc::clone(&state);
+        shutdown::on_shutdown(move || match state.mock.as_ref().map(MockStore::save) {
+            Some(Ok(Some(saved))) => log!("Saved {} mock users", saved),
+            Some(Err(e)) => log!("Error saving mock data: {}", e),
+            _ => {}
+        });
+    }
+    // Reload rate limits and CORS settings on SIGHUP (see `reload`)
+    reload::install(Arc::clone(&state));
+
+    // Digest emails (see `notifications`)
+    if let Some(interval) = notifications::check_interval_from_env().filter(|_| state.mock.is_none()) {
+        let state = Arc::clone(&state);
+        scheduler::every("digests", interval, move || {
+            let sent = with_client(&state, |client| Ok(notifications::send_due_digests(client, state.mailer.as_ref())?)).map_err(|e| e.to_string())?;
+            if sent > 0 {
+                log!("Sent {} digest(s)", sent);
+            }
+            Ok(())
+        });
+    }
+    // Change events from other instances, for this one's subscribers (see `events`)
+    if state.mock.is_none() {
+        let standby = state.standby.clone();
+        let generation = state.standby.clone();
+        events::listen(
+            move || db::connect(standby.as_deref()),
+            move || generation.as_ref().map_or(0, |standby| standby.generation()),
+        );
+    }
+    // Switching back from the standby database (see `standby`)
+    if let Some(standby) = state.standby.clone().filter(|_| state.mock.is_none()) {
+        scheduler::every("standby failback", standby.failback_interval(), move || {
+            standby.fail_back(&get_db_url())
+        });
+    }
+    // Audit log partitions and their retention (see `audit_partitions`)
+    if state.mock.is_none() {
+        let state = Arc::clone(&state);
+        let retention = audit_partitions::retention_months_from_env();
+        scheduler::every("audit partitions", audit_partitions::CHECK_INTERVAL, move || {
+            with_client(&state, |client| Ok(audit_partitions::maintain(client, retention)?)).map_err(|e| e.to_string())
+        });
+    }
+    // Cold storage for inactive users (see `archive`)
+    if let Some(days) = archive::inactive_days_from_env().filter(|_| state.mock.is_none()) {
+        let state = Arc::clone(&state);
+        scheduler::every("archive", archive::CHECK_INTERVAL, move || {
+            let archived = with_client(&state, |client| Ok(archive::archive_inactive(client, days)?)).map_err(|e| e.to_string())?;
+            if !archived.is_empty() {
+                log!("Archived {} user(s) inactive for {} days", archived.len(), days);
+                if let Some(cache) = &state.response_cache {
+                    cache.invalidate("/users");
+                }
+            }
+            Ok(())
+        });
+    }
+    // Webhook deliveries (see `webhooks`)
+    if state.mock.is_none() {
+        let state = Arc::clone(&state);
+        scheduler::every("webhooks", webhooks::poll_interval_from_env(), move || {
+            with_client(&state, |client| {
+                webhooks::deliver_due(client)?;
+                webhooks::delete_expired(client)?;
+                Ok(())
+            }).map_err(|e| e.to_string())
+        });
+    }
+    // Expired idempotency keys (see `idempotency`)
+    if state.mock.is_none() {
+        let state = Arc::clone(&state);
+        scheduler::every("idempotency keys", idempotency::CLEANUP_INTERVAL, move || {
+            with_client(&state, |client| Ok(idempotency::delete_expired(client)?)).map(|_| ()).map_err(|e| e.to_string())
+        });
+    }
+    // Routine maintenance (see `storage`)
+    if let Some(interval) = storage::vacuum_interval_from_env().filter(|_| state.mock.is_none()) {
+        let state = Arc::clone(&state);
+        scheduler::every("vacuum", interval, move || {
+            with_client(&state, |client| Ok(storage::vacuum_analyze(client)?)).map_err(|e| e.to_string())
+        });
+    }
+
+    // Optionally serve the API on a Unix socket for local operators
+    if let Ok(path) = env::var("ADMIN_SOCKET") {
+        if let Err(e) = admin_socket::serve(&path, Arc::clone(&state)) {
+            log!("Error starting admin socket: {}", e);
+            return;
+        }
+    }
+
+    serve(listener, state);
+}
+
+// Accepts connections until the listener fails for good. Integration tests can run the
+// server in-process by binding a listener to port 0 and calling this on a thread.
+pub fn serve(listener: TcpListener, state: Arc<AppState>) {
+    let max_connections = get_max_connections();
+    let open = Arc::new(AtomicUsize::new(0));
+
+    // Handle the client
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                // Connections over the limit are told so and closed, rather than each
+                // getting a thread until the server runs out of them
+                if open.fetch_add(1, Ordering::SeqCst) >= max_connections {
+                    open.fetch_sub(1, Ordering::SeqCst);
+                    let (status_line, content) = error(ErrorCode::TooManyConnections, "Too many connections");
+                    let response = Response::parse(&status_line, content).header("Retry-After", 1).header("Connection", "close");
+                    if let Err(e) = stream.set_write_timeout(Some(get_timeouts().write)).and_then(|()| response.write_to(&mut stream)) {
+                        log!("Failed to send response: {}", e);
+                    }
+                    continue;
+                }
+                let slot = OpenConnection(Arc::clone(&open));
+
+                // Each connection gets its own thread so an idle keep-alive client
+                // doesn't block everyone else
+                let state = Arc::clone(&state);
+                let caller = Caller::Remote {
+                    ip: match stream.peer_addr() {
+                        Ok(addr) => addr.ip().to_string(),
+                        Err(_) => "unknown".to_string(),
+                    },
+                };
+                thread::spawn(move || {
+                    let _slot = slot;
+                    handle_client(stream, &state, caller)
+                });
+            }
+            Err(e) => {
+                log!("Error: {}", e);
+            }
+        }
+    }
+}
+
+// Counts towards MAX_CONNECTIONS until the connection's thread is done with it
+struct OpenConnection(Arc<AtomicUsize>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) fn handle_client(mut stream: impl Connection, state: &AppState, caller: Caller) {
+    let timeouts = get_timeouts();
+    let max_body = body::max_bytes_from_env();
+    if let Err(e) = stream.set_write_timeout(Some(timeouts.write)) {
+        log!("Error: {}", e);
+        return;
+    }
+
+    // Bytes read from the socket but not yet consumed. Pipelined clients may send
+    // several requests at once, so anything after the current request is kept here.
+    let mut buffer = Vec::new();
+    let mut served_any = false;
+
+    loop {
+        request_id::set_current(None);
+        cors::set_current(String::new());
+        impersonation::set_current(None);
+        let request = match read_request(&mut stream, &mut buffer, &timeouts, max_body, served_any) {
+            Ok(RequestRead::Request(request)) => request,
+            Ok(RequestRead::Closed) => break,
+            // The body is never read, so the connection can't be reused
+            Ok(RequestRead::TooLarge(length)) => {
+                let message = format!("Body of {} bytes is over the limit of {} bytes", length, max_body);
+                let (status_line, content) = error(ErrorCode::PayloadTooLarge, &message);
+                if let Err(e) = write_response(&mut stream, Response::parse(&status_line, content), false) {
+                    log!("Failed to send response: {}", e);
+                }
+                break;
+            }
+            Ok(RequestRead::HeadersTooLarge) => {
+                let message = format!("Request headers are over the limit of {} bytes", MAX_HEADER_BYTES);
+                let (status_line, content) = error(ErrorCode::HeadersTooLarge, &message);
+                if let Err(e) = write_response(&mut stream, Response::parse(&status_line, content), false) {
+                    log!("Failed to send response: {}", e);
+                }
+                break;
+            }
+            // Where the body ends isn't known, so neither is where the next request starts
+            Ok(RequestRead::InvalidContentLength) => {
+                let (status_line, content) = error(ErrorCode::InvalidHeader, "Content-Length must be a single whole number");
+                if let Err(e) = write_response(&mut stream, Response::parse(&status_line, content), false) {
+                    log!("Failed to send response: {}", e);
+                }
+                break;
+            }
+            Ok(RequestRead::TransferEncoding(with_content_length)) => {
+                let (status_line, content) = if with_content_length {
+                    error(ErrorCode::InvalidHeader, "Transfer-Encoding and Content-Length can't both be sent")
+                } else {
+                    error(ErrorCode::TransferEncodingNotSupported, "Transfer-Encoding isn't supported; send a Content-Length")
+                };
+                if let Err(e) = write_response(&mut stream, Response::parse(&status_line, content), false) {
+                    log!("Failed to send response: {}", e);
+                }
+                break;
+            }
+            Ok(RequestRead::TimedOut) => {
+                let (status_line, content) = error(ErrorCode::RequestTimeout, "Request Timeout");
+                if let Err(e) = write_response(&mut stream, Response::parse(&status_line, content), false) {
+                    log!("Failed to send response: {}", e);
+                }
+                break;
+            }
+            Err(e) => {
+                log!("Error: {}", e);
+                break;
+            }
+        };
+        let started = Instant::now();
+        debug::start(state.debugging.as_ref().is_some_and(|debugging| debugging.wanted(&request, &caller)), started);
+        let keep_alive = wants_keep_alive(&request);
+        request_id::set_current(Some(request_id::from_header(get_header(&request, "X-Request-Id"))));
+        otel::start_request(get_header(&request, "traceparent"));
+        if let Some(cors) = &*state.cors.read().unwrap_or_else(|e| e.into_inner()) {
+            cors::set_current(cors.headers(&request));
+        }
+
+        if caller.is_admin() {
+            log!(
+                "Admin request from {}: {}",
+                caller.identity(),
+                request.lines().next().unwrap_or_default()
+            );
+        }
+
+        // Bearer tokens outside SCIM are impersonation tokens; the request acts as their user
+        if let Some(token) = get_header(&request, "Authorization").and_then(|value| value.strip_prefix("Bearer ")).filter(|_| !get_path(&request).starts_with("/scim/")) {
+            match state.impersonations.find(token.trim()) {
+                Some(session) => {
+                    log!("Request from {} impersonating user {} (started by {})", caller.identity(), session.user_id, session.admin);
+                    impersonation::set_current(Some(session));
+                }
+                None => {
+                    let (status_line, content) = error(ErrorCode::Unauthorized, "Unknown or expired impersonation token");
+                    let result = write_response(&mut stream, Response::parse(&status_line, content), keep_alive);
+                    record_request(state, &caller, &request, 401, started);
+                    if let Err(e) = result {
+                        log!("Failed to send response: {}", e);
+                        break;
+                    }
+                    served_any = true;
+                    if !keep_alive {
+                        break;
+                    }
+                    continue;
+                }
+            }
+        }
+
+        debug::mark("authentication");
+
+        if let Some(retry_after) = check_rate_limit(state, &caller, &request) {
+            let (status_line, content) = error(ErrorCode::RateLimited, "Too Many Requests");
+            // Rounded up, so a client that waits as long as it is told isn't turned away again
+            let response = Response::parse(&status_line, content).header("Retry-After", (retry_after.as_secs_f64().ceil() as u64).max(1));
+            let result = write_response(&mut stream, response, keep_alive);
+            record_request(state, &caller, &request, 429, started);
+            if let Err(e) = result {
+                log!("Failed to send response: {}", e);
+                break;
+            }
+            served_any = true;
+            if !keep_alive {
+                break;
+            }
+            continue;
+        }
+
+        state.features.record(&request, &caller);
+        debug::mark("rate_limit");
+
+        // HEAD is answered like GET, and only the headers are sent
+        let head = request.starts_with("HEAD ");
+        let request = match request.strip_prefix("HEAD ") {
+            Some(rest) => format!("GET {}", rest),
+            None => request,
+        };
+
+        // Chaos mode: slow down or fail the request as configured (see `chaos`)
+        let method = request.split_whitespace().next().unwrap_or_default();
+        if let Some(fault) = state.chaos.as_ref().and_then(|chaos| chaos.fault(method, get_path(&request))) {
+            log!("Injecting {}ms latency{}", fault.latency.as_millis(), if fault.error.is_some() { " and an error" } else { "" });
+            thread::sleep(fault.latency);
+            if let Some(status_line) = fault.error {
+                let (status_line, content) = error_with_status(&status_line, ErrorCode::InjectedFault, "Injected fault");
+                let response = Response::parse(&status_line, content);
+                let status = response.status();
+                let result = write_response(&mut stream, response, keep_alive);
+                record_request(state, &caller, &request, status, started);
+                if let Err(e) = result {
+                    log!("Failed to send response: {}", e);
+                    break;
+                }
+                served_any = true;
+                if !keep_alive {
+                    break;
+                }
+                continue;
+            }
+        }
+
+        debug::mark("chaos");
+
+        // WebSocket subscriptions to change events take the connection over (see `websocket`)
+        if method == "GET" && get_path(&request) == "/ws/users" && !head && state.mock.is_none() {
+            if let Some(accept_key) = websocket::accept_key(&request) {
+                let events = events::subscribe();
+                let result = websocket::write_handshake(&mut stream, &accept_key);
+                record_request(state, &caller, &request, 101, started);
+                if let Err(e) = result.and_then(|()| websocket::send_events(&mut stream, events)) {
+                    log!("WebSocket closed: {}", e);
+                }
+                break;
+            }
+        }
+        // So do Server-Sent Events feeds of them (see `sse`)
+        if method == "GET" && get_path(&request) == "/users/events" && !head && state.mock.is_none() {
+            let (missed, events) = sse::subscribe(&request);
+            let ndjson = wants_ndjson(&request);
+            let result = sse::write_head(&mut stream, ndjson);
+            record_request(state, &caller, &request, 200, started);
+            if let Err(e) = result.and_then(|()| sse::send_events(&mut stream, missed, events, ndjson)) {
+                log!("Event stream closed: {}", e);
+            }
+            break;
+        }
+
+        // Admin UI files may be binary and are served as they are (see `admin_ui`)
+        if method == "GET" && router::find_route(get_path(&request)).is_some_and(|route| route.pattern.starts_with("/admin/ui")) {
+            let mut response = admin_ui_response(state, &request);
+            if head {
+                response = response.without_body();
+            }
+            let status = response.status();
+            let result = write_response(&mut stream, response, keep_alive);
+            record_request(state, &caller, &request, status, started);
+            if let Err(e) = result {
+                log!("Failed to send response: {}", e);
+                break;
+            }
+            served_any = true;
+            if !keep_alive {
+                break;
+            }
+            continue;
+        }
+
+        // So are Parquet exports (see `export`)
+        if method == "GET" && router::find_route(get_path(&request)).is_some_and(|route| matches!(route.pattern, "/users/export" | "/admin/exports/{id}")) {
+            let mut response = export_response(state, &request, &caller);
+            if head {
+                response = response.without_body();
+            }
+            let status = response.status();
+            let result = write_response(&mut stream, response, keep_alive);
+            record_request(state, &caller, &request, status, started);
+            if let Err(e) = result {
+                log!("Failed to send response: {}", e);
+                break;
+            }
+            served_any = true;
+            if !keep_alive {
+                break;
+            }
+            continue;
+        }
+
+        // Cacheable reads are answered from the cache when they can be (see `response_cache`).
+        // Entries are JSON, so lists asked for as NDJSON skip it.
+        let cache_key = state.response_cache.as_ref().filter(|_| method == "GET" && get_header(&request, "If-None-Match").is_none() && !wants_ndjson(&request)).and_then(|_| {
+            let query = request.split_whitespace().nth(1).and_then(|target| target.split_once('?')).map(|(_, query)| query);
+            response_cache::key(get_path(&request), query, caller.is_admin())
+        });
+        let lookup = state.response_cache.as_ref().zip(cache_key.as_ref()).map(|(cache, key)| cache.get(key));
+        debug::mark("cache_lookup");
+
+        // The user list is streamed straight to the socket rather than built in memory.
+        // HTTP/1.0 clients don't understand chunked responses and get the buffered one,
+        // and so do all clients while the list may be cached or is being debugged.
+        if request.starts_with("GET /users") && get_path(&request) == "/users" && !is_http_1_0(&request) && !head && state.mock.is_none() && cache_key.is_none() && !debug::is_active() {
+            let result = stream_all_users(&mut stream, &request, state, &caller, keep_alive);
+            record_request(state, &caller, &request, *result.as_ref().unwrap_or(&500), started);
+            if let Err(e) = result {
+                log!("Failed to stream response: {}", e);
+                break;
+            }
+            served_any = true;
+            if !keep_alive {
+                break;
+            }
+            continue;
+        }
+
+        // The response, and whether it came from the cache (`X-Cache`)
+        let (status_line, content, cache_status) = match lookup {
+            Some(Lookup::Hit((status_line, content))) => {
+                debug::cache("hit");
+                (status_line, content, Some("HIT"))
+            }
+            lookup => {
+                let (status_line, content) = route(&request, state, &caller);
+                debug::mark("handler");
+                match (&state.response_cache, lookup) {
+                    (Some(cache), Some(Lookup::Miss(slot))) => {
+                        debug::cache("miss");
+                        cache.put(slot, &(status_line.clone(), content.clone()));
+                        (status_line, content, Some("MISS"))
+                    }
+                    // Anything but a read may have changed users
+                    (Some(cache), None) if !matches!(method, "GET" | "OPTIONS") => {
+                        debug::cache("invalidate");
+                        cache.invalidate(get_path(&request));
+                        (status_line, content, None)
+                    }
+                    (Some(_), _) => {
+                        debug::cache("bypass");
+                        (status_line, content, None)
+                    }
+                    _ => (status_line, content, None),
+                }
+            }
+        };
+        debug::mark("cache_store");
+        let content = debug::attach(&request, content);
+        let mut response = Response::parse(&status_line, content);
+        if let Some(cache_status) = cache_status {
+            response = response.header("X-Cache", cache_status);
+        }
+
+        let mut response = compress_response(state, &request, response);
+        if head {
+            response = response.without_body();
+        }
+
+        // `write_response` returns a `Result<(), io::Error>`. We check for errors
+        // to ensure the response was sent successfully.
+        let status = response.status();
+        let result = write_response(&mut stream, response, keep_alive);
+        record_request(state, &caller, &request, status, started);
+        if let Err(e) = result {
+            log!("Failed to send response: {}", e);
+            break;
+        }
+        served_any = true;
+
+        if !keep_alive {
+            break;
+        }
+    }
+}
+
+// Answers the first request on a connection with `response` and closes it. The request
+// is read first: closing a socket with unread input resets the connection, and the
+// client could lose the response.
+pub(crate) fn refuse_client(mut stream: impl Connection, response: Response) {
+    let timeouts = get_timeouts();
+    if let Err(e) = stream.set_write_timeout(Some(timeouts.write)) {
+        log!("Error: {}", e);
+        return;
+    }
+    if let Err(e) = read_request(&mut stream, &mut Vec::new(), &timeouts, body::max_bytes_from_env(), false) {
+        log!("Error: {}", e);
+    }
+    if let Err(e) = response.header("Connection", "close").write_to(&mut stream) {
+        log!("Failed to send response: {}", e);
+    }
+}
+
+// Routes a request. Handlers turn failures into error responses rather than panicking;
+// should one panic anyway, the request gets a 500 and the connection carries on.
+fn route(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    match panic::catch_unwind(AssertUnwindSafe(|| router::route(request, state, caller))) {
+        Ok(response) => response,
+        Err(_) => {
+            log!("Handler panicked on {} {}", request.split_whitespace().next().unwrap_or_default(), get_path(request));
+            error(ErrorCode::InternalError, "Internal server error")
+        }
+    }
+}
+
+// Counts a finished request towards the metrics history (and StatsD), the SLOs and the
+// slow request report, writes it to the access log and ends its trace span
+fn record_request(state: &AppState, caller: &Caller, request: &str, status: u16, started: Instant) {
+    let duration = started.elapsed();
+    let request_id = request_id::current();
+    state.metrics.record(status, duration, request_id.as_deref(), otel::current_trace_id().as_deref());
+    let method = request.split_whitespace().next().unwrap_or_default();
+    // Methods a route doesn't accept are counted as unmatched, so clients can't add keys
+    let route = router::find_route(get_path(request))
+        .filter(|route| route.methods.contains(&method))
+        .map(|route| route.pattern);
+    state.slo.record(method, route, status, duration);
+    otel::end_request(method, route, get_path(request), status);
+    let database = slow_requests::take_database_timings();
+    if let Some(route) = route.filter(|_| !database.is_empty()) {
+        state.database_latencies.record(route, database.iter().sum());
+    }
+    if let Some(statsd) = &state.statsd {
+        statsd.record(method, status, duration);
+    }
+    if let Some(access_log) = &state.access_log {
+        access_log.record(&caller.identity(), method, get_path(request), status, duration, request_id.as_deref());
+    }
+    state.slow_requests.record(SlowRequest::new(
+        method,
+        get_path(request),
+        status,
+        duration,
+        database,
+        request_id,
+    ));
+}
+
+// Returns how long the client has to wait if it exceeded its rate limit.
+// Clients are keyed by IP address, or by API key for the keys configured to be (see
+// `RateLimiter::client_key`). Local admins on the admin socket are never limited.
+fn check_rate_limit(state: &AppState, caller: &Caller, request: &str) -> Option<Duration> {
+    let limiter = state.rate_limiter.read().unwrap_or_else(|e| e.into_inner());
+    let limiter = limiter.as_ref()?;
+
+    let key = match caller {
+        Caller::LocalAdmin { .. } => return None,
+        Caller::Remote { ip } => limiter.client_key(get_header(request, "X-Api-Key"), ip),
+    };
+
+    limiter.check(&key).err()
+}
+
+// Sends a response with the headers every response gets
+fn write_response(stream: &mut impl Connection, response: Response, keep_alive: bool) -> std::io::Result<()> {
+    response
+        .header_lines(&request_id::header_line())
+        .header_lines(&cors::header_lines())
+        .header("Connection", if keep_alive { "keep-alive" } else { "close" })
+        .write_to(stream)
+}
+
+// Compresses a response body when the client accepts it and it's large enough (see
+// `compression`)
+fn compress_response(state: &AppState, request: &str, response: Response) -> Response {
+    let compression = &state.compression;
+    let content_type = response.get_header("Content-Type");
+    let length = response.content().len();
+    if !compression.applies_to(content_type, Some(length)) {
+        return response;
+    }
+    let encoding = compression.negotiate(get_header(request, "Accept-Encoding"), content_type, Some(length));
+    // Caches must not hand a compressed body to clients that didn't ask for one
+    let response = response.header("Vary", "Accept-Encoding");
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return response,
+    };
+    match compression.compress(encoding, response.content()) {
+        Ok(body) => response.header("Content-Encoding", encoding.name()).body(body),
+        Err(e) => {
+            log!("Error compressing response: {}", e);
+            response
+        }
+    }
+}
+
+// A file of the admin UI, precompressed when there's a version the client accepts
+fn admin_ui_response(state: &AppState, request: &str) -> Response {
+    let not_found = || {
+        let (status_line, content) = error(ErrorCode::RouteNotFound, "Not Found");
+        Response::parse(&status_line, content)
+    };
+    let Some(admin_ui) = &state.admin_ui else {
+        return not_found();
+    };
+    let accept_encoding = get_header(request, "Accept-Encoding");
+    let accepts = |encoding| state.compression.enabled && compression::accepts(accept_encoding, encoding);
+    match admin_ui.asset(get_path(request), accepts) {
+        Ok(Some(asset)) => {
+            let response = Response::new(200).header("Content-Type", asset.content_type).body(asset.body);
+            match asset.encoding {
+                Some(encoding) => response.header("Vary", "Accept-Encoding").header("Content-Encoding", encoding.name()),
+                None => compress_response(state, request, response),
+            }
+        }
+        Ok(None) => not_found(),
+        Err(e) => {
+            log!("Error reading the admin UI: {}", e);
+            let (status_line, content) = error(ErrorCode::InternalError, "Internal server error");
+            Response::parse(&status_line, content)
+        }
+    }
+}
+
+// A Parquet export, or how it's getting on; binary once it's the file
+fn export_response(state: &AppState, request: &str, caller: &Caller) -> Response {
+    #[cfg(feature = "parquet")]
+    let (status_line, content) = export::response(request, state, caller);
+    #[cfg(not(feature = "parquet"))]
+    let (status_line, content) = {
+        let _ = (request, caller);
+        let (status_line, content) = error(ErrorCode::FeatureDisabled, "This server was built without Parquet export");
+        (status_line, content.into_bytes())
+    };
+    compress_response(state, request, Response::parse(&status_line, content))
+}
+
+enum RequestRead {
+    Request(String),
+    // The client closed the connection, or an idle keep-alive connection expired
+    Closed,
+    // The client started a request (or never sent one) and didn't finish in time
+    TimedOut,
+    // The Content-Length is over MAX_BODY_BYTES
+    TooLarge(usize),
+    // The request line and headers are over MAX_HEADER_BYTES
+    HeadersTooLarge,
+    // The Content-Length isn't a number, or is sent more than once with different values
+    InvalidContentLength,
+    // The request has a Transfer-Encoding, which isn't supported; true when it also has a
+    // Content-Length, so it's ambiguous where the body ends
+    TransferEncoding(bool),
+}
+
+// Longest request line and headers accepted
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+
+// Reads the next complete request (headers plus a `Content-Length` body) from the stream.
+// Bodies are only ever framed by their Content-Length, so requests with a
+// Transfer-Encoding, or with a Content-Length that can't be trusted, are turned down
+// rather than having their body read as the next request.
+fn read_request(
+    stream: &mut impl Connection,
+    buffer: &mut Vec<u8>,
+    timeouts: &Timeouts,
+    max_body: usize,
+    served_any: bool,
+) -> std::io::Result<RequestRead> {
+    // The request deadline starts with the first byte of the request
+    let mut deadline = (!buffer.is_empty()).then(|| Instant::now() + timeouts.request);
+
+    loop {
+        let header_end = find_header_end(buffer);
+        if header_end.unwrap_or(buffer.len()) > MAX_HEADER_BYTES {
+            return Ok(RequestRead::HeadersTooLarge);
+        }
+        if let Some(header_end) = header_end {
+            let head = String::from_utf8_lossy(&buffer[..header_end]).into_owned();
+            if get_header(&head, "Transfer-Encoding").is_some() {
+                return Ok(RequestRead::TransferEncoding(get_header(&head, "Content-Length").is_some()));
+            }
+            let Some(content_length) = content_length(&head) else {
+                return Ok(RequestRead::InvalidContentLength);
+            };
+            if content_length > max_body {
+                return Ok(RequestRead::TooLarge(content_length));
+            }
+            let total = header_end + 4 + content_length;
+
+            if buffer.len() >= total {
+                let request = String::from_utf8_lossy(&buffer[..total]).into_owned();
+                buffer.drain(..total);
+                return Ok(RequestRead::Request(request));
+            }
+        }
+
+        let timeout = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Ok(RequestRead::TimedOut);
+                }
+                remaining.min(timeouts.read)
+            }
+            None if served_any => timeouts.idle,
+            None => timeouts.read,
+        };
+        stream.set_read_timeout(Some(timeout))?;
+
+        let mut chunk = [0; 1024];
+        match stream.read(&mut chunk) {
+            Ok(0) => return Ok(RequestRead::Closed),
+            Ok(size) => {
+                buffer.extend_from_slice(&chunk[..size]);
+                deadline.get_or_insert_with(|| Instant::now() + timeouts.request);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                // Between keep-alive requests silence just means the client is done
+                if buffer.is_empty() && served_any {
+                    return Ok(RequestRead::Closed);
+                }
+                return Ok(RequestRead::TimedOut);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn find_header_end(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+// The length of the body: 0 without a Content-Length, None when one isn't all digits or
+// copies of it disagree
+fn content_length(head: &str) -> Option<usize> {
+    let mut length = None;
+    for (name, value) in head.lines().skip(1).filter_map(|line| line.split_once(':')) {
+        if !name.trim().eq_ignore_ascii_case("Content-Length") {
+            continue;
+        }
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+            return None;
+        }
+        let value: usize = value.parse().ok()?;
+        if length.is_some_and(|length| length != value) {
+            return None;
+        }
+        length = Some(value);
+    }
+    Some(length.unwrap_or(0))
+}
+
+// HTTP/1.1 connections are persistent unless the client sends `Connection: close`;
+// HTTP/1.0 clients have to opt in with `Connection: keep-alive`.
+fn wants_keep_alive(request: &str) -> bool {
+    match get_header(request, "Connection") {
+        Some(value) if value.eq_ignore_ascii_case("close") => false,
+        Some(value) if value.eq_ignore_ascii_case("keep-alive") => true,
+        _ => !is_http_1_0(request),
+    }
+}
+
+fn is_http_1_0(request: &str) -> bool {
+    request.lines().next().unwrap_or_default().ends_with("HTTP/1.0")
+}
+
+// Rows fetched from the database for each chunk of a streamed list
+const STREAM_BATCH_SIZE: i32 = 500;
+
+// Handle GET All request, streamed
+// Same filters and body as `handle_get_all_request`, but rows are read through a cursor
+// in batches and each batch goes out as one chunk of a chunked response, so memory use
+// stays flat however many users there are. Errors before the first chunk get a normal
+// error response; after that the connection is dropped mid-body, which clients see as
+// a truncated response rather than a short but complete list.
+fn stream_all_users(
+    stream: &mut impl Connection,
+    request: &str,
+    state: &AppState,
+    caller: &Caller,
+    keep_alive: bool,
+) -> std::io::Result<u16> {
+    let filter = match list_filter(request, caller) {
+        Ok(filter) => filter,
+        Err((status_line, content)) => {
+            let response = Response::parse(&status_line, content);
+            let status = response.status();
+            write_response(stream, response, keep_alive)?;
+            return Ok(status);
+        }
+    };
+    let fields = match list_fields(request) {
+        Ok(fields) => fields,
+        Err((status_line, content)) => {
+            let response = Response::parse(&status_line, content);
+            let status = response.status();
+            write_response(stream, response, keep_alive)?;
+            return Ok(status);
+        }
+    };
+
+    // NDJSON lists are just the users, each on its own line
+    let ndjson = wants_ndjson(request);
+    let ok_response = if ndjson { NDJSON_RESPONSE } else { OK_RESPONSE };
+    let content_type = get_header(ok_response, "Content-Type");
+    let encoder = state.compression.negotiate(get_header(request, "Accept-Encoding"), content_type, None).map(|encoding| state.compression.encoder(encoding));
+    let mut head = Response::parse(ok_response, Vec::new())
+        .header_lines(&request_id::header_line())
+        .header_lines(&cors::header_lines());
+    if state.compression.applies_to(content_type, None) {
+        head = head.header("Vary", "Accept-Encoding");
+    }
+    // Clients that take trailers get the number of users and the SHA-256 of the
+    // (uncompressed) body after it, to check that the list arrived whole
+    let trailers = wants_trailers(request);
+    if trailers {
+        head = head.header("Trailer", "X-Row-Count, X-Content-SHA256");
+    }
+    let mut rows = 0;
+    let mut checksum = Sha256::new();
+    let mut body = ChunkedWriter::new(&mut *stream, head, keep_alive, encoder);
+    let mut write_error = None;
+    let result = with_client(state, |client| {
+        let mut transaction = client.transaction()?;
+        // Each batch goes out as one chunk of already serialized users
+        let mut write_batch = |users: Vec<String>| {
+            let mut chunk = String::new();
+            for user in &users {
+                if ndjson {
+                    chunk.push_str(user);
+                    chunk.push('\n');
+                } else {
+                    chunk.push(if body.started() || !chunk.is_empty() { ',' } else { '[' });
+                    chunk.push_str(user);
+                }
+            }
+            rows += users.len();
+            checksum.update(chunk.as_bytes());
+            body.write_chunk(chunk.as_bytes()).map_err(|e| {
+                write_error = Some(e);
+                let (status_line, body) = error(ErrorCode::InternalError, "Write failed");
+                AppError::Response(status_line, body)
+            })
+        };
+        match &fields {
+            Some(fields) => repo::for_each_user_fields_batch(&mut transaction, &filter, fields, STREAM_BATCH_SIZE, write_batch),
+            None => repo::for_each_user_batch(&mut transaction, &filter, STREAM_BATCH_SIZE, |users| {
+                let users = users.iter().map(serde_json::to_string).collect::<Result<_, _>>()?;
+                write_batch(users)
+            }),
+        }
+        .map_err(|e| match e {
+            // Part of the list has been sent, so the work mustn't be retried
+            e @ AppError::Db(_) if body.started() => {
+                let (status_line, body) = e.into_response();
+                AppError::Response(status_line, body)
+            }
+            e => e,
+        })
+    });
+
+    if let Some(e) = write_error {
+        return Err(e);
+    }
+    match result {
+        Ok(()) => {
+            let end = match (ndjson, body.started()) {
+                (true, _) => "",
+                (false, true) => "]",
+                (false, false) => "[]",
+            };
+            checksum.update(end.as_bytes());
+            body.write_chunk(end.as_bytes())?;
+            if trailers {
+                let checksum = checksum.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+                body.finish_with_trailers(&[("X-Row-Count", rows.to_string()), ("X-Content-SHA256", checksum)])?;
+            } else {
+                body.finish()?;
+            }
+            Ok(200)
+        }
+        Err(_) if body.started() => Err(std::io::Error::other("database error while streaming users")),
+        Err(e) => {
+            let (status_line, content) = list_error(e);
+            let response = Response::parse(&status_line, content);
+            let status = response.status();
+            write_response(body.into_inner(), response, keep_alive)?;
+            Ok(status)
+        }
+    }
+}
+
+// Whether the client says it takes trailer fields (`TE: trailers`)
+fn wants_trailers(request: &str) -> bool {
+    get_header(request, "TE").is_some_and(|te| te.split(',').any(|coding| coding.trim().eq_ignore_ascii_case("trailers")))
+}
@@ -0,0 +1,55 @@
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+// Graceful shutdown on SIGTERM (`docker stop`) and SIGINT (Ctrl-C): the hooks registered
+// with `on_shutdown` run, in order, before the process exits. Without the handlers a
+// server running as PID 1 in a container ignores SIGTERM and is killed after the grace
+// period. Requests still in flight are not waited for.
+
+// How often the watcher thread checks whether a signal arrived
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+type Hook = Box<dyn FnOnce() + Send>;
+
+static HOOKS: Mutex<Vec<Hook>> = Mutex::new(Vec::new());
+
+// Installs the signal handlers and starts the thread that runs the hooks and exits
+pub fn install() {
+    // Signal handlers may only do async-signal-safe things, so this one just raises a flag
+    extern "C" fn request(_signal: libc::c_int) {
+        REQUESTED.store(true, Ordering::SeqCst);
+    }
+    let handler = request as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+    thread::spawn(|| {
+        while !REQUESTED.load(Ordering::SeqCst) {
+            thread::sleep(POLL_INTERVAL);
+        }
+        log!("Shutting down");
+        run_hooks();
+        process::exit(0);
+    });
+}
+
+// Runs `hook` when the server is asked to stop
+pub fn on_shutdown(hook: impl FnOnce() + Send + 'static) {
+    HOOKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(Box::new(hook));
+}
+
+fn run_hooks() {
+    let hooks = std::mem::take(&mut *HOOKS.lock().unwrap_or_else(|e| e.into_inner()));
+    for hook in hooks {
+        hook();
+    }
+}
@@ -0,0 +1,259 @@
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Service level objectives, for GET /admin/slo. Every finished request counts towards
+// two SLIs of its route (method and pattern, like `GET /users/{id}`) and of the server
+// as a whole:
+// - availability: the share of requests not answered with a 5xx, against
+//   SLO_AVAILABILITY (0.999)
+// - latency: the share answered within SLO_LATENCY_MS (500), against
+//   SLO_LATENCY_TARGET (0.99)
+// Over the last SLO_WINDOW_HOURS (24) the report shows how much of each error budget
+// is left, and how fast it's burning over shorter windows: a burn rate of 1 uses the
+// budget up exactly at the end of the window, 14.4 over an hour is a common paging
+// threshold. Counts are kept in memory and start over when the server restarts.
+
+// The windows burn rates are reported for
+const BURN_WINDOWS: &[(&str, u64)] = &[("5m", 5), ("1h", 60), ("6h", 360)];
+
+// Where requests that matched no route, or none of its methods, are counted
+const UNMATCHED: &str = "unmatched";
+
+#[derive(Clone, Copy, Default)]
+struct Minute {
+    // Minutes since the epoch
+    minute: u64,
+    requests: u64,
+    // Answered with a 5xx
+    errors: u64,
+    // Slower than the latency threshold
+    slow: u64,
+}
+
+pub struct Slo {
+    availability: f64,
+    latency_threshold: Duration,
+    latency_target: f64,
+    window_minutes: u64,
+    // Oldest first, one entry per minute that had requests, per route
+    routes: Mutex<HashMap<String, VecDeque<Minute>>>,
+}
+
+impl Slo {
+    pub fn from_env() -> Slo {
+        let target = |name: &str, default: f64| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|target| *target > 0.0 && *target < 1.0)
+                .unwrap_or(default)
+        };
+        let setting = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        Slo::new(
+            target("SLO_AVAILABILITY", 0.999),
+            Duration::from_millis(setting("SLO_LATENCY_MS", 500)),
+            target("SLO_LATENCY_TARGET", 0.99),
+            setting("SLO_WINDOW_HOURS", 24).max(1) * 60,
+        )
+    }
+
+    pub fn new(
+        availability: f64,
+        latency_threshold: Duration,
+        latency_target: f64,
+        window_minutes: u64,
+    ) -> Slo {
+        Slo {
+            availability,
+            latency_threshold,
+            latency_target,
+            window_minutes,
+            routes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Counts a finished request to `route` (None if it matched no route)
+    pub fn record(&self, method: &str, route: Option<&str>, status: u16, latency: Duration) {
+        let route = match route {
+            Some(pattern) => format!("{} {}", method, pattern),
+            None => UNMATCHED.to_string(),
+        };
+        self.record_at(current_minute(), route, status, latency);
+    }
+
+    fn record_at(&self, minute: u64, route: String, status: u16, latency: Duration) {
+        let mut routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        let minutes = routes.entry(route).or_default();
+        if minutes.back().is_none_or(|last| last.minute < minute) {
+            minutes.push_back(Minute {
+                minute,
+                ..Default::default()
+            });
+        }
+        while minutes
+            .front()
+            .is_some_and(|first| first.minute + self.window_minutes <= minute)
+        {
+            minutes.pop_front();
+        }
+        // Clocks can step back; such requests count towards the latest minute
+        let Some(current) = minutes.back_mut() else {
+            return;
+        };
+        current.requests += 1;
+        current.errors += u64::from(status >= 500);
+        current.slow += u64::from(latency > self.latency_threshold);
+    }
+
+    pub fn report(&self) -> Value {
+        self.report_at(current_minute())
+    }
+
+    fn report_at(&self, now: u64) -> Value {
+        let routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        let mut names: Vec<&String> = routes.keys().collect();
+        names.sort();
+        let all: Vec<&VecDeque<Minute>> = routes.values().collect();
+        let reports: Vec<Value> = names
+            .into_iter()
+            .map(|name| {
+                let mut report = self.route_report(&[&routes[name]], now);
+                report["route"] = name.as_str().into();
+                report
+            })
+            .filter(|report| report["requests"] != 0)
+            .collect();
+        json!({
+            "window_hours": self.window_minutes / 60,
+            "objectives": {
+                "availability": self.availability,
+                "latency": {
+                    "threshold_ms": self.latency_threshold.as_millis() as u64,
+                    "target": self.latency_target,
+                },
+            },
+            "overall": self.route_report(&all, now),
+            "routes": reports,
+        })
+    }
+
+    fn route_report(&self, routes: &[&VecDeque<Minute>], now: u64) -> Value {
+        let total = totals(routes, now, self.window_minutes);
+        let sli = |target: f64, bad: fn(&Minute) -> u64| {
+            let burn_rates: serde_json::Map<String, Value> = BURN_WINDOWS
+                .iter()
+                .map(|(name, minutes)| {
+                    let window = totals(routes, now, *minutes);
+                    (
+                        name.to_string(),
+                        burn_rate(bad(&window), window.requests, target).into(),
+                    )
+                })
+                .collect();
+            json!({
+                "target": target,
+                "sli": (total.requests > 0)
+                    .then(|| 1.0 - bad(&total) as f64 / total.requests as f64),
+                "error_budget_remaining": burn_rate(bad(&total), total.requests, target)
+                    .map(|rate| 1.0 - rate),
+                "burn_rates": burn_rates,
+            })
+        };
+        json!({
+            "requests": total.requests,
+            "availability": sli(self.availability, |minute| minute.errors),
+            "latency": sli(self.latency_target, |minute| minute.slow),
+        })
+    }
+}
+
+// Sums the minutes of the last `window_minutes` (the current one included)
+fn totals(routes: &[&VecDeque<Minute>], now: u64, window_minutes: u64) -> Minute {
+    let first = (now + 1).saturating_sub(window_minutes);
+    routes
+        .iter()
+        .flat_map(|minutes| minutes.iter().filter(|minute| minute.minute >= first))
+        .fold(Minute::default(), |total, minute| Minute {
+            minute: now,
+            requests: total.requests + minute.requests,
+            errors: total.errors + minute.errors,
+            slow: total.slow + minute.slow,
+        })
+}
+
+// How many times faster than allowed the budget is being spent; None without requests
+fn burn_rate(bad: u64, requests: u64, target: f64) -> Option<f64> {
+    (requests > 0).then(|| bad as f64 / requests as f64 / (1.0 - target))
+}
+
+fn current_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 60
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(value: &Value, expected: f64) {
+        let actual = value.as_f64().unwrap();
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn budgets_and_burn_rates_follow_the_requests() {
+        let slo = Slo::new(0.99, Duration::from_millis(100), 0.9, 24 * 60);
+        let route = || "GET /users/{id}".to_string();
+        // An hour ago: 100 good requests
+        for _ in 0..100 {
+            slo.record_at(940, route(), 200, Duration::from_millis(10));
+        }
+        // Now: 1 error and 5 slow requests out of 10
+        slo.record_at(1000, route(), 503, Duration::from_millis(10));
+        for _ in 0..5 {
+            slo.record_at(1000, route(), 200, Duration::from_millis(150));
+        }
+        for _ in 0..4 {
+            slo.record_at(1000, route(), 404, Duration::from_millis(10));
+        }
+        slo.record_at(1000, "POST /users".to_string(), 200, Duration::ZERO);
+
+        let report = slo.report_at(1000);
+        let users = &report["routes"][0];
+        assert_eq!(users["route"], "GET /users/{id}");
+        assert_eq!(users["requests"], 110);
+        let availability = &users["availability"];
+        // 1 error in 110 requests, against 1% allowed
+        assert_close(&availability["error_budget_remaining"], 1.0 - 1.0 / 1.1);
+        // 1 error in the last 10 requests is 10%, burning the budget 10x too fast
+        assert_close(&availability["burn_rates"]["5m"], 10.0);
+        // The hour before doesn't reach into the 5 minute window, but into the 6 hour one
+        assert_close(&availability["burn_rates"]["6h"], 1.0 / 1.1);
+        assert_close(&users["latency"]["burn_rates"]["5m"], 5.0);
+        assert_eq!(report["overall"]["requests"], 111);
+        assert_eq!(report["routes"][1]["route"], "POST /users");
+
+        // Counts older than the window are dropped
+        let report = slo.report_at(940 + 24 * 60);
+        assert_eq!(report["routes"][0]["requests"], 10);
+        let report = slo.report_at(2000 + 24 * 60);
+        assert_eq!(report["overall"]["requests"], 0);
+        assert!(report["overall"]["availability"]["sli"].is_null());
+    }
+}
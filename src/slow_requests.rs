@@ -0,0 +1,170 @@
+use std::cell::RefCell;
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::debug;
+use crate::mock::format_timestamp;
+
+// The slowest requests of the last SLOW_REQUESTS_WINDOW_MINUTES (60), for
+// GET /admin/slow-requests. The top SLOW_REQUESTS_TOP (20) are kept along with how long
+// their database work took, so it's clear whether the time went to queries or elsewhere.
+// Requests that didn't make the list when they finished aren't brought back when
+// slower ones age out.
+
+thread_local! {
+    // Database work done for the request this thread is handling
+    static DATABASE_TIMINGS: RefCell<Vec<Duration>> = const { RefCell::new(Vec::new()) };
+}
+
+// Runs database work, timing it for the current request
+pub fn time_database<T>(work: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = work();
+    DATABASE_TIMINGS.with(|timings| timings.borrow_mut().push(started.elapsed()));
+    debug::end_database_work();
+    result
+}
+
+// Returns and forgets the current request's database timings
+pub fn take_database_timings() -> Vec<Duration> {
+    DATABASE_TIMINGS.with(|timings| timings.take())
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SlowRequest {
+    pub finished_at: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: f64,
+    // One entry per unit of database work (a retried one appears once per attempt)
+    pub database_ms: Vec<f64>,
+    pub request_id: Option<String>,
+    #[serde(skip)]
+    finished: Instant,
+}
+
+impl SlowRequest {
+    pub fn new(
+        method: &str,
+        path: &str,
+        status: u16,
+        duration: Duration,
+        database: Vec<Duration>,
+        request_id: Option<String>,
+    ) -> SlowRequest {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        SlowRequest {
+            finished_at: format_timestamp(now.as_secs(), now.subsec_micros()),
+            method: method.to_string(),
+            path: path.to_string(),
+            status,
+            duration_ms: millis(duration),
+            database_ms: database.into_iter().map(millis).collect(),
+            request_id,
+            finished: Instant::now(),
+        }
+    }
+}
+
+pub struct SlowRequests {
+    // Slowest first
+    requests: Mutex<Vec<SlowRequest>>,
+    top: usize,
+    pub window: Duration,
+}
+
+impl SlowRequests {
+    pub fn from_env() -> SlowRequests {
+        let setting = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        SlowRequests::new(
+            setting("SLOW_REQUESTS_TOP", 20) as usize,
+            Duration::from_secs(setting("SLOW_REQUESTS_WINDOW_MINUTES", 60) * 60),
+        )
+    }
+
+    pub fn new(top: usize, window: Duration) -> SlowRequests {
+        SlowRequests {
+            requests: Mutex::new(Vec::new()),
+            top,
+            window,
+        }
+    }
+
+    pub fn record(&self, request: SlowRequest) {
+        let mut requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        self.expire(&mut requests);
+        let position = requests.partition_point(|kept| kept.duration_ms >= request.duration_ms);
+        if position < self.top {
+            requests.insert(position, request);
+            requests.truncate(self.top);
+        }
+    }
+
+    // The slowest requests of the window, slowest first
+    pub fn slowest(&self) -> Vec<SlowRequest> {
+        let mut requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        self.expire(&mut requests);
+        requests.clone()
+    }
+
+    fn expire(&self, requests: &mut Vec<SlowRequest>) {
+        requests.retain(|request| request.finished.elapsed() < self.window);
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str, millis: u64) -> SlowRequest {
+        SlowRequest::new(
+            "GET",
+            path,
+            200,
+            Duration::from_millis(millis),
+            vec![],
+            None,
+        )
+    }
+
+    #[test]
+    fn only_the_slowest_requests_are_kept() {
+        let slow_requests = SlowRequests::new(2, Duration::from_secs(60));
+        slow_requests.record(request("/users/1", 5));
+        slow_requests.record(request("/users/2", 50));
+        slow_requests.record(request("/users/3", 1));
+        slow_requests.record(request("/users/4", 20));
+        let paths: Vec<String> = slow_requests
+            .slowest()
+            .into_iter()
+            .map(|request| request.path)
+            .collect();
+        assert_eq!(paths, ["/users/2", "/users/4"]);
+
+        let expired = SlowRequests::new(2, Duration::ZERO);
+        expired.record(request("/users/1", 5));
+        assert!(expired.slowest().is_empty());
+    }
+
+    #[test]
+    fn database_work_is_timed_per_thread() {
+        let result = time_database(|| 7);
+        time_database(|| ());
+        assert_eq!(result, 7);
+        assert_eq!(take_database_timings().len(), 2);
+        assert!(take_database_timings().is_empty());
+    }
+}
@@ -0,0 +1,129 @@
+use std::io;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cors;
+use crate::handlers::wants_ndjson;
+use crate::events::{self, ChangeEvent};
+use crate::http::Response;
+use crate::request_id;
+use crate::router::get_header;
+use crate::server::Connection;
+
+// GET /users/events: the change events of GET /ws/users (see `events`) as a
+// Server-Sent Events stream, for clients that can't use WebSockets. Each event is sent
+// as a message with the event's ID:
+//   id: 42
+//   data: {"id": 42, "type": "updated", "user_id": 7, "user": {...}}
+// Clients that reconnect with `Last-Event-ID` (EventSource does this by itself) get
+// the events they missed first. When those aren't all kept any more, they get a
+// `reset` event instead, and should reload what they show.
+// With `Accept: application/x-ndjson` the events are sent as NDJSON instead, one per
+// line, for piping into jq and the like. A reset is then `{"type":"reset"}` and blank
+// lines keep the connection alive.
+// The response has no length and ends when the connection closes, so it works for
+// HTTP/1.0 clients too. Subscribers that fall too far behind are disconnected, and
+// resume like any other reconnecting client.
+
+// Comments are sent this often when there are no events, so idle proxies keep the
+// connection open and clients that went away are noticed
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+const STATUS_LINE: &str =
+    "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n";
+const NDJSON_STATUS_LINE: &str =
+    "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nCache-Control: no-cache\r\n";
+
+fn status_line(ndjson: bool) -> &'static str {
+    if ndjson {
+        NDJSON_STATUS_LINE
+    } else {
+        STATUS_LINE
+    }
+}
+
+// Handle GET /users/events when it isn't streamed: HEAD requests get the headers
+// Returns a `(String, String)` tuple for HTTP status and body, like the handlers.
+pub(crate) fn handle_request(request: &str) -> (String, String) {
+    (status_line(wants_ndjson(request)).to_string(), String::new())
+}
+
+// Subscribes to events, resuming after the request's `Last-Event-ID` if it has one.
+// Also returns the missed events, or None if they can't all be sent.
+pub fn subscribe(request: &str) -> (Option<Vec<Arc<ChangeEvent>>>, Receiver<Arc<ChangeEvent>>) {
+    match get_header(request, "Last-Event-ID").and_then(|id| id.trim().parse().ok()) {
+        Some(last_id) => events::resume(last_id),
+        None => (Some(Vec::new()), events::subscribe()),
+    }
+}
+
+pub(crate) fn write_head(stream: &mut impl Connection, ndjson: bool) -> io::Result<()> {
+    let head = Response::parse(status_line(ndjson), Vec::new())
+        .header("Connection", "close")
+        .header_lines(&request_id::header_line())
+        .header_lines(&cors::header_lines());
+    stream.write_all(head.head().as_bytes())
+}
+
+// Sends the missed events, then new ones as they come, until the client goes away
+// or falls behind
+pub(crate) fn send_events(
+    stream: &mut impl Connection,
+    missed: Option<Vec<Arc<ChangeEvent>>>,
+    events: Receiver<Arc<ChangeEvent>>,
+    ndjson: bool,
+) -> io::Result<()> {
+    let (reset, keepalive): (&[u8], &[u8]) = if ndjson {
+        (b"{\"type\":\"reset\"}\n", b"\n")
+    } else {
+        (b"event: reset\ndata: {}\n\n", b": keepalive\n\n")
+    };
+    match missed {
+        Some(missed) => {
+            for event in missed {
+                stream.write_all(message(&event, ndjson)?.as_bytes())?;
+            }
+        }
+        None => stream.write_all(reset)?,
+    }
+    loop {
+        match events.recv_timeout(KEEPALIVE_INTERVAL) {
+            Ok(event) => stream.write_all(message(&event, ndjson)?.as_bytes())?,
+            Err(RecvTimeoutError::Timeout) => stream.write_all(keepalive)?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+fn message(event: &ChangeEvent, ndjson: bool) -> io::Result<String> {
+    let data = serde_json::to_string(event)?;
+    Ok(if ndjson {
+        format!("{}\n", data)
+    } else {
+        format!("id: {}\ndata: {}\n\n", event.id, data)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_sent_with_their_ids() {
+        let event = ChangeEvent {
+            id: 42,
+            kind: "deleted",
+            user_id: 7,
+            user: None,
+        };
+        assert_eq!(
+            message(&event, false).unwrap(),
+            "id: 42\ndata: {\"id\":42,\"type\":\"deleted\",\"user_id\":7,\"user\":null}\n\n"
+        );
+        assert_eq!(
+            message(&event, true).unwrap(),
+            "{\"id\":42,\"type\":\"deleted\",\"user_id\":7,\"user\":null}\n"
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use postgres::{Client, Error as PostgresError, NoTls};
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::retry;
+use crate::support_bundle::redact_url;
+
+// A warm standby for the primary database. With STANDBY_DATABASE_URL set, new
+// connections go to the standby once the primary has refused them for
+// STANDBY_FAILOVER_AFTER_SECS (30), and back to the primary once a check, every
+// STANDBY_FAILBACK_INTERVAL_SECS (30), finds it reachable again. Each switch is
+// logged and bumps `generation`, so the pool and the change event listener drop
+// their connections to the database that is no longer current.
+
+pub(crate) struct Standby {
+    url: String,
+    failover_after: Duration,
+    failback_interval: Duration,
+    target: Mutex<Target>,
+    generation: AtomicU64,
+}
+
+#[derive(Default)]
+struct Target {
+    on_standby: bool,
+    // When connecting to the primary first failed, while it keeps failing
+    primary_down_since: Option<Instant>,
+}
+
+impl Standby {
+    // None when STANDBY_DATABASE_URL isn't set
+    pub(crate) fn from_env() -> Option<Standby> {
+        let url = env::var("STANDBY_DATABASE_URL")
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        let secs = |name: &str, default: u64| {
+            let secs = env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default);
+            Duration::from_secs(secs)
+        };
+        log!("Standby database at {}", redact_url(&url));
+        Some(Standby::new(
+            url,
+            secs("STANDBY_FAILOVER_AFTER_SECS", 30),
+            secs("STANDBY_FAILBACK_INTERVAL_SECS", 30),
+        ))
+    }
+
+    pub(crate) fn new(url: String, failover_after: Duration, failback_interval: Duration) -> Self {
+        Standby {
+            url,
+            failover_after,
+            failback_interval,
+            target: Mutex::new(Target::default()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn failback_interval(&self) -> Duration {
+        self.failback_interval
+    }
+
+    // Bumped on every switch between the primary and the standby
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn on_standby(&self) -> bool {
+        self.lock().on_standby
+    }
+
+    // Connects to the current database, failing over to the standby when the primary
+    // at `primary` has been unreachable for long enough
+    pub(crate) fn connect(&self, primary: &str) -> Result<Client, PostgresError> {
+        if self.on_standby() {
+            return Client::connect(&self.url, NoTls);
+        }
+        let e = match Client::connect(primary, NoTls) {
+            Ok(client) => {
+                self.lock().primary_down_since = None;
+                return Ok(client);
+            }
+            // Bad credentials or a missing database aren't solved by switching
+            Err(e) if !retry::is_transient(&e) => return Err(e),
+            Err(e) => e,
+        };
+        if !self.primary_failed(Instant::now()) {
+            return Err(e);
+        }
+        log!(
+            "Database failover: the primary has been unreachable for {}s ({}), switching to the standby",
+            self.failover_after.as_secs(),
+            e
+        );
+        Client::connect(&self.url, NoTls)
+    }
+
+    // Whether the standby answers a query, whichever database is current
+    pub(crate) fn check(&self) -> Result<(), PostgresError> {
+        Client::connect(&self.url, NoTls)?.simple_query("SELECT 1")?;
+        Ok(())
+    }
+
+    // Records a failed connection to the primary; true when that made this fail over
+    fn primary_failed(&self, now: Instant) -> bool {
+        let mut target = self.lock();
+        if target.on_standby {
+            return false;
+        }
+        let since = *target.primary_down_since.get_or_insert(now);
+        if now.duration_since(since) < self.failover_after {
+            return false;
+        }
+        target.on_standby = true;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    // Switches back to the primary if this is on the standby and the primary at
+    // `primary` accepts connections again
+    pub(crate) fn fail_back(&self, primary: &str) -> Result<(), String> {
+        if !self.on_standby() {
+            return Ok(());
+        }
+        Client::connect(primary, NoTls)
+            .map_err(|e| format!("the primary is still unreachable: {}", e))?;
+        if self.primary_recovered() {
+            log!("Database failback: the primary is reachable again, switching back to it");
+        }
+        Ok(())
+    }
+
+    fn primary_recovered(&self) -> bool {
+        let mut target = self.lock();
+        if !target.on_standby {
+            return false;
+        }
+        *target = Target::default();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Target> {
+        self.target.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fails_over_after_the_threshold_and_back() {
+        let standby = Standby::new(
+            "postgres://standby/app".to_string(),
+            Duration::from_secs(30),
+            Duration::from_secs(30),
+        );
+        let start = Instant::now();
+
+        assert!(!standby.primary_failed(start));
+        assert!(!standby.primary_failed(start + Duration::from_secs(29)));
+        assert!(!standby.on_standby());
+        assert_eq!(standby.generation(), 0);
+
+        assert!(standby.primary_failed(start + Duration::from_secs(30)));
+        assert!(standby.on_standby());
+        assert_eq!(standby.generation(), 1);
+        // Already failed over
+        assert!(!standby.primary_failed(start + Duration::from_secs(31)));
+
+        assert!(standby.primary_recovered());
+        assert!(!standby.on_standby());
+        assert_eq!(standby.generation(), 2);
+        // The outage starts over
+        let later = start + Duration::from_secs(100);
+        assert!(!standby.primary_failed(later));
+        assert!(standby.primary_failed(later + Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,141 @@
+use std::env;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+// Push-based metrics for StatsD and DogStatsD agents. With STATSD_ADDR (host:port) set,
+// every finished request sends the counters and timing that GET /admin/metrics/history
+// aggregates, over UDP: `requests`, `client_errors`, `server_errors` and
+// `request_latency` (ms), named under STATSD_PREFIX (`rust_docker_pg_crud`).
+// STATSD_FLAVOR=datadog adds DogStatsD tags: the method and status class of the
+// request, plus any fixed tags in STATSD_TAGS (e.g. `env:prod,service:users`).
+// Sending never blocks or fails a request; lost packets are lost metrics.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Flavor {
+    StatsD,
+    DogStatsD,
+}
+
+pub struct StatsD {
+    socket: UdpSocket,
+    prefix: String,
+    flavor: Flavor,
+    // Fixed DogStatsD tags from STATSD_TAGS, like `env:prod`
+    tags: Vec<String>,
+}
+
+impl StatsD {
+    // None when STATSD_ADDR isn't set, or (after saying so) can't be used
+    pub fn from_env() -> Option<StatsD> {
+        let addr = env::var("STATSD_ADDR")
+            .ok()
+            .filter(|addr| !addr.is_empty())?;
+        let prefix =
+            env::var("STATSD_PREFIX").unwrap_or_else(|_| "rust_docker_pg_crud".to_string());
+        let flavor = match env::var("STATSD_FLAVOR").as_deref() {
+            Ok("datadog") | Ok("dogstatsd") => Flavor::DogStatsD,
+            _ => Flavor::StatsD,
+        };
+        let tags = env::var("STATSD_TAGS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_string)
+            .collect();
+        match StatsD::connect(&addr, prefix, flavor, tags) {
+            Ok(statsd) => {
+                log!("Sending metrics to StatsD at {}", addr);
+                Some(statsd)
+            }
+            Err(e) => {
+                log!("StatsD export disabled: can't use {}: {}", addr, e);
+                None
+            }
+        }
+    }
+
+    pub fn connect(
+        addr: &str,
+        prefix: String,
+        flavor: Flavor,
+        tags: Vec<String>,
+    ) -> std::io::Result<StatsD> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(StatsD {
+            socket,
+            prefix,
+            flavor,
+            tags,
+        })
+    }
+
+    // Sends the metrics of a finished request in a single packet
+    pub fn record(&self, method: &str, status: u16, latency: Duration) {
+        let packet = self.lines(method, status, latency).join("\n");
+        // A full buffer or an unreachable agent only costs these metrics
+        let _ = self.socket.send(packet.as_bytes());
+    }
+
+    fn lines(&self, method: &str, status: u16, latency: Duration) -> Vec<String> {
+        let tags = match self.flavor {
+            Flavor::StatsD => String::new(),
+            Flavor::DogStatsD => {
+                let mut tags = self.tags.clone();
+                tags.push(format!("method:{}", method.to_ascii_lowercase()));
+                tags.push(format!("status_class:{}xx", status / 100));
+                format!("|#{}", tags.join(","))
+            }
+        };
+        let mut lines = vec![format!("{}.requests:1|c{}", self.prefix, tags)];
+        if (400..500).contains(&status) {
+            lines.push(format!("{}.client_errors:1|c{}", self.prefix, tags));
+        }
+        if status >= 500 {
+            lines.push(format!("{}.server_errors:1|c{}", self.prefix, tags));
+        }
+        lines.push(format!(
+            "{}.request_latency:{}|ms{}",
+            self.prefix,
+            latency.as_secs_f64() * 1000.0,
+            tags
+        ));
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_sent_as_counters_and_timings() {
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        agent
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let addr = agent.local_addr().unwrap().to_string();
+
+        let statsd = StatsD::connect(&addr, "app".to_string(), Flavor::StatsD, vec![]).unwrap();
+        statsd.record("GET", 404, Duration::from_micros(12_500));
+        let mut buffer = [0; 512];
+        let len = agent.recv(&mut buffer).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buffer[..len]),
+            "app.requests:1|c\napp.client_errors:1|c\napp.request_latency:12.5|ms"
+        );
+
+        let tags = vec!["env:test".to_string()];
+        let statsd = StatsD::connect(&addr, "app".to_string(), Flavor::DogStatsD, tags).unwrap();
+        assert_eq!(
+            statsd.lines("POST", 503, Duration::from_millis(2)),
+            [
+                "app.requests:1|c|#env:test,method:post,status_class:5xx",
+                "app.server_errors:1|c|#env:test,method:post,status_class:5xx",
+                "app.request_latency:2|ms|#env:test,method:post,status_class:5xx",
+            ]
+        );
+    }
+}
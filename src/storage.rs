@@ -0,0 +1,181 @@
+use postgres::{Client, Error as PostgresError, GenericClient, NoTls};
+use serde_json::{json, Value};
+use std::env;
+use std::time::Duration;
+
+use crate::config::get_db_url;
+use crate::query_log::Timed;
+use crate::scheduler;
+
+// Storage report for GET /admin/storage: the size of each of the app's tables and
+// its indexes, live and dead tuples, and when it was last vacuumed and analyzed, as
+// tracked by Postgres' statistics collector. A high `dead_ratio` is bloat that
+// autovacuum hasn't caught up with. Partitioned tables (the audit log) are reported
+// as the sum of their partitions.
+// With STORAGE_VACUUM_INTERVAL_MINUTES set (it's off by default), the scheduler also
+// runs VACUUM ANALYZE on these tables at that interval.
+// Bulk changes (PATCH /users, `sync`) that touch at least BULK_ANALYZE_FRACTION (0.1)
+// of a table's rows are followed by an ANALYZE of it, so query plans don't go by
+// statistics from before the change until autovacuum gets round to it. 0 turns it off.
+
+// The tables the server creates, in the order they're reported
+const TABLES: &[&str] = &[
+    "users",
+    "audit_log",
+    "pending_email_changes",
+    "email_verifications",
+    "notification_preferences",
+    "posts",
+    "users_archive",
+    "webhook_deliveries",
+    "webhook_attempts",
+];
+
+pub fn report(client: &mut impl GenericClient) -> Result<Value, PostgresError> {
+    let tables: Vec<String> = TABLES.iter().map(|table| table.to_string()).collect();
+    let rows = client.timed_query(
+        "SELECT t.relname::text, p.total_bytes, p.table_bytes, p.index_bytes,
+                t.n_live_tup + p.live_tuples, t.n_dead_tup + p.dead_tuples,
+                to_json(t.last_vacuum) #>> '{}', to_json(t.last_autovacuum) #>> '{}',
+                to_json(t.last_analyze) #>> '{}', to_json(t.last_autoanalyze) #>> '{}',
+                (SELECT json_agg(json_build_object(
+                     'name', i.indexrelname,
+                     'bytes', pg_relation_size(i.indexrelid),
+                     'scans', i.idx_scan) ORDER BY i.indexrelname)::text
+                 FROM pg_stat_user_indexes i WHERE i.relid = t.relid)
+         FROM pg_stat_user_tables t,
+              LATERAL (SELECT sum(pg_total_relation_size(tree.relid))::bigint AS total_bytes,
+                              sum(pg_relation_size(tree.relid))::bigint AS table_bytes,
+                              sum(pg_indexes_size(tree.relid))::bigint AS index_bytes,
+                              coalesce(sum(s.n_live_tup), 0)::bigint AS live_tuples,
+                              coalesce(sum(s.n_dead_tup), 0)::bigint AS dead_tuples
+                       -- Empty for unpartitioned tables
+                       FROM (SELECT relid FROM pg_partition_tree(t.relid)
+                             UNION SELECT t.relid) tree
+                       LEFT JOIN pg_stat_user_tables s
+                           ON s.relid = tree.relid AND s.relid <> t.relid) p
+         WHERE t.schemaname = current_schema() AND t.relname = ANY($1)
+         ORDER BY array_position($1, t.relname::text)",
+        &[&tables],
+    )?;
+
+    let mut report = Vec::new();
+    for row in &rows {
+        let live: i64 = row.try_get(4)?;
+        let dead: i64 = row.try_get(5)?;
+        let indexes: Option<String> = row.try_get(10)?;
+        report.push(json!({
+            "table": row.try_get::<_, String>(0)?,
+            "total_bytes": row.try_get::<_, i64>(1)?,
+            "table_bytes": row.try_get::<_, i64>(2)?,
+            "index_bytes": row.try_get::<_, i64>(3)?,
+            "live_tuples": live,
+            "dead_tuples": dead,
+            "dead_ratio": dead_ratio(live, dead),
+            "last_vacuum": row.try_get::<_, Option<String>>(6)?,
+            "last_autovacuum": row.try_get::<_, Option<String>>(7)?,
+            "last_analyze": row.try_get::<_, Option<String>>(8)?,
+            "last_autoanalyze": row.try_get::<_, Option<String>>(9)?,
+            "indexes": indexes
+                .and_then(|indexes| serde_json::from_str::<Value>(&indexes).ok())
+                .unwrap_or_else(|| json!([])),
+        }));
+    }
+    Ok(json!({ "tables": report }))
+}
+
+// Share of the table's tuples that are dead, rounded to 4 places
+fn dead_ratio(live: i64, dead: i64) -> f64 {
+    if live + dead == 0 {
+        return 0.0;
+    }
+    (dead as f64 / (live + dead) as f64 * 10_000.0).round() / 10_000.0
+}
+
+// How often to VACUUM ANALYZE the app's tables; None when it's turned off
+pub fn vacuum_interval_from_env() -> Option<Duration> {
+    let minutes: u64 = env::var("STORAGE_VACUUM_INTERVAL_MINUTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    (minutes > 0).then(|| Duration::from_secs(minutes * 60))
+}
+
+// Vacuums and analyzes the app's tables. VACUUM can't run in a transaction, so this
+// takes a client rather than a transaction.
+pub fn vacuum_analyze(client: &mut Client) -> Result<(), PostgresError> {
+    client.batch_execute(&format!("VACUUM (ANALYZE) {}", TABLES.join(", ")))
+}
+
+// The share of a table a bulk change must touch to be analyzed; None when turned off
+pub fn bulk_analyze_fraction_from_env() -> Option<f64> {
+    let fraction: f64 = env::var("BULK_ANALYZE_FRACTION")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0.1);
+    (fraction > 0.0).then_some(fraction)
+}
+
+// Analyzes the tables among `touched` (table, rows changed) that a bulk change touched
+// enough of, returning their names
+pub fn analyze_after_bulk(
+    client: &mut impl GenericClient,
+    touched: &[(&'static str, u64)],
+    fraction: f64,
+) -> Result<Vec<&'static str>, PostgresError> {
+    let mut tables = Vec::new();
+    for &(table, rows) in touched {
+        // The planner's row estimate, from before the change; -1 if never analyzed
+        let estimate: f32 = client
+            .timed_query_one(
+                "SELECT coalesce((SELECT reltuples FROM pg_class WHERE oid = to_regclass($1::text)), -1)",
+                &[&table],
+            )?
+            .try_get(0)?;
+        if touched_enough(rows, estimate, fraction) {
+            tables.push(table);
+        }
+    }
+    if !tables.is_empty() {
+        client.batch_execute(&format!("ANALYZE {}", tables.join(", ")))?;
+    }
+    Ok(tables)
+}
+
+// `analyze_after_bulk` as a background job with a connection of its own, so a bulk
+// request doesn't wait for it
+pub fn analyze_after_bulk_in_background(touched: Vec<(&'static str, u64)>, fraction: f64) {
+    scheduler::once("analyze", move || {
+        let mut client = Client::connect(&get_db_url(), NoTls).map_err(|e| e.to_string())?;
+        let tables =
+            analyze_after_bulk(&mut client, &touched, fraction).map_err(|e| e.to_string())?;
+        if !tables.is_empty() {
+            log!("Analyzed {} after a bulk change", tables.join(", "));
+        }
+        Ok(())
+    });
+}
+
+fn touched_enough(rows: u64, estimate: f32, fraction: f64) -> bool {
+    rows > 0 && (estimate < 0.0 || rows as f64 >= fraction * estimate as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dead_ratios_are_shares_of_all_tuples() {
+        assert_eq!(dead_ratio(0, 0), 0.0);
+        assert_eq!(dead_ratio(3, 1), 0.25);
+        assert_eq!(dead_ratio(2, 1), 0.3333);
+    }
+
+    #[test]
+    fn only_large_changes_are_analyzed() {
+        assert!(touched_enough(100, 1000.0, 0.1));
+        assert!(!touched_enough(99, 1000.0, 0.1));
+        assert!(touched_enough(1, -1.0, 0.1));
+        assert!(!touched_enough(0, -1.0, 0.1));
+    }
+}
@@ -0,0 +1,299 @@
+use postgres::{Client, NoTls};
+use serde_json::Value;
+use std::fs;
+use std::io;
+
+use crate::config::get_db_url;
+use crate::http_client;
+use crate::output::{self, OutputFormat};
+use crate::repo::{self, Upsert};
+use crate::storage;
+
+const USAGE: &str = "usage: sync --from-url <http://host/path> [--map name=FIELD,email=FIELD] \
+    [--items-field FIELD] [--page-size N] [--page-param NAME] [--limit-param NAME] \
+    [--cursor-file PATH] [--dry-run]";
+
+struct Options {
+    base_url: String,
+    path: String,
+    // Dotted paths into each remote record for the local `name` and `email` columns
+    name_field: String,
+    email_field: String,
+    // Dotted path to the array of records in each page; the page itself when unset
+    items_field: Option<String>,
+    page_size: u64,
+    page_param: String,
+    limit_param: String,
+    cursor_file: String,
+    dry_run: bool,
+}
+
+#[derive(Default)]
+struct Totals {
+    pages: u64,
+    fetched: u64,
+    created: u64,
+    updated: u64,
+    unchanged: u64,
+    skipped: u64,
+}
+
+// Entry point for `app sync --from-url <api>`.
+// Pages through a remote user API and upserts every record into the local table,
+// matching on email. The next page number is saved to the cursor file after each
+// page is committed, so an interrupted sync picks up where it stopped. With
+// `--dry-run` each page is rolled back and the cursor is left untouched.
+pub fn run(args: &[String], format: OutputFormat) -> io::Result<()> {
+    let options = parse_options(args)?;
+    let mut client = Client::connect(&get_db_url(), NoTls).map_err(io::Error::other)?;
+
+    let mut page = match fs::read_to_string(&options.cursor_file) {
+        Ok(cursor) => {
+            let page = cursor
+                .trim()
+                .parse()
+                .map_err(|_| invalid(format!("corrupt cursor file {}", options.cursor_file)))?;
+            eprintln!("Resuming from page {} ({})", page, options.cursor_file);
+            page
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 1,
+        Err(e) => return Err(e),
+    };
+
+    let mut totals = Totals::default();
+    loop {
+        let records = fetch_page(&options, page)?;
+        let mut transaction = client.transaction().map_err(io::Error::other)?;
+        let mut counts = Totals::default();
+        for record in &records {
+            let name = lookup(record, &options.name_field).and_then(Value::as_str);
+            let email = lookup(record, &options.email_field).and_then(Value::as_str);
+            let (name, email) = match (name, email) {
+                (Some(name), Some(email)) => (name, email),
+                _ => {
+                    counts.skipped += 1;
+                    continue;
+                }
+            };
+            match repo::upsert_user_by_email(&mut transaction, name, email)
+                .map_err(io::Error::other)?
+                .1
+            {
+                Upsert::Created => counts.created += 1,
+                Upsert::Updated => counts.updated += 1,
+                Upsert::Unchanged => counts.unchanged += 1,
+            }
+        }
+
+        if options.dry_run {
+            transaction.rollback().map_err(io::Error::other)?;
+        } else {
+            transaction.commit().map_err(io::Error::other)?;
+            fs::write(&options.cursor_file, (page + 1).to_string())?;
+        }
+
+        eprintln!(
+            "Page {}: {} fetched, {} created, {} updated, {} unchanged, {} skipped",
+            page,
+            records.len(),
+            counts.created,
+            counts.updated,
+            counts.unchanged,
+            counts.skipped
+        );
+        totals.pages += 1;
+        totals.fetched += records.len() as u64;
+        totals.created += counts.created;
+        totals.updated += counts.updated;
+        totals.unchanged += counts.unchanged;
+        totals.skipped += counts.skipped;
+
+        if (records.len() as u64) < options.page_size {
+            break;
+        }
+        page += 1;
+    }
+
+    if !options.dry_run {
+        // A finished sync starts from the first page next time
+        let _ = fs::remove_file(&options.cursor_file);
+        if let Some(fraction) = storage::bulk_analyze_fraction_from_env() {
+            let touched = [("users", totals.created + totals.updated)];
+            let tables = storage::analyze_after_bulk(&mut client, &touched, fraction)
+                .map_err(io::Error::other)?;
+            if !tables.is_empty() {
+                eprintln!("Analyzed {}", tables.join(", "));
+            }
+        }
+    }
+
+    output::print_record(
+        format,
+        &[
+            ("dry_run", options.dry_run.into()),
+            ("pages", totals.pages.into()),
+            ("fetched", totals.fetched.into()),
+            ("created", totals.created.into()),
+            ("updated", totals.updated.into()),
+            ("unchanged", totals.unchanged.into()),
+            ("skipped", totals.skipped.into()),
+        ],
+    );
+    Ok(())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn parse_options(args: &[String]) -> io::Result<Options> {
+    let mut options = Options {
+        base_url: String::new(),
+        path: String::new(),
+        name_field: "name".to_string(),
+        email_field: "email".to_string(),
+        items_field: None,
+        page_size: 100,
+        page_param: "page".to_string(),
+        limit_param: "per_page".to_string(),
+        cursor_file: ".sync-cursor".to_string(),
+        dry_run: false,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--dry-run" {
+            options.dry_run = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| invalid(format!("missing value for {}", arg)))?;
+
+        match arg.as_str() {
+            "--from-url" => {
+                let (base_url, path) = split_url(value).map_err(invalid)?;
+                options.base_url = base_url;
+                options.path = path;
+            }
+            "--map" => {
+                for (local, remote) in parse_mapping(value).map_err(invalid)? {
+                    match local.as_str() {
+                        "name" => options.name_field = remote,
+                        "email" => options.email_field = remote,
+                        other => return Err(invalid(format!("unknown local field {}", other))),
+                    }
+                }
+            }
+            "--items-field" => options.items_field = Some(value.clone()),
+            "--page-size" => {
+                options.page_size = value
+                    .parse::<u64>()
+                    .map_err(|_| invalid(format!("invalid page size: {}", value)))?
+                    .max(1)
+            }
+            "--page-param" => options.page_param = value.clone(),
+            "--limit-param" => options.limit_param = value.clone(),
+            "--cursor-file" => options.cursor_file = value.clone(),
+            _ => return Err(invalid(format!("unknown option {}\n{}", arg, USAGE))),
+        }
+    }
+
+    if options.base_url.is_empty() {
+        return Err(invalid(USAGE.to_string()));
+    }
+    Ok(options)
+}
+
+fn fetch_page(options: &Options, page: u64) -> io::Result<Vec<Value>> {
+    let separator = if options.path.contains('?') { '&' } else { '?' };
+    let path = format!(
+        "{}{}{}={}&{}={}",
+        options.path, separator, options.page_param, page, options.limit_param, options.page_size
+    );
+    let response = http_client::send(&options.base_url, "GET", &path, &[], None)?;
+    if response.status != 200 {
+        return Err(io::Error::other(format!(
+            "GET {} returned {}",
+            path, response.status
+        )));
+    }
+
+    let body: Value = serde_json::from_str(&response.body)?;
+    let items = match &options.items_field {
+        Some(field) => lookup(&body, field),
+        None => Some(&body),
+    };
+    match items {
+        Some(Value::Array(items)) => Ok(items.clone()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("page {} doesn't contain an array of records", page),
+        )),
+    }
+}
+
+// Splits `http://host:port/path?query` into the base URL and the path with its query
+fn split_url(url: &str) -> Result<(String, String), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("only http:// URLs are supported: {}", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    Ok((format!("http://{}", host), path.to_string()))
+}
+
+// Parses `name=full_name,email=contact.email` into (local, remote) pairs
+fn parse_mapping(value: &str) -> Result<Vec<(String, String)>, String> {
+    value
+        .split(',')
+        .map(|pair| {
+            pair.split_once('=')
+                .map(|(local, remote)| (local.trim().to_string(), remote.trim().to_string()))
+                .ok_or_else(|| format!("invalid mapping: {}", pair))
+        })
+        .collect()
+}
+
+// Follows a dotted path like `contact.email` into a JSON value
+fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |value, key| value.get(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn urls_are_split_into_base_and_path() {
+        assert_eq!(
+            split_url("http://api:9000/v1/people?active=1").unwrap(),
+            (
+                "http://api:9000".to_string(),
+                "/v1/people?active=1".to_string()
+            )
+        );
+        assert_eq!(split_url("http://api").unwrap().1, "/");
+        assert!(split_url("https://api/people").is_err());
+    }
+
+    #[test]
+    fn mapped_fields_are_looked_up_by_dotted_path() {
+        let mapping = parse_mapping("name=full_name, email=contact.email").unwrap();
+        assert_eq!(
+            mapping[1],
+            ("email".to_string(), "contact.email".to_string())
+        );
+        assert!(parse_mapping("name").is_err());
+
+        let record = json!({"full_name": "Ada", "contact": {"email": "ada@example.com"}});
+        assert_eq!(
+            lookup(&record, "contact.email"),
+            Some(&json!("ada@example.com"))
+        );
+        assert_eq!(lookup(&record, "contact.phone"), None);
+    }
+}
@@ -0,0 +1,74 @@
+use postgres::GenericClient;
+use serde_json::json;
+
+use crate::audit;
+use crate::errors::{AppError, ErrorCode};
+use crate::models::User;
+use crate::repo::{self, Deletion};
+
+// Undoing a recorded change, for POST /admin/undo/{id}, from the before-image in its
+// audit entry:
+// - update: name and email go back to what they were
+// - delete: the user is restored
+// - create, restore: the user is soft-deleted again
+// Only the latest change to a user can be undone: if the user's version isn't the one
+// the entry left it at, something changed since and it's a 409. The undo is recorded
+// as a change of its own, with `undo_of` in its details, so it can be undone in turn.
+// Archiving and impersonation can't be undone this way.
+
+// Undoes the entry and returns the user as it is now
+pub fn apply(client: &mut impl GenericClient, actor: &str, audit_id: i64) -> Result<User, AppError> {
+    let Some(entry) = audit::entry(client, audit_id)? else {
+        return Err(AppError::NotFound(ErrorCode::RecordNotFound, "Audit entry not found".to_string()));
+    };
+    let action = match entry.action.as_str() {
+        "update" => "update",
+        "delete" => "restore",
+        "create" | "restore" => "delete",
+        other => {
+            return Err(AppError::Validation(
+                ErrorCode::ValidationFailed,
+                format!("{} entries can't be undone", other),
+            ))
+        }
+    };
+    let image = |state: Option<serde_json::Value>| state.and_then(|state| serde_json::from_value::<User>(state).ok());
+    let (before, after) = (image(entry.old), image(entry.new));
+
+    let id = entry.entity_id;
+    let current = match repo::find_user_for_update(client, id, true)? {
+        Some(user) if user.version.is_some() && user.version == after.and_then(|after| after.version) => user,
+        _ => return Err(conflict()),
+    };
+    let version = current.version.unwrap_or_default();
+    let undone = match (action, before) {
+        ("update", Some(before)) => repo::update_user(client, id, version, &before.name, &before.email)?.is_some(),
+        ("update", None) => {
+            return Err(AppError::Validation(
+                ErrorCode::ValidationFailed,
+                "The entry has no before-image".to_string(),
+            ))
+        }
+        ("restore", _) => repo::restore_user(client, id)?,
+        _ => match repo::delete_user(client, id)? {
+            Deletion::Deleted => true,
+            Deletion::NotFound => false,
+            Deletion::Restricted(dependents) => return Err(AppError::Restricted(dependents)),
+        },
+    };
+    if !undone {
+        return Err(conflict());
+    }
+
+    let new = repo::find_user(client, id, true)?;
+    let details = json!({ "undo_of": audit_id });
+    audit::record_with_details(client, actor, action, id, Some(&current), new.as_ref(), Some(&details))?;
+    new.ok_or_else(conflict)
+}
+
+fn conflict() -> AppError {
+    AppError::Validation(
+        ErrorCode::VersionConflict,
+        "The user changed since; undo the later changes first".to_string(),
+    )
+}
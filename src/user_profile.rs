@@ -0,0 +1,100 @@
+use postgres::{Error as PostgresError, GenericClient};
+
+use crate::query_log::Timed;
+
+// Users' public profiles, at GET/PUT /users/{id}/profile. A user has at most one, in
+// `profiles`; it's created by the first PUT, and until then GET is a 404. Every field
+// is optional, and a PUT replaces them all. Profiles go with their user when it's
+// archived (see `archive`).
+// (Not to be confused with deployment profiles, see `profile`.)
+
+const MAX_BIO_CHARS: usize = 1000;
+const MAX_LOCATION_CHARS: usize = 100;
+const MAX_AVATAR_URL_CHARS: usize = 2048;
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>,
+    pub location: Option<String>,
+}
+
+impl Profile {
+    // Err with what's wrong, for a 422
+    pub fn validate(&self) -> Result<(), String> {
+        let too_long = |value: &Option<String>, max| value.as_ref().is_some_and(|value| value.chars().count() > max);
+        if too_long(&self.bio, MAX_BIO_CHARS) {
+            return Err(format!("bio can be at most {} characters", MAX_BIO_CHARS));
+        }
+        if too_long(&self.location, MAX_LOCATION_CHARS) {
+            return Err(format!("location can be at most {} characters", MAX_LOCATION_CHARS));
+        }
+        if let Some(url) = &self.avatar_url {
+            if !(url.starts_with("https://") || url.starts_with("http://")) {
+                return Err("avatar_url must be an http or https URL".to_string());
+            }
+            if url.chars().count() > MAX_AVATAR_URL_CHARS {
+                return Err(format!("avatar_url can be at most {} characters", MAX_AVATAR_URL_CHARS));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The table, created by a migration (see `migrations`)
+pub(crate) const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS profiles (
+    user_id INTEGER PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    bio TEXT,
+    avatar_url TEXT,
+    location TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);";
+
+// The user's profile; None if they never set one
+pub fn get(client: &mut impl GenericClient, user_id: i32) -> Result<Option<Profile>, PostgresError> {
+    let row = client.timed_query_opt(
+        "SELECT bio, avatar_url, location FROM profiles WHERE user_id = $1",
+        &[&user_id],
+    )?;
+    row.map(|row| {
+        Ok(Profile {
+            bio: row.try_get(0)?,
+            avatar_url: row.try_get(1)?,
+            location: row.try_get(2)?,
+        })
+    })
+    .transpose()
+}
+
+// Creates or replaces the user's profile
+pub fn put(client: &mut impl GenericClient, user_id: i32, profile: &Profile) -> Result<(), PostgresError> {
+    client.timed_execute(
+        "INSERT INTO profiles (user_id, bio, avatar_url, location) VALUES ($1, $2, $3, $4)
+         ON CONFLICT (user_id) DO UPDATE SET bio = EXCLUDED.bio, avatar_url = EXCLUDED.avatar_url,
+             location = EXCLUDED.location, updated_at = now()",
+        &[&user_id, &profile.bio, &profile.avatar_url, &profile.location],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_are_checked_before_they_are_saved() {
+        let profile: Profile = serde_json::from_str(r#"{"bio": "Counts things", "avatar_url": "https://example.com/a.png"}"#).unwrap();
+        assert_eq!(profile.location, None);
+        assert_eq!(profile.validate(), Ok(()));
+        assert!(serde_json::from_str::<Profile>(r#"{"biography": "Counts things"}"#).is_err());
+
+        let profile = Profile { avatar_url: Some("javascript:alert(1)".to_string()), ..Default::default() };
+        assert_eq!(profile.validate(), Err("avatar_url must be an http or https URL".to_string()));
+        let profile = Profile { bio: Some("é".repeat(MAX_BIO_CHARS)), ..Default::default() };
+        assert_eq!(profile.validate(), Ok(()));
+        let profile = Profile { bio: Some("é".repeat(MAX_BIO_CHARS + 1)), ..Default::default() };
+        assert!(profile.validate().is_err());
+    }
+}
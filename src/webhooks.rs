@@ -0,0 +1,567 @@
+use hmac::{Hmac, Mac};
+use postgres::types::ToSql;
+use postgres::{Client, Error as PostgresError, GenericClient, Row};
+use serde_json::Value;
+use sha2::Sha256;
+use std::env;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::db::{in_transaction, with_client};
+use crate::errors::{AppError, ErrorCode};
+use crate::events;
+use crate::handlers::{json_response, OK_RESPONSE};
+use crate::http_client;
+use crate::models::User;
+use crate::query_log::Timed;
+use crate::resource::{self, Resource};
+use crate::router::{get_path, get_query_param};
+use crate::server::{AppState, Caller};
+use crate::tokens;
+
+// Webhooks: URLs (admin only, at `/webhooks`) that are POSTed the change events of
+// GET /ws/users (see `events`) they subscribe to, e.g.
+//   POST /webhooks {"url": "http://hooks.internal/users", "events": ["created", "deleted"]}
+// Events default to all of created, updated and deleted. Each webhook gets a secret,
+// generated unless one is given, that signs its deliveries:
+//   X-Webhook-Signature: sha256=<hex HMAC-SHA256 of "<X-Webhook-Timestamp>.<body>">
+// so receivers can check a delivery came from here and isn't a replay of an old one.
+// Deliveries are queued in the mutation's transaction, so there's one exactly for each
+// committed change, and sent by a job every WEBHOOK_POLL_INTERVAL_SECS (5). Anything
+// but a 2xx answer is retried with exponential backoff, MAX_ATTEMPTS times in all.
+// Every attempt is kept in `webhook_attempts` for debugging; GET /webhooks/{id}/deliveries
+// lists recent deliveries with theirs. Delivered ones are deleted after
+// WEBHOOK_RETENTION_DAYS (7), failed ones kept until their webhook is.
+// Only plain `http://` URLs can be delivered to (see `http_client`).
+
+const EVENTS: &[&str] = &["created", "updated", "deleted"];
+
+const MAX_ATTEMPTS: i32 = 8;
+
+// Delay before the second attempt; it doubles for each after that, up to MAX_BACKOFF
+const FIRST_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(6 * 60 * 60);
+
+// How long a receiver may take to accept the connection, and to read or answer
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Deliveries sent per run, so a backlog doesn't keep one run going indefinitely
+const BATCH_SIZE: usize = 100;
+
+// Longest error message kept for an attempt
+const MAX_ERROR_LENGTH: usize = 500;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Webhook {
+    #[serde(default)]
+    pub id: Option<i32>,
+    pub url: String,
+    #[serde(default = "all_events")]
+    pub events: Vec<String>,
+    // Generated when a webhook is created without one, and kept when it's replaced
+    // without one
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+}
+
+fn all_events() -> Vec<String> {
+    EVENTS.iter().map(|event| event.to_string()).collect()
+}
+
+impl Resource for Webhook {
+    const TABLE: &'static str = "webhooks";
+    const NAME: &'static str = "Webhook";
+    const SCHEMA: &'static str = "url TEXT NOT NULL,
+        events TEXT[] NOT NULL,
+        secret TEXT NOT NULL";
+    const COLUMNS: &'static [&'static str] = &["url", "events", "secret"];
+
+    fn from_row(row: &Row) -> Result<Self, PostgresError> {
+        Ok(Webhook {
+            id: Some(row.try_get("id")?),
+            url: row.try_get("url")?,
+            events: row.try_get("events")?,
+            secret: row.try_get("secret")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+
+    fn values(&self) -> Vec<&(dyn ToSql + Sync)> {
+        vec![&self.url, &self.events, &self.secret]
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if split_url(&self.url).is_none() {
+            return Err("url must be an http:// URL".to_string());
+        }
+        if self.events.is_empty() {
+            return Err("events can't be empty".to_string());
+        }
+        match self
+            .events
+            .iter()
+            .find(|event| !EVENTS.contains(&event.as_str()))
+        {
+            Some(event) => Err(format!(
+                "Unknown event {}; events are {}",
+                event,
+                EVENTS.join(", ")
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Delivery {
+    pub id: i64,
+    pub event: String,
+    pub payload: Value,
+    pub attempts: Vec<Attempt>,
+    // When it's next tried; None once it's delivered or has failed for good
+    pub next_attempt_at: Option<String>,
+    pub delivered_at: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Attempt {
+    pub attempted_at: String,
+    // The receiver's HTTP status; None when there was no answer
+    pub status: Option<i32>,
+    pub error: Option<String>,
+    pub duration_ms: i32,
+}
+
+// How often queued deliveries are sent
+pub fn poll_interval_from_env() -> Duration {
+    Duration::from_secs(
+        env::var("WEBHOOK_POLL_INTERVAL_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&secs| secs > 0)
+            .unwrap_or(5),
+    )
+}
+
+fn retention_days_from_env() -> i32 {
+    env::var("WEBHOOK_RETENTION_DAYS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|&days| days > 0)
+        .unwrap_or(7)
+}
+
+// The tables, created by a migration (see `migrations`): the webhooks themselves are a
+// resource, with tables for their deliveries and delivery attempts
+pub(crate) fn schema() -> String {
+    format!("{}\n{}", resource::table_sql::<Webhook>(), DELIVERY_SCHEMA)
+}
+
+const DELIVERY_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id BIGSERIAL PRIMARY KEY,
+    webhook_id INTEGER NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+    event TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    next_attempt_at TIMESTAMPTZ DEFAULT now(),
+    delivered_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS webhook_deliveries_due
+    ON webhook_deliveries (next_attempt_at) WHERE next_attempt_at IS NOT NULL;
+CREATE INDEX IF NOT EXISTS webhook_deliveries_webhook_id
+    ON webhook_deliveries (webhook_id, id);
+CREATE TABLE IF NOT EXISTS webhook_attempts (
+    id BIGSERIAL PRIMARY KEY,
+    delivery_id BIGINT NOT NULL REFERENCES webhook_deliveries (id) ON DELETE CASCADE,
+    attempted_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    status INTEGER,
+    error TEXT,
+    duration_ms INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS webhook_attempts_delivery_id
+    ON webhook_attempts (delivery_id);";
+
+// Queues a delivery of an audited change to each webhook subscribed to it. Called with
+// the mutation's client, so the deliveries are only there if it commits.
+pub fn enqueue(
+    client: &mut impl GenericClient,
+    action: &str,
+    user_id: i32,
+    old: Option<&User>,
+    new: Option<&User>,
+) -> Result<(), PostgresError> {
+    let Some(event) = events::kind_of(action) else {
+        return Ok(());
+    };
+    let payload = serde_json::json!({
+        "type": event,
+        "user_id": user_id,
+        "user": new.or(old),
+    });
+    client.timed_execute(
+        "INSERT INTO webhook_deliveries (webhook_id, event, payload)
+         SELECT id, $1, $2::text::jsonb FROM webhooks WHERE $1 = ANY (events)",
+        &[&event, &payload.to_string()],
+    )?;
+    Ok(())
+}
+
+// Sends the deliveries that are due, returning how many were delivered. Each is sent
+// in its own transaction, with its row locked, so replicas running the job at the same
+// time don't send it twice.
+pub fn deliver_due(client: &mut Client) -> Result<usize, PostgresError> {
+    let mut delivered = 0;
+    for _ in 0..BATCH_SIZE {
+        let mut transaction = client.transaction()?;
+        let Some(row) = transaction.timed_query_opt(
+            "SELECT d.id, d.event, d.payload::text, w.url, w.secret,
+                    (SELECT count(*) FROM webhook_attempts a WHERE a.delivery_id = d.id)::int
+             FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id
+             WHERE d.next_attempt_at <= now()
+             ORDER BY d.next_attempt_at, d.id LIMIT 1
+             FOR UPDATE OF d SKIP LOCKED",
+            &[],
+        )?
+        else {
+            break;
+        };
+        let id: i64 = row.try_get(0)?;
+        let event: String = row.try_get(1)?;
+        let payload: String = row.try_get(2)?;
+        let url: String = row.try_get(3)?;
+        let secret: String = row.try_get(4)?;
+        let attempts: i32 = row.try_get::<_, i32>(5)? + 1;
+
+        let started = Instant::now();
+        let result = send(&url, &secret, id, &event, &payload);
+        let duration_ms = started.elapsed().as_millis().min(i32::MAX as u128) as i32;
+        let (status, error) = match &result {
+            Ok(status) if (200..300).contains(status) => (Some(*status), None),
+            Ok(status) => (Some(*status), Some(format!("HTTP {}", status))),
+            Err(e) => (None, Some(truncate(&e.to_string()))),
+        };
+        transaction.timed_execute(
+            "INSERT INTO webhook_attempts (delivery_id, status, error, duration_ms)
+             VALUES ($1, $2, $3, $4)",
+            &[&id, &status.map(i32::from), &error, &duration_ms],
+        )?;
+        if error.is_none() {
+            transaction.timed_execute(
+                "UPDATE webhook_deliveries SET next_attempt_at = NULL, delivered_at = now()
+                 WHERE id = $1",
+                &[&id],
+            )?;
+            delivered += 1;
+        } else {
+            let backoff = (attempts < MAX_ATTEMPTS).then(|| backoff(attempts).as_secs() as f64);
+            transaction.timed_execute(
+                "UPDATE webhook_deliveries
+                 SET next_attempt_at = now() + make_interval(secs => $2)
+                 WHERE id = $1",
+                &[&id, &backoff],
+            )?;
+        }
+        transaction.commit()?;
+    }
+    Ok(delivered)
+}
+
+// Deletes the deliveries delivered more than WEBHOOK_RETENTION_DAYS ago
+pub fn delete_expired(client: &mut impl GenericClient) -> Result<u64, PostgresError> {
+    client.timed_execute(
+        "DELETE FROM webhook_deliveries
+         WHERE delivered_at < now() - make_interval(days => $1)",
+        &[&retention_days_from_env()],
+    )
+}
+
+// The webhook's most recent deliveries first, with their attempts
+pub fn deliveries(
+    client: &mut impl GenericClient,
+    webhook_id: i32,
+    limit: i64,
+) -> Result<Vec<Delivery>, PostgresError> {
+    let rows = client.timed_query(
+        "SELECT d.id, d.event, d.payload::text,
+                coalesce((SELECT json_agg(json_build_object(
+                              'attempted_at', to_json(a.attempted_at) #>> '{}',
+                              'status', a.status, 'error', a.error,
+                              'duration_ms', a.duration_ms) ORDER BY a.id)
+                          FROM webhook_attempts a WHERE a.delivery_id = d.id), '[]')::text,
+                to_json(d.next_attempt_at) #>> '{}', to_json(d.delivered_at) #>> '{}',
+                to_json(d.created_at) #>> '{}'
+         FROM webhook_deliveries d WHERE d.webhook_id = $1
+         ORDER BY d.id DESC LIMIT $2",
+        &[&webhook_id, &limit],
+    )?;
+    rows.iter()
+        .map(|row| {
+            let payload: String = row.try_get(2)?;
+            let attempts: String = row.try_get(3)?;
+            Ok(Delivery {
+                id: row.try_get(0)?,
+                event: row.try_get(1)?,
+                payload: serde_json::from_str(&payload).unwrap_or(Value::Null),
+                attempts: serde_json::from_str(&attempts).unwrap_or_default(),
+                next_attempt_at: row.try_get(4)?,
+                delivered_at: row.try_get(5)?,
+                created_at: row.try_get(6)?,
+            })
+        })
+        .collect()
+}
+
+// How a webhook's receiver has been doing, from its latest delivery attempts
+pub struct TargetHealth {
+    pub id: i32,
+    pub url: String,
+    // Whether the latest attempt got a 2xx, and how long it took; None before the first
+    pub last_attempt: Option<(bool, i32)>,
+    // What went wrong in the latest failed attempt, and when
+    pub last_failure: Option<(String, String)>,
+}
+
+pub fn target_health(client: &mut impl GenericClient) -> Result<Vec<TargetHealth>, PostgresError> {
+    let rows = client.timed_query(
+        "SELECT w.id, w.url, last.status BETWEEN 200 AND 299, last.duration_ms,
+                coalesce(failed.error, 'HTTP ' || failed.status), to_json(failed.attempted_at) #>> '{}'
+         FROM webhooks w
+         LEFT JOIN LATERAL (
+             SELECT a.status, a.duration_ms FROM webhook_attempts a
+             JOIN webhook_deliveries d ON d.id = a.delivery_id
+             WHERE d.webhook_id = w.id ORDER BY a.id DESC LIMIT 1
+         ) last ON true
+         LEFT JOIN LATERAL (
+             SELECT a.status, a.error, a.attempted_at FROM webhook_attempts a
+             JOIN webhook_deliveries d ON d.id = a.delivery_id
+             WHERE d.webhook_id = w.id AND (a.status IS NULL OR a.status NOT BETWEEN 200 AND 299)
+             ORDER BY a.id DESC LIMIT 1
+         ) failed ON true
+         ORDER BY w.id",
+        &[],
+    )?;
+    rows.iter()
+        .map(|row| {
+            let succeeded: Option<bool> = row.try_get(2)?;
+            let duration_ms: Option<i32> = row.try_get(3)?;
+            let error: Option<String> = row.try_get(4)?;
+            let failed_at: Option<String> = row.try_get(5)?;
+            Ok(TargetHealth {
+                id: row.try_get(0)?,
+                url: row.try_get(1)?,
+                last_attempt: duration_ms.map(|duration_ms| (succeeded.unwrap_or(false), duration_ms)),
+                last_failure: error.zip(failed_at),
+            })
+        })
+        .collect()
+}
+
+// POSTs a delivery, returning the receiver's status
+fn send(url: &str, secret: &str, id: i64, event: &str, payload: &str) -> std::io::Result<u16> {
+    let (base_url, path) = split_url(url).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "not an http:// URL")
+    })?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .to_string();
+    let headers = [
+        ("Content-Type", "application/json".to_string()),
+        ("X-Webhook-Delivery", id.to_string()),
+        ("X-Webhook-Event", event.to_string()),
+        ("X-Webhook-Timestamp", timestamp.clone()),
+        (
+            "X-Webhook-Signature",
+            format!("sha256={}", sign(secret, &timestamp, payload)),
+        ),
+    ]
+    .map(|(name, value)| (name.to_string(), value));
+    let response = http_client::send_with_timeout(
+        base_url,
+        "POST",
+        path,
+        &headers,
+        Some(payload),
+        Some(DELIVERY_TIMEOUT),
+    )?;
+    Ok(response.status)
+}
+
+// The hex HMAC-SHA256 of `<timestamp>.<payload>` with the webhook's secret
+pub fn sign(secret: &str, timestamp: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// The wait after the given number of failed attempts
+fn backoff(attempts: i32) -> Duration {
+    let doublings = (attempts - 1).clamp(0, 20) as u32;
+    FIRST_BACKOFF
+        .saturating_mul(1 << doublings)
+        .min(MAX_BACKOFF)
+}
+
+// `http://host:port/path?query` as `("http://host:port", "/path?query")`
+fn split_url(url: &str) -> Option<(&str, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let host = rest.split('/').next().unwrap_or_default();
+    if host.is_empty() || host.contains(['?', '#', '@', ' ']) {
+        return None;
+    }
+    let (base_url, path) = url.split_at("http://".len() + host.len());
+    Some((base_url, if path.is_empty() { "/" } else { path }))
+}
+
+fn truncate(message: &str) -> String {
+    match message.char_indices().nth(MAX_ERROR_LENGTH) {
+        Some((index, _)) => message[..index].to_string(),
+        None => message.to_string(),
+    }
+}
+
+// Handle requests to `/webhooks` and `/webhooks/{id}` (admin only)
+// Creating a webhook generates its secret when the body has none; replacing one keeps it.
+// Returns a `(String, String)` tuple for HTTP status and body, like the other handlers.
+pub(crate) fn handle(request: &str, state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    let method = request.split_whitespace().next().unwrap_or_default();
+    let collection = get_path(request).trim_matches('/').split('/').count() == 1;
+    match (method, collection) {
+        ("POST", true) => handle_create(request, state),
+        ("PUT", false) => match crate::router::parse_id(request, state.id_policy) {
+            Ok(id) => handle_update(request, id, state),
+            Err(response) => response,
+        },
+        _ => resource::handle::<Webhook>(request, state),
+    }
+}
+
+fn handle_create(request: &str, state: &AppState) -> (String, String) {
+    let mut webhook = match resource::body::<Webhook>(request) {
+        Ok(webhook) => webhook,
+        Err(e) => return e.into_response(),
+    };
+    if webhook.secret.is_none() {
+        match tokens::new_token() {
+            Ok(secret) => webhook.secret = Some(secret),
+            Err(e) => return AppError::Token(e).into_response(),
+        }
+    }
+    match with_client(state, |client| Ok(resource::insert(client, &webhook)?)) {
+        Ok(created) => json_response(OK_RESPONSE, &created),
+        Err(e) => e.into_response(),
+    }
+}
+
+fn handle_update(request: &str, id: i32, state: &AppState) -> (String, String) {
+    let webhook = match resource::body::<Webhook>(request) {
+        Ok(webhook) => webhook,
+        Err(e) => return e.into_response(),
+    };
+    in_transaction(state, |transaction| {
+        let current = resource::find::<Webhook>(transaction, id)?
+            .ok_or_else(resource::not_found::<Webhook>)?;
+        let webhook = Webhook {
+            secret: webhook.secret.clone().or(current.secret),
+            ..webhook.clone()
+        };
+        let updated = resource::update(transaction, id, &webhook)?
+            .ok_or_else(resource::not_found::<Webhook>)?;
+        Ok((OK_RESPONSE.to_string(), serde_json::to_string(&updated)?))
+    })
+}
+
+// Handle GET /webhooks/{id}/deliveries?limit=... (admin only)
+// The webhook's recent deliveries with their attempts; `limit` is 1-1000 (100).
+// Returns a `(String, String)` tuple for HTTP status and body, like the other handlers.
+pub(crate) fn handle_deliveries(
+    request: &str,
+    id: i32,
+    state: &AppState,
+    caller: &Caller,
+) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    let limit = match get_query_param(request, "limit").map(|limit| limit.parse::<i64>()) {
+        None => 100,
+        Some(Ok(limit)) if (1..=1000).contains(&limit) => limit,
+        Some(_) => {
+            return AppError::Validation(
+                ErrorCode::InvalidQuery,
+                "limit must be between 1 and 1000".to_string(),
+            )
+            .into_response()
+        }
+    };
+    let deliveries = with_client(state, |client| {
+        resource::find::<Webhook>(client, id)?.ok_or_else(resource::not_found::<Webhook>)?;
+        Ok(deliveries(client, id, limit)?)
+    });
+    match deliveries {
+        Ok(deliveries) => json_response(OK_RESPONSE, &deliveries),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Handle POST /admin/webhooks/run (admin only)
+// Sends the deliveries that are due now instead of waiting for the scheduler.
+// Returns a `(String, String)` tuple for HTTP status and body, like the other handlers.
+pub(crate) fn handle_run(state: &AppState, caller: &Caller) -> (String, String) {
+    if !caller.is_admin() {
+        return AppError::Auth.into_response();
+    }
+    match with_client(state, |client| Ok(deliver_due(client)?)) {
+        Ok(delivered) => json_response(OK_RESPONSE, &serde_json::json!({ "delivered": delivered })),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_split_into_host_and_path() {
+        assert_eq!(
+            split_url("http://hooks:8000/users?v=1"),
+            Some(("http://hooks:8000", "/users?v=1"))
+        );
+        assert_eq!(split_url("http://hooks?v=1"), None);
+        assert_eq!(split_url("http://hooks"), Some(("http://hooks", "/")));
+        assert_eq!(split_url("https://hooks/users"), None);
+        assert_eq!(split_url("http:///users"), None);
+    }
+
+    #[test]
+    fn retries_back_off_exponentially() {
+        assert_eq!(backoff(1), FIRST_BACKOFF);
+        assert_eq!(backoff(3), FIRST_BACKOFF * 4);
+        assert_eq!(backoff(MAX_ATTEMPTS + 30), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn signatures_cover_the_timestamp_and_body() {
+        // As computed by Python's hmac.new(secret, b"1700000000." + body, sha256)
+        assert_eq!(
+            sign("secret", "1700000000", r#"{"type":"created"}"#),
+            "abd297bcf8cef16a25963bef3c9a06616b8b542144b46aa6342a2ceec6998e25"
+        );
+    }
+}
@@ -0,0 +1,283 @@
+use std::io::{self, ErrorKind};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::cors;
+use crate::errors::{error, ErrorCode};
+use crate::events::ChangeEvent;
+use crate::http::Response;
+use crate::request_id;
+use crate::router::get_header;
+use crate::server::Connection;
+
+// GET /ws/users: a WebSocket (RFC 6455) pushing a text message of JSON for each change
+// to users (see `events`), like
+//   {"id": 42, "type": "updated", "user_id": 7, "user": {"id": 7, "name": ...}}
+// so dashboards can live-update without polling. The connection is only for receiving:
+// messages from the client are ignored, apart from pings and the closing handshake.
+// The server pings every PING_INTERVAL so idle proxies keep the connection open.
+// Subscribers that fall too far behind are closed with status 1013 (try again later),
+// and should reconnect and reload what they show.
+
+// Appended to the client's key to prove the server speaks WebSocket
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+// How long to wait for client frames before checking for events again
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Clients only send control frames, which are small; anything bigger closes the connection
+const MAX_CLIENT_FRAME: usize = 64 * 1024;
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// The Sec-WebSocket-Accept value for a valid version 13 handshake; None otherwise
+pub fn accept_key(request: &str) -> Option<String> {
+    let upgrade = get_header(request, "Upgrade")?;
+    let version = get_header(request, "Sec-WebSocket-Version")?;
+    let key = get_header(request, "Sec-WebSocket-Key")?.trim();
+    if !upgrade.trim().eq_ignore_ascii_case("websocket") || version.trim() != "13" || key.is_empty()
+    {
+        return None;
+    }
+    Some(base64(&sha1(
+        format!("{}{}", key, HANDSHAKE_GUID).as_bytes(),
+    )))
+}
+
+// Handle GET /ws/users without a valid handshake (those are served by `send_events`)
+// Returns a `(String, String)` tuple for HTTP status and body, like the handlers.
+pub(crate) fn handle_request() -> (String, String) {
+    let (status_line, body) = error(ErrorCode::UpgradeRequired, "Upgrade Required");
+    let status_line = format!(
+        "{}Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n",
+        status_line
+    );
+    (status_line, body)
+}
+
+// Accepts the handshake, switching the connection over to WebSocket
+pub(crate) fn write_handshake(stream: &mut impl Connection, accept_key: &str) -> io::Result<()> {
+    let response = Response::new(101)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", accept_key)
+        .header_lines(&request_id::header_line())
+        .header_lines(&cors::header_lines());
+    stream.write_all(response.head().as_bytes())
+}
+
+// Sends the events as they come until the client closes the connection or falls behind
+pub(crate) fn send_events(
+    stream: &mut impl Connection,
+    events: Receiver<Arc<ChangeEvent>>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut received = Vec::new();
+    let mut last_ping = Instant::now();
+    loop {
+        loop {
+            match events.try_recv() {
+                Ok(event) => {
+                    let message = serde_json::to_string(&*event)?;
+                    stream.write_all(&frame(OPCODE_TEXT, message.as_bytes()))?;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    return stream.write_all(&close_frame(1013, "Too far behind on events"));
+                }
+            }
+        }
+        if last_ping.elapsed() >= PING_INTERVAL {
+            stream.write_all(&frame(OPCODE_PING, b""))?;
+            last_ping = Instant::now();
+        }
+
+        let mut chunk = [0; 4096];
+        match stream.read(&mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(read) => received.extend_from_slice(&chunk[..read]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e),
+        }
+        while let Some((opcode, payload, length)) = parse_frame(&received)? {
+            received.drain(..length);
+            match opcode {
+                // The closing handshake: echo the status and hang up
+                OPCODE_CLOSE => {
+                    let status = payload.get(..2).unwrap_or_default();
+                    return stream.write_all(&frame(OPCODE_CLOSE, status));
+                }
+                OPCODE_PING => stream.write_all(&frame(OPCODE_PONG, &payload))?,
+                _ => {}
+            }
+        }
+    }
+}
+
+// A single unfragmented, unmasked frame, as servers send them
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn close_frame(status: u16, reason: &str) -> Vec<u8> {
+    let mut payload = status.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    frame(OPCODE_CLOSE, &payload)
+}
+
+// The first frame in `buffer` from a client: its opcode, unmasked payload and length
+// in the buffer. None until the whole frame has been received.
+fn parse_frame(buffer: &[u8]) -> io::Result<Option<(u8, Vec<u8>, usize)>> {
+    let invalid = |message: &str| io::Error::new(ErrorKind::InvalidData, message.to_string());
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let opcode = buffer[0] & 0x0f;
+    if buffer[1] & 0x80 == 0 {
+        return Err(invalid("client frames must be masked"));
+    }
+    let (length, header) = match buffer[1] & 0x7f {
+        126 if buffer.len() >= 4 => (u16::from_be_bytes([buffer[2], buffer[3]]) as usize, 4),
+        127 if buffer.len() >= 10 => {
+            let mut length = [0; 8];
+            length.copy_from_slice(&buffer[2..10]);
+            (u64::from_be_bytes(length) as usize, 10)
+        }
+        126 | 127 => return Ok(None),
+        length => (length as usize, 2),
+    };
+    if length > MAX_CLIENT_FRAME {
+        return Err(invalid("client frame too large"));
+    }
+    let total = header + 4 + length;
+    if buffer.len() < total {
+        return Ok(None);
+    }
+    let mask = &buffer[header..header + 4];
+    let payload = buffer[header + 4..total]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ mask[i % 4])
+        .collect();
+    Ok(Some((opcode, payload, total)))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let next = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = next;
+        }
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, value) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let byte = |i: usize| chunk.get(i).copied().unwrap_or(0) as u32;
+        let bits = byte(0) << 16 | byte(1) << 8 | byte(2);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshakes_are_accepted_with_the_rfc_key() {
+        let request =
+            "GET /ws/users HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\n\
+                       Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n";
+        assert_eq!(
+            accept_key(request).as_deref(),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert_eq!(accept_key(&request.replace("13", "8")), None);
+        assert_eq!(accept_key("GET /ws/users HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(base64(b"ab"), "YWI=");
+    }
+
+    #[test]
+    fn masked_client_frames_are_parsed() {
+        // A masked "Hello" ping, from RFC 6455 section 5.7
+        let ping = [
+            0x89, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        assert_eq!(parse_frame(&ping[..6]).unwrap(), None);
+        assert_eq!(
+            parse_frame(&ping).unwrap(),
+            Some((OPCODE_PING, b"Hello".to_vec(), ping.len()))
+        );
+        assert!(parse_frame(&frame(OPCODE_TEXT, b"unmasked")).is_err());
+        assert_eq!(&frame(OPCODE_TEXT, &[b'x'; 200])[..4], &[0x81, 126, 0, 200]);
+    }
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::Once;
+
+use common::server;
+use rust_docker_pg_crud::admin_socket;
+
+// The admin socket (see `admin_socket`), in its own test binary so it can be started
+// with ADMIN_UIDS leaving out the user running the tests
+
+static SETTINGS: Once = Once::new();
+
+// A path for a test's socket that nothing else uses
+fn socket_path(name: &str) -> PathBuf {
+    SETTINGS.call_once(|| {
+        env::set_var("ADMIN_UIDS", "4242");
+    });
+    let path = env::temp_dir().join(format!(
+        "admin-socket-{}-{}.sock",
+        std::process::id(),
+        name
+    ));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn files_in_the_way_are_left_alone() {
+    let path = socket_path("file");
+    fs::write(&path, "not a socket").unwrap();
+
+    assert!(admin_socket::serve(&path.to_string_lossy(), server::mock_state()).is_err());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn sockets_left_behind_are_replaced() {
+    let path = socket_path("stale");
+    drop(UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    admin_socket::serve(&path.to_string_lossy(), server::mock_state()).unwrap();
+    assert!(UnixStream::connect(&path).is_ok());
+}
+
+#[test]
+fn other_users_get_a_403_after_their_request() {
+    let path = socket_path("refused");
+    admin_socket::serve(&path.to_string_lossy(), server::mock_state()).unwrap();
+
+    let mut stream = UnixStream::connect(&path).unwrap();
+    let body = "x".repeat(64 * 1024);
+    stream
+        .write_all(
+            format!(
+                "POST /users HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 403 "), "{}", response);
+    assert!(response.contains("\"FORBIDDEN\""), "{}", response);
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use std::env;
+
+use common::factories::UserFactory;
+use common::server::{self, json, TestServer};
+
+// The response cache (see `response_cache`), in its own test binary so the server
+// can be started with it turned on
+
+fn start() -> Option<&'static TestServer> {
+    env::set_var("RESPONSE_CACHE_TTL_SECS", "60");
+    server::start()
+}
+
+#[test]
+fn reads_are_cached_until_a_change() {
+    let Some(server) = start() else {
+        return;
+    };
+    let mut client = server.client();
+    let id = UserFactory::new()
+        .with_name("Cached")
+        .create(&mut client)
+        .unwrap();
+    let path = format!("/users/{}", id);
+
+    let response = server.send("GET", &path, &[], None);
+    assert_eq!(response.header("X-Cache"), Some("MISS"));
+    let response = server.send("GET", &path, &[], None);
+    assert_eq!(response.header("X-Cache"), Some("HIT"));
+    assert!(response.header("ETag").is_some());
+    // Admins have entries of their own
+    let response = server.send_as_admin("GET", &path, &[], None);
+    assert_eq!(response.header("X-Cache"), Some("MISS"));
+
+    // Changes made behind the server's back are only seen once entries expire
+    client
+        .execute(
+            "UPDATE users SET name = 'Changed directly' WHERE id = $1",
+            &[&id],
+        )
+        .unwrap();
+    assert_eq!(
+        json(&server.send("GET", &path, &[], None))["name"],
+        "Cached"
+    );
+
+    let response = server.send(
+        "PATCH",
+        &path,
+        &[("If-Match", "*")],
+        Some(r#"{"name": "Changed"}"#),
+    );
+    assert_eq!(response.status, 200);
+    let response = server.send("GET", &path, &[], None);
+    assert_eq!(response.header("X-Cache"), Some("MISS"));
+    assert_eq!(json(&response)["name"], "Changed");
+
+    // Lists are keyed by their query, whatever the parameter order
+    let list = "/users?fields=id,name&created_after=2000-01-01";
+    let response = server.send("GET", list, &[], None);
+    assert_eq!(response.header("X-Cache"), Some("MISS"));
+    let reordered = "/users?created_after=2000-01-01&fields=id,name";
+    let response = server.send("GET", reordered, &[], None);
+    assert_eq!(response.header("X-Cache"), Some("HIT"));
+    let created = UserFactory::new().body();
+    let response = server.send("POST", "/users", &[], Some(&created));
+    assert_eq!(response.status, 200);
+    let response = server.send("GET", list, &[], None);
+    assert_eq!(response.header("X-Cache"), Some("MISS"));
+
+    // Errors aren't cached
+    let missing = "/users/2147483647";
+    server.send("GET", missing, &[], None);
+    let response = server.send("GET", missing, &[], None);
+    assert_eq!(response.status, 404);
+    assert_eq!(response.header("X-Cache"), Some("MISS"));
+}
+
+#[test]
+fn verifying_an_email_drops_the_cached_user() {
+    let Some(server) = start() else {
+        return;
+    };
+    let mut client = server.client();
+    let id = UserFactory::new().create(&mut client).unwrap();
+    client
+        .execute(
+            "INSERT INTO email_verifications (user_id, token_hash, expires_at)
+             VALUES ($1, sha256('cache-token'), now() + interval '1 day')",
+            &[&id],
+        )
+        .unwrap();
+    let path = format!("/users/{}", id);
+    server.send("GET", &path, &[], None);
+    let response = server.send("GET", &path, &[], None);
+    assert_eq!(response.header("X-Cache"), Some("HIT"));
+    assert_eq!(json(&response)["verified"], false);
+
+    let verified = server.send("GET", "/users/verify?token=cache-token", &[], None);
+    assert_eq!(verified.status, 200);
+    let response = server.send("GET", &path, &[], None);
+    assert_eq!(response.header("X-Cache"), Some("MISS"));
+    assert_eq!(json(&response)["verified"], true);
+    assert_eq!(response.header("ETag"), verified.header("ETag"));
+}
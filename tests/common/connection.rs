@@ -0,0 +1,74 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use rust_docker_pg_crud::http_client::HttpResponse;
+
+// A raw connection to a server, for requests `http_client` can't make: several on one
+// connection, or malformed ones
+
+pub struct Connection(BufReader<TcpStream>);
+
+impl Connection {
+    // `base_url` looks like `http://127.0.0.1:<port>`
+    pub fn open(base_url: &str) -> Connection {
+        let stream = TcpStream::connect(base_url.strip_prefix("http://").unwrap()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        Connection(BufReader::new(stream))
+    }
+
+    pub fn send(&mut self, raw: &str) {
+        self.0.get_mut().write_all(raw.as_bytes()).unwrap();
+    }
+
+    // Reads one response, with its Content-Length body
+    pub fn read_response(&mut self) -> HttpResponse {
+        let mut status_line = String::new();
+        self.0.read_line(&mut status_line).unwrap();
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or_else(|| panic!("expected a status line, got {:?}", status_line));
+
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            self.0.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        let mut response = HttpResponse {
+            status,
+            headers,
+            body: String::new(),
+        };
+        let length = response
+            .header("Content-Length")
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0; length];
+        self.0.read_exact(&mut body).unwrap();
+        response.body = String::from_utf8(body).unwrap();
+        response
+    }
+
+    // Asserts the server closed the connection without sending anything more
+    pub fn assert_closed(&mut self) {
+        let mut rest = Vec::new();
+        self.0.read_to_end(&mut rest).unwrap();
+        assert_eq!(String::from_utf8_lossy(&rest), "");
+    }
+}
+
+// The error code of a JSON error response
+pub fn error_code(response: &HttpResponse) -> String {
+    let body: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+    body["code"].as_str().unwrap_or_default().to_string()
+}
@@ -0,0 +1,179 @@
+use postgres::{Client, Error as PostgresError};
+use rust_docker_pg_crud::models::{Post, User};
+use rust_docker_pg_crud::repo;
+use rust_docker_pg_crud::resource::{self, Resource};
+use rust_docker_pg_crud::user_profile::{self, Profile};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Records are written through the same repository functions the handlers use, after
+// the same validation, so a factory can't set up a record the API would refuse.
+// There's no factory for groups: the schema has no groups yet.
+
+// Every factory takes the next number from this sequence so generated records
+// never collide on unique columns, and the values are predictable in failures.
+static SEQUENCE: AtomicUsize = AtomicUsize::new(1);
+
+// Builds valid users for tests.
+// `UserFactory::new().with_email("a@example.com").create(&mut client)`
+pub struct UserFactory {
+    name: String,
+    email: String,
+}
+
+impl UserFactory {
+    pub fn new() -> Self {
+        let n = SEQUENCE.fetch_add(1, Ordering::SeqCst);
+        UserFactory {
+            name: format!("User {}", n),
+            email: format!("user{}@example.com", n),
+        }
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn with_email(mut self, email: &str) -> Self {
+        self.email = email.to_string();
+        self
+    }
+
+    // JSON body accepted by `POST /users`
+    pub fn body(&self) -> String {
+        serde_json::json!({ "name": self.name, "email": self.email }).to_string()
+    }
+
+    // Creates the user with `repo::create_user` and returns its id
+    pub fn create(&self, client: &mut Client) -> Result<i32, PostgresError> {
+        repo::create_user(client, &User::new(&self.name, &self.email))
+    }
+}
+
+// Builds valid posts for a user.
+// `PostFactory::new(user_id).created_ago("2 years").create(&mut client)`
+pub struct PostFactory {
+    user_id: i32,
+    title: String,
+    body: String,
+    // How long ago it was written, as a Postgres interval; now if None
+    age: Option<String>,
+}
+
+impl PostFactory {
+    pub fn new(user_id: i32) -> Self {
+        let n = SEQUENCE.fetch_add(1, Ordering::SeqCst);
+        PostFactory {
+            user_id,
+            title: format!("Post {}", n),
+            body: format!("Body of post {}", n),
+            age: None,
+        }
+    }
+
+    pub fn with_title(mut self, title: &str) -> Self {
+        self.title = title.to_string();
+        self
+    }
+
+    pub fn with_body(mut self, body: &str) -> Self {
+        self.body = body.to_string();
+        self
+    }
+
+    // Backdates the post, e.g. `created_ago("2 years")`
+    pub fn created_ago(mut self, interval: &str) -> Self {
+        self.age = Some(interval.to_string());
+        self
+    }
+
+    // JSON body accepted by `POST /users/{id}/posts`
+    pub fn body(&self) -> String {
+        serde_json::json!({ "title": self.title, "body": self.body }).to_string()
+    }
+
+    // Creates the post with `resource::insert` and returns its id. Panics if the post
+    // wouldn't pass validation.
+    pub fn create(&self, client: &mut Client) -> Result<i32, PostgresError> {
+        let post = Post {
+            id: None,
+            user_id: Some(self.user_id),
+            title: self.title.clone(),
+            body: self.body.clone(),
+            created_at: None,
+            updated_at: None,
+        };
+        if let Err(message) = post.validate() {
+            panic!("PostFactory built an invalid post: {}", message);
+        }
+        let mut transaction = client.transaction()?;
+        let id = resource::insert(&mut transaction, &post)?.id.expect("inserted posts have an id");
+        // The API has no way to backdate a post, so that's done directly. The trigger
+        // would set `updated_at` back to now; it's off only within this transaction.
+        if let Some(age) = &self.age {
+            transaction.batch_execute("ALTER TABLE posts DISABLE TRIGGER posts_set_updated_at")?;
+            transaction.execute(
+                "UPDATE posts SET created_at = now() - $2::text::interval, updated_at = now() - $2::text::interval
+                 WHERE id = $1",
+                &[&id, age],
+            )?;
+            transaction.batch_execute("ALTER TABLE posts ENABLE TRIGGER posts_set_updated_at")?;
+        }
+        transaction.commit()?;
+        Ok(id)
+    }
+}
+
+// Builds valid profiles for a user. Fields are unset unless given.
+// `ProfileFactory::new(user_id).with_location("Lisbon").create(&mut client)`
+pub struct ProfileFactory {
+    user_id: i32,
+    bio: Option<String>,
+    avatar_url: Option<String>,
+    location: Option<String>,
+}
+
+impl ProfileFactory {
+    pub fn new(user_id: i32) -> Self {
+        ProfileFactory {
+            user_id,
+            bio: None,
+            avatar_url: None,
+            location: None,
+        }
+    }
+
+    pub fn with_bio(mut self, bio: &str) -> Self {
+        self.bio = Some(bio.to_string());
+        self
+    }
+
+    pub fn with_avatar_url(mut self, avatar_url: &str) -> Self {
+        self.avatar_url = Some(avatar_url.to_string());
+        self
+    }
+
+    pub fn with_location(mut self, location: &str) -> Self {
+        self.location = Some(location.to_string());
+        self
+    }
+
+    // JSON body accepted by `PUT /users/{id}/profile`
+    pub fn body(&self) -> String {
+        serde_json::json!({ "bio": self.bio, "avatar_url": self.avatar_url, "location": self.location }).to_string()
+    }
+
+    // Creates the profile with `user_profile::put`. Panics if the profile wouldn't pass
+    // validation.
+    pub fn create(&self, client: &mut Client) -> Result<(), PostgresError> {
+        let profile = Profile {
+            bio: self.bio.clone(),
+            avatar_url: self.avatar_url.clone(),
+            location: self.location.clone(),
+        };
+        if let Err(message) = profile.validate() {
+            panic!("ProfileFactory built an invalid profile: {}", message);
+        }
+        user_profile::put(client, self.user_id, &profile)
+    }
+}
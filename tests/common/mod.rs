@@ -0,0 +1,6 @@
+// Shared helpers for the integration tests. Not every test file uses every helper.
+#![allow(dead_code)]
+
+pub mod connection;
+pub mod factories;
+pub mod server;
@@ -0,0 +1,164 @@
+use postgres::{Client, NoTls};
+use std::env;
+use std::net::TcpListener;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+
+use rust_docker_pg_crud::admin_socket;
+use rust_docker_pg_crud::db::set_database;
+use rust_docker_pg_crud::http_client::{self, HttpResponse};
+use rust_docker_pg_crud::mock::{MockOptions, MockStore};
+use rust_docker_pg_crud::server::{self, AppState};
+
+// A real server for the integration tests, backed by a throwaway Postgres schema.
+// Each test binary gets its own schema (`it_<binary>`), dropped and recreated on its
+// first use, so runs start from empty tables without touching the app's own data.
+// Needs DATABASE_URL pointing at a database the user may create schemas in; without
+// it the tests that need the server are skipped.
+
+// Bearer token the SCIM endpoints are started with
+pub const SCIM_TOKEN: &str = "integration-test-token";
+// The one origin CORS is allowed for
+pub const CORS_ORIGIN: &str = "https://app.example";
+
+pub struct TestServer {
+    // `http://127.0.0.1:<port>`
+    pub base_url: String,
+    // `unix:<path>`, where requests are made as an admin
+    pub admin_url: String,
+    // DATABASE_URL with the test schema as the search path
+    pub database_url: String,
+}
+
+static SERVER: OnceLock<Option<TestServer>> = OnceLock::new();
+
+// The server shared by the tests of this binary, started on first use.
+// None (after saying so) when no database is configured.
+pub fn start() -> Option<&'static TestServer> {
+    let server = SERVER.get_or_init(|| {
+        let Ok(url) = env::var("DATABASE_URL") else {
+            eprintln!("DATABASE_URL is not set; skipping tests that need Postgres");
+            return None;
+        };
+        Some(TestServer::start(&url))
+    });
+    server.as_ref()
+}
+
+// Runs a mock mode server (no database needed) on a free port and returns its base URL.
+// It has the settings of the environment at the time.
+pub fn start_mock() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let state = mock_state();
+    thread::spawn(move || server::serve(listener, state));
+    base_url
+}
+
+// The state of a mock mode server, with five generated users
+pub fn mock_state() -> Arc<AppState> {
+    let store = MockStore::generate(&MockOptions {
+        seed: 1,
+        users: 5,
+        data: None,
+        wal: false,
+    });
+    Arc::new(AppState::from_env(Some(store)))
+}
+
+impl TestServer {
+    fn start(url: &str) -> TestServer {
+        let binary = env::current_exe().unwrap();
+        let binary = binary.file_stem().unwrap().to_string_lossy();
+        // Test binaries are named like `routes-0123abcd`
+        let suite = binary.split('-').next().unwrap_or_default();
+        let schema = format!(
+            "it_{}",
+            suite.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+        );
+
+        let mut client = Client::connect(url, NoTls).expect("can't connect to DATABASE_URL");
+        client
+            .batch_execute(&format!(
+                "DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema};"
+            ))
+            .unwrap();
+
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let database_url = format!("{url}{separator}options=-c%20search_path%3D{schema}");
+        // The server reads its settings from the environment. Tests only get here through
+        // `start`, which holds everyone else back until the server is up.
+        env::set_var("DATABASE_URL", &database_url);
+        env::set_var("SCIM_TOKEN", SCIM_TOKEN);
+        env::set_var("CORS_ALLOWED_ORIGINS", CORS_ORIGIN);
+        env::set_var("PROFILES_ON_USER_DELETE", "restrict");
+        set_database().unwrap();
+        let state = Arc::new(AppState::from_env(None));
+
+        let socket = env::temp_dir().join(format!("{}.sock", schema));
+        admin_socket::serve(&socket.to_string_lossy(), Arc::clone(&state)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || server::serve(listener, state));
+
+        TestServer {
+            base_url,
+            admin_url: format!("unix:{}", socket.display()),
+            database_url,
+        }
+    }
+
+    // A connection to the test schema, for arranging data directly
+    pub fn client(&self) -> Client {
+        Client::connect(&self.database_url, NoTls).unwrap()
+    }
+
+    pub fn send(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<&str>,
+    ) -> HttpResponse {
+        send(&self.base_url, method, path, headers, body)
+    }
+
+    // Sends a request over the admin socket
+    pub fn send_as_admin(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<&str>,
+    ) -> HttpResponse {
+        send(&self.admin_url, method, path, headers, body)
+    }
+}
+
+fn send(
+    base_url: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<&str>,
+) -> HttpResponse {
+    let headers: Vec<(String, String)> = headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    http_client::send(base_url, method, path, &headers, body)
+        .unwrap_or_else(|e| panic!("{} {} failed: {}", method, path, e))
+}
+
+// The parsed JSON body of a response
+pub fn json(response: &HttpResponse) -> serde_json::Value {
+    serde_json::from_str(&response.body)
+        .unwrap_or_else(|_| panic!("expected a JSON body, got {:?}", response.body))
+}
+
+// Asserts a JSON error response with this status and error code
+pub fn assert_error(response: &HttpResponse, status: u16, code: &str) {
+    assert_eq!(response.status, status, "{}", response.body);
+    assert_eq!(json(response)["code"], code, "{}", response.body);
+}
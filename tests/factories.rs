@@ -0,0 +1,38 @@
+mod common;
+
+use common::factories::{PostFactory, ProfileFactory, UserFactory};
+
+#[test]
+fn factories_generate_unique_users() {
+    let first: serde_json::Value = serde_json::from_str(&UserFactory::new().body()).unwrap();
+    let second: serde_json::Value = serde_json::from_str(&UserFactory::new().body()).unwrap();
+
+    assert_ne!(first["email"], second["email"]);
+    assert_ne!(first["name"], second["name"]);
+}
+
+#[test]
+fn factories_apply_overrides() {
+    let body = UserFactory::new()
+        .with_name("Ada")
+        .with_email("ada@example.com")
+        .body();
+    let user: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+    assert_eq!(user["name"], "Ada");
+    assert_eq!(user["email"], "ada@example.com");
+}
+
+#[test]
+fn post_and_profile_factories_build_request_bodies() {
+    let first: serde_json::Value = serde_json::from_str(&PostFactory::new(1).body()).unwrap();
+    let second: serde_json::Value = serde_json::from_str(&PostFactory::new(1).with_title("Hello").body()).unwrap();
+    assert_ne!(first["title"], second["title"]);
+    assert_eq!(second["title"], "Hello");
+
+    let profile: serde_json::Value = serde_json::from_str(&ProfileFactory::new(1).with_location("Lisbon").body()).unwrap();
+    assert_eq!(
+        profile,
+        serde_json::json!({ "bio": null, "avatar_url": null, "location": "Lisbon" })
+    );
+}
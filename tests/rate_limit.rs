@@ -0,0 +1,67 @@
+mod common;
+
+use std::env;
+use std::sync::Once;
+
+use common::connection::{error_code, Connection};
+use common::server;
+
+// Rate limiting (see `rate_limit`), in its own test binary so the servers can be
+// started with it turned on. One request per client every 100 seconds, so tests never
+// see a token refill.
+
+static SETTINGS: Once = Once::new();
+
+fn start_mock_server() -> String {
+    SETTINGS.call_once(|| {
+        env::set_var("RATE_LIMIT_RPS", "0.01");
+        env::set_var("RATE_LIMIT_BURST", "1");
+        env::set_var("RATE_LIMIT_BY_API_KEY", "true");
+        env::set_var("RATE_LIMIT_API_KEYS", "partner");
+    });
+    server::start_mock()
+}
+
+#[test]
+fn limited_requests_get_a_429_and_close_when_asked() {
+    let base_url = start_mock_server();
+    let mut connection = Connection::open(&base_url);
+
+    connection.send("GET /users HTTP/1.1\r\nHost: test\r\n\r\n");
+    assert_eq!(connection.read_response().status, 200);
+
+    // Kept alive like any other response
+    connection.send("GET /users HTTP/1.1\r\nHost: test\r\n\r\n");
+    let response = connection.read_response();
+    assert_eq!(response.status, 429);
+    assert_eq!(error_code(&response), "RATE_LIMITED");
+    assert_eq!(response.header("Retry-After"), Some("100"));
+    assert_eq!(response.header("Connection"), Some("keep-alive"));
+
+    // And closed at once when the client asked for that, with nothing more sent
+    connection.send("GET /users HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n");
+    let response = connection.read_response();
+    assert_eq!(response.status, 429);
+    assert_eq!(response.header("Connection"), Some("close"));
+    connection.assert_closed();
+}
+
+#[test]
+fn only_known_api_keys_escape_the_ip_limit() {
+    let base_url = start_mock_server();
+    let mut connection = Connection::open(&base_url);
+    let mut send = |api_key: &str| {
+        connection.send(&format!(
+            "GET /users HTTP/1.1\r\nHost: test\r\nX-Api-Key: {}\r\n\r\n",
+            api_key
+        ));
+        connection.read_response().status
+    };
+
+    // A new made-up key for each request still spends the IP's tokens
+    assert_eq!(send("made-up-1"), 200);
+    assert_eq!(send("made-up-2"), 429);
+    // A configured key has a bucket of its own
+    assert_eq!(send("partner"), 200);
+    assert_eq!(send("partner"), 429);
+}
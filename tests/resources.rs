@@ -0,0 +1,90 @@
+mod common;
+
+use postgres::types::ToSql;
+use postgres::{Error as PostgresError, Row};
+use serde_derive::{Deserialize, Serialize};
+
+use common::server;
+use rust_docker_pg_crud::resource::{self, Resource};
+
+// The generic resource queries against a real database (see `common::server`)
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Note {
+    #[serde(default)]
+    id: Option<i32>,
+    title: String,
+    pinned: bool,
+    #[serde(default)]
+    updated_at: Option<String>,
+}
+
+impl Resource for Note {
+    const TABLE: &'static str = "notes";
+    const NAME: &'static str = "Note";
+    const SCHEMA: &'static str = "title TEXT NOT NULL, pinned BOOLEAN NOT NULL DEFAULT false";
+    const COLUMNS: &'static [&'static str] = &["title", "pinned"];
+
+    fn from_row(row: &Row) -> Result<Self, PostgresError> {
+        Ok(Note {
+            id: Some(row.try_get("id")?),
+            title: row.try_get("title")?,
+            pinned: row.try_get("pinned")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+
+    fn values(&self) -> Vec<&(dyn ToSql + Sync)> {
+        vec![&self.title, &self.pinned]
+    }
+}
+
+fn note(title: &str, pinned: bool) -> Note {
+    Note {
+        id: None,
+        title: title.to_string(),
+        pinned,
+        updated_at: None,
+    }
+}
+
+#[test]
+fn resources_are_created_updated_and_deleted() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let mut client = server.client();
+    resource::create_table::<Note>(&mut client).unwrap();
+    // Creating it again is a no-op
+    resource::create_table::<Note>(&mut client).unwrap();
+
+    let created = resource::insert(&mut client, &note("First", false)).unwrap();
+    let id = created.id.unwrap();
+    assert_eq!(created.title, "First");
+    assert!(created.updated_at.is_some());
+    assert_eq!(
+        resource::find::<Note>(&mut client, id).unwrap(),
+        Some(created)
+    );
+
+    let updated = resource::update(&mut client, id, &note("Renamed", true))
+        .unwrap()
+        .unwrap();
+    assert_eq!((updated.title.as_str(), updated.pinned), ("Renamed", true));
+    assert_eq!(
+        resource::update(&mut client, i32::MAX, &note("Nobody", true)).unwrap(),
+        None
+    );
+
+    resource::insert(&mut client, &note("Second", false)).unwrap();
+    let titles: Vec<String> = resource::list::<Note>(&mut client)
+        .unwrap()
+        .into_iter()
+        .map(|note| note.title)
+        .collect();
+    assert_eq!(titles, ["Renamed", "Second"]);
+
+    assert!(resource::delete::<Note>(&mut client, id).unwrap());
+    assert!(!resource::delete::<Note>(&mut client, id).unwrap());
+    assert_eq!(resource::find::<Note>(&mut client, id).unwrap(), None);
+}
@@ -0,0 +1,1660 @@
+mod common;
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use rust_docker_pg_crud::ops;
+use rust_docker_pg_crud::output::OutputFormat;
+
+use common::factories::{PostFactory, ProfileFactory, UserFactory};
+use common::server::{self, assert_error, json, CORS_ORIGIN, SCIM_TOKEN};
+
+// Every route against a real server and database (see `common::server`)
+
+#[test]
+fn users_are_created_listed_and_fetched() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let factory = UserFactory::new().with_name("Created Over HTTP");
+
+    let response = server.send("POST", "/users", &[], Some(&factory.body()));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "User Created");
+
+    let response = server.send("GET", "/users", &[], None);
+    assert_eq!(response.status, 200);
+    let created = json(&response)
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|user| user["name"] == "Created Over HTTP")
+        .cloned()
+        .expect("the new user is listed");
+    assert_eq!(created["version"], 1);
+
+    let path = format!("/users/{}", created["id"]);
+    let response = server.send("GET", &path, &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response), created);
+    let etag = response.header("ETag").unwrap().to_string();
+
+    let response = server.send("GET", &path, &[("If-None-Match", &etag)], None);
+    assert_eq!(response.status, 304);
+    assert_eq!(response.body, "");
+
+    let response = server.send("HEAD", &path, &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "");
+    assert!(response
+        .header("Content-Length")
+        .is_some_and(|length| length != "0"));
+}
+
+#[test]
+fn retried_creates_are_applied_once() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let factory = UserFactory::new().with_name("Created Once");
+    let headers = [("Idempotency-Key", "8e03978e-created-once")];
+
+    let first = server.send("POST", "/users", &headers, Some(&factory.body()));
+    assert_eq!(first.status, 200);
+    assert_eq!(first.header("Idempotent-Replayed"), None);
+    let retry = server.send("POST", "/users", &headers, Some(&factory.body()));
+    assert_eq!(retry.status, 200);
+    assert_eq!(retry.body, first.body);
+    assert_eq!(retry.header("Idempotent-Replayed"), Some("true"));
+
+    let created = server
+        .client()
+        .query_one("SELECT count(*) FROM users WHERE name = 'Created Once'", &[])
+        .unwrap();
+    assert_eq!(created.get::<_, i64>(0), 1);
+
+    let other = UserFactory::new().with_name("Created Once");
+    let response = server.send("POST", "/users", &headers, Some(&other.body()));
+    assert_error(&response, 422, "IDEMPOTENCY_KEY_REUSED");
+    let long_key = "k".repeat(256);
+    let response = server.send("POST", "/users", &[("Idempotency-Key", &long_key)], Some(&factory.body()));
+    assert_error(&response, 400, "INVALID_HEADER");
+}
+
+#[test]
+fn checksums_cover_the_filtered_users() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let mut client = server.client();
+    let id = UserFactory::new().with_name("Checksummed").create(&mut client).unwrap();
+    // A window of two microseconds around the user's creation, which nobody else shares
+    let format = r#"'YYYY-MM-DD"T"HH24:MI:SS.US"Z"'"#;
+    let row = client
+        .query_one(
+            &format!(
+                "SELECT to_char((created_at - interval '1 microsecond') AT TIME ZONE 'UTC', {format}),
+                        to_char((created_at + interval '1 microsecond') AT TIME ZONE 'UTC', {format}),
+                        name, email
+                 FROM users WHERE id = $1"
+            ),
+            &[&id],
+        )
+        .unwrap();
+    let (after, before): (String, String) = (row.get(0), row.get(1));
+    let sha256 = |text: &str| -> String {
+        Sha256::digest(text.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+    };
+
+    let path = format!("/users/checksum?created_after={}&created_before={}", after, before);
+    let response = server.send("GET", &path, &[], None);
+    assert_eq!(response.status, 200);
+    let line = format!("{}\t{}\t{}\n", id, row.get::<_, String>(2), row.get::<_, String>(3));
+    assert_eq!(json(&response), serde_json::json!({ "count": 1, "sha256": sha256(&line) }));
+
+    let response = server.send("GET", "/users/checksum?created_before=1970-01-01T00:00:00Z", &[], None);
+    assert_eq!(json(&response)["count"], 0);
+    assert_eq!(json(&response)["sha256"], sha256(""));
+
+    let response = server.send("GET", "/users/checksum?created_after=not-a-date", &[], None);
+    assert_error(&response, 400, "INVALID_QUERY");
+}
+
+#[test]
+fn users_are_fetched_in_batches() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let mut client = server.client();
+    let first = UserFactory::new().create(&mut client).unwrap();
+    let second = UserFactory::new().create(&mut client).unwrap();
+    let deleted = UserFactory::new().create(&mut client).unwrap();
+    assert_eq!(server.send("DELETE", &format!("/users/{}", deleted), &[], None).status, 200);
+
+    let body = format!(r#"{{"ids": [{}, 2147483647, {}, {}, {}]}}"#, second, first, deleted, second);
+    let response = server.send("POST", "/users/batch-get", &[], Some(&body));
+    assert_eq!(response.status, 200);
+    let result = json(&response);
+    let ids: Vec<_> = result["users"].as_array().unwrap().iter().map(|user| user["id"].as_i64().unwrap()).collect();
+    assert_eq!(ids, [second as i64, first as i64]);
+    assert_eq!(result["missing"], serde_json::json!([2147483647, deleted]));
+
+    let path = "/users/batch-get?include_deleted=true";
+    assert_error(&server.send("POST", path, &[], Some(&body)), 403, "FORBIDDEN");
+    let response = server.send_as_admin("POST", path, &[], Some(&body));
+    assert_eq!(json(&response)["users"].as_array().unwrap().len(), 3);
+
+    let too_many = serde_json::json!({ "ids": vec![1; 1001] }).to_string();
+    assert_error(&server.send("POST", "/users/batch-get", &[], Some(&too_many)), 400, "INVALID_BODY");
+    assert_error(&server.send("POST", "/users/batch-get", &[], Some(r#"{"ids": "1,2"}"#)), 400, "INVALID_BODY");
+}
+
+#[test]
+fn migrations_are_listed_with_their_checksums() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    assert_error(&server.send("GET", "/admin/migrations", &[], None), 403, "FORBIDDEN");
+    let response = server.send_as_admin("GET", "/admin/migrations", &[], None);
+    assert_eq!(response.status, 200);
+    let migrations = json(&response);
+    let migrations = migrations.as_array().unwrap();
+    assert!(migrations.iter().all(|migration| migration["status"] == "applied"));
+    assert_eq!(migrations[0]["name"], "create_users");
+
+    // An edited migration shows up as such; the server doesn't run it again
+    let mut client = server.client();
+    client.execute("UPDATE schema_migrations SET checksum = 'edited' WHERE version = 1", &[]).unwrap();
+    let response = server.send_as_admin("GET", "/admin/migrations", &[], None);
+    let migration = json(&response)[0].clone();
+    assert_eq!(migration["status"], "modified");
+    assert_eq!(migration["applied_checksum"], "edited");
+    client
+        .execute("UPDATE schema_migrations SET checksum = $1 WHERE version = 1", &[&migration["checksum"].as_str()])
+        .unwrap();
+}
+
+#[test]
+fn user_counts_are_aggregated() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let mut client = server.client();
+    UserFactory::new().with_email("one@stats.example").create(&mut client).unwrap();
+    UserFactory::new().with_email("two@STATS.example").create(&mut client).unwrap();
+    let response = server.send("GET", "/users/stats", &[], None);
+    assert_eq!(response.status, 200);
+    let stats = json(&response);
+    assert!(stats["total"].as_i64().unwrap() >= 2);
+    let domains = stats["by_domain"].as_array().unwrap();
+    assert!(domains.contains(&serde_json::json!({ "domain": "stats.example", "count": 2 })));
+    let days = stats["created_per_day"].as_array().unwrap();
+    assert_eq!(days.len(), 30);
+    assert!(days.last().unwrap()["count"].as_i64().unwrap() >= 2);
+
+    assert_error(&server.send("GET", "/users/stats?include_deleted=true", &[], None), 403, "FORBIDDEN");
+    assert_error(&server.send("GET", "/users/stats?created_after=soon", &[], None), 400, "INVALID_QUERY");
+}
+
+#[test]
+fn users_are_upserted_by_email() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let path = "/users/by-email/synced+1%40example.com";
+    let response = server.send("PUT", path, &[], Some(r#"{"name": "Synced"}"#));
+    assert_eq!(response.status, 201, "{}", response.body);
+    let created = json(&response);
+    assert_eq!(created["email"], "synced+1@example.com");
+    assert_eq!(response.header("Location"), Some(format!("/users/{}", created["id"]).as_str()));
+
+    let response = server.send("PUT", path, &[], Some(r#"{"name": "Synced Again"}"#));
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["id"], created["id"]);
+    assert_eq!(json(&response)["version"], 2);
+    let response = server.send("PUT", path, &[], Some(r#"{"name": "Synced Again"}"#));
+    assert_eq!(json(&response)["version"], 2);
+    let audit = json(&server.send("GET", &format!("/users/{}/audit", created["id"]), &[], None));
+    assert_eq!(audit.as_array().unwrap().len(), 2);
+
+    let body = r#"{"name": "Synced", "email": "other@example.com"}"#;
+    assert_error(&server.send("PUT", path, &[], Some(body)), 422, "VALIDATION_FAILED");
+    // Live users' emails are unique
+    let body = UserFactory::new().with_email("synced+1@example.com").body();
+    assert_error(&server.send("POST", "/users", &[], Some(&body)), 409, "EMAIL_TAKEN");
+}
+
+#[test]
+fn lists_can_be_narrowed_to_some_fields() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    UserFactory::new().create(&mut server.client()).unwrap();
+    // The list is streamed to HTTP/1.1 clients like this one
+    let response = server.send("GET", "/users?fields=email,id", &[], None);
+    assert_eq!(response.status, 200);
+    let users = json(&response);
+    let user = users
+        .as_array()
+        .unwrap()
+        .first()
+        .unwrap()
+        .as_object()
+        .unwrap();
+    let keys: Vec<&str> = user.keys().map(String::as_str).collect();
+    assert_eq!(keys.len(), 2);
+    assert!(user.contains_key("id") && user.contains_key("email"));
+    assert!(response.body.starts_with(r#"[{"id""#));
+
+    // Clients that take trailers can check that the list arrived whole
+    let headers = [("TE", "trailers")];
+    let response = server.send("GET", "/users?fields=email,id", &headers, None);
+    let rows = json(&response).as_array().unwrap().len().to_string();
+    assert_eq!(response.header("X-Row-Count"), Some(rows.as_str()));
+    let checksum: String = Sha256::digest(response.body.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    assert_eq!(response.header("X-Content-SHA256"), Some(checksum.as_str()));
+
+    // Or ask for one user per line
+    let headers = [("Accept", "application/x-ndjson")];
+    let response = server.send("GET", "/users?fields=email,id", &headers, None);
+    assert_eq!(response.header("Content-Type"), Some("application/x-ndjson"));
+    assert!(response.body.ends_with('\n'));
+    let lines: Vec<serde_json::Value> = response
+        .body
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(lines.iter().all(|user| user.as_object().unwrap().len() == 2));
+
+    let response = server.send("GET", "/users?fields=id,password", &[], None);
+    assert_error(&response, 400, "INVALID_QUERY");
+}
+
+#[test]
+fn users_are_found_by_words_in_name_and_email() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let mut client = server.client();
+    let by_email = UserFactory::new()
+        .with_name("Otto Nobody")
+        .with_email("quixotic.reader@example.com")
+        .create(&mut client)
+        .unwrap();
+    let by_name = UserFactory::new()
+        .with_name("Philippa Quixotic")
+        .with_email("pq@example.com")
+        .create(&mut client)
+        .unwrap();
+    let deleted = UserFactory::new()
+        .with_name("Quixotic Gone")
+        .create(&mut client)
+        .unwrap();
+    let response = server.send("DELETE", &format!("/users/{}", deleted), &[], None);
+    assert_eq!(response.status, 200);
+
+    // Name matches rank above email matches; deleted users aren't found
+    let response = server.send("GET", "/users/search?q=quixo", &[], None);
+    assert_eq!(response.status, 200);
+    let ids: Vec<i64> = json(&response)
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| user["id"].as_i64().unwrap())
+        .collect();
+    assert_eq!(ids, [by_name as i64, by_email as i64]);
+
+    let response = server.send("GET", "/users/search?q=quixotic+phil&limit=5", &[], None);
+    assert_eq!(json(&response)[0]["name"], "Philippa Quixotic");
+    let response = server.send("GET", "/users/search?q=quixotic&limit=1", &[], None);
+    assert_eq!(json(&response).as_array().unwrap().len(), 1);
+
+    let response = server.send("GET", "/users/search?q=%21%21", &[], None);
+    assert_error(&response, 400, "INVALID_QUERY");
+    let response = server.send("GET", "/users/search?q=ada&limit=0", &[], None);
+    assert_error(&response, 400, "INVALID_QUERY");
+}
+
+#[test]
+fn invalid_requests_get_error_codes() {
+    let Some(server) = server::start() else {
+        return;
+    };
+
+    let response = server.send("POST", "/users", &[], Some("not json"));
+    assert_error(&response, 400, "INVALID_BODY");
+    let response = server.send("POST", "/users", &[], Some(r#"{"name": "Ada", "email": 5}"#));
+    assert_error(&response, 400, "INVALID_BODY");
+    assert!(json(&response)["message"].as_str().unwrap().starts_with("Invalid body at email: "));
+    let response = server.send("GET", "/users/abc", &[], None);
+    assert_error(&response, 400, "INVALID_ID");
+    for malformed in ["0", "-1", "+5", "5x", "2147483648"] {
+        let response = server.send("GET", &format!("/users/{}", malformed), &[], None);
+        assert_error(&response, 400, "INVALID_ID");
+    }
+    let response = server.send("DELETE", "/users/abc", &[], None);
+    assert_error(&response, 400, "INVALID_ID");
+    let response = server.send("GET", "/users/2147483647", &[], None);
+    assert_error(&response, 404, "USER_NOT_FOUND");
+    let response = server.send("GET", "/users?created_after=not-a-date", &[], None);
+    assert_error(&response, 400, "INVALID_QUERY");
+    let response = server.send("GET", "/nowhere", &[], None);
+    assert_error(&response, 404, "ROUTE_NOT_FOUND");
+
+    let response = server.send("PUT", "/users", &[], None);
+    assert_error(&response, 405, "METHOD_NOT_ALLOWED");
+    assert_eq!(
+        response.header("Allow"),
+        Some("GET, HEAD, POST, PATCH, OPTIONS")
+    );
+    let response = server.send("OPTIONS", "/users/1", &[], None);
+    assert_eq!(response.status, 204);
+    assert_eq!(
+        response.header("Allow"),
+        Some("GET, HEAD, PUT, PATCH, DELETE, OPTIONS")
+    );
+
+    let response = server.send("GET", "/users/abc", &[("X-Request-Id", "trace-1")], None);
+    assert_eq!(response.header("X-Request-Id"), Some("trace-1"));
+    assert_eq!(json(&response)["request_id"], "trace-1");
+
+    // Oversized bodies are refused from the Content-Length alone
+    let mut socket = TcpStream::connect(server.base_url.trim_start_matches("http://")).unwrap();
+    socket
+        .write_all(b"POST /users HTTP/1.1\r\nContent-Length: 1073741824\r\n\r\n{")
+        .unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+    assert!(response.contains("PAYLOAD_TOO_LARGE"), "{}", response);
+
+    // Chunked bodies aren't read, so the connection is closed rather than their
+    // chunks being taken for the next request
+    let requests: [(&[u8], &str); 2] = [
+        (b"POST /users HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}\r\n0\r\n\r\n", "HTTP/1.1 501 "),
+        (b"POST /users HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 2\r\n\r\n{}", "HTTP/1.1 400 "),
+    ];
+    for (request, status_line) in requests {
+        let mut socket = TcpStream::connect(server.base_url.trim_start_matches("http://")).unwrap();
+        socket.write_all(request).unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with(status_line), "{}", response);
+        assert_eq!(response.matches("HTTP/1.1 ").count(), 1, "{}", response);
+        assert!(response.contains("Connection: close"), "{}", response);
+    }
+}
+
+#[test]
+fn malformed_input_never_takes_the_server_down() {
+    let Some(server) = server::start() else {
+        return;
+    };
+
+    let bodies = [
+        "",
+        "[]",
+        "null",
+        r#"{"name": 1, "email": []}"#,
+        r#"{"name": "\u0000", "email": "é@\ud83d"}"#,
+        r#"{"name": "Ada", "email": "ada@example.com", "version": 99999999999999999999}"#,
+    ];
+    for body in bodies {
+        let response = server.send("POST", "/users", &[], Some(body));
+        assert!(response.status < 500, "{:?}: {}", body, response.body);
+        let response = server.send("PATCH", "/users/1", &[("If-Match", "*")], Some(body));
+        assert!(response.status < 500, "{:?}: {}", body, response.body);
+    }
+    for query in [
+        "filter=%ZZ",
+        "window=-1",
+        "created_before=%00",
+        "include_deleted",
+    ] {
+        let response = server.send("GET", &format!("/users?{}", query), &[], None);
+        assert!(response.status < 500, "{}: {}", query, response.body);
+    }
+
+    // Postgres refuses NUL characters; that's a bad request, not a server error
+    let id = UserFactory::new().create(&mut server.client()).unwrap();
+    let response = server.send(
+        "PATCH",
+        &format!("/users/{}", id),
+        &[("If-Match", "*")],
+        Some(r#"{"name": "\u0000"}"#),
+    );
+    assert_error(&response, 400, "INVALID_BODY");
+
+    let response = server.send("GET", "/errors", &[], None);
+    assert_eq!(response.status, 200);
+}
+
+#[test]
+fn error_codes_are_listed() {
+    let Some(server) = server::start() else {
+        return;
+    };
+
+    let response = server.send("GET", "/errors", &[], None);
+    assert_eq!(response.status, 200);
+    let errors = json(&response)["errors"].as_array().unwrap().clone();
+    let not_found = errors
+        .iter()
+        .find(|error| error["code"] == "USER_NOT_FOUND")
+        .unwrap();
+    assert_eq!(not_found["status"], 404);
+}
+
+#[test]
+fn readiness_is_reported_and_checked() {
+    let Some(server) = server::start() else {
+        return;
+    };
+
+    let response = server.send("GET", "/readyz", &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["status"], "ready");
+    ops::healthcheck(&server.base_url, Duration::from_secs(5), OutputFormat::Json).unwrap();
+    // Nothing listens here
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let url = format!("http://{}", closed);
+    assert!(ops::healthcheck(&url, Duration::from_secs(5), OutputFormat::Json).is_err());
+
+    assert_error(&server.send("GET", "/health/detail", &[], None), 403, "FORBIDDEN");
+    let response = server.send_as_admin("GET", "/health/detail", &[], None);
+    assert_eq!(response.status, 200);
+    let report = json(&response);
+    // Other tests' webhook receivers may be failing, which doesn't take the service down
+    assert_ne!(report["status"], "down");
+    let database = &report["dependencies"][0];
+    assert_eq!(database["name"], "database");
+    assert_eq!(database["critical"], true);
+    assert_eq!(database["status"], "up");
+    assert!(database["latency_ms"].is_number());
+}
+
+#[test]
+fn updates_need_the_current_etag() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let id = UserFactory::new().create(&mut server.client()).unwrap();
+    let path = format!("/users/{}", id);
+    let etag = server
+        .send("GET", &path, &[], None)
+        .header("ETag")
+        .unwrap()
+        .to_string();
+    let body = UserFactory::new().with_name("Replaced").body();
+
+    let response = server.send("PUT", &path, &[], Some(&body));
+    assert_error(&response, 428, "PRECONDITION_REQUIRED");
+    let response = server.send("PUT", &path, &[("If-Match", "\"stale\"")], Some(&body));
+    assert_error(&response, 412, "PRECONDITION_FAILED");
+
+    let response = server.send("PUT", &path, &[("If-Match", &etag)], Some(&body));
+    assert_eq!(response.status, 200);
+    let replaced = json(&response);
+    assert_eq!(replaced["name"], "Replaced");
+    assert_eq!(replaced["version"], 2);
+    let etag = response.header("ETag").unwrap().to_string();
+
+    let response = server.send(
+        "PATCH",
+        &path,
+        &[("If-Match", &etag)],
+        Some(r#"{"name": "Patched", "version": 1}"#),
+    );
+    assert_error(&response, 409, "VERSION_CONFLICT");
+    let response = server.send(
+        "PATCH",
+        &path,
+        &[("If-Match", &etag)],
+        Some(r#"{"name": "Patched"}"#),
+    );
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["name"], "Patched");
+    assert_eq!(json(&response)["email"], replaced["email"]);
+    let response = server.send("PATCH", &path, &[("If-Match", "*")], Some("[]"));
+    assert_error(&response, 400, "INVALID_BODY");
+
+    let response = server.send("GET", &format!("{}/audit", path), &[], None);
+    assert_eq!(response.status, 200);
+    let actions: Vec<String> = json(&response)
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["action"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(actions, ["update", "update"]);
+    // Entries are kept in a partition for their month
+    let partitions: Vec<String> = server
+        .client()
+        .query(
+            "SELECT DISTINCT tableoid::regclass::text FROM audit_log WHERE entity_id = $1",
+            &[&id],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    let this_month: String = server
+        .client()
+        .query_one(
+            "SELECT to_char(now() AT TIME ZONE 'UTC', '\"audit_log_y\"YYYY\"m\"MM')",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(partitions, [this_month]);
+    let response = server.send("GET", "/users/2147483647/audit", &[], None);
+    assert_error(&response, 404, "USER_NOT_FOUND");
+}
+
+#[test]
+fn email_changes_wait_for_confirmation() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let mut client = server.client();
+    let id = UserFactory::new()
+        .with_email("before@example.com")
+        .create(&mut client)
+        .unwrap();
+    let path = format!("/users/{}", id);
+    let email_change = format!("{}/email-change", path);
+    let confirm = format!("{}/confirm", email_change);
+
+    let response = server.send(
+        "PATCH",
+        &path,
+        &[("If-Match", "*")],
+        Some(r#"{"email": "after@example.com"}"#),
+    );
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("X-Email-Change"), Some("pending"));
+    assert_eq!(json(&response)["email"], "before@example.com");
+    let response = server.send("GET", &email_change, &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["new_email"], "after@example.com");
+
+    // The token only goes out by mail, so swap in one the test knows
+    client
+        .execute(
+            "UPDATE pending_email_changes SET token_hash = sha256('known-token') WHERE user_id = $1",
+            &[&id],
+        )
+        .unwrap();
+    let response = server.send("POST", &confirm, &[], Some(r#"{"token": "guess"}"#));
+    assert_error(&response, 400, "EMAIL_TOKEN_INVALID");
+    let response = server.send("POST", &confirm, &[], Some(r#"{"token": "known-token"}"#));
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["email"], "after@example.com");
+    let response = server.send("POST", &confirm, &[], Some(r#"{"token": "known-token"}"#));
+    assert_error(&response, 404, "NO_PENDING_EMAIL_CHANGE");
+
+    let response = server.send(
+        "PATCH",
+        &path,
+        &[("If-Match", "*")],
+        Some(r#"{"email": "again@example.com"}"#),
+    );
+    assert_eq!(response.status, 200);
+    let response = server.send("DELETE", &email_change, &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "Email Change Cancelled");
+    let response = server.send("GET", &email_change, &[], None);
+    assert_error(&response, 404, "NO_PENDING_EMAIL_CHANGE");
+    let response = server.send("GET", &path, &[], None);
+    assert_eq!(json(&response)["email"], "after@example.com");
+}
+
+#[test]
+fn new_users_verify_their_email_address() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let mut client = server.client();
+    let body = UserFactory::new()
+        .with_email("unverified@example.com")
+        .body();
+    let response = server.send("POST", "/users", &[], Some(&body));
+    assert_eq!(response.status, 200);
+    let id: i32 = client
+        .query_one(
+            "SELECT id FROM users WHERE email = 'unverified@example.com'",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    let path = format!("/users/{}", id);
+    assert_eq!(
+        json(&server.send("GET", &path, &[], None))["verified"],
+        false
+    );
+
+    // The token only goes out by mail, so swap in one the test knows
+    client
+        .execute(
+            "UPDATE email_verifications SET token_hash = sha256('verify-token') WHERE user_id = $1",
+            &[&id],
+        )
+        .unwrap();
+    let response = server.send("GET", "/users/verify", &[], None);
+    assert_error(&response, 400, "INVALID_QUERY");
+    let response = server.send("GET", "/users/verify?token=guess", &[], None);
+    assert_error(&response, 400, "VERIFICATION_TOKEN_INVALID");
+    let response = server.send("GET", "/users/verify?token=verify-token", &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["id"], id);
+    assert_eq!(json(&response)["verified"], true);
+    let response = server.send("GET", "/users/verify?token=verify-token", &[], None);
+    assert_error(&response, 400, "VERIFICATION_TOKEN_INVALID");
+
+    let listed = |query: &str| -> Vec<i64> {
+        let response = server.send("GET", &format!("/users?{}", query), &[], None);
+        assert_eq!(response.status, 200);
+        json(&response)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user["id"].as_i64().unwrap())
+            .collect()
+    };
+    assert!(listed("verified=true").contains(&(id as i64)));
+    assert!(!listed("verified=false").contains(&(id as i64)));
+    let response = server.send("GET", "/users?verified=maybe", &[], None);
+    assert_error(&response, 400, "INVALID_QUERY");
+}
+
+#[test]
+fn posts_belong_to_live_users() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let mut client = server.client();
+    let id = UserFactory::new().create(&mut client).unwrap();
+    let posts = format!("/users/{}/posts", id);
+    let new_post = PostFactory::new(id).with_title("Hello").with_body("First post").body();
+    let body = Some(new_post.as_str());
+
+    let response = server.send("POST", &posts, &[], body);
+    assert_eq!(response.status, 200);
+    let post = json(&response);
+    assert_eq!(post["user_id"], id);
+    let path = format!("/posts/{}", post["id"]);
+    let response = server.send("GET", &posts, &[], None);
+    assert_eq!(json(&response).as_array().unwrap().len(), 1);
+    let expanded = format!("/users/{}?include=posts", id);
+    let response = server.send("GET", &expanded, &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["id"], id);
+    assert_eq!(json(&response)["posts"][0]["title"], "Hello");
+    assert_eq!(response.header("ETag"), None);
+    let response = server.send("GET", &format!("/users/{}?include=friends", id), &[], None);
+    assert_error(&response, 400, "INVALID_QUERY");
+
+    let response = server.send("POST", "/users/2147483647/posts", &[], body);
+    assert_error(&response, 404, "USER_NOT_FOUND");
+    let response = server.send("POST", &posts, &[], Some(r#"{"title": " ", "body": ""}"#));
+    assert_error(&response, 422, "VALIDATION_FAILED");
+
+    let response = server.send(
+        "PUT",
+        &path,
+        &[],
+        Some(r#"{"title": "Renamed", "body": "Edited"}"#),
+    );
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["title"], "Renamed");
+    assert_eq!(json(&response)["user_id"], id);
+    let moved = r#"{"user_id": 2147483647, "title": "Renamed", "body": "Edited"}"#;
+    let response = server.send("PUT", &path, &[], Some(moved));
+    assert_error(&response, 404, "USER_NOT_FOUND");
+
+    assert_eq!(server.send("DELETE", &path, &[], None).status, 204);
+    let response = server.send("GET", &path, &[], None);
+    assert_error(&response, 404, "RECORD_NOT_FOUND");
+
+    // Removing the user row takes its posts with it
+    let response = server.send("POST", &posts, &[], body);
+    let path = format!("/posts/{}", json(&response)["id"]);
+    client
+        .execute("DELETE FROM users WHERE id = $1", &[&id])
+        .unwrap();
+    let response = server.send("GET", &path, &[], None);
+    assert_error(&response, 404, "RECORD_NOT_FOUND");
+    let response = server.send("GET", &posts, &[], None);
+    assert_error(&response, 404, "USER_NOT_FOUND");
+}
+
+#[test]
+fn impersonated_changes_are_flagged() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let id = UserFactory::new().create(&mut server.client()).unwrap();
+    let start = format!("/admin/impersonate/{}", id);
+
+    assert_error(&server.send("POST", &start, &[], None), 403, "FORBIDDEN");
+    let response = server.send_as_admin("POST", "/admin/impersonate/2147483647", &[], None);
+    assert_error(&response, 404, "USER_NOT_FOUND");
+    let response = server.send_as_admin("POST", &start, &[], None);
+    assert_eq!(response.status, 200);
+    let token = json(&response)["token"].as_str().unwrap().to_string();
+    let bearer = format!("Bearer {}", token);
+
+    let response = server.send(
+        "PATCH",
+        &format!("/users/{}", id),
+        &[("Authorization", &bearer), ("If-Match", "*")],
+        Some(r#"{"name": "Changed by support"}"#),
+    );
+    assert_eq!(response.status, 200);
+    let response = server.send("GET", &format!("/users/{}/audit", id), &[], None);
+    let entries = json(&response);
+    let update = entries.as_array().unwrap().last().unwrap();
+    assert_eq!(update["action"], "update");
+    assert_eq!(update["details"]["impersonation"]["user_id"], id);
+
+    let response = server.send("GET", "/users/1", &[("Authorization", "Bearer nope")], None);
+    assert_error(&response, 401, "UNAUTHORIZED");
+    let stop = "/admin/impersonate/stop";
+    assert_error(&server.send("POST", stop, &[], None), 401, "UNAUTHORIZED");
+    let response = server.send("POST", stop, &[("Authorization", &bearer)], None);
+    assert_eq!(response.status, 200);
+    let response = server.send("POST", stop, &[("Authorization", &bearer)], None);
+    assert_error(&response, 401, "UNAUTHORIZED");
+}
+
+#[test]
+fn digests_are_sent_once_their_period_has_passed() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let id = UserFactory::new().create(&mut server.client()).unwrap();
+    let path = format!("/users/{}/notifications", id);
+    let run = "/admin/digests/run";
+
+    let response = server.send("GET", &path, &[], None);
+    assert_eq!(json(&response)["digest"], "off");
+    let response = server.send("PUT", &path, &[], Some(r#"{"digest": "hourly"}"#));
+    assert_error(&response, 400, "INVALID_BODY");
+    let response = server.send("PUT", &path, &[], Some(r#"{"digest": "daily"}"#));
+    assert_eq!(response.status, 200);
+    assert_eq!(
+        json(&server.send("GET", &path, &[], None))["digest"],
+        "daily"
+    );
+
+    assert_error(&server.send("POST", run, &[], None), 403, "FORBIDDEN");
+    // The first period has only just started
+    let response = server.send_as_admin("POST", run, &[], None);
+    assert_eq!(json(&response)["sent"], 0);
+
+    let response = server.send(
+        "PATCH",
+        &format!("/users/{}", id),
+        &[("If-Match", "*")],
+        Some(r#"{"name": "Digest reader"}"#),
+    );
+    assert_eq!(response.status, 200);
+    server
+        .client()
+        .execute(
+            "UPDATE notification_preferences
+             SET last_digest_at = now() - interval '2 days' WHERE user_id = $1",
+            &[&id],
+        )
+        .unwrap();
+    let response = server.send_as_admin("POST", run, &[], None);
+    assert_eq!(json(&response)["sent"], 1);
+    let response = server.send_as_admin("POST", run, &[], None);
+    assert_eq!(json(&response)["sent"], 0);
+
+    let missing = "/users/2147483647/notifications";
+    assert_error(
+        &server.send("GET", missing, &[], None),
+        404,
+        "USER_NOT_FOUND",
+    );
+}
+
+#[test]
+fn deleted_users_can_be_restored_by_admins() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let id = UserFactory::new().create(&mut server.client()).unwrap();
+    let path = format!("/users/{}", id);
+    let restore = format!("/users/{}/restore", id);
+
+    let response = server.send("DELETE", &path, &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "User Deleted");
+    assert_error(&server.send("GET", &path, &[], None), 404, "USER_NOT_FOUND");
+    assert_error(
+        &server.send("DELETE", &path, &[], None),
+        404,
+        "USER_NOT_FOUND",
+    );
+
+    let deleted = format!("{}?include_deleted=true", path);
+    assert_error(&server.send("GET", &deleted, &[], None), 403, "FORBIDDEN");
+    let response = server.send_as_admin("GET", &deleted, &[], None);
+    assert_eq!(response.status, 200);
+    assert!(json(&response)["deleted_at"].is_string());
+
+    assert_error(&server.send("POST", &restore, &[], None), 403, "FORBIDDEN");
+    let response = server.send_as_admin("POST", &restore, &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "User Restored");
+    assert_eq!(server.send("GET", &path, &[], None).status, 200);
+    let response = server.send_as_admin("POST", &restore, &[], None);
+    assert_error(&response, 404, "USER_NOT_FOUND");
+}
+
+#[test]
+fn past_states_are_rebuilt_from_the_audit_log() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let mut client = server.client();
+    let now = |client: &mut postgres::Client| -> String {
+        let row = client
+            .query_one(r#"SELECT to_char(clock_timestamp() AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS.US"Z"')"#, &[])
+            .unwrap();
+        row.get(0)
+    };
+    let before_creation = now(&mut client);
+    let factory = UserFactory::new().with_name("Before Rename").with_email("as-of@example.com");
+    assert_eq!(server.send("POST", "/users", &[], Some(&factory.body())).status, 200);
+    let id: i32 = client
+        .query_one("SELECT id FROM users WHERE email = 'as-of@example.com'", &[])
+        .unwrap()
+        .get(0);
+    let path = format!("/users/{}", id);
+    let before_rename = now(&mut client);
+    let response = server.send("PATCH", &path, &[("If-Match", "*")], Some(r#"{"name": "After Rename"}"#));
+    assert_eq!(response.status, 200);
+    let before_deletion = now(&mut client);
+    assert_eq!(server.send("DELETE", &path, &[], None).status, 200);
+
+    let as_of = |time: &str| format!("{}?as_of={}", path, time);
+    let response = server.send("GET", &as_of(&before_rename), &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["name"], "Before Rename");
+    assert_eq!(response.header("ETag"), None);
+    let response = server.send("GET", &as_of(&before_deletion), &[], None);
+    assert_eq!(json(&response)["name"], "After Rename");
+    assert_error(&server.send("GET", &as_of(&before_creation), &[], None), 404, "USER_NOT_FOUND");
+
+    // Deleted by now, which only admins may see
+    let latest = now(&mut client);
+    assert_error(&server.send("GET", &as_of(&latest), &[], None), 404, "USER_NOT_FOUND");
+    let response = server.send_as_admin("GET", &format!("{}&include_deleted=true", as_of(&latest)), &[], None);
+    assert!(json(&response)["deleted_at"].is_string());
+
+    assert_error(&server.send("GET", &as_of("yesterday-ish"), &[], None), 400, "INVALID_QUERY");
+    let response = server.send("GET", &format!("{}&include=posts", as_of(&latest)), &[], None);
+    assert_error(&response, 400, "INVALID_QUERY");
+}
+
+#[test]
+fn recorded_changes_are_undone_by_admins() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let factory = UserFactory::new().with_name("Before Undo").with_email("undo@example.com");
+    let id = factory.create(&mut server.client()).unwrap();
+    let path = format!("/users/{}", id);
+    let latest_entry = || {
+        let response = server.send("GET", &format!("{}/audit", path), &[], None);
+        json(&response).as_array().unwrap().last().unwrap()["id"].as_i64().unwrap()
+    };
+    let undo = |entry: i64| format!("/admin/undo/{}", entry);
+
+    let response = server.send("PATCH", &path, &[("If-Match", "*")], Some(r#"{"name": "After Undo"}"#));
+    assert_eq!(response.status, 200);
+    let rename = latest_entry();
+    assert_error(&server.send("POST", &undo(rename), &[], None), 403, "FORBIDDEN");
+    let response = server.send_as_admin("POST", &undo(rename), &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["name"], "Before Undo");
+    assert_eq!(json(&server.send("GET", &path, &[], None))["name"], "Before Undo");
+    // The user changed since: the undo itself
+    assert_error(&server.send_as_admin("POST", &undo(rename), &[], None), 409, "VERSION_CONFLICT");
+
+    assert_eq!(server.send("DELETE", &path, &[], None).status, 200);
+    let response = server.send_as_admin("POST", &undo(latest_entry()), &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["deleted_at"], serde_json::Value::Null);
+    assert_eq!(server.send("GET", &path, &[], None).status, 200);
+
+    assert_error(&server.send_as_admin("POST", "/admin/undo/9223372036854775807", &[], None), 404, "RECORD_NOT_FOUND");
+    assert_error(&server.send_as_admin("POST", "/admin/undo/latest", &[], None), 400, "INVALID_ID");
+}
+
+#[test]
+fn profiles_are_created_by_their_first_put() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let id = UserFactory::new().create(&mut server.client()).unwrap();
+    let path = format!("/users/{}/profile", id);
+
+    assert_error(&server.send("GET", &path, &[], None), 404, "RECORD_NOT_FOUND");
+    let profile = ProfileFactory::new(id).with_bio("Counts things").with_location("Lisbon").body();
+    let response = server.send("PUT", &path, &[], Some(&profile));
+    assert_eq!(response.status, 200);
+    let response = server.send("GET", &path, &[], None);
+    assert_eq!(
+        json(&response),
+        serde_json::json!({ "bio": "Counts things", "avatar_url": null, "location": "Lisbon" })
+    );
+
+    // A PUT replaces every field
+    let response = server.send("PUT", &path, &[], Some(r#"{"avatar_url": "https://example.com/me.png"}"#));
+    assert_eq!(response.status, 200);
+    let response = server.send("GET", &path, &[], None);
+    assert_eq!(json(&response)["bio"], serde_json::Value::Null);
+    assert_eq!(json(&response)["avatar_url"], "https://example.com/me.png");
+
+    let response = server.send("PUT", &path, &[], Some(r#"{"avatar_url": "ftp://example.com/me.png"}"#));
+    assert_error(&response, 422, "VALIDATION_FAILED");
+    let response = server.send("PUT", &path, &[], Some(r#"{"biography": "Counts things"}"#));
+    assert_error(&response, 400, "INVALID_BODY");
+    assert_error(&server.send("GET", "/users/2147483647/profile", &[], None), 404, "USER_NOT_FOUND");
+
+    // The test server restricts deleting users with profiles
+    let response = server.send("DELETE", &format!("/users/{}", id), &[], None);
+    assert_error(&response, 409, "DEPENDENTS_EXIST");
+    assert_eq!(json(&response)["dependents"], serde_json::json!({ "profiles": [id] }));
+    assert_eq!(server.send("GET", &format!("/users/{}", id), &[], None).status, 200);
+    let removed = server.client().execute("DELETE FROM users WHERE id = $1", &[&id]);
+    assert!(removed.is_err());
+}
+
+#[test]
+fn inactive_users_are_archived_and_restored() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let mut client = server.client();
+    let id: i32 = client
+        .query_one(
+            "INSERT INTO users (name, email, created_at, updated_at)
+             VALUES ('Dormant', 'dormant@example.com', now() - interval '3 years', now() - interval '2 years')
+             RETURNING id",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    PostFactory::new(id)
+        .with_title("Old news")
+        .created_ago("2 years")
+        .create(&mut client)
+        .unwrap();
+    ProfileFactory::new(id).with_location("Atlantis").create(&mut client).unwrap();
+    client
+        .execute("INSERT INTO notification_preferences (user_id, digest) VALUES ($1, 'weekly')", &[&id])
+        .unwrap();
+
+    assert_error(
+        &server.send("POST", "/admin/archive/run?inactive_days=365", &[], None),
+        403,
+        "FORBIDDEN",
+    );
+    let response = server.send_as_admin("POST", "/admin/archive/run", &[], None);
+    assert_error(&response, 400, "INVALID_QUERY");
+    let response = server.send_as_admin("POST", "/admin/archive/run?inactive_days=365", &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["archived"], serde_json::json!([id]));
+
+    let path = format!("/users/{}", id);
+    assert_error(&server.send("GET", &path, &[], None), 404, "USER_NOT_FOUND");
+    let response = server.send_as_admin("GET", "/admin/archived-users", &[], None);
+    let archived = json(&response);
+    let entry = archived
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|entry| entry["id"] == id)
+        .unwrap();
+    assert_eq!(
+        (&entry["user"]["name"], &entry["posts"]),
+        (&"Dormant".into(), &1.into())
+    );
+
+    let restore = format!("/admin/archived-users/{}/restore", id);
+    assert_error(&server.send("POST", &restore, &[], None), 403, "FORBIDDEN");
+    let response = server.send_as_admin("POST", &restore, &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["id"], id);
+    // The user comes back as they were, with their posts, preferences and profile
+    let user = json(&server.send("GET", &path, &[], None));
+    assert_eq!(user["name"], "Dormant");
+    assert_eq!(user["updated_at"], entry["user"]["updated_at"]);
+    let posts = json(&server.send("GET", &format!("{}/posts", path), &[], None));
+    assert_eq!(posts[0]["title"], "Old news");
+    let preferences = json(&server.send("GET", &format!("{}/notifications", path), &[], None));
+    assert_eq!(preferences["digest"], "weekly");
+    let profile = json(&server.send("GET", &format!("{}/profile", path), &[], None));
+    assert_eq!(profile["location"], "Atlantis");
+    let audit = json(&server.send("GET", &format!("{}/audit", path), &[], None));
+    let actions: Vec<&str> = audit
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["action"].as_str().unwrap())
+        .collect();
+    assert_eq!(actions, ["archive", "unarchive"]);
+
+    let response = server.send_as_admin("POST", &restore, &[], None);
+    assert_error(&response, 404, "USER_NOT_FOUND");
+}
+
+// Accepts webhook deliveries, answering 200 and passing on each request's head and body
+fn start_webhook_receiver() -> (String, mpsc::Receiver<(String, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut head = String::new();
+            while reader.read_line(&mut head).unwrap() > 2 {}
+            let length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .map_or(0, |length| length.trim().parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            let _ = sender.send((head, String::from_utf8(body).unwrap()));
+        }
+    });
+    (url, receiver)
+}
+
+#[test]
+fn webhooks_are_delivered_signed_and_retried() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let (url, requests) = start_webhook_receiver();
+    // Nothing listens on a port that was just freed
+    let unreachable = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let body = format!(r#"{{"url": "{}/hooks", "events": ["created"]}}"#, url);
+    assert_error(
+        &server.send("POST", "/webhooks", &[], Some(&body)),
+        403,
+        "FORBIDDEN",
+    );
+    let response =
+        server.send_as_admin("POST", "/webhooks", &[], Some(r#"{"url": "ftp://hooks"}"#));
+    assert_error(&response, 422, "VALIDATION_FAILED");
+    let response = server.send_as_admin("POST", "/webhooks", &[], Some(&body));
+    assert_eq!(response.status, 200);
+    let webhook = json(&response);
+    let secret = webhook["secret"].as_str().unwrap().to_string();
+    assert_eq!(secret.len(), 64);
+    let body = format!(
+        r#"{{"url": "http://{}", "events": ["created"]}}"#,
+        unreachable
+    );
+    let failing = json(&server.send_as_admin("POST", "/webhooks", &[], Some(&body)));
+
+    let factory = UserFactory::new().with_name("Hooked");
+    server.send("POST", "/users", &[], Some(&factory.body()));
+    let response = server.send_as_admin("POST", "/admin/webhooks/run", &[], None);
+    assert_eq!(response.status, 200);
+
+    // Other tests create users too, so look for this one's delivery
+    let (head, payload) = loop {
+        let (head, payload) = requests.recv_timeout(Duration::from_secs(10)).unwrap();
+        if payload.contains("Hooked") {
+            break (head, payload);
+        }
+    };
+    assert!(head.starts_with("POST /hooks HTTP/1.1"));
+    assert!(head.contains("X-Webhook-Event: created"));
+    let header = |name: &str| {
+        head.lines()
+            .find_map(|line| line.strip_prefix(name))
+            .unwrap()
+            .to_string()
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("{}.{}", header("X-Webhook-Timestamp: "), payload).as_bytes());
+    let expected: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    assert_eq!(
+        header("X-Webhook-Signature: "),
+        format!("sha256={}", expected)
+    );
+    let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+    assert_eq!(payload["type"], "created");
+
+    let deliveries = |webhook: &serde_json::Value| {
+        let path = format!("/webhooks/{}/deliveries", webhook["id"]);
+        let deliveries = json(&server.send_as_admin("GET", &path, &[], None));
+        deliveries
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|delivery| delivery["payload"]["user_id"] == payload["user_id"])
+            .cloned()
+            .unwrap()
+    };
+    let delivered = deliveries(&webhook);
+    assert!(delivered["delivered_at"].is_string());
+    assert!(delivered["next_attempt_at"].is_null());
+    assert_eq!(delivered["attempts"][0]["status"], 200);
+    // Failed deliveries are tried again later, with what went wrong kept
+    let failed = deliveries(&failing);
+    assert!(failed["delivered_at"].is_null());
+    assert!(failed["next_attempt_at"].is_string());
+    assert!(failed["attempts"][0]["status"].is_null());
+    assert!(failed["attempts"][0]["error"].is_string());
+
+    for webhook in [&webhook, &failing] {
+        let path = format!("/webhooks/{}", webhook["id"]);
+        assert_eq!(server.send_as_admin("DELETE", &path, &[], None).status, 204);
+        let response = server.send_as_admin("GET", &format!("{}/deliveries", path), &[], None);
+        assert_error(&response, 404, "RECORD_NOT_FOUND");
+    }
+}
+
+#[test]
+fn bulk_updates_are_previewed_before_they_apply() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let mut client = server.client();
+    for _ in 0..2 {
+        let n = UserFactory::new().create(&mut client).unwrap();
+        client
+            .execute(
+                "UPDATE users SET email = $1 WHERE id = $2",
+                &[&format!("bulk{}@bulk-old.example", n), &n],
+            )
+            .unwrap();
+    }
+    let path = "/users?filter=email_domain%20eq%20%22bulk-old.example%22";
+    let body = Some(r#"{"email_domain": "bulk-new.example"}"#);
+
+    assert_error(&server.send("PATCH", path, &[], body), 403, "FORBIDDEN");
+    let response = server.send_as_admin("PATCH", path, &[], body);
+    assert_error(&response, 400, "CONFIRMATION_REQUIRED");
+    let response = server.send_as_admin("PATCH", &format!("{}&confirm=true", path), &[], body);
+    assert_eq!(response.status, 200);
+    // Only the emails change, and those wait for confirmation
+    assert_eq!(json(&response)["updated"], 0);
+    assert_eq!(
+        json(&response)["pending_email_ids"]
+            .as_array()
+            .unwrap()
+            .len(),
+        2
+    );
+    // Which touched most of the pending email changes, so they're analyzed
+    let analyzed = (0..100).any(|_| {
+        thread::sleep(Duration::from_millis(100));
+        let row = client
+            .query_one(
+                "SELECT last_analyze IS NOT NULL FROM pg_stat_user_tables
+                 WHERE schemaname = current_schema() AND relname = 'pending_email_changes'",
+                &[],
+            )
+            .unwrap();
+        row.get(0)
+    });
+    assert!(analyzed);
+
+    let response = server.send_as_admin("PATCH", "/users?filter=nonsense", &[], body);
+    assert_error(&response, 400, "INVALID_QUERY");
+    let response = server.send_as_admin("PATCH", path, &[], Some("{}"));
+    assert_error(&response, 400, "INVALID_BODY");
+}
+
+#[test]
+fn changes_are_pushed_to_websocket_subscribers() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    assert_error(
+        &server.send("GET", "/ws/users", &[], None),
+        426,
+        "UPGRADE_REQUIRED",
+    );
+
+    let mut socket = TcpStream::connect(server.base_url.trim_start_matches("http://")).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    socket
+        .write_all(
+            b"GET /ws/users HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+    let mut reader = BufReader::new(socket.try_clone().unwrap());
+    let mut head = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line == "\r\n" {
+            break;
+        }
+        head.push(line.trim_end().to_string());
+    }
+    assert!(head[0].starts_with("HTTP/1.1 101"), "{:?}", head);
+    assert!(head.contains(&"Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string()));
+
+    let body = UserFactory::new()
+        .with_email("live-updates@example.com")
+        .body();
+    assert_eq!(server.send("POST", "/users", &[], Some(&body)).status, 200);
+    // Other tests change users too; only this user's events count
+    let mut next_event = || loop {
+        let (opcode, payload) = read_frame(&mut reader);
+        assert_eq!(opcode, 0x1);
+        let event: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        if event["user"]["email"] == "live-updates@example.com" {
+            return event;
+        }
+    };
+    let created = next_event();
+    assert_eq!(created["type"], "created");
+    let path = format!("/users/{}", created["user_id"]);
+    let response = server.send(
+        "PATCH",
+        &path,
+        &[("If-Match", "*")],
+        Some(r#"{"name": "Live"}"#),
+    );
+    assert_eq!(response.status, 200);
+    let updated = next_event();
+    assert_eq!(
+        (&updated["type"], &updated["user"]["name"]),
+        (&"updated".into(), &"Live".into())
+    );
+    assert!(updated["id"].as_u64() > created["id"].as_u64());
+    assert_eq!(server.send("DELETE", &path, &[], None).status, 200);
+    assert_eq!(next_event()["type"], "deleted");
+
+    // A masked close frame with status 1000 is answered with one of its own
+    socket
+        .write_all(&[0x88, 0x82, 1, 2, 3, 4, 0x03 ^ 1, 0xe8 ^ 2])
+        .unwrap();
+    loop {
+        let (opcode, payload) = read_frame(&mut reader);
+        if opcode == 0x8 {
+            assert_eq!(payload, [0x03, 0xe8]);
+            break;
+        }
+    }
+}
+
+#[test]
+fn change_events_are_streamed_and_resumed() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let connect = |last_event_id: Option<&str>| {
+        let mut socket = TcpStream::connect(server.base_url.trim_start_matches("http://")).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let resume = last_event_id
+            .map(|id| format!("Last-Event-ID: {}\r\n", id))
+            .unwrap_or_default();
+        let request = format!("GET /users/events HTTP/1.1\r\nHost: localhost\r\n{resume}\r\n");
+        socket.write_all(request.as_bytes()).unwrap();
+        let mut reader = BufReader::new(socket);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("HTTP/1.1 200"), "{}", line);
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        reader
+    };
+    // The next event's fields, like [("id", "3"), ("data", "{...}")]
+    let next_event = |reader: &mut BufReader<TcpStream>| {
+        let mut fields = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            match line.trim_end().split_once(": ") {
+                Some((field, value)) if !field.is_empty() => {
+                    fields.push((field.to_string(), value.to_string()))
+                }
+                _ if line == "\n" && !fields.is_empty() => return fields,
+                _ => {}
+            }
+        }
+    };
+    // Other tests change users too; only this user's events count
+    let next_change = |reader: &mut BufReader<TcpStream>| loop {
+        let fields = next_event(reader);
+        let data: serde_json::Value = serde_json::from_str(&fields[1].1).unwrap();
+        if data["user"]["email"] == "streamed@example.com" {
+            assert_eq!(fields[0], ("id".to_string(), data["id"].to_string()));
+            return data;
+        }
+    };
+
+    let mut events = connect(None);
+    let body = UserFactory::new().with_email("streamed@example.com").body();
+    assert_eq!(server.send("POST", "/users", &[], Some(&body)).status, 200);
+    let created = next_change(&mut events);
+    assert_eq!(created["type"], "created");
+    drop(events);
+
+    // Changes made while disconnected are sent first on reconnecting
+    let path = format!("/users/{}", created["user_id"]);
+    assert_eq!(server.send("DELETE", &path, &[], None).status, 200);
+    let mut events = connect(Some(&created["id"].to_string()));
+    assert_eq!(next_change(&mut events)["type"], "deleted");
+
+    // Unknown event IDs can't be resumed from
+    let mut events = connect(Some("999999999"));
+    assert_eq!(
+        next_event(&mut events)[0],
+        ("event".to_string(), "reset".to_string())
+    );
+}
+
+// The opcode and payload of the next (unmasked) frame from the server
+fn read_frame(reader: &mut impl Read) -> (u8, Vec<u8>) {
+    let mut header = [0; 2];
+    reader.read_exact(&mut header).unwrap();
+    let length = match header[1] {
+        126 => {
+            let mut length = [0; 2];
+            reader.read_exact(&mut length).unwrap();
+            u16::from_be_bytes(length) as usize
+        }
+        127 => {
+            let mut length = [0; 8];
+            reader.read_exact(&mut length).unwrap();
+            u64::from_be_bytes(length) as usize
+        }
+        length => length as usize,
+    };
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload).unwrap();
+    (header[0] & 0x0f, payload)
+}
+
+#[test]
+fn the_email_policy_is_managed_by_admins() {
+    let Some(server) = server::start() else {
+        return;
+    };
+
+    assert_error(
+        &server.send("GET", "/admin/email-policy", &[], None),
+        403,
+        "FORBIDDEN",
+    );
+    let response = server.send_as_admin("GET", "/admin/email-policy", &[], None);
+    assert_eq!(response.status, 200);
+    let original = response.body;
+
+    // Only this domain is denied, so concurrent tests aren't affected
+    let policy = r#"{"denied_domains": ["denied.example"]}"#;
+    let response = server.send_as_admin("PUT", "/admin/email-policy", &[], Some(policy));
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["denied_domains"][0], "denied.example");
+
+    let body = UserFactory::new()
+        .with_email("someone@denied.example")
+        .body();
+    let response = server.send("POST", "/users", &[], Some(&body));
+    assert_error(&response, 422, "EMAIL_REJECTED");
+    let response = server.send_as_admin("PUT", "/admin/email-policy", &[], Some("nope"));
+    assert_error(&response, 400, "INVALID_BODY");
+
+    let response = server.send_as_admin("PUT", "/admin/email-policy", &[], Some(&original));
+    assert_eq!(response.status, 200);
+}
+
+#[test]
+fn admin_reports_are_served() {
+    let Some(server) = server::start() else {
+        return;
+    };
+
+    for path in [
+        "/admin/data-quality",
+        "/admin/metrics/history",
+        "/admin/slow-requests",
+        "/admin/slo",
+        "/admin/features",
+        "/admin/storage",
+    ] {
+        assert_error(&server.send("GET", path, &[], None), 403, "FORBIDDEN");
+        let response = server.send_as_admin("GET", path, &[], None);
+        assert_eq!(response.status, 200, "{}", path);
+        assert!(json(&response).is_object(), "{}", path);
+    }
+    let response = server.send_as_admin("GET", "/admin/metrics/history?window=2h", &[], None);
+    let minutes = json(&response)["minutes"].as_array().unwrap().clone();
+    assert_eq!(minutes.len(), 120);
+    // The slowest request of each busy minute is named
+    assert!(minutes
+        .iter()
+        .any(|minute| minute["max_latency_request_id"].is_string()));
+    // Counted in latency buckets too; without tracing, they have no exemplars
+    let buckets = minutes[119]["latency_buckets"].as_array().unwrap();
+    assert!(buckets.last().unwrap()["le_ms"].is_null());
+    assert!(buckets.iter().map(|bucket| bucket["requests"].as_u64().unwrap()).sum::<u64>() > 0);
+    assert!(buckets.iter().all(|bucket| bucket.get("exemplar").is_none()));
+    // So is the database time of the routes that use the database
+    let database = json(&response)["database"].as_array().unwrap().clone();
+    assert!(database
+        .iter()
+        .any(|route| route["route"] == "/admin/storage" && route["p99_ms"].is_number()));
+    let response = server.send_as_admin("GET", "/admin/metrics/history?window=2w", &[], None);
+    assert_error(&response, 400, "INVALID_QUERY");
+    let response = server.send_as_admin("GET", "/admin/slo", &[], None);
+    let slo = json(&response);
+    assert!(slo["overall"]["requests"].as_u64().unwrap() > 0);
+    assert!(slo["routes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|route| route["route"] == "GET /admin/metrics/history"));
+    let response = server.send_as_admin("GET", "/admin/storage", &[], None);
+    let tables = json(&response)["tables"].as_array().unwrap().clone();
+    assert_eq!(tables[0]["table"], "users");
+    assert_eq!(tables.len(), 9);
+    assert!(tables[0]["indexes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|index| index["name"] == "users_search"));
+
+    assert_error(&server.send("GET", "/admin/support-bundle", &[], None), 403, "FORBIDDEN");
+    let response = server.send_as_admin("GET", "/admin/support-bundle", &[], None);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Type"), Some("application/x-tar"));
+    for file in ["config.json", "schema.json", "jobs.json", "logs.json", "metrics.json", "pool.json"] {
+        assert!(response.body.contains(file), "{}", file);
+    }
+    assert!(response.body.contains(r#""schema_version":"#));
+    assert!(response.body.contains(r#""max_size":"#));
+
+    let headers = [("X-Api-Key", "feature-test-key")];
+    server.send("GET", "/users?created_before=2000-01-01", &headers, None);
+    let response = server.send_as_admin("GET", "/admin/features", &[], None);
+    let report = json(&response);
+    let list = report["routes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|route| route["route"] == "GET /users")
+        .unwrap();
+    let feature = list["features"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|feature| feature["feature"] == "query:created_before")
+        .unwrap();
+    let keys: Vec<&String> = feature["api_keys"].as_object().unwrap().keys().collect();
+    assert!(
+        keys.iter().any(|key| key.starts_with("feat…")),
+        "{:?}",
+        keys
+    );
+
+    // Chaos mode is off unless CHAOS is set
+    let response = server.send_as_admin("GET", "/admin/chaos", &[], None);
+    assert_error(&response, 404, "FEATURE_DISABLED");
+}
+
+#[test]
+fn admins_can_debug_single_requests() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let mut client = server.client();
+    let id = UserFactory::new().create(&mut client).unwrap();
+    let path = format!("/users/{}", id);
+    let debug = [("X-Debug", "true")];
+
+    let response = server.send("GET", &path, &debug, None);
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["id"], id);
+
+    let response = server.send_as_admin("GET", &path, &debug, None);
+    assert_eq!(response.status, 200);
+    let body = json(&response);
+    assert_eq!(body["response"]["id"], id);
+    assert_eq!(body["debug"]["route"], "/users/{id}");
+    let stages: Vec<&str> = body["debug"]["stages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|stage| stage["name"].as_str().unwrap())
+        .collect();
+    assert!(stages.contains(&"handler"));
+    let queries = body["debug"]["queries"].as_array().unwrap();
+    assert!(queries.iter().any(
+        |query| query["sql"].as_str().unwrap().contains("FROM users") && query["ms"].is_number()
+    ));
+}
+
+#[test]
+fn scim_provisioning_needs_the_token() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let authorization = format!("Bearer {}", SCIM_TOKEN);
+    let headers = [("Authorization", authorization.as_str())];
+
+    let response = server.send("GET", "/scim/v2/Users", &[], None);
+    assert_eq!(response.status, 401);
+
+    let body = serde_json::json!({
+        "schemas": ["urn:ietf:params:scim:schemas:core:2.0:User"],
+        "userName": "scim.user@example.com",
+        "displayName": "Scim User",
+    })
+    .to_string();
+    let response = server.send("POST", "/scim/v2/Users", &headers, Some(&body));
+    assert_eq!(response.status, 201);
+    let location = response.header("Location").unwrap().to_string();
+    let response = server.send("POST", "/scim/v2/Users", &headers, Some(&body));
+    assert_eq!(response.status, 409);
+
+    let response = server.send("GET", &location, &headers, None);
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["displayName"], "Scim User");
+    let filter = "/scim/v2/Users?filter=userName%20eq%20%22scim.user%40example.com%22";
+    let response = server.send("GET", filter, &headers, None);
+    assert_eq!(json(&response)["totalResults"], 1);
+
+    let patch = serde_json::json!({
+        "schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+        "Operations": [{ "op": "replace", "path": "active", "value": false }],
+    })
+    .to_string();
+    let response = server.send("PATCH", &location, &headers, Some(&patch));
+    assert_eq!(response.status, 200);
+    assert_eq!(json(&response)["active"], false);
+    let response = server.send("DELETE", &location, &headers, None);
+    assert_eq!(response.status, 404);
+}
+
+#[test]
+fn cors_preflights_follow_the_routes() {
+    let Some(server) = server::start() else {
+        return;
+    };
+    let preflight = |path: &str, method: &str| {
+        server.send(
+            "OPTIONS",
+            path,
+            &[
+                ("Origin", CORS_ORIGIN),
+                ("Access-Control-Request-Method", method),
+            ],
+            None,
+        )
+    };
+
+    let response = preflight("/users/1", "DELETE");
+    assert_eq!(response.status, 204);
+    assert_eq!(
+        response.header("Access-Control-Allow-Origin"),
+        Some(CORS_ORIGIN)
+    );
+    assert_eq!(
+        response.header("Access-Control-Allow-Methods"),
+        Some("GET, HEAD, PUT, PATCH, DELETE, OPTIONS")
+    );
+    let response = preflight("/users/1/restore", "DELETE");
+    assert_eq!(response.header("Access-Control-Allow-Origin"), None);
+
+    // Actual requests, errors included, carry the origin for the browser
+    let response = server.send("GET", "/users/abc", &[("Origin", CORS_ORIGIN)], None);
+    assert_error(&response, 400, "INVALID_ID");
+    assert_eq!(
+        response.header("Access-Control-Allow-Origin"),
+        Some(CORS_ORIGIN)
+    );
+    let response = server.send(
+        "GET",
+        "/users",
+        &[("Origin", "https://other.example")],
+        None,
+    );
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Access-Control-Allow-Origin"), None);
+}
@@ -0,0 +1,192 @@
+mod common;
+
+use std::env;
+use std::sync::Once;
+use std::thread;
+use std::time::Duration;
+
+use common::connection::{error_code, Connection};
+use common::server;
+use rust_docker_pg_crud::http_client;
+use rust_docker_pg_crud::models::User;
+
+// Most connections each test server takes at once
+const MAX_CONNECTIONS: usize = 4;
+
+static SETTINGS: Once = Once::new();
+
+// Runs a mock mode server (no database needed) on a free port and returns its base URL.
+// Every server in this binary is started with the same settings, so they're set once,
+// before the first one.
+fn start_mock_server() -> String {
+    SETTINGS.call_once(|| {
+        env::set_var("MAX_CONNECTIONS", MAX_CONNECTIONS.to_string());
+    });
+    server::start_mock()
+}
+
+#[test]
+fn the_server_runs_in_process() {
+    let base_url = start_mock_server();
+
+    let response = http_client::send(&base_url, "GET", "/users", &[], None).unwrap();
+    assert_eq!(response.status, 200);
+    let users: Vec<User> = serde_json::from_str(&response.body).unwrap();
+    assert!(!users.is_empty());
+
+    let path = format!("/users/{}", users[0].id.unwrap());
+    let response = http_client::send(&base_url, "GET", &path, &[], None).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("ETag"), Some(users[0].etag().as_str()));
+
+    let response = http_client::send(&base_url, "PUT", "/users", &[], None).unwrap();
+    assert_eq!(response.status, 405);
+    assert_eq!(
+        response.header("Allow"),
+        Some("GET, HEAD, POST, PATCH, OPTIONS")
+    );
+}
+
+#[test]
+fn connections_are_kept_alive_until_the_client_closes_them() {
+    let base_url = start_mock_server();
+    let mut connection = Connection::open(&base_url);
+
+    for _ in 0..3 {
+        connection.send("GET /users HTTP/1.1\r\nHost: test\r\n\r\n");
+        let response = connection.read_response();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Connection"), Some("keep-alive"));
+    }
+
+    connection.send("GET /users HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n");
+    let response = connection.read_response();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Connection"), Some("close"));
+    connection.assert_closed();
+}
+
+#[test]
+fn pipelined_requests_are_answered_in_order() {
+    let base_url = start_mock_server();
+    let mut connection = Connection::open(&base_url);
+
+    let body = r#"{"name": "Pipelined", "email": "pipelined@example.com"}"#;
+    connection.send(&format!(
+        "POST /users HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n{}\
+         GET /nowhere HTTP/1.1\r\nHost: test\r\n\r\n\
+         GET /users HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        body.len(),
+        body
+    ));
+
+    assert_eq!(connection.read_response().body, "User Created");
+    assert_eq!(connection.read_response().status, 404);
+    let response = connection.read_response();
+    assert_eq!(response.status, 200);
+    assert!(response.body.contains("pipelined@example.com"));
+    connection.assert_closed();
+}
+
+#[test]
+fn untrustworthy_content_lengths_close_the_connection() {
+    let base_url = start_mock_server();
+
+    // The body would otherwise be read as a request of its own
+    for content_length in [
+        "Content-Length: abc\r\n",
+        "Content-Length: +4\r\n",
+        "Content-Length: 0\r\nContent-Length: 32\r\n",
+    ] {
+        let mut connection = Connection::open(&base_url);
+        connection.send(&format!(
+            "POST /users HTTP/1.1\r\nHost: test\r\n{}\r\nGET /errors HTTP/1.1\r\nHost: test\r\n\r\n",
+            content_length
+        ));
+        let response = connection.read_response();
+        assert_eq!(response.status, 400, "{}", content_length);
+        assert_eq!(error_code(&response), "INVALID_HEADER");
+        assert_eq!(response.header("Connection"), Some("close"));
+        connection.assert_closed();
+    }
+
+    // Copies that agree are fine, and the body is read as the body
+    let mut connection = Connection::open(&base_url);
+    connection.send(
+        "POST /users HTTP/1.1\r\nHost: test\r\nContent-Length: 2\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}",
+    );
+    let response = connection.read_response();
+    assert_eq!(error_code(&response), "INVALID_BODY", "{}", response.body);
+    connection.assert_closed();
+}
+
+#[test]
+fn oversized_headers_close_the_connection() {
+    let base_url = start_mock_server();
+    let mut connection = Connection::open(&base_url);
+
+    // One byte over the limit, with the headers never finished
+    let head = format!("GET /users HTTP/1.1\r\nX-Padding: {}", "a".repeat(16 * 1024));
+    connection.send(&head[..16 * 1024 + 1]);
+    let response = connection.read_response();
+    assert_eq!(response.status, 431);
+    assert_eq!(error_code(&response), "HEADERS_TOO_LARGE");
+    connection.assert_closed();
+}
+
+#[test]
+fn connections_over_the_limit_are_turned_away() {
+    let base_url = start_mock_server();
+
+    let mut open: Vec<_> = (0..MAX_CONNECTIONS)
+        .map(|_| Connection::open(&base_url))
+        .collect();
+    let mut turned_away = Connection::open(&base_url);
+    let response = turned_away.read_response();
+    assert_eq!(response.status, 503);
+    assert_eq!(error_code(&response), "TOO_MANY_CONNECTIONS");
+    assert_eq!(response.header("Retry-After"), Some("1"));
+    turned_away.assert_closed();
+
+    // Once one closes, there's room again
+    open[0].send("GET /users HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n");
+    assert_eq!(open[0].read_response().status, 200);
+    open[0].assert_closed();
+    // The thread may take a moment to let go of it. Until then requests are turned
+    // away, possibly before they're even read.
+    let served = (0..50).any(|_| {
+        let response = http_client::send(&base_url, "GET", "/users", &[], None);
+        if response.is_ok_and(|response| response.status == 200) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+        false
+    });
+    assert!(served);
+}
+
+#[test]
+fn latencies_are_exposed_for_prometheus() {
+    let base_url = start_mock_server();
+    http_client::send(&base_url, "GET", "/users", &[], None).unwrap();
+
+    let response = http_client::send(&base_url, "GET", "/metrics", &[], None).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(
+        response.header("Content-Type"),
+        Some("application/openmetrics-text; version=1.0.0; charset=utf-8")
+    );
+    assert!(response
+        .body
+        .contains("# TYPE http_request_duration_seconds histogram\n"));
+    // The request before this one, at least
+    let count: u64 = response
+        .body
+        .lines()
+        .find_map(|line| line.strip_prefix("http_request_duration_seconds_count "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(count >= 1);
+    assert!(response.body.ends_with("# EOF\n"));
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use std::env;
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::connection::{error_code, Connection};
+use common::server;
+
+// Socket timeouts (see `config::get_timeouts`), in their own test binary so the
+// servers can be started with them shortened to a second or two
+
+static SETTINGS: Once = Once::new();
+
+fn start_mock_server() -> String {
+    SETTINGS.call_once(|| {
+        env::set_var("READ_TIMEOUT_SECS", "1");
+        env::set_var("IDLE_TIMEOUT_SECS", "1");
+        env::set_var("REQUEST_TIMEOUT_SECS", "2");
+    });
+    server::start_mock()
+}
+
+fn assert_timed_out(connection: &mut Connection) {
+    let response = connection.read_response();
+    assert_eq!(response.status, 408);
+    assert_eq!(error_code(&response), "REQUEST_TIMEOUT");
+    assert_eq!(response.header("Connection"), Some("close"));
+    connection.assert_closed();
+}
+
+#[test]
+fn unfinished_requests_get_a_408_and_are_closed() {
+    let base_url = start_mock_server();
+    let mut connection = Connection::open(&base_url);
+
+    connection.send("GET /users HTTP/1.1\r\nHost: te");
+    assert_timed_out(&mut connection);
+}
+
+#[test]
+fn requests_trickled_in_get_a_408_at_the_deadline() {
+    let base_url = start_mock_server();
+    let mut connection = Connection::open(&base_url);
+    let started = Instant::now();
+
+    // Each byte comes well within READ_TIMEOUT_SECS, but the whole request doesn't come
+    // within REQUEST_TIMEOUT_SECS. Sending stops before the deadline, so nothing is
+    // sent to a closed connection.
+    for byte in "GET ".chars() {
+        connection.send(&byte.to_string());
+        thread::sleep(Duration::from_millis(500));
+    }
+    assert_timed_out(&mut connection);
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_secs(2), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(4), "{:?}", elapsed);
+}
+
+#[test]
+fn idle_keep_alive_connections_close_quietly() {
+    let base_url = start_mock_server();
+    let mut connection = Connection::open(&base_url);
+
+    connection.send("GET /users HTTP/1.1\r\nHost: test\r\n\r\n");
+    let response = connection.read_response();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Connection"), Some("keep-alive"));
+
+    // No 408: the client was done, not slow
+    let started = Instant::now();
+    connection.assert_closed();
+    assert!(started.elapsed() < Duration::from_secs(3));
+}